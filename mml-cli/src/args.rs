@@ -0,0 +1,252 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::config;
+use crate::input::Input;
+use mml_core::Lang;
+
+// コマンドで共通のオプションと<mml-file>
+// (読み込みの設定の--no-config, --input-mode, --encodingと<mml-file>は全てのコマンドで読み込む)
+#[derive(Default)]
+pub(crate) struct CommonArgs {
+    // <mml-file> (- は標準入力) と--exprで指定したMMLの読み込み元 (指定した順)
+    pub(crate) inputs: Vec<Input>,
+    // --output (- は標準出力)
+    pub(crate) output_file: Option<String>,
+    // --quiet (進捗を表示しない。エラーは表示する)
+    pub(crate) quiet: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    pub(crate) settings: config::Settings,
+}
+
+// コマンドが受け付ける共通のオプション
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Accepts {
+    pub(crate) output: bool,
+    pub(crate) quiet: bool,
+    pub(crate) expr: bool,
+}
+
+impl CommonArgs {
+    // 共通のオプション以外はcommand_optionで読み込む (コマンドのオプションでなければfalseを返す)
+    pub(crate) fn parse<T, F>(
+        iter: &mut T,
+        accepts: Accepts,
+        lang: Lang,
+        mut command_option: F,
+    ) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+        F: FnMut(&str, &mut T, &mut config::Settings) -> Result<bool, String>,
+    {
+        let mut args = CommonArgs::default();
+        while let Some(arg) = iter.next() {
+            match arg.as_ref() {
+                "--no-config" => args.settings.no_config = true,
+                "--input-mode" => {
+                    let mode = option_value(iter, "<input-mode>", lang)?;
+                    args.settings.input_mode = Some(parse_input_mode(&mode, lang)?);
+                }
+                "--encoding" => {
+                    let encoding = option_value(iter, "<encoding>", lang)?;
+                    args.settings.encoding = Some(parse_encoding(&encoding, lang)?);
+                }
+                "--output" if accepts.output => {
+                    args.output_file = Some(option_value(iter, "<output-file>", lang)?)
+                }
+                "--quiet" if accepts.quiet => args.quiet = true,
+                "--expr" if accepts.expr => args
+                    .inputs
+                    .push(Input::Expr(option_value(iter, "<mml>", lang)?)),
+                file if file == "-" || !file.starts_with('-') => {
+                    args.inputs.push(Input::from_arg(file))
+                }
+                option => {
+                    if !command_option(option, iter, &mut args.settings)? {
+                        return Err(tr!(
+                            lang,
+                            "不明のオプション: {}",
+                            "unknown option: {}",
+                            option
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    // <mml-file>か--exprで指定した一つの読み込み元
+    pub(crate) fn take_input(&mut self, lang: Lang) -> Result<Input, String> {
+        match self.inputs.len() {
+            0 => Err(tr!(
+                lang,
+                "<mml-file>が指定されていません",
+                "<mml-file> is not specified"
+            )),
+            1 => Ok(self.inputs.pop().unwrap()),
+            _ => Err(tr!(
+                lang,
+                "読み込むMMLは一つだけ指定できます (<mml-file>か--expr): {}",
+                "specify only one MML to read (<mml-file> or --expr): {}",
+                self.inputs[1].name(lang)
+            )),
+        }
+    }
+
+    // ファイルだけを読み込むコマンドの<mml-file>
+    pub(crate) fn take_input_file(&mut self, lang: Lang) -> Result<String, String> {
+        match self.take_input(lang)? {
+            Input::File(file) => Ok(file),
+            _ => Err(tr!(
+                lang,
+                "<mml-file>にはファイルを指定してください (標準入力(-)は使用できません)",
+                "<mml-file> must be a file (stdin (-) cannot be used)"
+            )),
+        }
+    }
+}
+
+// オプションの値 (次のコマンドライン引数)
+pub(crate) fn option_value<T>(iter: &mut T, name: &str, lang: Lang) -> Result<String, String>
+where
+    T: Iterator,
+    T::Item: AsRef<str>,
+{
+    match iter.next() {
+        Some(value) => Ok(value.as_ref().to_owned()),
+        None => Err(tr!(
+            lang,
+            "{}が指定されていません",
+            "{} is not specified",
+            name
+        )),
+    }
+}
+
+fn parse_input_mode(mode: &str, lang: Lang) -> Result<mml_core::InputMode, String> {
+    mml_core::InputMode::parse(mode).ok_or_else(|| {
+        tr!(
+            lang,
+            "<input-mode>の指定が不正です: {} (standard か japanese)",
+            "invalid <input-mode>: {} (standard or japanese)",
+            mode
+        )
+    })
+}
+
+fn parse_encoding(encoding: &str, lang: Lang) -> Result<mml_core::TextEncoding, String> {
+    mml_core::TextEncoding::parse(encoding).ok_or_else(|| {
+        tr!(
+            lang,
+            "<encoding>の指定が不正です: {} (auto, utf-8, utf-16le, utf-16be, shift_jis, euc-jp)",
+            "invalid <encoding>: {} (auto, utf-8, utf-16le, utf-16be, shift_jis, euc-jp)",
+            encoding
+        )
+    })
+}
+
+pub(crate) fn parse_instrument(num_str: &str, lang: Lang) -> Result<mml_core::Instrument, String> {
+    match num_str.parse::<usize>() {
+        Ok(num) if (1..=mml_core::INSTRUMENTS.len()).contains(&num) => {
+            Ok(mml_core::INSTRUMENTS[num - 1])
+        }
+        _ => Err(tr!(
+            lang,
+            "<instrument-number>の指定が不正です: {}",
+            "invalid <instrument-number>: {}",
+            num_str
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str], accepts: Accepts) -> Result<CommonArgs, String> {
+        CommonArgs::parse(
+            &mut args.iter(),
+            accepts,
+            Lang::En,
+            |arg, iter, settings| {
+                match arg {
+                    "--karaoke" => settings.karaoke = Some(true),
+                    "--instrument" => {
+                        let num_str = option_value(iter, "<instrument-number>", Lang::En)?;
+                        settings.instrument = Some(parse_instrument(&num_str, Lang::En)?);
+                    }
+                    _ => return Ok(false),
+                }
+                Ok(true)
+            },
+        )
+    }
+
+    #[test]
+    fn common_options() {
+        let all = Accepts {
+            output: true,
+            quiet: true,
+            expr: true,
+        };
+        let args = parse(
+            &[
+                "a.mml",
+                "--encoding",
+                "shift_jis",
+                "--output",
+                "-",
+                "--quiet",
+                "--karaoke",
+                "-",
+                "--expr",
+                "C D",
+                "--no-config",
+            ],
+            all,
+        )
+        .unwrap();
+        let inputs: Vec<String> = args.inputs.iter().map(|i| i.name(Lang::En)).collect();
+        assert_eq!(inputs, ["a.mml", "(stdin)", "(--expr)"]);
+        assert_eq!(args.output_file.as_deref(), Some("-"));
+        assert!(args.quiet);
+        assert!(args.settings.no_config);
+        assert_eq!(args.settings.karaoke, Some(true));
+        assert_eq!(
+            args.settings.encoding,
+            Some(mml_core::TextEncoding::ShiftJis)
+        );
+
+        // 受け付けない共通のオプションとコマンドのオプションでないものは不明のオプション
+        for option in ["--output", "--quiet", "--expr", "--watch"] {
+            let res = parse(&["a.mml", option, "b"], Accepts::default());
+            assert_eq!(res.err(), Some(format!("unknown option: {}", option)));
+        }
+        let res = parse(&["a.mml", "--output"], all);
+        assert_eq!(res.err().as_deref(), Some("<output-file> is not specified"));
+        let res = parse(&["--instrument", "0"], all);
+        assert_eq!(res.err().as_deref(), Some("invalid <instrument-number>: 0"));
+        let res = parse(&["--encoding", "latin1"], all);
+        assert!(res.err().unwrap().starts_with("invalid <encoding>: latin1"));
+    }
+
+    #[test]
+    fn take_inputs() {
+        let take_file = |args: &[&str]| parse(args, Accepts::default())?.take_input_file(Lang::En);
+        assert_eq!(take_file(&["a.mml"]), Ok("a.mml".to_string()));
+        assert_eq!(
+            take_file(&[]),
+            Err("<mml-file> is not specified".to_string())
+        );
+        assert_eq!(
+            take_file(&["a.mml", "b.mml"]),
+            Err("specify only one MML to read (<mml-file> or --expr): b.mml".to_string())
+        );
+        assert_eq!(
+            take_file(&["-"]),
+            Err("<mml-file> must be a file (stdin (-) cannot be used)".to_string())
+        );
+    }
+}
@@ -0,0 +1,575 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::config;
+use crate::convert::MmlToSmfArgs;
+use crate::input::{render_errors, Input};
+use mml_core::Lang;
+
+// まとめて変換する場合のファイルごとの変換
+struct BatchJob {
+    input_file: std::path::PathBuf,
+    output_file: std::path::PathBuf,
+    settings: config::Settings,
+}
+
+// 複数のMMLファイルを並列に変換し、結果の一覧を表示する
+pub(crate) fn mml2smf_batch(
+    MmlToSmfArgs {
+        inputs,
+        output_file,
+        out_dir,
+        watch,
+        quiet,
+        settings,
+    }: MmlToSmfArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    if output_file.is_some() {
+        return Err(tr!(
+            lang,
+            "複数のファイルを変換する場合は--outputを指定できません (--out-dirで出力先のディレクトリを指定してください)",
+            "--output cannot be used when converting multiple files (specify the output directory with --out-dir)"
+        ));
+    }
+    if watch {
+        return Err(tr!(
+            lang,
+            "複数のファイルを変換する場合は--watchを指定できません",
+            "--watch cannot be used when converting multiple files"
+        ));
+    }
+    let mut jobs: Vec<(std::path::PathBuf, std::path::PathBuf)> = Vec::new();
+    for input in inputs.iter() {
+        let Input::File(arg) = input else {
+            unreachable!("only files are converted in batch");
+        };
+        let files = collect_mml_files(arg);
+        if files.is_empty() {
+            return Err(tr!(
+                lang,
+                "{}に変換するMMLファイルがありません",
+                "no MML files to convert in {}",
+                arg
+            ));
+        }
+        for (file, relative) in files {
+            if jobs.iter().all(|(other, _)| *other != file) {
+                jobs.push((file, relative));
+            }
+        }
+    }
+    // ファイルごとにmml.tomlを探して設定と出力先を決める
+    let jobs: Vec<BatchJob> = jobs
+        .into_iter()
+        .map(|(input_file, relative)| {
+            let settings = config::resolve(Some(&input_file), settings.clone(), lang)?;
+            let extension = if settings.karaoke() { "kar" } else { "mid" };
+            let output_file = batch_output_file(
+                &input_file,
+                &relative,
+                out_dir.as_deref(),
+                &settings,
+                extension,
+            );
+            Ok(BatchJob {
+                input_file,
+                output_file,
+                settings,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+    if let Some((first, second)) = find_output_collision(&jobs) {
+        return Err(tr!(
+            lang,
+            "{}と{}の出力先が同じです ({})",
+            "{} and {} would be written to the same file ({})",
+            first.input_file.display(),
+            second.input_file.display(),
+            first.output_file.display()
+        ));
+    }
+
+    let convert = |job: &BatchJob| {
+        let BatchJob {
+            input_file,
+            output_file,
+            settings,
+        } = job;
+        let song = mml_core::parse_file_all_with(input_file, &settings.parse_options())?;
+        let instrument = settings.instrument_for(&song);
+        let dst = if settings.karaoke() {
+            song.to_karaoke(instrument)
+        } else {
+            song.to_smf(instrument)
+        };
+        let dst = dst.map_err(|error| vec![error])?;
+        if let Some(dir) = output_file.parent() {
+            std::fs::create_dir_all(dir).map_err(|error| vec![error.into()])?;
+        }
+        std::fs::write(output_file, dst).map_err(|error| vec![error.into()])
+    };
+
+    // 変換する順番を共有して、空いているスレッドが次のファイルを変換する
+    let next = std::sync::atomic::AtomicUsize::new(0);
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(jobs.len());
+    let mut results: Vec<(usize, Result<(), Vec<mml_core::MMLError>>)> =
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            match jobs.get(index) {
+                                Some(job) => results.push((index, convert(job))),
+                                None => return results,
+                            }
+                        }
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("conversion thread panicked"))
+                .collect()
+        });
+    results.sort_by_key(|(index, _)| *index);
+
+    // 失敗したファイルのエラーを全て表示してから一覧を表示する
+    for (job, (_, res)) in jobs.iter().zip(results.iter()) {
+        if let Err(errors) = res {
+            eprintln!("{}", render_errors(job.input_file.display(), errors, lang));
+            eprintln!();
+        }
+    }
+    let rows: Vec<(String, String, String)> = jobs
+        .iter()
+        .zip(results.iter())
+        .filter(|(_, (_, res))| !quiet || res.is_err())
+        .map(|(job, (_, res))| {
+            let (status, detail) = match res {
+                Ok(()) => (
+                    tr!(lang, "成功", "ok"),
+                    job.output_file.display().to_string(),
+                ),
+                Err(errors) => {
+                    let error = &errors[0];
+                    let mut detail = format!("{} {}", error.code(), error.message_in(lang));
+                    if let Some(span) = error.span() {
+                        detail.push_str(&format!(" ({}:{})", span.start.row, span.start.col));
+                    }
+                    if errors.len() > 1 {
+                        detail.push_str(&tr!(
+                            lang,
+                            " ほか{}個のエラー",
+                            " and {} more error(s)",
+                            errors.len() - 1
+                        ));
+                    }
+                    (tr!(lang, "失敗", "failed"), detail)
+                }
+            };
+            (status, job.input_file.display().to_string(), detail)
+        })
+        .collect();
+    if !rows.is_empty() {
+        let header = (
+            tr!(lang, "結果", "result"),
+            tr!(lang, "入力", "input"),
+            tr!(lang, "出力またはエラー", "output or error"),
+        );
+        let width = |text: &String| text.chars().map(mml_core::char_width).sum::<usize>();
+        let status_width = rows
+            .iter()
+            .map(|row| width(&row.0))
+            .chain([width(&header.0)])
+            .max()
+            .unwrap();
+        let input_width = rows
+            .iter()
+            .map(|row| width(&row.1))
+            .chain([width(&header.1)])
+            .max()
+            .unwrap();
+        for (status, input, detail) in std::iter::once(&header).chain(rows.iter()) {
+            println!(
+                "{}{}  {}{}  {}",
+                status,
+                " ".repeat(status_width - width(status)),
+                input,
+                " ".repeat(input_width - width(input)),
+                detail
+            );
+        }
+    }
+    let failures = results.iter().filter(|(_, res)| res.is_err()).count();
+    if !quiet {
+        println!();
+        println!(
+            "{}",
+            tr!(
+                lang,
+                "成功: {}  失敗: {}",
+                "succeeded: {}  failed: {}",
+                results.len() - failures,
+                failures
+            )
+        );
+    }
+    if failures > 0 {
+        return Err(tr!(
+            lang,
+            "{}個のファイルの変換に失敗しました",
+            "failed to convert {} file(s)",
+            failures
+        ));
+    }
+    Ok(())
+}
+
+// まとめて変換する場合の出力先 (入力ファイル名に拡張子を付ける)。
+// --out-dirの中にはrelative (collect_mml_filesの相対パス) のディレクトリの構成をそのまま作る
+// (--out-dirとmml.tomlのout-dirの省略時は入力ファイルと同じディレクトリ)
+fn batch_output_file(
+    input_file: &std::path::Path,
+    relative: &std::path::Path,
+    out_dir: Option<&str>,
+    settings: &config::Settings,
+    extension: &str,
+) -> std::path::PathBuf {
+    let file_name = format!(
+        "{}.{}",
+        input_file.file_name().unwrap().to_string_lossy(),
+        extension
+    );
+    match out_dir {
+        Some(dir) => std::path::Path::new(dir)
+            .join(relative)
+            .with_file_name(file_name),
+        None => settings
+            .output_in_out_dir(input_file, extension)
+            .unwrap_or_else(|| input_file.with_file_name(file_name)),
+    }
+}
+
+// 出力先が同じになる2つの変換 (片方の出力がもう片方に上書きされる)
+fn find_output_collision(jobs: &[BatchJob]) -> Option<(&BatchJob, &BatchJob)> {
+    jobs.iter().enumerate().find_map(|(i, job)| {
+        let output_file = normalize_path(&job.output_file);
+        jobs[i + 1..]
+            .iter()
+            .find(|other| normalize_path(&other.output_file) == output_file)
+            .map(|other| (job, other))
+    })
+}
+
+// ファイル名のパターンに使う文字 (* と ?) を含むか
+pub(crate) fn is_pattern(arg: &str) -> bool {
+    arg.contains(['*', '?'])
+}
+
+// 変換するMMLファイルと出力先ディレクトリでの相対パスの組を返す。
+// ディレクトリはその中の全ての.mmlファイル、パターンは一致する全てのファイル (**は0個以上のディレクトリに一致する)。
+// 相対パスはディレクトリやパターンのディレクトリ部分からのパス
+// (ファイルはカレントディレクトリからのパス、カレントディレクトリの外ならファイル名だけ)
+fn collect_mml_files(arg: &str) -> Vec<(std::path::PathBuf, std::path::PathBuf)> {
+    let path = std::path::Path::new(arg);
+    let mut files: Vec<std::path::PathBuf> = Vec::new();
+    let base: std::path::PathBuf = if is_pattern(arg) {
+        let components: Vec<String> = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        let split = components
+            .iter()
+            .position(|c| is_pattern(c) || c == "**")
+            .unwrap();
+        let base: std::path::PathBuf = components[..split].iter().collect();
+        find_pattern(&base, &components[split..], &mut files);
+        base
+    } else if path.is_dir() {
+        find_mml_files(path, &mut files);
+        path.to_path_buf()
+    } else if path.is_file() {
+        let file = normalize_path(path);
+        let relative = relative_to_current_dir(&file);
+        return vec![(file, relative)];
+    } else {
+        return Vec::new();
+    };
+    let base = normalize_path(&base);
+    let mut files: Vec<std::path::PathBuf> = files.iter().map(|f| normalize_path(f)).collect();
+    files.sort();
+    files.dedup();
+    files
+        .into_iter()
+        .map(|file| {
+            let relative = file.strip_prefix(&base).unwrap_or(&file).to_path_buf();
+            (file, relative)
+        })
+        .collect()
+}
+
+// ./ を取り除いたパス (a/./b.mml は a/b.mml、./b.mml は b.mml にする)
+fn normalize_path(path: &std::path::Path) -> std::path::PathBuf {
+    let normalized: std::path::PathBuf = path
+        .components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect();
+    if normalized.as_os_str().is_empty() {
+        std::path::PathBuf::from(".")
+    } else {
+        normalized
+    }
+}
+
+// カレントディレクトリからの相対パス (カレントディレクトリの外のファイルはファイル名だけ)
+fn relative_to_current_dir(file: &std::path::Path) -> std::path::PathBuf {
+    let inside = file.is_relative()
+        && file
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+    if inside {
+        return file.to_path_buf();
+    }
+    let relative = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .ok()
+        .zip(file.canonicalize().ok())
+        .and_then(|(dir, file)| file.strip_prefix(dir).ok().map(|r| r.to_path_buf()));
+    relative.unwrap_or_else(|| file.file_name().unwrap().into())
+}
+
+fn read_dir_sorted(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let dir = if dir.as_os_str().is_empty() {
+        std::path::Path::new(".")
+    } else {
+        dir
+    };
+    let mut entries: Vec<std::path::PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort();
+    entries
+}
+
+fn find_mml_files(dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>) {
+    for entry in read_dir_sorted(dir) {
+        if entry.is_dir() {
+            find_mml_files(&entry, files);
+        } else if entry
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("mml"))
+        {
+            files.push(entry);
+        }
+    }
+}
+
+fn find_pattern(dir: &std::path::Path, pattern: &[String], files: &mut Vec<std::path::PathBuf>) {
+    let Some((first, rest)) = pattern.split_first() else {
+        return;
+    };
+    if first == "**" {
+        find_pattern(dir, rest, files);
+        for entry in read_dir_sorted(dir) {
+            if entry.is_dir() {
+                find_pattern(&entry, pattern, files);
+            }
+        }
+        return;
+    }
+    for entry in read_dir_sorted(dir) {
+        let name = entry.file_name().unwrap().to_string_lossy().into_owned();
+        if !wildcard_match(first, &name) {
+            continue;
+        }
+        if rest.is_empty() {
+            if entry.is_file() {
+                files.push(entry);
+            }
+        } else if entry.is_dir() {
+            find_pattern(&entry, rest, files);
+        }
+    }
+}
+
+// * は0文字以上、? は1文字に一致する
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // matched[j]: パターンのここまでが名前のj文字目までに一致するか
+    let mut matched: Vec<bool> = vec![false; name.len() + 1];
+    matched[0] = true;
+    for p in pattern.iter() {
+        let mut next = vec![false; name.len() + 1];
+        for j in 0..=name.len() {
+            next[j] = match p {
+                '*' => matched[j] || (j > 0 && next[j - 1]),
+                '?' => j > 0 && matched[j - 1],
+                ch => j > 0 && matched[j - 1] && name[j - 1] == *ch,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.mml", "song.mml"));
+        assert!(wildcard_match("*.mml", ".mml"));
+        assert!(!wildcard_match("*.mml", "song.mml.bak"));
+        assert!(wildcard_match("s?ng*", "sung"));
+        assert!(wildcard_match("s?ng*", "song_2.mml"));
+        assert!(!wildcard_match("s?ng", "sng"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("曲?.mml", "曲1.mml"));
+        assert!(!wildcard_match("*.mml", "song.MML"));
+        assert!(is_pattern("songs/*.mml"));
+        assert!(!is_pattern("songs/a.mml"));
+    }
+
+    // パターンやディレクトリで集めたファイルの相対パスは--out-dirの中にそのまま作る
+    #[test]
+    fn collect_files_and_out_dir() {
+        let dir = std::env::temp_dir().join(format!("mml-cli-test-{}", std::process::id()));
+        let songs = dir.join("songs");
+        std::fs::create_dir_all(songs.join("sub/deep")).unwrap();
+        for file in ["a.mml", "b.txt", "sub/c.mml", "sub/E.MML", "sub/deep/d.mml"] {
+            std::fs::write(songs.join(file), "C").unwrap();
+        }
+        let collect = |arg: &Path| -> Vec<(PathBuf, PathBuf)> {
+            collect_mml_files(&arg.display().to_string())
+        };
+        let relatives = |arg: &Path| -> Vec<PathBuf> {
+            collect(arg)
+                .into_iter()
+                .map(|(_, relative)| relative)
+                .collect()
+        };
+        let paths = |names: &[&str]| -> Vec<PathBuf> { names.iter().map(PathBuf::from).collect() };
+
+        // ディレクトリは拡張子が.mmlの全てのファイル (大文字小文字は区別しない)
+        assert_eq!(
+            relatives(&songs),
+            paths(&["a.mml", "sub/E.MML", "sub/c.mml", "sub/deep/d.mml"])
+        );
+        // **は0個以上のディレクトリに一致する
+        assert_eq!(
+            relatives(&songs.join("**/*.mml")),
+            paths(&["a.mml", "sub/c.mml", "sub/deep/d.mml"])
+        );
+        assert_eq!(
+            relatives(&songs.join("s?b/*")),
+            paths(&["sub/E.MML", "sub/c.mml"])
+        );
+        assert_eq!(
+            relatives(&songs.join("*/deep/*.mml")),
+            paths(&["sub/deep/d.mml"])
+        );
+        assert_eq!(
+            relatives(&songs.join("**/deep/**/*.mml")),
+            paths(&["sub/deep/d.mml"])
+        );
+        assert!(relatives(&songs.join("*.mid")).is_empty());
+        assert!(relatives(&dir.join("missing")).is_empty());
+        // ./ は取り除く
+        assert_eq!(
+            relatives(&songs.join(".").join("sub")),
+            paths(&["E.MML", "c.mml", "deep/d.mml"])
+        );
+        assert_eq!(
+            collect(&songs.join("./sub/./c.mml")),
+            vec![(songs.join("sub/c.mml"), PathBuf::from("c.mml"))]
+        );
+        // カレントディレクトリの外のファイルはファイル名だけ、中のファイルはカレントディレクトリからのパス
+        assert_eq!(
+            collect(&songs.join("sub/c.mml")),
+            vec![(songs.join("sub/c.mml"), PathBuf::from("c.mml"))]
+        );
+        assert_eq!(
+            relative_to_current_dir(Path::new("a/x.mml")),
+            PathBuf::from("a/x.mml")
+        );
+        assert_eq!(
+            normalize_path(Path::new("./a/./x.mml")),
+            PathBuf::from("a/x.mml")
+        );
+        assert_eq!(normalize_path(Path::new("./")), PathBuf::from("."));
+
+        let settings = config::Settings {
+            no_config: true,
+            ..Default::default()
+        };
+        let outputs: Vec<PathBuf> = collect(&songs.join("**/*.mml"))
+            .into_iter()
+            .map(|(file, relative)| {
+                batch_output_file(&file, &relative, Some("midi"), &settings, "mid")
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            paths(&[
+                "midi/a.mml.mid",
+                "midi/sub/c.mml.mid",
+                "midi/sub/deep/d.mml.mid"
+            ])
+        );
+        // --out-dirの省略時は入力ファイルと同じディレクトリ
+        assert_eq!(
+            batch_output_file(
+                &songs.join("sub/c.mml"),
+                Path::new("sub/c.mml"),
+                None,
+                &settings,
+                "kar"
+            ),
+            songs.join("sub/c.mml.kar")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 出力先が同じになる入力があれば変換しない
+    #[test]
+    fn output_collision() {
+        let settings = config::Settings {
+            no_config: true,
+            ..Default::default()
+        };
+        let job = |input_file: &str, out_dir: Option<&str>| {
+            let input_file = PathBuf::from(input_file);
+            let relative = relative_to_current_dir(&input_file);
+            let output_file = batch_output_file(&input_file, &relative, out_dir, &settings, "mid");
+            BatchJob {
+                input_file,
+                output_file,
+                settings: settings.clone(),
+            }
+        };
+        // 同じ名前でもディレクトリが違えば--out-dirの中でも別のファイルになる
+        let jobs = [job("a/x.mml", Some("out")), job("b/x.mml", Some("out"))];
+        assert_eq!(jobs[0].output_file, PathBuf::from("out/a/x.mml.mid"));
+        assert_eq!(jobs[1].output_file, PathBuf::from("out/b/x.mml.mid"));
+        assert!(find_output_collision(&jobs).is_none());
+
+        let jobs = [
+            job("a/x.mml", Some("out")),
+            job("y.mml", Some("out")),
+            job("a/x.mml", Some("./out")),
+        ];
+        let (first, second) = find_output_collision(&jobs).unwrap();
+        assert_eq!(first.input_file, PathBuf::from("a/x.mml"));
+        assert_eq!(second.output_file, PathBuf::from("./out/a/x.mml.mid"));
+    }
+}
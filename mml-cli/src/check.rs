@@ -0,0 +1,166 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{Accepts, CommonArgs};
+use crate::config;
+use crate::input::{load_song, render_errors};
+use mml_core::Lang;
+
+// 読み込みの設定だけを指定するコマンド (check, info) の引数
+pub(crate) struct SourceArgs {
+    input_file: String,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl SourceArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut args = CommonArgs::parse(iter, Accepts::default(), lang, |_, _, _| Ok(false))?;
+        Ok(SourceArgs {
+            input_file: args.take_input_file(lang)?,
+            settings: args.settings,
+        })
+    }
+}
+
+pub(crate) fn check(
+    SourceArgs {
+        input_file,
+        settings,
+    }: SourceArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    let input_file = std::path::Path::new(&input_file);
+    if !input_file.is_file() {
+        return Err(tr!(
+            lang,
+            "{}が見つかりません",
+            "{} not found",
+            input_file.display()
+        ));
+    }
+    let settings = config::resolve(Some(input_file), settings, lang)?;
+    load_song(input_file, &settings.parse_options(), lang)?;
+    eprintln!(
+        "{}",
+        tr!(
+            lang,
+            "{}: エラーはありません",
+            "{}: no errors",
+            input_file.display()
+        )
+    );
+    Ok(())
+}
+
+// SMFを生成せずに読み込んだトーンシーケンスから曲の情報を集計して表示する
+pub(crate) fn info(
+    SourceArgs {
+        input_file,
+        settings,
+    }: SourceArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    let input_file = std::path::Path::new(&input_file);
+    if !input_file.is_file() {
+        return Err(tr!(
+            lang,
+            "{}が見つかりません",
+            "{} not found",
+            input_file.display()
+        ));
+    }
+    let settings = config::resolve(Some(input_file), settings, lang)?;
+    let analysis = mml_core::analyze_file(input_file, &settings.parse_options())
+        .map_err(|errors| render_errors(input_file.display(), &errors, lang))?;
+    let song = &analysis.song;
+    let summary = analysis.summary();
+
+    println!(
+        "{}",
+        tr!(
+            lang,
+            "長さ: {:.3}秒 ({} tick)",
+            "duration: {:.3} s ({} ticks)",
+            summary.seconds,
+            summary.ticks
+        )
+    );
+    println!("{}", tr!(lang, "テンポ: {}", "tempo: {}", song.tempo()));
+    println!(
+        "{}",
+        tr!(lang, "分解能: {}", "resolution: {}", song.resolution())
+    );
+    if let Some(time_signature) = song.time_signature() {
+        println!(
+            "{}",
+            tr!(
+                lang,
+                "拍子: {}/{}",
+                "time signature: {}/{}",
+                time_signature.numerator,
+                time_signature.denominator
+            )
+        );
+    }
+    let instrument = settings.instrument_for(song);
+    println!(
+        "{}",
+        tr!(
+            lang,
+            "楽器: {} - {}{}",
+            "instrument: {} - {}{}",
+            instrument as i32,
+            instrument.name_in(lang),
+            if song.instrument().is_some() {
+                " (#INSTRUMENT)".to_string()
+            } else if settings.default_instrument.is_some() {
+                format!(" ({})", config::CONFIG_FILE_NAME)
+            } else {
+                tr!(lang, " (省略時)", " (default)")
+            }
+        )
+    );
+    match (summary.lowest, summary.highest) {
+        (Some(lowest), Some(highest)) => println!(
+            "{}",
+            tr!(
+                lang,
+                "音符: {}個 (音域 {} ～ {}、ノート番号 {} ～ {})",
+                "notes: {} (range {} to {}, note numbers {} to {})",
+                summary.notes,
+                mml_core::note_name(lowest),
+                mml_core::note_name(highest),
+                lowest,
+                highest
+            )
+        ),
+        _ => println!("{}", tr!(lang, "音符: 0個", "notes: 0")),
+    }
+    println!("{}", tr!(lang, "休符: {}個", "rests: {}", summary.rests));
+    if !analysis.blocks.is_empty() {
+        println!("{}", tr!(lang, "ブロック:", "blocks:"));
+        for block in analysis.blocks.iter() {
+            let name = match &block.name {
+                Some(name) => format!("{} {}", block.id, name),
+                None => block.id.to_string(),
+            };
+            println!(
+                "{}",
+                tr!(
+                    lang,
+                    "    {}: 再生 {}回 (ブロック再生の記述 {}箇所)",
+                    "    {}: played {} time(s) ({} call(s) in the source)",
+                    name,
+                    block.plays,
+                    block.calls.len()
+                )
+            );
+        }
+    }
+    Ok(())
+}
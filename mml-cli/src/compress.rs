@@ -0,0 +1,103 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{option_value, Accepts, CommonArgs};
+use crate::config;
+use crate::input::{output_or_stdout, render_errors, write_output, Input};
+use mml_core::Lang;
+
+pub(crate) struct CompressArgs {
+    input: Input,
+    output_file: Option<String>,
+    // 圧縮後のトーンシーケンスのバイト数の上限
+    target_bytes: Option<usize>,
+    quiet: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl CompressArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut target_bytes: Option<usize> = None;
+        let accepts = Accepts {
+            output: true,
+            quiet: true,
+            expr: true,
+        };
+        let mut args = CommonArgs::parse(iter, accepts, lang, |arg, iter, _| {
+            match arg {
+                "--target-bytes" => {
+                    let bytes = option_value(iter, "<bytes>", lang)?;
+                    match bytes.parse::<usize>() {
+                        Ok(bytes) => target_bytes = Some(bytes),
+                        Err(_) => {
+                            return Err(tr!(
+                                lang,
+                                "<bytes>の指定が不正です: {}",
+                                "invalid <bytes>: {}",
+                                bytes
+                            ))
+                        }
+                    }
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(CompressArgs {
+            input: args.take_input(lang)?,
+            output_file: args.output_file,
+            target_bytes,
+            quiet: args.quiet,
+            settings: args.settings,
+        })
+    }
+}
+
+// 繰り返し現れる並びをブロックにしてトーンシーケンスを小さくしたMMLを出力する (--outputの省略時は標準出力)。
+// --target-bytesのバイト数以下にできない場合は出力しない
+pub(crate) fn compress(
+    CompressArgs {
+        input,
+        output_file,
+        target_bytes,
+        quiet,
+        settings,
+    }: CompressArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    input.check_exists(lang)?;
+    let settings = config::resolve(input.path(), settings, lang)?;
+    let song = input.load_mml_or_smf(&settings.parse_options(), lang)?;
+    let compressed = song
+        .compress(target_bytes)
+        .map_err(|error| render_errors(input.name(lang), &[error], lang))?;
+    if !quiet {
+        eprintln!(
+            "{}",
+            tr!(
+                lang,
+                "トーンシーケンス: {}バイト → {}バイト (ブロック{}個)",
+                "tone sequence: {} bytes -> {} bytes ({} blocks)",
+                compressed.original_bytes,
+                compressed.bytes,
+                compressed.blocks
+            )
+        );
+    }
+    if let Some(target_bytes) = target_bytes.filter(|&t| compressed.bytes > t) {
+        return Err(tr!(
+            lang,
+            "{}バイト以下に圧縮できませんでした ({}バイト)",
+            "could not compress to {} bytes or less ({} bytes)",
+            target_bytes,
+            compressed.bytes
+        ));
+    }
+    let output_file = output_or_stdout(output_file);
+    write_output(output_file.as_deref(), compressed.mml.as_bytes())
+}
@@ -0,0 +1,372 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::batch::wildcard_match;
+use mml_core::{InputMode, Instrument, Lang, Lint, ParseOptions, Song, TextEncoding, INSTRUMENTS};
+use std::path::{Path, PathBuf};
+
+// 入力ファイルのディレクトリから親ディレクトリをたどって探す設定ファイル
+pub(crate) const CONFIG_FILE_NAME: &str = "mml.toml";
+
+// mml.tomlとコマンドライン引数で指定する設定 (Noneは指定なし)。
+// mml.tomlの値はMMLで省略した場合の既定値で、コマンドライン引数の値はMMLの記述より優先する
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Settings {
+    // --instrument (MMLの#INSTRUMENTより優先する)
+    pub(crate) instrument: Option<Instrument>,
+    // mml.tomlのinstrument (MMLに#INSTRUMENTが無い場合の楽器)
+    pub(crate) default_instrument: Option<Instrument>,
+    // MMLでTと%を省略した場合のテンポと分解能
+    pub(crate) tempo: Option<i32>,
+    pub(crate) resolution: Option<i32>,
+    pub(crate) input_mode: Option<InputMode>,
+    pub(crate) encoding: Option<TextEncoding>,
+    // mml.tomlのout-dir (出力ファイルはmml.tomlのディレクトリと同じ構成で作る)
+    pub(crate) out_dir: Option<PathBuf>,
+    // mml2smfの出力形式 (trueはカラオケ形式)
+    pub(crate) karaoke: Option<bool>,
+    // 警告の確認項目の有効・無効 (mml.toml、コマンドライン引数の順に適用する)
+    pub(crate) lint: Vec<(String, bool)>,
+    // 見つかったmml.tomlのディレクトリ
+    pub(crate) project_dir: Option<PathBuf>,
+    // --no-config (mml.tomlを探さない)
+    pub(crate) no_config: bool,
+}
+
+impl Settings {
+    // otherで指定された設定で上書きする
+    fn merge(&mut self, other: Settings) {
+        self.instrument = other.instrument.or(self.instrument);
+        self.default_instrument = other.default_instrument.or(self.default_instrument);
+        self.tempo = other.tempo.or(self.tempo);
+        self.resolution = other.resolution.or(self.resolution);
+        self.input_mode = other.input_mode.or(self.input_mode);
+        self.encoding = other.encoding.or(self.encoding);
+        self.out_dir = other.out_dir.or(self.out_dir.take());
+        self.karaoke = other.karaoke.or(self.karaoke);
+        self.lint.extend(other.lint);
+        self.project_dir = other.project_dir.or(self.project_dir.take());
+        self.no_config |= other.no_config;
+    }
+
+    pub(crate) fn parse_options(&self) -> ParseOptions {
+        let default = ParseOptions::default();
+        ParseOptions {
+            input_mode: self.input_mode.unwrap_or(default.input_mode),
+            encoding: self.encoding.unwrap_or(default.encoding),
+            tempo: self.tempo,
+            resolution: self.resolution,
+        }
+    }
+
+    pub(crate) fn lint(&self) -> Lint {
+        let mut lint = Lint::default();
+        for (name, enabled) in self.lint.iter() {
+            lint.set(name, *enabled);
+        }
+        // MMLの#INSTRUMENTはinstrument_forと同じく--instrumentの次、mml.tomlより前に使う
+        lint.instrument_override = self.instrument;
+        lint.instrument = self.default_instrument.unwrap_or(INSTRUMENTS[0]);
+        lint
+    }
+
+    // --instrument、MMLの#INSTRUMENT、mml.tomlのinstrument、1番の楽器の順に決める
+    pub(crate) fn instrument_for(&self, song: &Song) -> Instrument {
+        self.instrument
+            .or_else(|| song.instrument())
+            .or(self.default_instrument)
+            .unwrap_or(INSTRUMENTS[0])
+    }
+
+    pub(crate) fn karaoke(&self) -> bool {
+        self.karaoke.unwrap_or(false)
+    }
+
+    // mml.tomlのout-dirの中の出力ファイル (入力ファイル名に拡張子を付ける)。
+    // out-dirの指定が無い場合と入力ファイルがmml.tomlのディレクトリの外にある場合はNone
+    pub(crate) fn output_in_out_dir(&self, input_file: &Path, extension: &str) -> Option<PathBuf> {
+        let (out_dir, project_dir) = (self.out_dir.as_ref()?, self.project_dir.as_ref()?);
+        let input_file = std::path::absolute(input_file).ok()?;
+        let relative = input_file.strip_prefix(project_dir).ok()?;
+        let file_name = format!("{}.{}", relative.file_name()?.to_string_lossy(), extension);
+        Some(out_dir.join(relative).with_file_name(file_name))
+    }
+}
+
+// 入力ファイルのディレクトリ (Noneの場合はカレントディレクトリ) から親ディレクトリをたどってmml.tomlを探し、
+// mml.tomlの設定にファイルごとの設定とコマンドライン引数の設定 (cli) を上書きした設定を返す
+pub(crate) fn resolve(
+    input_file: Option<&Path>,
+    cli: Settings,
+    lang: Lang,
+) -> Result<Settings, String> {
+    if cli.no_config {
+        return Ok(cli);
+    }
+    let input_file: Option<PathBuf> = input_file.and_then(|file| std::path::absolute(file).ok());
+    let start: PathBuf = match input_file.as_deref().and_then(Path::parent) {
+        Some(dir) => dir.to_path_buf(),
+        None => match std::env::current_dir() {
+            Ok(dir) => dir,
+            Err(_) => return Ok(cli),
+        },
+    };
+    let config_file = match start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|file| file.is_file())
+    {
+        Some(file) => file,
+        None => return Ok(cli),
+    };
+    let mut settings = load(&config_file, input_file.as_deref(), lang)?;
+    settings.merge(cli);
+    Ok(settings)
+}
+
+// mml.tomlを読み込み、input_fileに一致するファイルごとの設定 ([files."パターン"]) を書かれた順に上書きする
+fn load(config_file: &Path, input_file: Option<&Path>, lang: Lang) -> Result<Settings, String> {
+    let text = std::fs::read_to_string(config_file).map_err(|error| {
+        tr!(
+            lang,
+            "{}を読み込めませんでした: {}",
+            "could not read {}: {}",
+            config_file.display(),
+            error
+        )
+    })?;
+    let table: toml::Table = text.parse().map_err(|error| {
+        tr!(
+            lang,
+            "{}の書式が不正です: {}",
+            "invalid format in {}: {}",
+            config_file.display(),
+            error
+        )
+    })?;
+    let project_dir = config_file.parent().unwrap_or(Path::new("")).to_path_buf();
+    let reader = Reader {
+        config_file,
+        project_dir: &project_dir,
+        lang,
+    };
+    let mut settings = reader.read(&table, "")?;
+    settings.project_dir = Some(project_dir.clone());
+
+    let relative: Option<&Path> = input_file.and_then(|file| file.strip_prefix(&project_dir).ok());
+    if let Some(files) = table.get("files") {
+        let files = files
+            .as_table()
+            .ok_or_else(|| reader.invalid("files", files))?;
+        for (pattern, value) in files.iter() {
+            let key = format!("files.\"{}\"", pattern);
+            let file_settings = match value.as_table() {
+                Some(table) => reader.read(table, &key)?,
+                None => return Err(reader.invalid(&key, value)),
+            };
+            if relative.is_some_and(|relative| path_matches(pattern, relative)) {
+                settings.merge(file_settings);
+            }
+        }
+    }
+    Ok(settings)
+}
+
+struct Reader<'a> {
+    config_file: &'a Path,
+    project_dir: &'a Path,
+    lang: Lang,
+}
+
+impl Reader<'_> {
+    // トップレベルかファイルごとの設定 (prefixは表示用のキー) を読み込む
+    fn read(&self, table: &toml::Table, prefix: &str) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        for (name, value) in table.iter() {
+            let key = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}.{}", prefix, name)
+            };
+            let int = |range: std::ops::RangeInclusive<i64>| -> Result<i32, String> {
+                match value.as_integer() {
+                    Some(n) if range.contains(&n) => Ok(n as i32),
+                    _ => Err(self.invalid(&key, value)),
+                }
+            };
+            let string = || value.as_str().ok_or_else(|| self.invalid(&key, value));
+            match name.as_str() {
+                "instrument" => {
+                    let num = int(1..=INSTRUMENTS.len() as i64)?;
+                    settings.default_instrument = Some(INSTRUMENTS[num as usize - 1]);
+                }
+                "tempo" => settings.tempo = Some(int(20..=508)?),
+                "resolution" => settings.resolution = Some(int(1..=127)?),
+                "input-mode" => {
+                    let mode =
+                        InputMode::parse(string()?).ok_or_else(|| self.invalid(&key, value))?;
+                    settings.input_mode = Some(mode);
+                }
+                "encoding" => {
+                    let encoding =
+                        TextEncoding::parse(string()?).ok_or_else(|| self.invalid(&key, value))?;
+                    settings.encoding = Some(encoding);
+                }
+                // 相対パスはmml.tomlのディレクトリが基準
+                "out-dir" => settings.out_dir = Some(self.project_dir.join(string()?)),
+                "format" => {
+                    settings.karaoke = match string()? {
+                        "smf" => Some(false),
+                        "karaoke" => Some(true),
+                        _ => return Err(self.invalid(&key, value)),
+                    }
+                }
+                "lint" => settings.lint = self.read_lint(value, &key)?,
+                // ファイルごとの設定はトップレベルだけに書ける
+                "files" if prefix.is_empty() => {}
+                _ => {
+                    return Err(tr!(
+                        self.lang,
+                        "{}: 不明な設定です: {}",
+                        "{}: unknown setting: {}",
+                        self.config_file.display(),
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    // [lint] enable = ["octave-jump"], disable = ["redundant-octave"]
+    fn read_lint(&self, value: &toml::Value, key: &str) -> Result<Vec<(String, bool)>, String> {
+        let table = value.as_table().ok_or_else(|| self.invalid(key, value))?;
+        let mut lint: Vec<(String, bool)> = Vec::new();
+        for (name, names) in table.iter() {
+            let key = format!("{}.{}", key, name);
+            let enabled = match name.as_str() {
+                "enable" => true,
+                "disable" => false,
+                _ => return Err(self.invalid(&key, names)),
+            };
+            let names = names.as_array().ok_or_else(|| self.invalid(&key, names))?;
+            for check in names.iter() {
+                match check.as_str() {
+                    Some(check) if Lint::default().set(check, enabled) => {
+                        lint.push((check.to_string(), enabled))
+                    }
+                    _ => return Err(self.invalid(&key, check)),
+                }
+            }
+        }
+        Ok(lint)
+    }
+
+    fn invalid(&self, key: &str, value: &toml::Value) -> String {
+        tr!(
+            self.lang,
+            "{}: {}の値が不正です: {}",
+            "{}: invalid value of {}: {}",
+            self.config_file.display(),
+            key,
+            value
+        )
+    }
+}
+
+// mml.tomlのディレクトリからの相対パスがパターンに一致するか (**は0個以上のディレクトリに一致する)
+fn path_matches(pattern: &str, relative: &Path) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let names: Vec<String> = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    fn matches(pattern: &[&str], names: &[String]) -> bool {
+        match pattern.split_first() {
+            None => names.is_empty(),
+            Some((&"**", rest)) => (0..=names.len()).any(|skip| matches(rest, &names[skip..])),
+            Some((first, rest)) => match names.split_first() {
+                Some((name, names)) => wildcard_match(first, name) && matches(rest, names),
+                None => false,
+            },
+        }
+    }
+    matches(&pattern, &names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_patterns() {
+        let matches = |pattern: &str, relative: &str| path_matches(pattern, Path::new(relative));
+        assert!(matches("songs/*.mml", "songs/a.mml"));
+        assert!(!matches("songs/*.mml", "songs/sub/a.mml"));
+        assert!(!matches("songs/*.mml", "a.mml"));
+        assert!(matches("songs/**/*.mml", "songs/a.mml"));
+        assert!(matches("songs/**/*.mml", "songs/sub/deep/a.mml"));
+        assert!(matches("**/fast.mml", "fast.mml"));
+        assert!(matches("**/fast.mml", "songs/fast.mml"));
+        assert!(!matches("**/fast.mml", "songs/fast.mml.bak"));
+        assert!(matches("songs//s?b/", "songs/sub"));
+    }
+
+    // mml.toml、一致するファイルごとの設定 (書かれた順)、コマンドライン引数の順に上書きする
+    #[test]
+    fn resolve_order() {
+        let dir = std::env::temp_dir().join(format!("mml-cli-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("songs/sub")).unwrap();
+        std::fs::write(
+            dir.join(CONFIG_FILE_NAME),
+            "tempo = 100\ninstrument = 11\nout-dir = \"midi\"\n\
+             [files.\"songs/**/*.mml\"]\ntempo = 110\nformat = \"karaoke\"\n\
+             [files.\"**/fast.mml\"]\ntempo = 150\nresolution = 96\n",
+        )
+        .unwrap();
+        let resolve =
+            |file: &str, cli: Settings| resolve(Some(&dir.join(file)), cli, Lang::En).unwrap();
+
+        let settings = resolve("other.mml", Settings::default());
+        assert_eq!((settings.tempo, settings.resolution), (Some(100), None));
+        assert_eq!(settings.default_instrument, Some(INSTRUMENTS[10]));
+        assert!(!settings.karaoke());
+
+        let settings = resolve("songs/sub/slow.mml", Settings::default());
+        assert_eq!((settings.tempo, settings.resolution), (Some(110), None));
+        assert!(settings.karaoke());
+        assert_eq!(
+            settings.output_in_out_dir(&dir.join("songs/sub/slow.mml"), "kar"),
+            Some(dir.join("midi/songs/sub/slow.mml.kar"))
+        );
+
+        // 後に書かれたパターンの設定が優先する
+        let settings = resolve("songs/fast.mml", Settings::default());
+        assert_eq!((settings.tempo, settings.resolution), (Some(150), Some(96)));
+        assert!(settings.karaoke());
+
+        // コマンドライン引数の設定はmml.tomlより優先する
+        let cli = Settings {
+            tempo: Some(200),
+            instrument: Some(INSTRUMENTS[0]),
+            ..Default::default()
+        };
+        let settings = resolve("songs/fast.mml", cli.clone());
+        assert_eq!((settings.tempo, settings.resolution), (Some(200), Some(96)));
+        assert_eq!(settings.instrument, Some(INSTRUMENTS[0]));
+        assert_eq!(settings.default_instrument, Some(INSTRUMENTS[10]));
+
+        // --no-configの場合はmml.tomlを読み込まない
+        let settings = resolve(
+            "songs/fast.mml",
+            Settings {
+                no_config: true,
+                ..cli
+            },
+        );
+        assert_eq!((settings.tempo, settings.resolution), (Some(200), None));
+        assert_eq!(settings.out_dir, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{option_value, parse_instrument, Accepts, CommonArgs};
+use crate::batch::{is_pattern, mml2smf_batch};
+use crate::config;
+use crate::input::{output_name, output_path, write_output, Input};
+use crate::watch::watch_files;
+use mml_core::Lang;
+
+pub(crate) struct MmlToSmfArgs {
+    // 複数のファイル・ディレクトリ・パターン (*.mmlなど) を指定した場合はまとめて変換する
+    pub(crate) inputs: Vec<Input>,
+    pub(crate) output_file: Option<String>,
+    // まとめて変換する場合の出力先ディレクトリ (入力のディレクトリの構成をそのまま作る)
+    pub(crate) out_dir: Option<String>,
+    pub(crate) watch: bool,
+    pub(crate) quiet: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    pub(crate) settings: config::Settings,
+}
+
+impl MmlToSmfArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut out_dir: Option<String> = None;
+        let mut watch = false;
+        let accepts = Accepts {
+            output: true,
+            quiet: true,
+            expr: true,
+        };
+        let CommonArgs {
+            inputs,
+            output_file,
+            quiet,
+            settings,
+        } = CommonArgs::parse(iter, accepts, lang, |arg, iter, settings| {
+            match arg {
+                "--out-dir" => out_dir = Some(option_value(iter, "<out-dir>", lang)?),
+                "--instrument" => {
+                    let num_str = option_value(iter, "<instrument-number>", lang)?;
+                    settings.instrument = Some(parse_instrument(&num_str, lang)?);
+                }
+                "--karaoke" => settings.karaoke = Some(true),
+                "--watch" => watch = true,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let files_only = inputs.iter().all(|input| matches!(input, Input::File(_)));
+        if inputs.is_empty() {
+            return Err(tr!(
+                lang,
+                "<mml-file>が指定されていません",
+                "<mml-file> is not specified"
+            ));
+        }
+        if inputs.len() > 1 && !files_only {
+            return Err(tr!(
+                lang,
+                "標準入力(-)と--exprは他の<mml-file>と同時に指定できません",
+                "stdin (-) and --expr cannot be combined with other <mml-file>s"
+            ));
+        }
+        if watch && !files_only {
+            return Err(tr!(
+                lang,
+                "--watchは<mml-file>にファイルを指定した場合だけ使用できます",
+                "--watch can be used only when <mml-file> is a file"
+            ));
+        }
+        if out_dir.is_some() && !files_only {
+            return Err(tr!(
+                lang,
+                "--out-dirは<mml-file>にファイルを指定した場合だけ使用できます",
+                "--out-dir can be used only when <mml-file> is a file"
+            ));
+        }
+        Ok(MmlToSmfArgs {
+            inputs,
+            output_file,
+            out_dir,
+            watch,
+            quiet,
+            settings,
+        })
+    }
+
+    // 複数のファイル・ディレクトリ・パターンを指定した場合と--out-dirを指定した場合はまとめて変換する
+    fn is_batch(&self) -> bool {
+        match self.inputs.as_slice() {
+            [Input::File(file)] => {
+                self.out_dir.is_some() || is_pattern(file) || std::path::Path::new(file).is_dir()
+            }
+            [_] => false,
+            _ => true,
+        }
+    }
+}
+
+pub(crate) fn mml2smf(args: MmlToSmfArgs, lang: Lang) -> Result<(), String> {
+    if args.is_batch() {
+        return mml2smf_batch(args, lang);
+    }
+    let MmlToSmfArgs {
+        inputs,
+        output_file,
+        watch,
+        quiet,
+        settings,
+        ..
+    } = args;
+    let input = inputs.into_iter().next().expect("no input");
+    input.check_exists(lang)?;
+    let settings = config::resolve(input.path(), settings, lang)?;
+    let options = settings.parse_options();
+    let karaoke = settings.karaoke();
+    let output_file = output_path(
+        output_file,
+        &input,
+        &settings,
+        if karaoke { "kar" } else { "mid" },
+    );
+    let progress = |msg: &str| {
+        if !quiet {
+            eprintln!("{}", msg);
+        }
+    };
+    progress(&tr!(lang, "入力: {}", "input: {}", input.name(lang)));
+    progress(&tr!(
+        lang,
+        "出力: {}",
+        "output: {}",
+        output_name(output_file.as_deref(), lang)
+    ));
+    let convert = || -> Result<(), String> {
+        progress("");
+        progress(&tr!(lang, "処理を開始します", "starting conversion"));
+        let song = input.load(&options, lang)?;
+        let instrument = settings.instrument_for(&song);
+        progress(&tr!(
+            lang,
+            "楽器: {} - {}",
+            "instrument: {} - {}",
+            instrument as i32,
+            instrument.name_in(lang)
+        ));
+        let dst = if karaoke {
+            song.to_karaoke(instrument)
+        } else {
+            song.to_smf(instrument)
+        };
+        let dst = match dst {
+            Ok(dst) => dst,
+            Err(error) => return Err(error.render_in(lang)),
+        };
+        write_output(output_file.as_deref(), &dst)?;
+        progress(&tr!(
+            lang,
+            "MMLからSMFファイルへの変換に成功しました",
+            "converted MML to an SMF file successfully"
+        ));
+        if !quiet {
+            show_metadata(song.metadata(), lang);
+        }
+        Ok(())
+    };
+    match &input {
+        Input::File(file) if watch => {
+            watch_files(std::path::Path::new(file), &options, quiet, lang, convert)
+        }
+        _ => convert(),
+    }
+}
+
+pub(crate) struct MmlToToneArgs {
+    input: Input,
+    output_file: Option<String>,
+    loop_only: bool,
+    watch: bool,
+    quiet: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl MmlToToneArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut loop_only = false;
+        let mut watch = false;
+        let accepts = Accepts {
+            output: true,
+            quiet: true,
+            expr: true,
+        };
+        let mut args = CommonArgs::parse(iter, accepts, lang, |arg, _, _| {
+            match arg {
+                "--loop-only" => loop_only = true,
+                "--watch" => watch = true,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let input = args.take_input(lang)?;
+        if watch && !matches!(input, Input::File(_)) {
+            return Err(tr!(
+                lang,
+                "--watchは<mml-file>にファイルを指定した場合だけ使用できます",
+                "--watch can be used only when <mml-file> is a file"
+            ));
+        }
+        Ok(MmlToToneArgs {
+            input,
+            output_file: args.output_file,
+            loop_only,
+            watch,
+            quiet: args.quiet,
+            settings: args.settings,
+        })
+    }
+}
+
+pub(crate) fn mml2tone(
+    MmlToToneArgs {
+        input,
+        output_file,
+        loop_only,
+        watch,
+        quiet,
+        settings,
+    }: MmlToToneArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    input.check_exists(lang)?;
+    let settings = config::resolve(input.path(), settings, lang)?;
+    let options = settings.parse_options();
+    let output_file = output_path(output_file, &input, &settings, "jts");
+    let progress = |msg: &str| {
+        if !quiet {
+            eprintln!("{}", msg);
+        }
+    };
+    progress(&tr!(lang, "入力: {}", "input: {}", input.name(lang)));
+    progress(&tr!(
+        lang,
+        "出力: {}",
+        "output: {}",
+        output_name(output_file.as_deref(), lang)
+    ));
+    let convert = || -> Result<(), String> {
+        progress("");
+        progress(&tr!(lang, "処理を開始します", "starting conversion"));
+        let song = input.load(&options, lang)?;
+        let dst = if loop_only {
+            match song.to_loop_tone_sequence() {
+                Some(dst) => dst,
+                None => {
+                    return Err(tr!(
+                        lang,
+                        "ループ位置(!loopstart)が指定されていません",
+                        "no loop position (!loopstart) is specified"
+                    ))
+                }
+            }
+        } else {
+            song.to_tone_sequence()
+        };
+        write_output(output_file.as_deref(), &dst)?;
+        progress(&tr!(
+            lang,
+            "MMLからトーンシーケンスへの変換に成功しました ({}バイト)",
+            "converted MML to a tone sequence successfully ({} bytes)",
+            dst.len()
+        ));
+        Ok(())
+    };
+    match &input {
+        Input::File(file) if watch => {
+            watch_files(std::path::Path::new(file), &options, quiet, lang, convert)
+        }
+        _ => convert(),
+    }
+}
+
+fn show_metadata(metadata: &mml_core::Metadata, lang: Lang) {
+    if metadata.is_empty() {
+        return;
+    }
+    eprintln!();
+    if let Some(title) = &metadata.title {
+        eprintln!("{}", tr!(lang, "タイトル: {}", "title: {}", title));
+    }
+    if let Some(composer) = &metadata.composer {
+        eprintln!("{}", tr!(lang, "作曲者: {}", "composer: {}", composer));
+    }
+    if let Some(copyright) = &metadata.copyright {
+        eprintln!("{}", tr!(lang, "著作権: {}", "copyright: {}", copyright));
+    }
+    for comment in metadata.comments.iter() {
+        eprintln!("{}", tr!(lang, "コメント: {}", "comment: {}", comment));
+    }
+}
@@ -0,0 +1,163 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{Accepts, CommonArgs};
+use crate::config;
+use crate::input::Input;
+use mml_core::Lang;
+
+pub(crate) struct DiffArgs {
+    // 比較する元のファイルと新しいファイル
+    old: Input,
+    new: Input,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl DiffArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let CommonArgs {
+            mut inputs,
+            settings,
+            ..
+        } = CommonArgs::parse(iter, Accepts::default(), lang, |_, _, _| Ok(false))?;
+        if let Some(input) = inputs.get(2) {
+            return Err(tr!(
+                lang,
+                "比較するファイルは2つだけ指定できます: {}",
+                "specify only two files to compare: {}",
+                input.name(lang)
+            ));
+        }
+        if inputs.len() < 2 {
+            return Err(tr!(
+                lang,
+                "比較する2つのファイルが指定されていません",
+                "two files to compare are not specified"
+            ));
+        }
+        if inputs.iter().all(|input| matches!(input, Input::Stdin)) {
+            return Err(tr!(
+                lang,
+                "標準入力(-)はどちらか一方だけに指定できます",
+                "stdin (-) can be specified for only one of the files"
+            ));
+        }
+        let new = inputs.pop().unwrap();
+        let old = inputs.pop().unwrap();
+        Ok(DiffArgs { old, new, settings })
+    }
+}
+
+// 2つのMMLかSMFの曲を、ブロック再生とリピートを展開した音出し・無音の並びで比較し、
+// 追加・削除・変更した音符と休符を小節と拍の位置と記述位置とともに表示する
+pub(crate) fn diff(DiffArgs { old, new, settings }: DiffArgs, lang: Lang) -> Result<(), String> {
+    let load = |input: &Input| -> Result<mml_core::Song, String> {
+        input.check_exists(lang)?;
+        let settings = config::resolve(input.path(), settings.clone(), lang)?;
+        input.load_analyzed_or_smf(&settings.parse_options(), lang)
+    };
+    let old_song = load(&old)?;
+    let new_song = load(&new)?;
+    let diff = old_song.diff(&new_song);
+
+    println!("--- {}", old.name(lang));
+    println!("+++ {}", new.name(lang));
+    if diff.is_empty() {
+        println!("{}", tr!(lang, "違いはありません", "no differences"));
+        return Ok(());
+    }
+    if let Some((old_tempo, new_tempo)) = diff.tempo {
+        println!(
+            "{}",
+            tr!(
+                lang,
+                "テンポ: T{} → T{}",
+                "tempo: T{} -> T{}",
+                old_tempo,
+                new_tempo
+            )
+        );
+    }
+    if let Some((old_ts, new_ts)) = diff.time_signature {
+        println!(
+            "{}",
+            tr!(
+                lang,
+                "拍子: {}/{} → {}/{}",
+                "time signature: {}/{} -> {}/{}",
+                old_ts.numerator,
+                old_ts.denominator,
+                new_ts.numerator,
+                new_ts.denominator
+            )
+        );
+    }
+
+    // 記述位置とその行 (SMFの場合は表示しない)
+    let show_source = |input: &Input, sound: &mml_core::DiffSound| {
+        if let Some(span) = sound.span.as_ref() {
+            let file: String = span.start.file.clone().unwrap_or_else(|| input.name(lang));
+            let line: &str = span.line.as_deref().unwrap_or("").trim();
+            println!(
+                "    {}:{}:{}: {}",
+                file, span.start.row, span.start.col, line
+            );
+        }
+    };
+    let arrow: String = tr!(lang, "→", "->");
+    let (mut inserted, mut removed, mut changed) = (0, 0, 0);
+    for change in diff.changes.iter() {
+        match change {
+            mml_core::SoundChange::Inserted(sound) => {
+                inserted += 1;
+                println!("+ {} {}", sound.position(), sound.code);
+                show_source(&new, sound);
+            }
+            mml_core::SoundChange::Removed(sound) => {
+                removed += 1;
+                println!("- {} {}", sound.position(), sound.code);
+                show_source(&old, sound);
+            }
+            mml_core::SoundChange::Changed {
+                old: old_sound,
+                new: new_sound,
+            } => {
+                changed += 1;
+                let mut position: String = old_sound.position();
+                if new_sound.position() != position {
+                    position = format!("{} {} {}", position, arrow, new_sound.position());
+                }
+                let mut line = format!(
+                    "~ {} {} {} {}",
+                    position, old_sound.code, arrow, new_sound.code
+                );
+                if old_sound.velocity != new_sound.velocity {
+                    line.push_str(&tr!(
+                        lang,
+                        " (ベロシティ {} → {})",
+                        " (velocity {} -> {})",
+                        old_sound.velocity,
+                        new_sound.velocity
+                    ));
+                }
+                println!("{}", line);
+                show_source(&old, old_sound);
+                show_source(&new, new_sound);
+            }
+        }
+    }
+    // diff(1)と同じく違いがある場合は終了コードを0以外にする
+    Err(tr!(
+        lang,
+        "追加{}個・削除{}個・変更{}個",
+        "{} inserted, {} removed, {} changed",
+        inserted,
+        removed,
+        changed
+    ))
+}
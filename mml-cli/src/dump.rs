@@ -0,0 +1,280 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{option_value, Accepts, CommonArgs};
+use crate::config;
+use crate::input::{output_or_stdout, render_errors, write_output};
+use mml_core::{Analysis, Lang, SequenceEvent, Span};
+use serde_json::{json, Value};
+
+// dumpの出力のスキーマのバージョン (互換性の無い変更をした場合に上げる)
+const SCHEMA_VERSION: i32 = 1;
+
+// MMLの解析結果のJSON (スキーマはREADMEを参照)。
+// timelineがtrueの場合はブロック再生とリピートを展開した再生順の音出し・無音も出力する
+fn to_json(analysis: &Analysis, timeline: bool) -> Value {
+    let song = &analysis.song;
+    let metadata = song.metadata();
+    let mut root = json!({
+        "schema_version": SCHEMA_VERSION,
+        "header": {
+            "tempo": song.tempo(),
+            "resolution": song.resolution(),
+            "time_signature": song.time_signature().map(|ts| json!({
+                "numerator": ts.numerator,
+                "denominator": ts.denominator,
+            })),
+            "instrument": song.instrument().map(|inst| inst as i32),
+            "title": metadata.title,
+            "composer": metadata.composer,
+            "copyright": metadata.copyright,
+            "comments": metadata.comments,
+        },
+        "blocks": analysis.blocks.iter().map(|block| json!({
+            "id": block.id,
+            "name": block.name,
+            "source": span_json(&block.span),
+            "calls": block.calls.iter().map(span_json).collect::<Vec<Value>>(),
+            "plays": block.plays,
+            "events": events_json(&block.events),
+        })).collect::<Vec<Value>>(),
+        "sequence": events_json(&analysis.sequence),
+    });
+    if timeline {
+        root["timeline"] = analysis
+            .sounds
+            .iter()
+            .map(|sound| {
+                json!({
+                    "tick": sound.tick,
+                    "seconds": song.seconds(sound.tick),
+                    "duration": sound.duration,
+                    "duration_seconds": song.seconds(sound.duration),
+                    "note": sound.note,
+                    "name": sound.note.map(mml_core::note_name),
+                    // 無音は0
+                    "velocity": sound.velocity(),
+                    "source": span_json(&sound.span),
+                })
+            })
+            .collect();
+    }
+    root
+}
+
+fn events_json(events: &[SequenceEvent]) -> Vec<Value> {
+    events
+        .iter()
+        .map(|event| match event {
+            SequenceEvent::Sound {
+                note,
+                duration,
+                repeat,
+                span,
+            } => json!({
+                "type": if note.is_some() { "note" } else { "rest" },
+                "note": note,
+                "duration": duration,
+                "repeat": repeat,
+                "source": span_json(span),
+            }),
+            SequenceEvent::Volume(volume) => json!({"type": "volume", "volume": volume}),
+            SequenceEvent::PlayBlock(id) => json!({"type": "play_block", "block": id}),
+            SequenceEvent::BarLine => json!({"type": "bar_line"}),
+            SequenceEvent::Lyric(text) => json!({"type": "lyric", "text": text}),
+            SequenceEvent::LoopStart => json!({"type": "loop_start"}),
+            SequenceEvent::LoopEnd => json!({"type": "loop_end"}),
+        })
+        .collect()
+}
+
+// 記述範囲 (行と列は1始まり、endは範囲の次の位置)
+fn span_json(span: &Span) -> Value {
+    json!({
+        "file": span.start.file,
+        "start": {"row": span.start.row, "col": span.start.col},
+        "end": {"row": span.end.row, "col": span.end.col},
+    })
+}
+
+pub(crate) struct DumpArgs {
+    input_file: String,
+    output_file: Option<String>,
+    // 再生順に展開した音出し・無音も出力する
+    timeline: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl DumpArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut timeline = false;
+        let accepts = Accepts {
+            output: true,
+            ..Default::default()
+        };
+        let mut args = CommonArgs::parse(iter, accepts, lang, |arg, iter, _| {
+            match arg {
+                "--format" => {
+                    let format = option_value(iter, "<format>", lang)?;
+                    if format != "json" {
+                        return Err(tr!(
+                            lang,
+                            "<format>の指定が不正です: {} (json)",
+                            "invalid <format>: {} (json)",
+                            format
+                        ));
+                    }
+                }
+                "--timeline" => timeline = true,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(DumpArgs {
+            input_file: args.take_input_file(lang)?,
+            output_file: args.output_file,
+            timeline,
+            settings: args.settings,
+        })
+    }
+}
+
+// MMLの解析結果をJSONで出力する (--outputの省略時は標準出力)
+pub(crate) fn dump(
+    DumpArgs {
+        input_file,
+        output_file,
+        timeline,
+        settings,
+    }: DumpArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    let input_file = std::path::Path::new(&input_file);
+    if !input_file.is_file() {
+        return Err(tr!(
+            lang,
+            "{}が見つかりません",
+            "{} not found",
+            input_file.display()
+        ));
+    }
+    let settings = config::resolve(Some(input_file), settings, lang)?;
+    let analysis = mml_core::analyze_file(input_file, &settings.parse_options())
+        .map_err(|errors| render_errors(input_file.display(), &errors, lang))?;
+    let mut json = serde_json::to_string_pretty(&to_json(&analysis, timeline))
+        .map_err(|error| format!("{:?}", error))?;
+    json.push('\n');
+    let output_file = output_or_stdout(output_file);
+    write_output(output_file.as_deref(), json.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_shape() {
+        let src = "#TITLE t\n{0 C }\nV80 [2 D ] [2 E8 R8 ] | $0";
+        let analysis = mml_core::analyze(src, None, &Default::default()).unwrap();
+        let json = to_json(&analysis, false);
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["header"]["title"], "t");
+        assert_eq!(json["header"]["time_signature"], Value::Null);
+        assert!(json.get("timeline").is_none());
+
+        let block = &json["blocks"][0];
+        assert_eq!(block["id"], 0);
+        assert_eq!(block["plays"], 1);
+        assert_eq!(block["events"][0]["type"], "note");
+        assert_eq!(block["source"]["start"], json!({"row": 2, "col": 1}));
+
+        // 同じ音符のリピートはrepeat、複数の音符・休符のリピートは展開する
+        let events: Vec<(String, Value)> = json["sequence"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                let kind = event["type"].as_str().unwrap().to_string();
+                let value = match kind.as_str() {
+                    "note" | "rest" => json!([event["note"], event["repeat"]]),
+                    "volume" => event["volume"].clone(),
+                    "play_block" => event["block"].clone(),
+                    _ => Value::Null,
+                };
+                (kind, value)
+            })
+            .collect();
+        let expected = [
+            ("volume", json!(80)),
+            ("note", json!([62, 2])),
+            ("note", json!([64, 1])),
+            ("rest", json!([null, 1])),
+            ("note", json!([64, 1])),
+            ("rest", json!([null, 1])),
+            ("bar_line", Value::Null),
+            ("play_block", json!(0)),
+        ];
+        assert_eq!(
+            events,
+            expected
+                .iter()
+                .map(|(kind, value)| (kind.to_string(), value.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        let json = to_json(&analysis, true);
+        let timeline = json["timeline"].as_array().unwrap();
+        assert_eq!(timeline.len(), 7);
+        assert_eq!(timeline[0]["name"], "O4 D");
+        assert_eq!(timeline[0]["velocity"], 101);
+        assert_eq!(timeline[6]["note"], 60);
+
+        // キーはREADMEのスキーマと同じ順に出力する
+        let keys =
+            |value: &Value| -> Vec<String> { value.as_object().unwrap().keys().cloned().collect() };
+        assert_eq!(
+            keys(&json),
+            ["schema_version", "header", "blocks", "sequence", "timeline"]
+        );
+        assert_eq!(
+            keys(&json["header"]),
+            [
+                "tempo",
+                "resolution",
+                "time_signature",
+                "instrument",
+                "title",
+                "composer",
+                "copyright",
+                "comments"
+            ]
+        );
+        assert_eq!(
+            keys(&json["blocks"][0]),
+            ["id", "name", "source", "calls", "plays", "events"]
+        );
+        assert_eq!(
+            keys(&json["blocks"][0]["events"][0]),
+            ["type", "note", "duration", "repeat", "source"]
+        );
+        assert_eq!(
+            keys(&timeline[0]),
+            [
+                "tick",
+                "seconds",
+                "duration",
+                "duration_seconds",
+                "note",
+                "name",
+                "velocity",
+                "source"
+            ]
+        );
+        assert_eq!(keys(&timeline[0]["source"]), ["file", "start", "end"]);
+    }
+}
@@ -0,0 +1,50 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{Accepts, CommonArgs};
+use crate::config;
+use crate::input::{output_or_stdout, write_output, Input};
+use mml_core::Lang;
+
+pub(crate) struct ExpandArgs {
+    input: Input,
+    output_file: Option<String>,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl ExpandArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let accepts = Accepts {
+            output: true,
+            expr: true,
+            ..Default::default()
+        };
+        let mut args = CommonArgs::parse(iter, accepts, lang, |_, _, _| Ok(false))?;
+        Ok(ExpandArgs {
+            input: args.take_input(lang)?,
+            output_file: args.output_file,
+            settings: args.settings,
+        })
+    }
+}
+
+// ブロック再生とリピートを展開したMMLを出力する (--outputの省略時は標準出力)
+pub(crate) fn expand(
+    ExpandArgs {
+        input,
+        output_file,
+        settings,
+    }: ExpandArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    input.check_exists(lang)?;
+    let settings = config::resolve(input.path(), settings, lang)?;
+    let song = input.load(&settings.parse_options(), lang)?;
+    let output_file = output_or_stdout(output_file);
+    write_output(output_file.as_deref(), song.expand().as_bytes())
+}
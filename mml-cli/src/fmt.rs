@@ -0,0 +1,121 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{Accepts, CommonArgs};
+use crate::config;
+use crate::input::render_errors;
+use mml_core::Lang;
+
+pub(crate) struct FmtArgs {
+    input_file: String,
+    check: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl FmtArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut check = false;
+        let mut args = CommonArgs::parse(iter, Accepts::default(), lang, |arg, _, _| {
+            match arg {
+                "--check" => check = true,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(FmtArgs {
+            input_file: args.take_input_file(lang)?,
+            check,
+            settings: args.settings,
+        })
+    }
+}
+
+// 整形したMMLはUTF-8で書き込む
+pub(crate) fn fmt(
+    FmtArgs {
+        input_file,
+        check,
+        settings,
+    }: FmtArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    let input_file = std::path::Path::new(&input_file);
+    if !input_file.is_file() {
+        return Err(tr!(
+            lang,
+            "{}が見つかりません",
+            "{} not found",
+            input_file.display()
+        ));
+    }
+    let settings = config::resolve(Some(input_file), settings, lang)?;
+    let options = settings.parse_options();
+    let formatted = match mml_core::format_file(input_file, &options) {
+        Ok(formatted) => formatted,
+        Err(mml_core::FormatError::InvalidSource(errors)) => {
+            return Err(render_errors(input_file.display(), &errors, lang))
+        }
+        Err(error) => return Err(error.render_in(lang)),
+    };
+    // 元の文字コードで読み込んだテキストと比べ、書き戻す場合も元の文字コードにする
+    let bytes = std::fs::read(input_file).map_err(|error| format!("{:?}", error))?;
+    let original = mml_core::decode(&bytes, options.encoding)
+        .map_err(|error| render_errors(input_file.display(), &[error.into()], lang))?;
+    if original == formatted {
+        eprintln!(
+            "{}",
+            tr!(
+                lang,
+                "{}は整形済みです",
+                "{} is already formatted",
+                input_file.display()
+            )
+        );
+        return Ok(());
+    }
+    if check {
+        let row = original
+            .lines()
+            .zip(formatted.lines())
+            .position(|(a, b)| a != b)
+            .unwrap_or_else(|| original.lines().count().min(formatted.lines().count()));
+        return Err(tr!(
+            lang,
+            "{}:{}: 整形されていません ({} fmt で整形できます)",
+            "{}:{}: not formatted (run {} fmt to format it)",
+            input_file.display(),
+            row + 1,
+            env!("CARGO_BIN_NAME")
+        ));
+    }
+    let formatted = match mml_core::encode(&formatted, &bytes, options.encoding) {
+        Ok(formatted) => formatted,
+        Err(error) => {
+            return Err(tr!(
+                lang,
+                "{}を書き換えませんでした: 整形したMMLを元の文字コードで書き込めません: {}",
+                "{} was not rewritten: the formatted MML cannot be written in the original encoding: {}",
+                input_file.display(),
+                error
+            ))
+        }
+    };
+    if let Err(error) = std::fs::write(input_file, formatted) {
+        return Err(format!("{:?}", error));
+    }
+    eprintln!(
+        "{}",
+        tr!(
+            lang,
+            "{}を整形しました",
+            "formatted {}",
+            input_file.display()
+        )
+    );
+    Ok(())
+}
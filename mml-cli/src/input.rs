@@ -0,0 +1,231 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::config;
+use mml_core::Lang;
+
+// 変換するMMLの読み込み元
+pub(crate) enum Input {
+    File(String),
+    // <mml-file>に - を指定した場合は標準入力から読み込む
+    Stdin,
+    // --exprで指定したMML
+    Expr(String),
+}
+
+impl Input {
+    pub(crate) fn from_arg(arg: &str) -> Self {
+        if arg == "-" {
+            Input::Stdin
+        } else {
+            Input::File(arg.to_owned())
+        }
+    }
+
+    pub(crate) fn name(&self, lang: Lang) -> String {
+        match self {
+            Input::File(file) => file.clone(),
+            Input::Stdin => tr!(lang, "(標準入力)", "(stdin)"),
+            Input::Expr(_) => "(--expr)".to_string(),
+        }
+    }
+
+    // 入力ファイルのパス (標準入力と--exprの場合はNone)
+    pub(crate) fn path(&self) -> Option<&std::path::Path> {
+        match self {
+            Input::File(file) => Some(std::path::Path::new(file)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn check_exists(&self, lang: Lang) -> Result<(), String> {
+        match self {
+            Input::File(file) if !std::path::Path::new(file).is_file() => {
+                Err(tr!(lang, "{}が見つかりません", "{} not found", file))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // 標準入力と--exprの#includeの相対パスはカレントディレクトリが基準
+    pub(crate) fn load(
+        &self,
+        options: &mml_core::ParseOptions,
+        lang: Lang,
+    ) -> Result<mml_core::Song, String> {
+        let src = match self {
+            Input::File(file) => return load_song(std::path::Path::new(file), options, lang),
+            Input::Expr(expr) => expr.clone(),
+            Input::Stdin => {
+                let bytes: Vec<u8> = self.read_stdin(lang)?;
+                mml_core::decode(&bytes, options.encoding)
+                    .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?
+            }
+        };
+        mml_core::parse_all_with(&src, options)
+            .map_err(|errors| render_errors(self.name(lang), &errors, lang))
+    }
+
+    // MMLかSMF (先頭がMThdのファイル) を読み込む
+    pub(crate) fn load_mml_or_smf(
+        &self,
+        options: &mml_core::ParseOptions,
+        lang: Lang,
+    ) -> Result<mml_core::Song, String> {
+        let bytes: Vec<u8> = match self {
+            Input::File(file) => std::fs::read(file)
+                .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?,
+            Input::Stdin => self.read_stdin(lang)?,
+            Input::Expr(_) => return self.load(options, lang),
+        };
+        if mml_core::is_smf(&bytes) {
+            return mml_core::read_smf(&bytes)
+                .map_err(|error| format!("{}: {}", self.name(lang), error.render_in(lang)));
+        }
+        match self {
+            Input::File(file) => load_song(std::path::Path::new(file), options, lang),
+            _ => {
+                let src = mml_core::decode(&bytes, options.encoding)
+                    .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?;
+                mml_core::parse_all_with(&src, options)
+                    .map_err(|errors| render_errors(self.name(lang), &errors, lang))
+            }
+        }
+    }
+
+    // MMLは記述範囲付きで読み込む (diffで記述位置を表示するため)。SMFはload_mml_or_smfと同じ
+    pub(crate) fn load_analyzed_or_smf(
+        &self,
+        options: &mml_core::ParseOptions,
+        lang: Lang,
+    ) -> Result<mml_core::Song, String> {
+        let bytes: Vec<u8> = match self {
+            Input::File(file) => std::fs::read(file)
+                .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?,
+            Input::Stdin => self.read_stdin(lang)?,
+            Input::Expr(expr) => expr.clone().into_bytes(),
+        };
+        if !matches!(self, Input::Expr(_)) && mml_core::is_smf(&bytes) {
+            return mml_core::read_smf(&bytes)
+                .map_err(|error| format!("{}: {}", self.name(lang), error.render_in(lang)));
+        }
+        let src = match self {
+            Input::Expr(expr) => expr.clone(),
+            _ => mml_core::decode(&bytes, options.encoding)
+                .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?,
+        };
+        mml_core::analyze(&src, self.path(), options)
+            .map(|analysis| analysis.song)
+            .map_err(|errors| render_errors(self.name(lang), &errors, lang))
+    }
+
+    fn read_stdin(&self, lang: Lang) -> Result<Vec<u8>, String> {
+        let mut bytes: Vec<u8> = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut bytes)
+            .map_err(|error| render_errors(self.name(lang), &[error.into()], lang))?;
+        Ok(bytes)
+    }
+}
+
+// 出力先のファイル (Noneは標準出力)。
+// --outputの省略時はmml.tomlのout-dirか、カレントディレクトリの入力ファイル名に拡張子を付けたファイル
+// (標準入力と--exprの場合は標準出力)
+pub(crate) fn output_path(
+    output_file: Option<String>,
+    input: &Input,
+    settings: &config::Settings,
+    extension: &str,
+) -> Option<std::path::PathBuf> {
+    match (output_file, input) {
+        (Some(file), _) if file == "-" => None,
+        (Some(file), _) => Some(file.into()),
+        (None, Input::File(file)) => {
+            let file = std::path::Path::new(file);
+            settings.output_in_out_dir(file, extension).or_else(|| {
+                let file_name = file.file_name().unwrap();
+                Some(format!("{}.{}", file_name.to_string_lossy(), extension).into())
+            })
+        }
+        (None, _) => None,
+    }
+}
+
+// --outputの省略時が標準出力のコマンドの出力先のファイル (Noneは標準出力)
+pub(crate) fn output_or_stdout(output_file: Option<String>) -> Option<std::path::PathBuf> {
+    output_file
+        .filter(|file| file != "-")
+        .map(std::path::PathBuf::from)
+}
+
+pub(crate) fn output_name(output_file: Option<&std::path::Path>, lang: Lang) -> String {
+    match output_file {
+        Some(file) => file.display().to_string(),
+        None => tr!(lang, "(標準出力)", "(stdout)"),
+    }
+}
+
+pub(crate) fn write_output(
+    output_file: Option<&std::path::Path>,
+    dst: &[u8],
+) -> Result<(), String> {
+    let res = match output_file {
+        Some(file) => file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(file, dst)),
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            std::io::Write::write_all(&mut stdout, dst)
+                .and_then(|_| std::io::Write::flush(&mut stdout))
+        }
+    };
+    res.map_err(|error| format!("{:?}", error))
+}
+
+pub(crate) fn load_song(
+    input_file: &std::path::Path,
+    options: &mml_core::ParseOptions,
+    lang: Lang,
+) -> Result<mml_core::Song, String> {
+    mml_core::parse_file_all_with(input_file, options)
+        .map_err(|errors| render_errors(input_file.display(), &errors, lang))
+}
+
+pub(crate) fn render_errors(
+    input_name: impl std::fmt::Display,
+    errors: &[mml_core::MMLError],
+    lang: Lang,
+) -> String {
+    if let [mml_core::MMLError::IoError(error)] = errors {
+        if error.kind() == std::io::ErrorKind::InvalidData {
+            return tr!(
+                lang,
+                "{}を読み込めませんでした: 文字コードが不正です (--encodingで文字コードを指定してください): {}",
+                "could not read {}: invalid encoding (specify the encoding with --encoding): {}",
+                input_name,
+                error
+            );
+        }
+        return tr!(
+            lang,
+            "{}を読み込めませんでした: {:?}",
+            "could not read {}: {:?}",
+            input_name,
+            error
+        );
+    }
+    let mut msg = String::new();
+    for error in errors.iter() {
+        msg.push_str(&error.render_in(lang));
+        msg.push_str("\n\n");
+    }
+    msg.push_str(&tr!(
+        lang,
+        "{}個のエラーがあります (エラーの説明は {} explain <error-code> で表示できます)",
+        "{} error(s) (run {} explain <error-code> for an explanation of an error)",
+        errors.len(),
+        env!("CARGO_BIN_NAME")
+    ));
+    msg
+}
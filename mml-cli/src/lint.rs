@@ -0,0 +1,82 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{option_value, parse_instrument, Accepts, CommonArgs};
+use crate::config;
+use crate::input::render_errors;
+use mml_core::Lang;
+
+pub(crate) struct LintArgs {
+    input_file: String,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl LintArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut args = CommonArgs::parse(iter, Accepts::default(), lang, |arg, iter, settings| {
+            match arg {
+                "--instrument" => {
+                    let num_str = option_value(iter, "<instrument-number>", lang)?;
+                    settings.instrument = Some(parse_instrument(&num_str, lang)?);
+                }
+                option @ ("--enable" | "--disable") => {
+                    let name = option_value(iter, "<check>", lang)?;
+                    let enabled = option == "--enable";
+                    if !mml_core::Lint::default().set(&name, enabled) {
+                        return Err(tr!(lang, "不明の確認項目: {}", "unknown check: {}", name));
+                    }
+                    settings.lint.push((name, enabled));
+                }
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(LintArgs {
+            input_file: args.take_input_file(lang)?,
+            settings: args.settings,
+        })
+    }
+}
+
+pub(crate) fn lint(
+    LintArgs {
+        input_file,
+        settings,
+    }: LintArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    let input_file = std::path::Path::new(&input_file);
+    if !input_file.is_file() {
+        return Err(tr!(
+            lang,
+            "{}が見つかりません",
+            "{} not found",
+            input_file.display()
+        ));
+    }
+    let settings = config::resolve(Some(input_file), settings, lang)?;
+    let warnings =
+        match mml_core::lint_file_with(input_file, &settings.lint(), &settings.parse_options()) {
+            Ok(warnings) => warnings,
+            Err(errors) => return Err(render_errors(input_file.display(), &errors, lang)),
+        };
+    if warnings.is_empty() {
+        eprintln!("{}", tr!(lang, "警告はありません", "no warnings"));
+        return Ok(());
+    }
+    for warning in warnings.iter() {
+        eprintln!("{}", warning.render_in(lang));
+        eprintln!();
+    }
+    Err(tr!(
+        lang,
+        "{}個の警告があります",
+        "{} warning(s)",
+        warnings.len()
+    ))
+}
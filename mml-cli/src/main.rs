@@ -1,19 +1,130 @@
 // mml-cli
 // author: Leonardone @ NEETSDKASU
 
+use mml_core::Lang;
+
+// 表示言語に合わせて日本語か英語の書式で文字列を作る
+macro_rules! tr {
+    ($lang:expr, $ja:literal, $en:literal $(, $arg:expr)* $(,)?) => {
+        match $lang {
+            Lang::Ja => format!($ja $(, $arg)*),
+            Lang::En => format!($en $(, $arg)*),
+        }
+    };
+}
+
+mod args;
+mod batch;
+mod check;
+mod compress;
+mod config;
+mod convert;
+mod diff;
+mod dump;
+mod expand;
+mod fmt;
+mod input;
+mod lint;
+mod transform;
+mod watch;
+
+use check::{check, info, SourceArgs};
+use compress::{compress, CompressArgs};
+use convert::{mml2smf, mml2tone, MmlToSmfArgs, MmlToToneArgs};
+use diff::{diff, DiffArgs};
+use dump::{dump, DumpArgs};
+use expand::{expand, ExpandArgs};
+use fmt::{fmt, FmtArgs};
+use lint::{lint, LintArgs};
+use transform::{transform, TransformArgs};
+
 fn main() -> Result<(), ()> {
-    match parse_args() {
-        Err(None) => show_usage(),
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let lang = match take_lang(&mut args) {
+        Ok(lang) => lang,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            return Err(());
+        }
+    };
+    match parse_args(args, lang) {
+        Err(None) => show_usage(lang),
         Err(Some(msg)) => {
             eprintln!("{}", msg);
             eprintln!();
-            show_usage();
+            show_usage(lang);
             return Err(());
         }
-        Ok(Command::ListInst) => list_inst(),
-        Ok(Command::ShowMmlSyntax) => show_mml_syntax(),
+        Ok(Command::ListInst) => list_inst(lang),
+        Ok(Command::ShowMmlSyntax) => show_mml_syntax(lang),
+        Ok(Command::Explain(code)) => {
+            if let Err(msg) = explain(code, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
         Ok(Command::MmlToSmf(args)) => {
-            if let Err(msg) = mml2smf(args) {
+            if let Err(msg) = mml2smf(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::MmlToTone(args)) => {
+            if let Err(msg) = mml2tone(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Lint(args)) => {
+            if let Err(msg) = lint(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Check(args)) => {
+            if let Err(msg) = check(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Info(args)) => {
+            if let Err(msg) = info(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Fmt(args)) => {
+            if let Err(msg) = fmt(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Dump(args)) => {
+            if let Err(msg) = dump(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Expand(args)) => {
+            if let Err(msg) = expand(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Compress(args)) => {
+            if let Err(msg) = compress(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Transform(args)) => {
+            if let Err(msg) = transform(args, lang) {
+                eprintln!("{}", msg);
+                return Err(());
+            }
+        }
+        Ok(Command::Diff(args)) => {
+            if let Err(msg) = diff(args, lang) {
                 eprintln!("{}", msg);
                 return Err(());
             }
@@ -29,41 +140,213 @@ fn main() -> Result<(), ()> {
     Ok(())
 }
 
-fn show_usage() {
-    println!(
-        r#"{pkg_name} {version}
+// --lang en|ja をコマンドライン引数から取り除いて表示言語を決める。
+// 指定が無ければ環境変数LANGから決める (LANGが無いか不明な言語の場合は日本語)。
+fn take_lang(args: &mut Vec<String>) -> Result<Lang, String> {
+    let env_lang = std::env::var("LANG")
+        .ok()
+        .and_then(|lang| Lang::parse(&lang));
+    let default_lang = env_lang.unwrap_or(Lang::Ja);
+    let index = match args.iter().position(|arg| arg == "--lang") {
+        Some(index) => index,
+        None => return Ok(default_lang),
+    };
+    args.remove(index);
+    if index >= args.len() {
+        return Err(tr!(
+            default_lang,
+            "--langの言語が指定されていません",
+            "missing language for --lang"
+        ));
+    }
+    let value = args.remove(index);
+    match Lang::parse(&value) {
+        Some(lang) => Ok(lang),
+        None => Err(tr!(
+            default_lang,
+            "不明の言語: {} (en か ja)",
+            "unknown language: {} (en or ja)",
+            value
+        )),
+    }
+}
+
+fn show_usage(lang: Lang) {
+    match lang {
+        Lang::Ja => println!(
+            r#"{pkg_name} {version}
 {description}
 
 USAGE:
-    {bin_name} mml2smf <mml-file> [OPTIONS]
+    {bin_name} mml2smf (<mml-file>... | --expr <mml>) [OPTIONS]
             MMLが記述されたテキストファイルからSMFファイルを生成します
+            (<mml-file>に - を指定すると標準入力から読み込み、<output-file>に - を指定すると標準出力に書き出します)
+            (<mml-file>に複数のファイル・ディレクトリ・パターン(*.mml, songs/**/*.mmlなど)を指定すると並列にまとめて変換し、結果の一覧を表示します)
+    {bin_name} mml2tone (<mml-file> | --expr <mml>) [--output <output-file>] [--loop-only] [--watch] [--quiet] [--input-mode <input-mode>] [--encoding <encoding>]
+            MMLが記述されたテキストファイルからトーンシーケンス(MMAPIのToneControl用)を生成します
+    {bin_name} lint <mml-file> [--instrument <instrument-number>] [--enable <check>] [--disable <check>] [--input-mode <input-mode>] [--encoding <encoding>]
+            MMLの誤りの可能性が高い記述を警告します
+    {bin_name} check <mml-file> [--input-mode <input-mode>] [--encoding <encoding>]
+            MMLのエラーを確認します (ファイルは出力しません。エラーがある場合は終了コードが0以外になります)
+    {bin_name} info <mml-file> [--input-mode <input-mode>] [--encoding <encoding>]
+            曲の長さ・音域・音符と休符の数・ブロックの使用回数・テンポ・分解能・楽器を表示します
+    {bin_name} fmt <mml-file> [--check] [--input-mode <input-mode>] [--encoding <encoding>]
+            MMLファイルを整形して上書きします (整形しても生成されるSMFは変わりません)
+    {bin_name} dump <mml-file> [--format json] [--timeline] [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            MMLの解析結果(ヘッダー・ブロック・メインのシーケンス)をJSONで出力します (--outputの省略時は標準出力)
+    {bin_name} expand (<mml-file> | --expr <mml>) [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            ブロック再生とリピートを展開し、音符ごとにオクターブと長さを明記したMMLを出力します (--outputの省略時は標準出力)
+    {bin_name} compress (<mml-file> | <smf-file> | --expr <mml>) [--target-bytes <bytes>] [--output <output-file>] [--quiet] [--input-mode <input-mode>] [--encoding <encoding>]
+            繰り返し現れる並びをブロックとリピートにしてトーンシーケンスを小さくしたMMLを出力します (--outputの省略時は標準出力)
+    {bin_name} transform (<mml-file> | <smf-file> | --expr <mml>) [--transpose <semitones>] [--scale <ratio>] [--retrograde] [--invert <pivot>] [--format <mml|smf>] [--instrument <instrument-number>] [--karaoke] [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            移調・拡大縮小・逆行・反行を指定した順に適用し、MMLかSMFで出力します (ブロックはできるだけ保ちます) (--outputの省略時は標準出力)
+    {bin_name} diff (<mml-file> | <smf-file>) (<mml-file> | <smf-file>) [--input-mode <input-mode>] [--encoding <encoding>]
+            ブロック再生とリピートを展開した音符と休符の並びを比較し、追加・削除・変更した音符と休符を小節:拍の位置と記述位置とともに表示します (違いがある場合は終了コードが0以外になります)
     {bin_name} list-instruments
             mml2smfコマンドで使用できる楽器一覧を表示します
     {bin_name} show-mml-syntax
             MMLの構文の説明を表示します
+    {bin_name} explain [<error-code>]
+            エラーコード(E0001など)の説明と誤った例・修正した例を表示します (省略時はエラーコード一覧)
     {bin_name} [-h | --help]
             このコマンドヘルプを表示します
 
 OPTIONS:
-    --output <output-file>              出力ファイル名を指定します
-    --instrument <instrument-number>    楽器番号を指定します
+    --output <output-file>              出力ファイル名を指定します (標準入力と--exprの場合の省略時は標準出力)
+    --out-dir <out-dir>                 まとめて変換する場合の出力先ディレクトリを指定します (入力のディレクトリの構成をそのまま作ります) (mml2smf)
+                                        省略時は入力ファイルと同じディレクトリに出力します
+    --instrument <instrument-number>    楽器番号を指定します (省略時はMMLの#INSTRUMENTの楽器)
+    --karaoke                           カラオケ形式(.kar)で出力します (歌詞をテキストイベントで書き込みます)
+    --loop-only                         ループ区間(!loopstart～!loopend)だけを出力します (mml2tone)
+    --watch                             入力ファイルと#includeのファイルを監視し、変更されるたびに変換し直します (mml2smf, mml2tone)
+    --expr <mml>                        ファイルの代わりにMMLを直接指定します (mml2smf, mml2tone, expand, compress, transform)
+    --quiet                             進行状況を表示しません (エラーは表示します) (mml2smf, mml2tone, compress)
+    --target-bytes <bytes>              圧縮後のトーンシーケンスのバイト数の上限を指定します (超える場合はエラー、以下になった時点で圧縮をやめます) (compress)
+    --input-mode <standard|japanese>    入力モードを指定します (japaneseはドレミファソラシ・♯♭・全角英数字も受け付けます)
+    --encoding <encoding>               入力ファイルの文字コードを指定します (省略時はauto)
+                                        文字コード: auto, utf-8, utf-16le, utf-16be, shift_jis, euc-jp
+    --enable <check>                    警告の確認項目を有効にします (lint)
+    --disable <check>                   警告の確認項目を無効にします (lint)
+                                        確認項目: {lint_names}
+    --check                             ファイルを書き換えずに整形済みかどうかを確認します (fmt)
+    --format <json>                     出力形式を指定します (dump)
+    --format <mml|smf>                  出力形式を指定します (省略時はmml) (transform)
+    --transpose <semitones>             半音単位で移調します (符号付き) (transform)
+    --scale <ratio>                     音の長さを倍率 (2 や 1/2 など) にします。拍子も同じ倍率にします (transform)
+    --retrograde                        最後の音から順に並べ替えます (逆行) (transform)
+    --invert <pivot>                    音値 (O4のCが60) を軸に音程を上下反転します (反行) (transform)
+    --timeline                          ブロック再生とリピートを展開した再生順の音符・休符も出力します (dump)
+    --no-config                         mml.tomlを読み込みません
+    --lang <en|ja>                      表示言語を指定します (省略時は環境変数LANGから決めます)
+
+CONFIG:
+    入力ファイルのディレクトリ (標準入力と--exprの場合はカレントディレクトリ) から親ディレクトリをたどって
+    最初に見つかったmml.tomlの設定を使います (コマンドライン引数の指定が優先されます)
 "#,
-        pkg_name = env!("CARGO_PKG_NAME"),
-        version = env!("CARGO_PKG_VERSION"),
-        description = env!("CARGO_PKG_DESCRIPTION"),
-        bin_name = env!("CARGO_BIN_NAME")
-    );
+            pkg_name = env!("CARGO_PKG_NAME"),
+            version = env!("CARGO_PKG_VERSION"),
+            description = env!("CARGO_PKG_DESCRIPTION"),
+            bin_name = env!("CARGO_BIN_NAME"),
+            lint_names = mml_core::LINT_NAMES.join(", ")
+        ),
+        Lang::En => println!(
+            r#"{pkg_name} {version}
+Converts MML (from MML-on-OAP) to SMF (MIDI) files
+
+USAGE:
+    {bin_name} mml2smf (<mml-file>... | --expr <mml>) [OPTIONS]
+            Generates an SMF file from a text file written in MML
+            (reads from stdin when <mml-file> is - and writes to stdout when <output-file> is -)
+            (converts multiple files, directories and patterns (*.mml, songs/**/*.mml, ...) in parallel and shows a summary table)
+    {bin_name} mml2tone (<mml-file> | --expr <mml>) [--output <output-file>] [--loop-only] [--watch] [--quiet] [--input-mode <input-mode>] [--encoding <encoding>]
+            Generates a tone sequence (for MMAPI ToneControl) from a text file written in MML
+    {bin_name} lint <mml-file> [--instrument <instrument-number>] [--enable <check>] [--disable <check>] [--input-mode <input-mode>] [--encoding <encoding>]
+            Warns about MML that is legal but probably wrong
+    {bin_name} check <mml-file> [--input-mode <input-mode>] [--encoding <encoding>]
+            Checks the MML for errors without writing any file (exits with a non-zero code on errors)
+    {bin_name} info <mml-file> [--input-mode <input-mode>] [--encoding <encoding>]
+            Shows the duration, note range, note and rest counts, block usage, tempo, resolution and instrument
+    {bin_name} fmt <mml-file> [--check] [--input-mode <input-mode>] [--encoding <encoding>]
+            Formats an MML file in place (the generated SMF does not change)
+    {bin_name} dump <mml-file> [--format json] [--timeline] [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            Outputs the parse result (header, blocks and main sequence) as JSON (to stdout when --output is omitted)
+    {bin_name} expand (<mml-file> | --expr <mml>) [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            Outputs the MML with blocks and repeats expanded and the octave and length written on every note (to stdout when --output is omitted)
+    {bin_name} compress (<mml-file> | <smf-file> | --expr <mml>) [--target-bytes <bytes>] [--output <output-file>] [--quiet] [--input-mode <input-mode>] [--encoding <encoding>]
+            Outputs the MML with repeated runs rewritten as blocks and repeats to make the tone sequence smaller (to stdout when --output is omitted)
+    {bin_name} transform (<mml-file> | <smf-file> | --expr <mml>) [--transpose <semitones>] [--scale <ratio>] [--retrograde] [--invert <pivot>] [--format <mml|smf>] [--instrument <instrument-number>] [--karaoke] [--output <output-file>] [--input-mode <input-mode>] [--encoding <encoding>]
+            Applies transposition, augmentation/diminution, retrograde and inversion in the given order and outputs MML or SMF (keeping blocks where possible) (to stdout when --output is omitted)
+    {bin_name} diff (<mml-file> | <smf-file>) (<mml-file> | <smf-file>) [--input-mode <input-mode>] [--encoding <encoding>]
+            Compares the notes and rests with blocks and repeats expanded, and shows the inserted, removed and changed ones with their bar:beat positions and source locations (exits with a non-zero code when they differ)
+    {bin_name} list-instruments
+            Shows the instruments available to the mml2smf command
+    {bin_name} show-mml-syntax
+            Shows the description of the MML syntax
+    {bin_name} explain [<error-code>]
+            Shows the rule of an error code (such as E0001) with a wrong and a corrected example (lists the error codes when omitted)
+    {bin_name} [-h | --help]
+            Shows this command help
+
+OPTIONS:
+    --output <output-file>              Specifies the output file name (stdout when omitted with stdin or --expr)
+    --out-dir <out-dir>                 Specifies the output directory when converting multiple files (mirrors the input directory tree) (mml2smf)
+                                        outputs next to the input files when omitted
+    --instrument <instrument-number>    Specifies the instrument number (the #INSTRUMENT of the MML when omitted)
+    --karaoke                           Outputs in karaoke format (.kar) (lyrics are written as text events)
+    --loop-only                         Outputs only the loop section (!loopstart to !loopend) (mml2tone)
+    --watch                             Watches the input file and #include files and converts again on every change (mml2smf, mml2tone)
+    --expr <mml>                        Specifies the MML directly instead of a file (mml2smf, mml2tone, expand, compress, transform)
+    --quiet                             Does not show progress (errors are still shown) (mml2smf, mml2tone, compress)
+    --target-bytes <bytes>              Specifies the maximum tone sequence size after compression (an error when exceeded; stops compressing once reached) (compress)
+    --input-mode <standard|japanese>    Specifies the input mode (japanese also accepts ドレミファソラシ, ♯♭ and full-width letters and digits)
+    --encoding <encoding>               Specifies the encoding of the input file (auto when omitted)
+                                        encodings: auto, utf-8, utf-16le, utf-16be, shift_jis, euc-jp
+    --enable <check>                    Enables a warning check (lint)
+    --disable <check>                   Disables a warning check (lint)
+                                        checks: {lint_names}
+    --check                             Checks whether the file is formatted without rewriting it (fmt)
+    --format <json>                     Specifies the output format (dump)
+    --format <mml|smf>                  Specifies the output format (mml when omitted) (transform)
+    --transpose <semitones>             Transposes by semitones (signed) (transform)
+    --scale <ratio>                     Scales the note lengths by a ratio (such as 2 or 1/2), and the time signature too (transform)
+    --retrograde                        Reverses the order of the notes (retrograde) (transform)
+    --invert <pivot>                    Inverts the intervals around a note number (60 is C in O4) (inversion) (transform)
+    --timeline                          Also outputs the notes and rests in playing order with blocks and repeats expanded (dump)
+    --no-config                         Does not read mml.toml
+    --lang <en|ja>                      Specifies the display language (taken from the LANG environment variable when omitted)
+
+CONFIG:
+    Uses the first mml.toml found by walking up from the directory of the input file
+    (the current directory for stdin and --expr) (command line options take precedence)
+"#,
+            pkg_name = env!("CARGO_PKG_NAME"),
+            version = env!("CARGO_PKG_VERSION"),
+            bin_name = env!("CARGO_BIN_NAME"),
+            lint_names = mml_core::LINT_NAMES.join(", ")
+        ),
+    }
 }
 
 enum Command {
     ListInst,
     MmlToSmf(MmlToSmfArgs),
+    MmlToTone(MmlToToneArgs),
+    Lint(LintArgs),
+    Check(SourceArgs),
+    Info(SourceArgs),
+    Fmt(FmtArgs),
+    Dump(DumpArgs),
+    Expand(ExpandArgs),
+    Compress(CompressArgs),
+    Transform(TransformArgs),
+    Diff(DiffArgs),
     ShowVersion,
     ShowMmlSyntax,
+    Explain(Option<String>),
 }
 
-fn parse_args() -> Result<Command, Option<String>> {
-    let mut iter = std::env::args().skip(1);
+fn parse_args(args: Vec<String>, lang: Lang) -> Result<Command, Option<String>> {
+    let mut iter = args.into_iter();
     let command = match iter.next() {
         Some(command) => command,
         None => return Err(None),
@@ -71,126 +354,120 @@ fn parse_args() -> Result<Command, Option<String>> {
     match command.as_str() {
         "-h" | "--help" => Err(None),
         "-v" | "--version" => Ok(Command::ShowVersion),
-        "mml2smf" => match MmlToSmfArgs::parse(&mut iter) {
+        "mml2smf" => match MmlToSmfArgs::parse(&mut iter, lang) {
             Ok(args) => Ok(Command::MmlToSmf(args)),
             Err(msg) => Err(Some(msg)),
         },
+        "mml2tone" => match MmlToToneArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::MmlToTone(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "lint" => match LintArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Lint(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "check" => match SourceArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Check(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "info" => match SourceArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Info(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "fmt" => match FmtArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Fmt(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "dump" => match DumpArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Dump(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "expand" => match ExpandArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Expand(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "compress" => match CompressArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Compress(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "transform" => match TransformArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Transform(args)),
+            Err(msg) => Err(Some(msg)),
+        },
+        "diff" => match DiffArgs::parse(&mut iter, lang) {
+            Ok(args) => Ok(Command::Diff(args)),
+            Err(msg) => Err(Some(msg)),
+        },
         "list-instruments" => Ok(Command::ListInst),
         "show-mml-syntax" => Ok(Command::ShowMmlSyntax),
-        unknown => Err(Some(format!("不明のコマンド: {}", unknown))),
+        "explain" => Ok(Command::Explain(iter.next())),
+        unknown => Err(Some(tr!(
+            lang,
+            "不明のコマンド: {}",
+            "unknown command: {}",
+            unknown
+        ))),
     }
 }
 
-fn list_inst() {
-    for cat in mml_core::INSTRUMENT_CATEGORIES.iter() {
-        println!("{}", cat.name_ja());
-        for inst in cat.instruments().iter() {
-            println!("   {:3} - {}", *inst as i32, inst.name_ja());
+fn explain(code: Option<String>, lang: Lang) -> Result<(), String> {
+    let code = match code {
+        Some(code) => code,
+        None => {
+            for code in mml_core::error_codes() {
+                let explanation = mml_core::explain(code, lang).unwrap();
+                println!("{}  {}", explanation.code, explanation.name);
+            }
+            return Ok(());
+        }
+    };
+    let explanation = match mml_core::explain(&code, lang) {
+        Some(explanation) => explanation,
+        None => {
+            return Err(tr!(
+                lang,
+                "不明のエラーコード: {}",
+                "unknown error code: {}",
+                code
+            ))
+        }
+    };
+    println!("{}: {}", explanation.code, explanation.name);
+    println!();
+    println!("{}", explanation.rule);
+    let examples = [
+        (explanation.wrong, tr!(lang, "誤った例:", "wrong:")),
+        (
+            explanation.corrected,
+            tr!(lang, "修正した例:", "corrected:"),
+        ),
+    ];
+    for (example, title) in examples.iter() {
+        if let Some(example) = example {
+            println!();
+            println!("{}", title);
+            for line in example.lines() {
+                println!("    {}", line);
+            }
         }
     }
+    Ok(())
 }
 
-fn show_mml_syntax() {
-    const TEXT: &str = include_str!("mml_syntax.in");
-    println!("{}", TEXT);
-}
-
-struct MmlToSmfArgs {
-    input_file: String,
-    output_file: Option<String>,
-    instrument: mml_core::Instrument,
-}
-
-impl MmlToSmfArgs {
-    fn parse<T>(iter: &mut T) -> Result<Self, String>
-    where
-        T: Iterator,
-        T::Item: AsRef<str>,
-    {
-        let input_file = match iter.next() {
-            None => return Err("<mml-file>が指定されていません".into()),
-            Some(file) => file.as_ref().to_owned(),
-        };
-        let mut output_file: Option<T::Item> = None;
-        let mut instrument: Option<T::Item> = None;
-        while let Some(arg) = iter.next() {
-            match arg.as_ref() {
-                "--output" => match iter.next() {
-                    None => return Err("<output-file>が指定されてまいません".into()),
-                    item => output_file = item,
-                },
-                "--instrument" => match iter.next() {
-                    None => return Err("<instrument-number>が指定されてまいません".into()),
-                    item => instrument = item,
-                },
-                unknown => return Err(format!("不明のオプション: {}", unknown)),
-            }
+fn list_inst(lang: Lang) {
+    for cat in mml_core::INSTRUMENT_CATEGORIES.iter() {
+        println!("{}", cat.name_in(lang));
+        for inst in cat.instruments().iter() {
+            println!("   {:3} - {}", *inst as i32, inst.name_in(lang));
         }
-        let output_file = output_file.map(|s| s.as_ref().to_owned());
-        let instrument = match instrument {
-            None => mml_core::INSTRUMENTS[0],
-            Some(num_str) => {
-                let num_str = num_str.as_ref();
-                let num = match num_str.parse::<usize>() {
-                    Ok(num) => num,
-                    Err(_) => {
-                        return Err(format!("<instrument-number>の指定が不正です: {}", num_str))
-                    }
-                };
-                if (1..=mml_core::INSTRUMENTS.len()).contains(&num) {
-                    mml_core::INSTRUMENTS[num - 1]
-                } else {
-                    return Err(format!("<instrument-number>の指定が不正です: {}", num_str));
-                }
-            }
-        };
-        Ok(MmlToSmfArgs {
-            input_file,
-            output_file,
-            instrument,
-        })
     }
 }
 
-fn mml2smf(
-    MmlToSmfArgs {
-        input_file,
-        output_file,
-        instrument,
-    }: MmlToSmfArgs,
-) -> Result<(), String> {
-    let input_file = std::path::Path::new(&input_file);
-    if !input_file.is_file() {
-        return Err(format!("{}が見つかりません", input_file.display()));
-    }
-    let output_file = match output_file {
-        Some(file) => file,
-        None => format!("{}.mid", input_file.file_name().unwrap().to_string_lossy()),
-    };
-    let output_file = std::path::Path::new(&output_file);
-    eprintln!("入力: {}", input_file.display());
-    eprintln!("出力: {}", output_file.display());
-    eprintln!("楽器: {} - {}", instrument as i32, instrument.name_ja());
-    eprintln!();
-    eprintln!("処理を開始します");
-    let src = match std::fs::read_to_string(input_file) {
-        Ok(src) => src,
-        Err(error) => {
-            return Err(format!(
-                "{}を読み込めませんでした: {:?}",
-                input_file.display(),
-                error
-            ))
-        }
-    };
-    let dst = match mml_core::convert(&src, instrument) {
-        Ok(dst) => dst,
-        Err(mml_core::MMLError::IoError(error)) => return Err(format!("{:?}", error)),
-        Err(error) => return Err(format!("MMLエラー: {:?}", error)),
-    };
-    if let Err(error) = std::fs::write(output_file, dst) {
-        return Err(format!("{:?}", error));
+fn show_mml_syntax(lang: Lang) {
+    const TEXT: &str = include_str!("mml_syntax.in");
+    const TEXT_EN: &str = include_str!("mml_syntax_en.in");
+    match lang {
+        Lang::Ja => println!("{}", TEXT),
+        Lang::En => println!("{}", TEXT_EN),
     }
-    eprintln!("MMLからSMFファイルへの変換に成功しました");
-    Ok(())
 }
@@ -0,0 +1,157 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use crate::args::{option_value, parse_instrument, Accepts, CommonArgs};
+use crate::config;
+use crate::input::{output_or_stdout, render_errors, write_output, Input};
+use mml_core::Lang;
+
+pub(crate) struct TransformArgs {
+    input: Input,
+    output_file: Option<String>,
+    // 指定した順に適用する
+    transforms: Vec<mml_core::Transform>,
+    // trueはSMF、falseはMMLで出力する
+    smf: bool,
+    // コマンドライン引数で指定した設定 (mml.tomlの設定に上書きする)
+    settings: config::Settings,
+}
+
+impl TransformArgs {
+    pub(crate) fn parse<T>(iter: &mut T, lang: Lang) -> Result<Self, String>
+    where
+        T: Iterator,
+        T::Item: AsRef<str>,
+    {
+        let mut transforms: Vec<mml_core::Transform> = Vec::new();
+        let mut smf = false;
+        let accepts = Accepts {
+            output: true,
+            expr: true,
+            ..Default::default()
+        };
+        let mut args = CommonArgs::parse(iter, accepts, lang, |arg, iter, settings| {
+            match arg {
+                "--transpose" => {
+                    let value = option_value(iter, "<semitones>", lang)?;
+                    match value.parse::<i32>() {
+                        Ok(semitones) => transforms.push(mml_core::Transform::Transpose(semitones)),
+                        Err(_) => {
+                            return Err(tr!(
+                                lang,
+                                "<semitones>の指定が不正です: {}",
+                                "invalid <semitones>: {}",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "--scale" => {
+                    let value = option_value(iter, "<ratio>", lang)?;
+                    let (numerator, denominator) = value.split_once('/').unwrap_or((&value, "1"));
+                    match (numerator.parse::<i32>(), denominator.parse::<i32>()) {
+                        (Ok(numerator), Ok(denominator)) if numerator > 0 && denominator > 0 => {
+                            transforms.push(mml_core::Transform::Scale {
+                                numerator,
+                                denominator,
+                            })
+                        }
+                        _ => {
+                            return Err(tr!(
+                                lang,
+                                "<ratio>の指定が不正です: {} (2 や 1/2 など)",
+                                "invalid <ratio>: {} (such as 2 or 1/2)",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "--retrograde" => transforms.push(mml_core::Transform::Retrograde),
+                "--invert" => {
+                    let value = option_value(iter, "<pivot>", lang)?;
+                    match value.parse::<i32>() {
+                        Ok(pivot) if (0..=127).contains(&pivot) => {
+                            transforms.push(mml_core::Transform::Invert { pivot })
+                        }
+                        _ => {
+                            return Err(tr!(
+                                lang,
+                                "<pivot>の指定が不正です: {} (音値 0～127)",
+                                "invalid <pivot>: {} (note number 0-127)",
+                                value
+                            ))
+                        }
+                    }
+                }
+                "--format" => match option_value(iter, "<format>", lang)?.as_str() {
+                    "mml" => smf = false,
+                    "smf" => smf = true,
+                    format => {
+                        return Err(tr!(
+                            lang,
+                            "<format>の指定が不正です: {} (mml か smf)",
+                            "invalid <format>: {} (mml or smf)",
+                            format
+                        ))
+                    }
+                },
+                "--instrument" => {
+                    let num_str = option_value(iter, "<instrument-number>", lang)?;
+                    settings.instrument = Some(parse_instrument(&num_str, lang)?);
+                }
+                "--karaoke" => settings.karaoke = Some(true),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(TransformArgs {
+            input: args.take_input(lang)?,
+            output_file: args.output_file,
+            transforms,
+            smf,
+            settings: args.settings,
+        })
+    }
+}
+
+// 移調・拡大縮小・逆行・反行をコマンドライン引数の順に適用し、MMLかSMFで出力する (--outputの省略時は標準出力)
+pub(crate) fn transform(
+    TransformArgs {
+        input,
+        output_file,
+        transforms,
+        smf,
+        settings,
+    }: TransformArgs,
+    lang: Lang,
+) -> Result<(), String> {
+    input.check_exists(lang)?;
+    let settings = config::resolve(input.path(), settings, lang)?;
+    let mut song = input.load_mml_or_smf(&settings.parse_options(), lang)?;
+    for transform in transforms.iter() {
+        song = song
+            .transform(*transform)
+            .map_err(|error| format!("{}: {}", input.name(lang), error.render_in(lang)))?;
+    }
+    let output_file = output_or_stdout(output_file);
+    if smf {
+        let inst = settings.instrument_for(&song);
+        let dst = if settings.karaoke() {
+            song.to_karaoke(inst)
+        } else {
+            song.to_smf(inst)
+        };
+        let dst = dst.map_err(|error| render_errors(input.name(lang), &[error], lang))?;
+        return write_output(output_file.as_deref(), &dst);
+    }
+    let mml = song.to_mml();
+    // 逆行で小節の長さが合わなくなった場合など
+    if let Err(errors) = mml_core::parse_all(&mml) {
+        return Err(render_errors(
+            tr!(lang, "(変形したMML)", "(transformed MML)"),
+            &errors,
+            lang,
+        ));
+    }
+    write_output(output_file.as_deref(), mml.as_bytes())
+}
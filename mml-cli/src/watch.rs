@@ -0,0 +1,181 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+use mml_core::{Lang, ParseOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// 変更を確認する間隔
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// 監視するファイルと変換の前に取得した更新日時 (読み込めないファイルはNone)
+struct WatchedFiles {
+    files: Vec<PathBuf>,
+    times: Vec<Option<SystemTime>>,
+}
+
+impl WatchedFiles {
+    // 入力ファイルと#includeで指定されたファイル (MMLにエラーがあっても見つけた分は監視する)
+    fn new(input_file: &Path, options: &ParseOptions) -> Self {
+        let files = mml_core::source_files(input_file, options);
+        let times = modified_times(&files);
+        WatchedFiles { files, times }
+    }
+
+    // 更新日時を取得したときから変更されたファイル (削除・作成も含む)
+    fn changed(&self) -> Vec<&PathBuf> {
+        self.files
+            .iter()
+            .zip(self.times.iter().zip(modified_times(&self.files)))
+            .filter(|(_, (old, new))| **old != *new)
+            .map(|(file, _)| file)
+            .collect()
+    }
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// 一回分の変換をして監視するファイルを返す。変換に失敗してもエラーを表示するだけで監視は続ける
+fn convert_once<F>(input_file: &Path, options: &ParseOptions, convert: &mut F) -> WatchedFiles
+where
+    F: FnMut() -> Result<(), String>,
+{
+    // 変換中に保存された変更も検出できるように変換の前に更新日時を取得する
+    let watched = WatchedFiles::new(input_file, options);
+    if let Err(msg) = convert() {
+        eprintln!("{}", msg);
+    }
+    watched
+}
+
+// 入力ファイルと#includeで指定されたファイルの更新日時を監視し、変更があるたびに変換し直す。
+// 変換に失敗してもエラーを表示して監視を続ける (Ctrl+Cで終了する)
+pub(crate) fn watch_files<F>(
+    input_file: &Path,
+    options: &ParseOptions,
+    quiet: bool,
+    lang: Lang,
+    mut convert: F,
+) -> Result<(), String>
+where
+    F: FnMut() -> Result<(), String>,
+{
+    loop {
+        let watched = convert_once(input_file, options, &mut convert);
+        if !quiet {
+            eprintln!();
+            eprintln!(
+                "{}",
+                tr!(
+                    lang,
+                    "変更を監視しています ({}個のファイル、Ctrl+Cで終了します)",
+                    "watching for changes ({} file(s), press Ctrl+C to quit)",
+                    watched.files.len()
+                )
+            );
+        }
+        let changed = loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            let changed = watched.changed();
+            if !changed.is_empty() {
+                break changed;
+            }
+        };
+        if quiet {
+            continue;
+        }
+        eprintln!();
+        for file in changed {
+            eprintln!(
+                "{}",
+                tr!(
+                    lang,
+                    "変更を検出しました: {}",
+                    "change detected: {}",
+                    file.display()
+                )
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 更新日時の精度に頼らないように、書き換えたファイルの更新日時は明示的に進める
+    fn touch(file: &Path, time: SystemTime) {
+        let f = std::fs::OpenOptions::new().write(true).open(file).unwrap();
+        f.set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn included_file_change() {
+        let dir = std::env::temp_dir().join(format!("mml-cli-watch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.mml");
+        let motif = dir.join("motif.mml");
+        std::fs::write(&main, "#include \"motif.mml\"\nC D").unwrap();
+        std::fs::write(&motif, "E F").unwrap();
+        let options = ParseOptions::default();
+
+        let mut count = 0;
+        let watched = convert_once(&main, &options, &mut || {
+            count += 1;
+            Ok(())
+        });
+        assert_eq!(count, 1);
+        assert_eq!(watched.files.len(), 2);
+        assert!(watched.files[1].ends_with("motif.mml"));
+        assert!(watched.changed().is_empty());
+
+        // #includeしたファイルを編集すると変換し直す対象になる
+        std::fs::write(&motif, "G A").unwrap();
+        touch(&motif, SystemTime::now() + Duration::from_secs(10));
+        let changed = watched.changed();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].ends_with("motif.mml"));
+
+        // 削除されたファイルも変更として検出する
+        let watched = WatchedFiles::new(&main, &options);
+        std::fs::remove_file(&motif).unwrap();
+        assert_eq!(watched.changed().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_watching_after_error() {
+        let dir = std::env::temp_dir().join(format!("mml-cli-watch-err-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = dir.join("main.mml");
+        let motif = dir.join("motif.mml");
+        // 構文エラーがあっても#includeしたファイルは監視する
+        std::fs::write(&main, "#include \"motif.mml\"\nC X D").unwrap();
+        std::fs::write(&motif, "E F").unwrap();
+        let options = ParseOptions::default();
+
+        let mut convert = || Err("parse error".to_string());
+        let watched = convert_once(&main, &options, &mut convert);
+        assert_eq!(watched.files.len(), 2);
+
+        // エラーを直して保存すると変更を検出し、次の変換に進める
+        std::fs::write(&main, "#include \"motif.mml\"\nC D").unwrap();
+        touch(&main, SystemTime::now() + Duration::from_secs(10));
+        assert_eq!(watched.changed(), [&main]);
+        let mut count = 0;
+        let watched = convert_once(&main, &options, &mut || {
+            count += 1;
+            Ok(())
+        });
+        assert_eq!(count, 1);
+        assert!(watched.changed().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
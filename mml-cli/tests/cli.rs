@@ -0,0 +1,209 @@
+// mml-cli
+// author: Leonardone @ NEETSDKASU
+
+// コマンドの終了コードと標準出力・標準エラー出力の確認 (ビルドしたmml-cliを実行する)
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// テストごとの一時ディレクトリ (mml.tomlは探さないように--no-configを付けて実行する)
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("mml-cli-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn write(&self, name: &str, text: &str) -> String {
+        let file = self.0.join(name);
+        std::fs::write(&file, text).unwrap();
+        file.display().to_string()
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// 英語の表示でコマンドを実行する (stdinは標準入力に書き込む)
+fn mml_cli(args: &[&str], stdin: Option<&[u8]>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mml-cli"))
+        .args(args)
+        .args(["--lang", "en"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = stdin.unwrap_or_default().to_vec();
+    let mut pipe = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || std::io::Write::write_all(&mut pipe, &input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    output
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn check_exit_code() {
+    let dir = TempDir::new("check");
+    let ok = dir.write("ok.mml", "C D E");
+    let output = mml_cli(&["check", &ok, "--no-config"], None);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(stderr(&output), format!("{}: no errors\n", ok));
+
+    // エラーは1回の実行で全て表示し、終了コードは1
+    let bad = dir.write("bad.mml", "C X D\nE Y\n");
+    let output = mml_cli(&["check", &bad, "--no-config"], None);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(stdout(&output).is_empty());
+    let expected = format!(
+        "MML error[E0008]: unknown character\n  --> {bad}:1:3\n  |\n1 | C X D\n  |   ^\n\n\
+         MML error[E0008]: unknown character\n  --> {bad}:2:3\n  |\n2 | E Y\n  |   ^\n\n\
+         2 error(s) (run mml-cli explain <error-code> for an explanation of an error)\n",
+        bad = bad
+    );
+    assert!(
+        stderr(&output).starts_with(&expected),
+        "{}",
+        stderr(&output)
+    );
+
+    let missing = dir.path().join("missing.mml").display().to_string();
+    let output = mml_cli(&["check", &missing, "--no-config"], None);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(stderr(&output).starts_with(&format!("{} not found\n", missing)));
+}
+
+#[test]
+fn info_block_usage() {
+    let dir = TempDir::new("info");
+    let file = dir.write(
+        "blocks.mml",
+        "{intro C D } {outro E } $intro $intro [2 F ]\n",
+    );
+    let output = mml_cli(&["info", &file, "--no-config"], None);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = stdout(&output);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines[0], "duration: 3.000 s (96 ticks)");
+    assert!(lines.contains(&"notes: 6 (range O4 C to O4 F, note numbers 60 to 65)"));
+    assert!(lines.contains(&"rests: 0"));
+    // ブロックごとの再生回数とブロック再生の記述の数 (使われていないブロックも表示する)
+    assert_eq!(
+        lines[lines.len() - 3..],
+        [
+            "blocks:",
+            "    0 intro: played 2 time(s) (2 call(s) in the source)",
+            "    1 outro: played 0 time(s) (0 call(s) in the source)",
+        ]
+    );
+}
+
+// 標準入力から読み込んで標準出力に書き出すSMFは、ファイルに書き出したSMFと同じ (進捗は標準エラー出力)
+#[test]
+fn mml2smf_stdin_stdout() {
+    let dir = TempDir::new("mml2smf");
+    let file = dir.write("song.mml", "C D E");
+    let out = dir.path().join("song.mid").display().to_string();
+    let output = mml_cli(&["mml2smf", &file, "--output", &out, "--no-config"], None);
+    assert!(output.status.success(), "{:?}", output);
+    let smf = std::fs::read(&out).unwrap();
+    assert!(smf.starts_with(b"MThd"));
+
+    let output = mml_cli(
+        &["mml2smf", "-", "--output", "-", "--no-config"],
+        Some(b"C D E"),
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, smf);
+    let progress = stderr(&output);
+    assert!(
+        progress.starts_with("input: (stdin)\noutput: (stdout)\n"),
+        "{}",
+        progress
+    );
+    assert!(progress.contains("converted MML to an SMF file successfully\n"));
+
+    let output = mml_cli(
+        &["mml2smf", "--expr", "C D E", "--output", "-", "--no-config"],
+        None,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, smf);
+    assert!(stderr(&output).starts_with("input: (--expr)\n"));
+}
+
+// --quietは進捗を表示しないが、エラーは表示して終了コードは1
+#[test]
+fn quiet_progress() {
+    let output = mml_cli(
+        &[
+            "mml2smf",
+            "--expr",
+            "C D",
+            "--output",
+            "-",
+            "--quiet",
+            "--no-config",
+        ],
+        None,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stdout.starts_with(b"MThd"));
+    assert_eq!(stderr(&output), "");
+
+    let output = mml_cli(
+        &[
+            "mml2smf",
+            "--expr",
+            "C X",
+            "--output",
+            "-",
+            "--quiet",
+            "--no-config",
+        ],
+        None,
+    );
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(output.stdout.is_empty());
+    assert!(
+        stderr(&output).starts_with("MML error[E0008]: unknown character\n  --> 1:3\n"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = mml_cli(
+        &[
+            "mml2tone",
+            "--expr",
+            "C D",
+            "--output",
+            "-",
+            "--quiet",
+            "--no-config",
+        ],
+        None,
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        output.stdout,
+        [0xfe, 0x01, 0xfd, 0x1e, 0xfc, 0x40, 0x3c, 0x10, 0x3e, 0x10]
+    );
+    assert_eq!(stderr(&output), "");
+}
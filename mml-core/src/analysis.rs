@@ -0,0 +1,336 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::{MMLError, Span};
+use crate::input::ParseOptions;
+use crate::mml;
+use crate::song::{Annotation, Song};
+use crate::timeline::{self, Event};
+use crate::tone_control;
+use std::path::Path;
+
+// 再生される音出し・無音 (ブロックやリピートで繰り返す場合は再生される回数だけある)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sound {
+    // 記述範囲 (マクロで展開した場合はマクロの値の記述範囲)
+    pub span: Span,
+    // 開始時刻 (分解能単位)
+    pub tick: i32,
+    pub duration: i32,
+    // ノート番号 (無音はNone)
+    pub note: Option<i32>,
+    // トーンシーケンスの音量 (0～100)
+    pub volume: i32,
+}
+
+impl Sound {
+    // SMFのノートオンのベロシティ (0～127)
+    pub fn velocity(&self) -> i32 {
+        (127 * self.volume / 100) & 0x7F
+    }
+}
+
+// ブロックの定義とメインのシーケンスの記述順のイベント。
+// ブロック再生と同じ音出し・無音だけのリピートは展開しない (複数の音出し・無音のリピートはトーンシーケンスと同じく展開する)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SequenceEvent {
+    // 音出し・無音 (noteがNoneは無音)。
+    // 同じ音出し・無音だけを繰り返すリピートはrepeatが回数 (それ以外は1)
+    Sound {
+        note: Option<i32>,
+        duration: i32,
+        repeat: i32,
+        span: Span,
+    },
+    // 音量 (V) の変更 (0～100)
+    Volume(i32),
+    // ブロック再生 (移調したブロック再生は展開した音出し・無音になる)
+    PlayBlock(i32),
+    BarLine,
+    // 直後の音符の歌詞
+    Lyric(String),
+    LoopStart,
+    LoopEnd,
+}
+
+// ブロックの定義と再生の記述範囲
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub id: i32,
+    // 名前で定義したブロックの名前
+    pub name: Option<String>,
+    // 定義の記述範囲 ({番号 か {名前)
+    pub span: Span,
+    // ブロック再生 ($番号 など) の記述範囲
+    pub calls: Vec<Span>,
+    // 再生される回数 (リピートの中やブロックの中のブロック再生は再生される回数だけ数える)
+    pub plays: usize,
+    // ブロックの中のイベント
+    pub events: Vec<SequenceEvent>,
+}
+
+// MMLの解析結果と記述範囲の情報 (エディタとの連携などで使う)
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub song: Song,
+    // 再生順の音出し・無音
+    pub sounds: Vec<Sound>,
+    pub blocks: Vec<Block>,
+    // メインのシーケンス (ブロックの定義の後) のイベント
+    pub sequence: Vec<SequenceEvent>,
+}
+
+// 曲全体の集計 (mml-cliのinfoで表示する)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    // 曲の長さ (分解能単位と秒)
+    pub ticks: i32,
+    pub seconds: f64,
+    // 再生される音符と休符の数
+    pub notes: usize,
+    pub rests: usize,
+    // 最も低い音と高い音のノート番号 (音符が無い場合はNone)
+    pub lowest: Option<i32>,
+    pub highest: Option<i32>,
+}
+
+impl Analysis {
+    pub fn summary(&self) -> Summary {
+        let ticks: i32 = self
+            .sounds
+            .last()
+            .map_or(0, |sound| sound.tick + sound.duration);
+        let notes = || self.sounds.iter().filter_map(|sound| sound.note);
+        Summary {
+            ticks,
+            seconds: self.song.seconds(ticks),
+            notes: notes().count(),
+            rests: self
+                .sounds
+                .iter()
+                .filter(|sound| sound.note.is_none())
+                .count(),
+            lowest: notes().min(),
+            highest: notes().max(),
+        }
+    }
+}
+
+pub(crate) fn analyze(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> Result<Analysis, Vec<MMLError>> {
+    let (song, blocks) = mml::parse_with_sources(src, file, options)?;
+    Ok(analyze_song(song, blocks))
+}
+
+// エラーがあっても読み込めた分の解析結果を見つけたエラーとともに返す (エラーから復帰できなかった場合はNone)
+pub(crate) fn analyze_partial(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> (Option<Analysis>, Vec<MMLError>) {
+    let (parsed, errors) = mml::parse_partial_with_sources(src, file, options);
+    let analysis = parsed.map(|(song, blocks)| analyze_song(song, blocks));
+    (analysis, errors)
+}
+
+fn analyze_song(song: Song, mut blocks: Vec<Block>) -> Analysis {
+    let mut sounds: Vec<Sound> = Vec::new();
+    let mut span: Option<&Span> = None;
+    let res: Result<(), ()> = timeline::walk(&song.tseq, |tick, event| {
+        let (note, duration, volume) = match event {
+            Event::Annotation(index) => {
+                match &song.annotations[index] {
+                    Annotation::Source(source) => span = Some(source),
+                    Annotation::BlockCall(id) => blocks[*id].plays += 1,
+                    _ => {}
+                }
+                return Ok(());
+            }
+            Event::Note {
+                note,
+                duration,
+                volume,
+            } => (Some(note), duration, volume),
+            Event::Rest { duration } => (None, duration, 0),
+        };
+        // REPEATで繰り返す音出し・無音は同じ記述範囲
+        sounds.push(Sound {
+            span: span.expect("source annotation").clone(),
+            tick,
+            duration,
+            note,
+            volume,
+        });
+        Ok(())
+    });
+    res.unwrap();
+
+    let sequence = read_sequence(&song, &mut blocks);
+
+    Analysis {
+        song,
+        sounds,
+        blocks,
+        sequence,
+    }
+}
+
+// トーンシーケンスを記述順に読み、ブロックの定義のイベントはblocksに入れ、メインのシーケンスのイベントを返す
+fn read_sequence(song: &Song, blocks: &mut [Block]) -> Vec<SequenceEvent> {
+    let tseq = &song.tseq;
+    let mut sequence: Vec<SequenceEvent> = Vec::new();
+    let mut block: Option<usize> = None;
+    let mut span: Option<&Span> = None;
+
+    // VERSION, TEMPO, RESOLUTION
+    let mut pos: usize = 6;
+    while let Some(cmd) = tseq.get(pos).map(|b| *b as i8) {
+        let sound = |note: Option<i32>, duration: u8, repeat: i32| SequenceEvent::Sound {
+            note,
+            duration: (duration & 0x7F).into(),
+            repeat,
+            span: span.expect("source annotation").clone(),
+        };
+        let event: Option<SequenceEvent> = match cmd {
+            tone_control::BLOCK_START => {
+                block = Some(tseq[pos + 1].into());
+                None
+            }
+            tone_control::BLOCK_END => {
+                block = None;
+                None
+            }
+            tone_control::PLAY_BLOCK => {
+                Some(SequenceEvent::PlayBlock((tseq[pos + 1] & 0x7F).into()))
+            }
+            tone_control::SET_VOLUME => Some(SequenceEvent::Volume((tseq[pos + 1] & 0x7F).into())),
+            tone_control::SILENCE => Some(sound(None, tseq[pos + 1], 1)),
+            tone_control::REPEAT => {
+                let note = tseq[pos + 2] as i8;
+                let note = (note != tone_control::SILENCE).then_some(note.into());
+                Some(sound(note, tseq[pos + 3], tseq[pos + 1].into()))
+            }
+            tone_control::ANNOTATION => {
+                match &song.annotations[timeline::annotation_index(tseq, pos)] {
+                    Annotation::BarLine(_) => Some(SequenceEvent::BarLine),
+                    Annotation::Lyric(lyric) => Some(SequenceEvent::Lyric(lyric.clone())),
+                    Annotation::LoopStart(_) => Some(SequenceEvent::LoopStart),
+                    Annotation::LoopEnd(_) => Some(SequenceEvent::LoopEnd),
+                    Annotation::Source(source) => {
+                        span = Some(source);
+                        None
+                    }
+                    Annotation::BlockCall(_) => None,
+                }
+            }
+            _ => Some(sound(Some(cmd.into()), tseq[pos + 1], 1)),
+        };
+        if let Some(event) = event {
+            match block.and_then(|id| blocks.get_mut(id)) {
+                Some(block) => block.events.push(event),
+                None => sequence.push(event),
+            }
+        }
+        pos += timeline::event_size(cmd);
+    }
+    sequence
+}
+
+// ノート番号のMMLでの書き方 (60なら "O4 C"、61なら "O4 C#")
+pub fn note_name(note: i32) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    format!(
+        "O{} {}",
+        note.div_euclid(12) - 1,
+        NAMES[note.rem_euclid(12) as usize]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sounds_and_blocks() {
+        let src = "#INSTRUMENT 33\nT120 %64\n{motif C D }\nO5 [2 E ] $motif(transpose=2) R $motif";
+        let analysis = analyze(src, None, &ParseOptions::default()).unwrap();
+        let song = &analysis.song;
+        assert_eq!(song.instrument(), Some(crate::inst::INSTRUMENTS[32]));
+        assert_eq!((song.tempo(), song.resolution()), (120, 64));
+        assert_eq!(song.seconds(32), 1.0);
+
+        let sounds: Vec<(usize, i32, Option<i32>)> = analysis
+            .sounds
+            .iter()
+            .map(|sound| (sound.span.start.col, sound.tick, sound.note))
+            .collect();
+        assert_eq!(
+            sounds,
+            [
+                (7, 0, Some(76)),
+                (7, 16, Some(76)),
+                (8, 32, Some(62)),
+                (10, 48, Some(64)),
+                (31, 64, None),
+                (8, 80, Some(60)),
+                (10, 96, Some(62)),
+            ]
+        );
+        assert_eq!(analysis.sounds[0].span.start.row, 4);
+
+        let block = &analysis.blocks[0];
+        assert_eq!(block.name.as_deref(), Some("motif"));
+        assert_eq!((block.span.start.row, block.span.start.col), (3, 1));
+        let calls: Vec<(usize, usize)> = block
+            .calls
+            .iter()
+            .map(|span| (span.start.col, span.end.col))
+            .collect();
+        assert_eq!(calls, [(11, 30), (33, 39)]);
+        assert_eq!(block.plays, 2);
+        let notes = |events: &[SequenceEvent]| -> Vec<(Option<i32>, i32)> {
+            events
+                .iter()
+                .filter_map(|event| match event {
+                    SequenceEvent::Sound { note, repeat, .. } => Some((*note, *repeat)),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(notes(&block.events), [(Some(60), 1), (Some(62), 1)]);
+        // 移調したブロック再生は展開した音出し・無音になる
+        assert_eq!(
+            notes(&analysis.sequence),
+            [(Some(76), 2), (Some(62), 1), (Some(64), 1), (None, 1)]
+        );
+        assert_eq!(analysis.sequence.last(), Some(&SequenceEvent::PlayBlock(0)));
+        assert_eq!(analysis.sounds[0].velocity(), 127);
+        let nested = analyze(
+            "{0 C }\n{1 [2 $0 ] }\n[3 $1 ] $0",
+            None,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let plays: Vec<usize> = nested.blocks.iter().map(|block| block.plays).collect();
+        assert_eq!(plays, [7, 3]);
+
+        // 記述範囲を記録してもSMFは変わらない
+        let plain = crate::mml::parse(src, None, &ParseOptions::default()).unwrap();
+        let inst = crate::inst::INSTRUMENTS[0];
+        assert_eq!(song.to_smf(inst).unwrap(), plain.to_smf(inst).unwrap());
+        assert_eq!(song.to_tone_sequence(), plain.to_tone_sequence());
+
+        let summary = analysis.summary();
+        assert_eq!((summary.ticks, summary.seconds), (112, 3.5));
+        assert_eq!((summary.notes, summary.rests), (6, 1));
+        assert_eq!((summary.lowest, summary.highest), (Some(60), Some(76)));
+
+        assert_eq!(note_name(60), "O4 C");
+        assert_eq!(note_name(1), "O-1 C#");
+    }
+}
@@ -0,0 +1,517 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::MMLError;
+use crate::expand::{self, Lines};
+use crate::song::{Annotation, Song};
+use crate::timeline::{self, Event};
+use std::collections::HashMap;
+
+// ブロック番号 0～127
+const MAX_BLOCKS: usize = 128;
+
+// ブロックにする並びの最大の長さ (イベント数)
+const MAX_LENGTH: usize = 64;
+
+// リピートにする繰り返しの単位の最大の長さ (イベント数)
+const MAX_PERIOD: usize = 16;
+
+// MMLの圧縮の結果
+#[derive(Debug, Clone)]
+pub struct Compressed {
+    // 圧縮したMML
+    pub mml: String,
+    // 圧縮前と圧縮後のトーンシーケンスのバイト数
+    pub original_bytes: usize,
+    pub bytes: usize,
+    // 作ったブロックの数
+    pub blocks: usize,
+}
+
+// 圧縮の単位 (トーンシーケンスのイベントと注釈)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Token {
+    Note {
+        note: i32,
+        duration: i32,
+        lyric: Option<String>,
+    },
+    Rest {
+        duration: i32,
+    },
+    // 同じ音符・休符の繰り返し (REPEAT) (noteがNoneは休符)
+    Repeat {
+        note: Option<i32>,
+        duration: i32,
+        count: i32,
+    },
+    Volume(i32),
+    BarLine,
+    LoopStart,
+    LoopEnd,
+    // ブロック再生 (圧縮中のブロックの添字)
+    Call(usize),
+}
+
+impl Token {
+    // トーンシーケンスのバイト数 (注釈はToneControl用のトーンシーケンスには含まれない)
+    fn bytes(&self) -> usize {
+        match self {
+            Token::Repeat { .. } => 4,
+            Token::BarLine | Token::LoopStart | Token::LoopEnd => 0,
+            _ => 2,
+        }
+    }
+
+    // ブロックとリピートの中に書けない (1回だけ再生される位置に書く)
+    fn is_barrier(&self) -> bool {
+        matches!(self, Token::LoopStart | Token::LoopEnd)
+    }
+}
+
+// 同じトークンに同じ番号を付ける (並びの比較を速くするため)
+#[derive(Default)]
+struct Tokens {
+    table: Vec<Token>,
+    ids: HashMap<Token, usize>,
+}
+
+impl Tokens {
+    fn id(&mut self, token: Token) -> usize {
+        if let Some(&id) = self.ids.get(&token) {
+            return id;
+        }
+        let id: usize = self.table.len();
+        self.table.push(token.clone());
+        self.ids.insert(token, id);
+        id
+    }
+
+    fn bytes(&self, seq: &[usize]) -> usize {
+        seq.iter().map(|&id| self.table[id].bytes()).sum()
+    }
+}
+
+// ブロック再生とリピートを展開した音符・休符の並びから、繰り返し現れる並びをブロックにしてトーンシーケンスを小さくする。
+// target_bytesを指定した場合はそのバイト数以下になった時点でブロックを作るのをやめる。
+// 圧縮したMMLから元のMMLと同じSMFが生成される。
+// 圧縮したMMLが読み込めない場合 (音符・休符の無い曲など) はそのエラーを返す
+pub(crate) fn compress(song: &Song, target_bytes: Option<usize>) -> Result<Compressed, MMLError> {
+    let resolution: i32 = song.resolution();
+    let mut tokens = Tokens::default();
+    let main: Vec<usize> = read_tokens(song)
+        .into_iter()
+        .map(|token| tokens.id(token))
+        .collect();
+
+    // seqs[0]はメインのシーケンス、seqs[1..]はブロック (Noneは展開して無くしたブロック)
+    let mut seqs: Vec<Option<Vec<usize>>> = vec![Some(main)];
+    let size = |seqs: &[Option<Vec<usize>>], tokens: &Tokens| -> usize {
+        // VERSION, TEMPO, RESOLUTION
+        let header: usize = 6;
+        let blocks: usize = seqs[1..]
+            .iter()
+            .flatten()
+            .map(|body| tokens.bytes(body) + 4)
+            .sum();
+        header + tokens.bytes(seqs[0].as_ref().unwrap()) + blocks
+    };
+
+    while seqs.len() <= MAX_BLOCKS && target_bytes.is_none_or(|t| size(&seqs, &tokens) > t) {
+        let body: Vec<usize> = match find_best(&seqs, &tokens) {
+            Some(body) => body,
+            None => break,
+        };
+        let call: usize = tokens.id(Token::Call(seqs.len()));
+        for seq in seqs.iter_mut().flatten() {
+            *seq = replace(seq, &body, call);
+        }
+        seqs.push(Some(body));
+    }
+
+    inline_single_calls(&mut seqs, &mut tokens);
+
+    // 呼び出すブロックが先に定義されるようにブロック番号を付け直す
+    let mut order: Vec<usize> = Vec::new();
+    let mut numbers: Vec<Option<usize>> = vec![None; seqs.len()];
+    number_blocks(0, &seqs, &tokens, &mut order, &mut numbers);
+
+    let mut mml = expand::header(song);
+    let mut lines = Lines::default();
+    for &index in order.iter() {
+        lines.push(&format!("{{{}", numbers[index].unwrap()));
+        write_sequence(
+            &mut lines,
+            seqs[index].as_ref().unwrap(),
+            &tokens,
+            &numbers,
+            resolution,
+        );
+        lines.push("}");
+        lines.break_line();
+    }
+    write_sequence(
+        &mut lines,
+        seqs[0].as_ref().unwrap(),
+        &tokens,
+        &numbers,
+        resolution,
+    );
+    mml.push_str(&lines.finish());
+
+    let bytes: usize = crate::parse(&mml)?.to_tone_sequence().len();
+    Ok(Compressed {
+        mml,
+        original_bytes: song.to_tone_sequence().len(),
+        bytes,
+        blocks: order.len(),
+    })
+}
+
+// 再生順のトークン。
+// 音量は音符の音量が変わる位置にだけ置き、続く休符はまとめ、3回以上続く同じ音符・休符はREPEATにする
+fn read_tokens(song: &Song) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut current_volume: i32 = 100;
+    let mut lyric: Option<String> = None;
+    let res: Result<(), ()> = timeline::walk(&song.tseq, |_, event| {
+        match event {
+            Event::Annotation(index) => match &song.annotations[index] {
+                Annotation::BarLine(_) => tokens.push(Token::BarLine),
+                Annotation::Lyric(text) => lyric = Some(text.clone()),
+                Annotation::LoopStart(_) => tokens.push(Token::LoopStart),
+                Annotation::LoopEnd(_) => tokens.push(Token::LoopEnd),
+                Annotation::Source(_) | Annotation::BlockCall(_) => {}
+            },
+            Event::Note {
+                note,
+                duration,
+                volume,
+            } => {
+                if volume != current_volume {
+                    tokens.push(Token::Volume(volume));
+                    current_volume = volume;
+                }
+                tokens.push(Token::Note {
+                    note,
+                    duration,
+                    lyric: lyric.take(),
+                });
+            }
+            Event::Rest { duration } => match tokens.last_mut() {
+                Some(Token::Rest { duration: last }) if *last + duration <= 127 => {
+                    *last += duration
+                }
+                _ => tokens.push(Token::Rest { duration }),
+            },
+        }
+        Ok(())
+    });
+    res.expect("invalid tone sequence");
+
+    let mut compressed: Vec<Token> = Vec::new();
+    let mut pos: usize = 0;
+    while pos < tokens.len() {
+        let token = &tokens[pos];
+        let (note, duration) = match token {
+            Token::Note {
+                note,
+                duration,
+                lyric: None,
+            } => (Some(*note), *duration),
+            Token::Rest { duration } => (None, *duration),
+            _ => (None, -1),
+        };
+        let run: usize = tokens[pos..]
+            .iter()
+            .take(127)
+            .take_while(|t| *t == token)
+            .count();
+        if duration >= 0 && run >= 3 {
+            compressed.push(Token::Repeat {
+                note,
+                duration,
+                count: run as i32,
+            });
+            pos += run;
+        } else {
+            compressed.push(token.clone());
+            pos += 1;
+        }
+    }
+    compressed
+}
+
+// ブロックにすると最もバイト数が減る並び (減らない場合はNone)。
+// 並びの出現回数は重ならないように先頭から数える
+fn find_best(seqs: &[Option<Vec<usize>>], tokens: &Tokens) -> Option<Vec<usize>> {
+    struct Count {
+        count: usize,
+        seq: usize,
+        end: usize,
+        first: (usize, usize),
+    }
+
+    // 最も減るバイト数、最初の出現位置、並び
+    let mut best: Option<(usize, (usize, usize), &[usize])> = None;
+    for len in 2..=MAX_LENGTH {
+        let mut counts: HashMap<&[usize], Count> = HashMap::new();
+        for (index, seq) in seqs.iter().enumerate() {
+            let seq = match seq {
+                Some(seq) if seq.len() >= len => seq,
+                _ => continue,
+            };
+            for pos in 0..=seq.len() - len {
+                let sub: &[usize] = &seq[pos..pos + len];
+                let (first, last) = (&tokens.table[sub[0]], &tokens.table[sub[len - 1]]);
+                if first.bytes() == 0
+                    || last.bytes() == 0
+                    || sub.iter().any(|&id| tokens.table[id].is_barrier())
+                {
+                    continue;
+                }
+                let count = counts.entry(sub).or_insert(Count {
+                    count: 0,
+                    seq: index,
+                    end: 0,
+                    first: (index, pos),
+                });
+                if count.seq != index || count.end <= pos {
+                    count.count += 1;
+                    count.seq = index;
+                    count.end = pos + len;
+                }
+            }
+        }
+        // 繰り返し現れる並びが無ければそれより長い並びも無い
+        if counts.values().all(|count| count.count < 2) {
+            break;
+        }
+        for (sub, count) in counts.iter() {
+            // ブロック再生 (2バイト) に置き換わり、ブロックの定義 (BLOCK_START, BLOCK_END) が増える
+            let before: usize = tokens.bytes(sub) * count.count;
+            let after: usize = 2 * count.count + tokens.bytes(sub) + 4;
+            if before <= after {
+                continue;
+            }
+            let saving: usize = before - after;
+            let better = match best {
+                None => true,
+                Some((best_saving, best_first, best_sub)) => {
+                    (saving, std::cmp::Reverse(count.first), sub.len())
+                        > (best_saving, std::cmp::Reverse(best_first), best_sub.len())
+                }
+            };
+            if better {
+                best = Some((saving, count.first, *sub));
+            }
+        }
+    }
+    best.map(|(_, _, sub)| sub.to_vec())
+}
+
+// 並びbodyを先頭から重ならないようにcallに置き換える
+fn replace(seq: &[usize], body: &[usize], call: usize) -> Vec<usize> {
+    let mut replaced: Vec<usize> = Vec::with_capacity(seq.len());
+    let mut pos: usize = 0;
+    while pos < seq.len() {
+        if seq[pos..].starts_with(body) {
+            replaced.push(call);
+            pos += body.len();
+        } else {
+            replaced.push(seq[pos]);
+            pos += 1;
+        }
+    }
+    replaced
+}
+
+// 1回しか再生しないブロックは再生する位置に展開する (ブロックの定義の分だけ小さくなる)
+fn inline_single_calls(seqs: &mut [Option<Vec<usize>>], tokens: &mut Tokens) {
+    loop {
+        let mut calls: Vec<usize> = vec![0; seqs.len()];
+        for seq in seqs.iter().flatten() {
+            for &id in seq.iter() {
+                if let Token::Call(block) = tokens.table[id] {
+                    calls[block] += 1;
+                }
+            }
+        }
+        let block = match (1..seqs.len()).find(|&block| seqs[block].is_some() && calls[block] < 2) {
+            Some(block) => block,
+            None => return,
+        };
+        let body: Vec<usize> = seqs[block].take().unwrap();
+        let call: usize = tokens.id(Token::Call(block));
+        for seq in seqs.iter_mut().flatten() {
+            *seq = seq
+                .iter()
+                .flat_map(|&id| if id == call { body.clone() } else { vec![id] })
+                .collect();
+        }
+    }
+}
+
+// seqs[index]から呼び出すブロックに、呼び出すブロックが先になる順に番号を付ける
+fn number_blocks(
+    index: usize,
+    seqs: &[Option<Vec<usize>>],
+    tokens: &Tokens,
+    order: &mut Vec<usize>,
+    numbers: &mut [Option<usize>],
+) {
+    for &id in seqs[index].as_ref().unwrap().iter() {
+        if let Token::Call(block) = tokens.table[id] {
+            if numbers[block].is_none() {
+                number_blocks(block, seqs, tokens, order, numbers);
+                numbers[block] = Some(order.len());
+                order.push(block);
+            }
+        }
+    }
+}
+
+// 並びを書き込む。同じ並びが続く部分はリピートにする (トーンシーケンスは変わらない)
+fn write_sequence(
+    lines: &mut Lines,
+    seq: &[usize],
+    tokens: &Tokens,
+    numbers: &[Option<usize>],
+    resolution: i32,
+) {
+    let mut pos: usize = 0;
+    while pos < seq.len() {
+        // 最も長い範囲を繰り返す単位 (長さ, 回数)
+        let mut repeat: Option<(usize, usize)> = None;
+        for period in 1..=MAX_PERIOD.min((seq.len() - pos) / 2) {
+            let unit: &[usize] = &seq[pos..pos + period];
+            let first = &tokens.table[unit[0]];
+            // 1つの音符・休符の繰り返しはREPEATにしてある
+            if (period == 1 && !matches!(first, Token::Call(_)))
+                || first.bytes() == 0
+                || unit.iter().any(|&id| tokens.table[id].is_barrier())
+            {
+                continue;
+            }
+            let count: usize = seq[pos..]
+                .chunks(period)
+                .take(127)
+                .take_while(|chunk| *chunk == unit)
+                .count();
+            if count >= 2 && repeat.is_none_or(|(p, c)| period * count > p * c) {
+                repeat = Some((period, count));
+            }
+        }
+        match repeat {
+            Some((period, count)) => {
+                lines.push(&format!("[{}", count));
+                for &id in seq[pos..pos + period].iter() {
+                    write_token(lines, &tokens.table[id], numbers, resolution);
+                }
+                lines.push("]");
+                pos += period * count;
+            }
+            None => {
+                write_token(lines, &tokens.table[seq[pos]], numbers, resolution);
+                pos += 1;
+            }
+        }
+    }
+}
+
+fn write_token(lines: &mut Lines, token: &Token, numbers: &[Option<usize>], resolution: i32) {
+    match token {
+        Token::Note {
+            note,
+            duration,
+            lyric,
+        } => lines.push(&expand::note_code(
+            *note,
+            *duration,
+            resolution,
+            lyric.as_deref(),
+        )),
+        Token::Rest { duration } => {
+            lines.push(&format!("R{}", expand::length(*duration, resolution)))
+        }
+        Token::Repeat {
+            note,
+            duration,
+            count,
+        } => {
+            let code = match note {
+                Some(note) => expand::note_code(*note, *duration, resolution, None),
+                None => format!("R{}", expand::length(*duration, resolution)),
+            };
+            lines.push(&format!("[{} {} ]", count, code));
+        }
+        Token::Volume(volume) => lines.push(&format!("V{}", volume)),
+        Token::BarLine => {
+            lines.push("|");
+            lines.break_line();
+        }
+        Token::LoopStart => lines.push("!loopstart"),
+        Token::LoopEnd => lines.push("!loopend"),
+        Token::Call(block) => lines.push(&format!("${}", numbers[*block].unwrap())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inst::INSTRUMENTS;
+
+    // 圧縮したMMLは元のMMLと同じSMFになる
+    fn compress_src(src: &str, target_bytes: Option<usize>) -> Compressed {
+        let song = crate::parse(src).unwrap();
+        let compressed = compress(&song, target_bytes).unwrap();
+        assert_eq!(
+            crate::parse(&compressed.mml)
+                .unwrap()
+                .to_smf(INSTRUMENTS[0])
+                .unwrap(),
+            song.to_smf(INSTRUMENTS[0]).unwrap(),
+            "{}",
+            compressed.mml
+        );
+        compressed
+    }
+
+    #[test]
+    fn compress_repeated_notes() {
+        let compressed = compress_src("C D E C D E C D E F G A F G A", None);
+        assert_eq!(
+            compressed.mml,
+            "T120 %64\n{0 O4 C4 O4 D4 O4 E4 }\n[3 $0 ] [2 O4 F4 O4 G4 O4 A4 ]\n"
+        );
+        assert_eq!((compressed.original_bytes, compressed.bytes), (36, 34));
+        assert_eq!(compressed.blocks, 1);
+
+        // 同じ音符の繰り返しはREPEAT、続く休符は1つにまとめる
+        let compressed = compress_src("C C C C R8 R8 D", None);
+        assert_eq!(compressed.mml, "T120 %64\n[4 O4 C4 ] R4 O4 D4\n");
+        assert_eq!((compressed.original_bytes, compressed.bytes), (20, 14));
+    }
+
+    #[test]
+    fn compress_expanded_song() {
+        // READMEの例1を展開したMMLからブロックを作り直す
+        let src = "{0 O5 L4 D C > B R }\n{1 O4 L8 A B < C > A G4 R4 }\n{2 $0 $1 }\n$2 [2 O4 L8 B < C D > B A B < C > A ] $2";
+        let song = crate::parse(src).unwrap();
+        let flat = song.expand();
+        let compressed = compress_src(&flat, None);
+        assert!(compressed.bytes < compressed.original_bytes);
+        assert!(compressed.bytes <= song.to_tone_sequence().len());
+        assert!(compressed.blocks > 0);
+
+        // 目標のバイト数以下になればブロックを作らない
+        let compressed = compress_src(&flat, Some(1000));
+        assert_eq!(compressed.blocks, 0);
+
+        // 小節線・歌詞・ループ位置・音量も保つ
+        compress_src(
+            "#TIME 2/4\nL8 !loopstart [4 V80 C\"ら\" D V90 E F | ] !loopend [3 G A B R | ]",
+            None,
+        );
+    }
+}
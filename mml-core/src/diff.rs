@@ -0,0 +1,485 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::Span;
+use crate::expand;
+use crate::song::{Annotation, Song, TimeSignature};
+use crate::timeline::{self, Event};
+
+// 比較した音出し・無音 (続けて並ぶ無音は1つにまとめる)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffSound {
+    // MMLでの書き方 (O4 E4 や R8)
+    pub code: String,
+    // ノート番号 (無音はNone)
+    pub note: Option<i32>,
+    // 開始時刻と長さ (その曲の分解能単位)
+    pub tick: i32,
+    pub duration: i32,
+    // SMFのノートオンのベロシティ (0～127、無音は0)
+    pub velocity: i32,
+    // 小節 (1始まり、弱起の小節は0) と拍 (1始まり)
+    pub bar: i32,
+    pub beat: i32,
+    // 拍の途中の位置 (拍の長さに対する分数 (分子, 分母)、拍の頭はNone)
+    pub offset: Option<(i32, i32)>,
+    // 記述範囲 (analyzeで読み込んだMMLの場合だけ)
+    pub span: Option<Span>,
+}
+
+impl DiffSound {
+    // 小節:拍 の表記 (3:2 や 3:2+1/2)
+    pub fn position(&self) -> String {
+        match self.offset {
+            None => format!("{}:{}", self.bar, self.beat),
+            Some((numerator, denominator)) => {
+                format!("{}:{}+{}/{}", self.bar, self.beat, numerator, denominator)
+            }
+        }
+    }
+}
+
+// 音出し・無音の違い
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SoundChange {
+    // 新しい曲にだけある音出し・無音
+    Inserted(DiffSound),
+    // 元の曲にだけある音出し・無音
+    Removed(DiffSound),
+    // 音高・長さ・ベロシティのどれかが変わった音出し・無音
+    Changed { old: DiffSound, new: DiffSound },
+}
+
+// 曲の違い (テンポ・拍子は異なる場合だけ (元, 新しい) の値になる)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SongDiff {
+    pub tempo: Option<(i32, i32)>,
+    pub time_signature: Option<(TimeSignature, TimeSignature)>,
+    pub changes: Vec<SoundChange>,
+}
+
+impl SongDiff {
+    pub fn is_empty(&self) -> bool {
+        self.tempo.is_none() && self.time_signature.is_none() && self.changes.is_empty()
+    }
+}
+
+// 比較に使う値 (長さは2つの曲の分解能の最小公倍数の単位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    note: Option<i32>,
+    duration: i32,
+    velocity: i32,
+}
+
+// 編集距離の重み (変更は追加1つより重く、追加と削除の組より軽い)
+const INSERT_COST: u32 = 2;
+const CHANGE_COST: u32 = 3;
+
+// 編集距離の表の大きさの上限 (長い曲どうしの比較で表が大きくなりすぎないようにする)
+const MAX_TABLE: usize = 1 << 20;
+
+// ブロック再生とリピートを展開した音出し・無音の並びを比較する。
+// 追加・削除・変更の重みの合計が最も小さくなるように対応付ける
+// (途中に音符を追加しても後の音符は位置がずれるだけで違いにはならない)
+pub(crate) fn diff(old: &Song, new: &Song) -> SongDiff {
+    let unit: i32 = lcm(old.resolution(), new.resolution());
+    let (mut old_sounds, old_bar_line) = sounds(old);
+    let (mut new_sounds, new_bar_line) = sounds(new);
+    // 小節線の無い曲 (SMFなど) は他方の曲の最初の小節線で弱起を決める
+    set_positions(&mut old_sounds, old, old_bar_line.or(new_bar_line));
+    set_positions(&mut new_sounds, new, new_bar_line.or(old_bar_line));
+    let key = |scale: i32| {
+        move |sound: &DiffSound| Key {
+            note: sound.note,
+            duration: sound.duration * scale,
+            velocity: sound.velocity,
+        }
+    };
+    let old_keys: Vec<Key> = old_sounds
+        .iter()
+        .map(key(unit / old.resolution()))
+        .collect();
+    let new_keys: Vec<Key> = new_sounds
+        .iter()
+        .map(key(unit / new.resolution()))
+        .collect();
+
+    let changes: Vec<SoundChange> = edit_steps(&old_keys, &new_keys)
+        .into_iter()
+        .filter_map(|step| match step {
+            Step::Same => None,
+            Step::Changed(i, j) => Some(SoundChange::Changed {
+                old: old_sounds[i].clone(),
+                new: new_sounds[j].clone(),
+            }),
+            Step::Removed(i) => Some(SoundChange::Removed(old_sounds[i].clone())),
+            Step::Inserted(j) => Some(SoundChange::Inserted(new_sounds[j].clone())),
+        })
+        .collect();
+
+    let old_ts: TimeSignature = old.time_signature().unwrap_or_default();
+    let new_ts: TimeSignature = new.time_signature().unwrap_or_default();
+    SongDiff {
+        tempo: (old.tempo() != new.tempo()).then_some((old.tempo(), new.tempo())),
+        time_signature: (old_ts != new_ts).then_some((old_ts, new_ts)),
+        changes,
+    }
+}
+
+// 再生順の音出し・無音と最初の小節線の位置 (時刻, 分解能)
+fn sounds(song: &Song) -> (Vec<DiffSound>, Option<(i32, i32)>) {
+    let resolution: i32 = song.resolution();
+    let mut sounds: Vec<DiffSound> = Vec::new();
+    let mut first_bar_line: Option<(i32, i32)> = None;
+    let mut span: Option<&Span> = None;
+    let res: Result<(), ()> = timeline::walk(&song.tseq, |tick, event| {
+        let (note, duration, volume) = match event {
+            Event::Annotation(index) => {
+                match &song.annotations[index] {
+                    Annotation::BarLine(_) => {
+                        first_bar_line.get_or_insert((tick, resolution));
+                    }
+                    Annotation::Source(source) => span = Some(source),
+                    _ => {}
+                }
+                return Ok(());
+            }
+            Event::Note {
+                note,
+                duration,
+                volume,
+            } => (Some(note), duration, volume),
+            Event::Rest { duration } => {
+                if let Some(last) = sounds.last_mut().filter(|last| last.note.is_none()) {
+                    last.duration += duration;
+                    return Ok(());
+                }
+                (None, duration, 0)
+            }
+        };
+        sounds.push(DiffSound {
+            code: String::new(),
+            note,
+            tick,
+            duration,
+            velocity: (127 * volume / 100) & 0x7F,
+            bar: 0,
+            beat: 0,
+            offset: None,
+            span: span.cloned(),
+        });
+        Ok(())
+    });
+    res.expect("invalid tone sequence");
+    // 曲の最後の無音は比較しない (聞こえない上に、SMFから読み込んだ曲には無い)
+    if sounds.last().is_some_and(|last| last.note.is_none()) {
+        sounds.pop();
+    }
+
+    for sound in sounds.iter_mut() {
+        sound.code = match sound.note {
+            Some(note) => expand::note_code(note, sound.duration, resolution, None),
+            None => format!("R{}", expand::length(sound.duration, resolution)),
+        };
+    }
+    (sounds, first_bar_line)
+}
+
+// 小節と拍の位置 (拍子の指定が無い場合は4/4)。
+// 最初の小節線が1小節より前にある場合は弱起 (最初の小節を0小節目にする)
+fn set_positions(sounds: &mut [DiffSound], song: &Song, first_bar_line: Option<(i32, i32)>) {
+    let resolution: i32 = song.resolution();
+    let ts: TimeSignature = song.time_signature().unwrap_or_default();
+    // 小節の長さ (分解能×拍子の分母の単位、拍の長さは分解能になる)
+    let denominator: i32 = ts.denominator.into();
+    let bar_length: i32 = resolution * ts.numerator as i32;
+
+    let pickup: i32 = match first_bar_line {
+        Some((tick, bar_line_resolution)) => {
+            let time: i32 = tick * denominator * resolution;
+            if time % bar_line_resolution == 0
+                && (1..bar_length).contains(&(time / bar_line_resolution))
+            {
+                bar_length - time / bar_line_resolution
+            } else {
+                0
+            }
+        }
+        None => 0,
+    };
+    for sound in sounds.iter_mut() {
+        let time: i32 = sound.tick * denominator + pickup;
+        let in_bar: i32 = time % bar_length;
+        sound.bar = time / bar_length + if pickup > 0 { 0 } else { 1 };
+        sound.beat = in_bar / resolution + 1;
+        let rest: i32 = in_bar % resolution;
+        if rest != 0 {
+            let g: i32 = gcd(rest, resolution);
+            sound.offset = Some((rest / g, resolution / g));
+        }
+    }
+}
+
+enum Step {
+    Same,
+    Changed(usize, usize),
+    Removed(usize),
+    Inserted(usize),
+}
+
+// 編集距離 (重み付き) が最小になる手順 (先頭と末尾の同じ部分を除いてから対応付ける)
+fn edit_steps(old: &[Key], new: &[Key]) -> Vec<Step> {
+    let prefix: usize = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix: usize = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a: &[Key] = &old[prefix..old.len() - suffix];
+    let b: &[Key] = &new[prefix..new.len() - suffix];
+
+    let mut steps: Vec<Step> = (0..prefix).map(|_| Step::Same).collect();
+    align(a, b, prefix, prefix, &mut steps);
+    steps.extend((0..suffix).map(|_| Step::Same));
+    steps
+}
+
+// aをbにする手順をstepsに追加する (aとbはoldとnewのi0とj0からの部分)。
+// 表が大きすぎる場合はHirschbergの方法でaを半分に分け、表を作らずにbの分ける位置を決める
+fn align(a: &[Key], b: &[Key], i0: usize, j0: usize, steps: &mut Vec<Step>) {
+    if a.len() < 2 || (a.len() + 1) * (b.len() + 1) <= MAX_TABLE {
+        table_steps(a, b, i0, j0, steps);
+        return;
+    }
+    let mid: usize = a.len() / 2;
+    let head: Vec<u32> = costs(&a[..mid], b, false);
+    let tail: Vec<u32> = costs(&a[mid..], b, true);
+    let split: usize = (0..=b.len())
+        .min_by_key(|&j| head[j] + tail[b.len() - j])
+        .unwrap();
+    align(&a[..mid], &b[..split], i0, j0, steps);
+    align(&a[mid..], &b[split..], i0 + mid, j0 + split, steps);
+}
+
+// aをbの先頭j個にする編集距離 (j = 0..=b.len())。
+// reverseの場合は後ろから比べ、aをbの末尾j個にする編集距離
+fn costs(a: &[Key], b: &[Key], reverse: bool) -> Vec<u32> {
+    let at = |i: usize| if reverse { a[a.len() - 1 - i] } else { a[i] };
+    let bt = |j: usize| if reverse { b[b.len() - 1 - j] } else { b[j] };
+    let mut prev: Vec<u32> = (0..=b.len() as u32).map(|j| j * INSERT_COST).collect();
+    let mut cur: Vec<u32> = vec![0; b.len() + 1];
+    for i in 0..a.len() {
+        cur[0] = (i as u32 + 1) * INSERT_COST;
+        for j in 1..=b.len() {
+            cur[j] = if at(i) == bt(j - 1) {
+                prev[j - 1]
+            } else {
+                (CHANGE_COST + prev[j - 1])
+                    .min(INSERT_COST + prev[j])
+                    .min(INSERT_COST + cur[j - 1])
+            };
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+    prev
+}
+
+// 編集距離の表を作って手順を求める
+fn table_steps(a: &[Key], b: &[Key], i0: usize, j0: usize, steps: &mut Vec<Step>) {
+    // table[i * width + j] は a[i..] を b[j..] にする編集距離
+    let width: usize = b.len() + 1;
+    let mut table: Vec<u32> = vec![0; (a.len() + 1) * width];
+    for i in (0..=a.len()).rev() {
+        for j in (0..=b.len()).rev() {
+            table[i * width + j] = if i == a.len() {
+                (b.len() - j) as u32 * INSERT_COST
+            } else if j == b.len() {
+                (a.len() - i) as u32 * INSERT_COST
+            } else if a[i] == b[j] {
+                table[(i + 1) * width + j + 1]
+            } else {
+                (CHANGE_COST + table[(i + 1) * width + j + 1])
+                    .min(INSERT_COST + table[(i + 1) * width + j])
+                    .min(INSERT_COST + table[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let cost: u32 = table[i * width + j];
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            steps.push(Step::Same);
+            i += 1;
+            j += 1;
+        } else if i < a.len() && j < b.len() && cost == CHANGE_COST + table[(i + 1) * width + j + 1]
+        {
+            steps.push(Step::Changed(i0 + i, j0 + j));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && cost == INSERT_COST + table[(i + 1) * width + j] {
+            steps.push(Step::Removed(i0 + i));
+            i += 1;
+        } else {
+            steps.push(Step::Inserted(j0 + j));
+            j += 1;
+        }
+    }
+}
+
+fn gcd(a: i32, b: i32) -> i32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn lcm(a: i32, b: i32) -> i32 {
+    a / gcd(a, b) * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff_src(old: &str, new: &str) -> SongDiff {
+        let old = crate::analyze(old, None, &Default::default()).unwrap().song;
+        let new = crate::analyze(new, None, &Default::default()).unwrap().song;
+        diff(&old, &new)
+    }
+
+    // (種類, 位置, 元の記述, 新しい記述)
+    fn summary(diff: &SongDiff) -> Vec<(&str, String, &str, &str)> {
+        diff.changes
+            .iter()
+            .map(|change| match change {
+                SoundChange::Inserted(new) => ("+", new.position(), "", new.code.as_str()),
+                SoundChange::Removed(old) => ("-", old.position(), old.code.as_str(), ""),
+                SoundChange::Changed { old, new } => {
+                    ("~", new.position(), old.code.as_str(), new.code.as_str())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_sounds() {
+        // ブロックやリピートの書き方が違っても同じ曲なら違いは無い
+        let diff = diff_src("{0 C D } $0 [3 E ] R8 R8 $0", "C D E E E R4 C D R1");
+        assert!(diff.is_empty(), "{:?}", diff);
+        // 分解能が違っても同じ長さなら違いは無い
+        assert!(diff_src("%64 C8 D4.", "%96 C8 D4.").is_empty());
+
+        let diff = diff_src("C D E F | G A B < C", "C D F F | G A < C D");
+        assert_eq!(
+            summary(&diff),
+            [
+                ("~", "1:3".to_string(), "O4 E4", "O4 F4"),
+                ("-", "2:3".to_string(), "O4 B4", ""),
+                ("+", "2:4".to_string(), "", "O5 D4"),
+            ]
+        );
+        // 記述範囲
+        let SoundChange::Changed { old, new } = &diff.changes[0] else {
+            panic!("{:?}", diff.changes[0]);
+        };
+        let col = |sound: &DiffSound| sound.span.as_ref().unwrap().start.col;
+        assert_eq!((col(old), col(new)), (5, 5));
+
+        // 途中に追加した音符の後は位置がずれても違いにならない。音量はベロシティの違いになる
+        let diff = diff_src("T120 C R8 D E F", "T132 C R4 D V50 E V100 C F");
+        assert_eq!(diff.tempo, Some((120, 132)));
+        assert_eq!(
+            summary(&diff),
+            [
+                ("~", "1:2".to_string(), "R8", "R4"),
+                ("~", "1:4".to_string(), "O4 E4", "O4 E4"),
+                ("+", "2:1".to_string(), "", "O4 C4"),
+            ]
+        );
+    }
+
+    #[test]
+    fn bar_and_beat() {
+        // 弱起の小節は0小節目
+        let diff = diff_src(
+            "#TIME 3/4\nC | D E F | G8 A8 B2 |",
+            "#TIME 3/4\nC | D E F | G8 B8 B2 |",
+        );
+        assert_eq!(
+            summary(&diff),
+            [("~", "2:1+1/2".to_string(), "O4 A8", "O4 B8")]
+        );
+
+        let diff = diff_src(
+            "#TIME 6/8\nC8 D8 E8 F8 G8 A8 | B4. C4. |",
+            "#TIME 6/8\nC8 D8 E8 F8 G8 A8 | B4 R8 C4. |",
+        );
+        assert_eq!(
+            summary(&diff),
+            [
+                ("~", "2:1".to_string(), "O4 B4.", "O4 B4"),
+                ("+", "2:3".to_string(), "", "R8"),
+            ]
+        );
+        // 曲の最後の無音は比較しない
+        assert!(diff_src("C D", "C D R2").is_empty());
+    }
+
+    // 表が大きすぎる長い並びは分けて対応付けても編集距離が最小になる
+    #[test]
+    fn long_sequences() {
+        let key = |note: i32| Key {
+            note: Some(note),
+            duration: 16,
+            velocity: 100,
+        };
+        let old: Vec<Key> = (0..3000).map(|i| key(i * 7 % 53)).collect();
+        let mut new: Vec<Key> = old.clone();
+        new[100] = key(80);
+        new[2900] = key(81);
+        new.remove(1500);
+        new.insert(700, key(82));
+        new.insert(2000, key(83));
+        assert!(old.len() * new.len() > MAX_TABLE);
+
+        let steps = edit_steps(&old, &new);
+        let mut cost: u32 = 0;
+        let mut rebuilt: Vec<Key> = Vec::new();
+        let mut i: usize = 0;
+        for step in steps.iter() {
+            match *step {
+                Step::Same => {
+                    rebuilt.push(old[i]);
+                    i += 1;
+                }
+                Step::Changed(old_index, new_index) => {
+                    assert_eq!(old_index, i);
+                    rebuilt.push(new[new_index]);
+                    cost += CHANGE_COST;
+                    i += 1;
+                }
+                Step::Removed(old_index) => {
+                    assert_eq!(old_index, i);
+                    cost += INSERT_COST;
+                    i += 1;
+                }
+                Step::Inserted(new_index) => {
+                    assert_eq!(new_index, rebuilt.len());
+                    rebuilt.push(new[new_index]);
+                    cost += INSERT_COST;
+                }
+            }
+        }
+        assert_eq!(i, old.len());
+        assert_eq!(rebuilt, new);
+        assert_eq!(cost, CHANGE_COST * 2 + INSERT_COST * 3);
+    }
+}
@@ -0,0 +1,201 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use encoding_rs::{Encoding, EUC_JP, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8};
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+
+// MMLファイルの文字コード
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    // BOMと内容から判定する (UTF-8, UTF-16, Shift_JIS, EUC-JP)
+    #[default]
+    Auto,
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    // MML-on-OAPの頃のツールで作ったファイルの文字コード (Windows-31J)
+    ShiftJis,
+    EucJp,
+}
+
+impl TextEncoding {
+    // 文字コード名 (大文字小文字と - _ の違いは区別しない。mml-cliの--encodingで指定する)
+    pub fn parse(s: &str) -> Option<TextEncoding> {
+        let name = s.to_ascii_lowercase().replace('_', "-");
+        match name.as_str() {
+            "auto" => Some(TextEncoding::Auto),
+            "utf-8" | "utf8" => Some(TextEncoding::Utf8),
+            "utf-16" | "utf-16le" | "utf16le" => Some(TextEncoding::Utf16Le),
+            "utf-16be" | "utf16be" => Some(TextEncoding::Utf16Be),
+            "shift-jis" | "sjis" | "cp932" | "windows-31j" => Some(TextEncoding::ShiftJis),
+            "euc-jp" | "eucjp" => Some(TextEncoding::EucJp),
+            _ => None,
+        }
+    }
+
+    fn encoding(self) -> Option<&'static Encoding> {
+        match self {
+            TextEncoding::Auto => None,
+            TextEncoding::Utf8 => Some(UTF_8),
+            TextEncoding::Utf16Le => Some(UTF_16LE),
+            TextEncoding::Utf16Be => Some(UTF_16BE),
+            TextEncoding::ShiftJis => Some(SHIFT_JIS),
+            TextEncoding::EucJp => Some(EUC_JP),
+        }
+    }
+}
+
+// 指定した文字コードでテキストにする (BOMがある場合はBOMの文字コードを優先する)。
+// 文字コードとして不正なバイト列がある場合はInvalidDataの入出力エラーを返す
+pub fn decode(bytes: &[u8], encoding: TextEncoding) -> io::Result<String> {
+    let (encoding, bom_length) = resolve(bytes, encoding);
+    match encoding.decode_without_bom_handling_and_without_replacement(&bytes[bom_length..]) {
+        Some(text) => Ok(text.into_owned()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stream did not contain valid {}", encoding.name()),
+        )),
+    }
+}
+
+// decodeで読み込んだ元のバイト列と同じ文字コード (BOMの有無も同じ) でテキストをバイト列にする (fmtで書き戻すため)。
+// その文字コードで表せない文字がある場合はInvalidDataの入出力エラーを返す
+pub fn encode(text: &str, original: &[u8], encoding: TextEncoding) -> io::Result<Vec<u8>> {
+    let (encoding, bom_length) = resolve(original, encoding);
+    let mut bytes: Vec<u8> = original[..bom_length].to_vec();
+    // encoding_rsのencodeはUTF-16への変換に対応していない (UTF-8になる) ため自前で変換する
+    if encoding == UTF_16LE {
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    } else if encoding == UTF_16BE {
+        bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+    } else {
+        let (encoded, _, had_errors) = encoding.encode(text);
+        if had_errors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("text contains characters not in {}", encoding.name()),
+            ));
+        }
+        bytes.extend_from_slice(&encoded);
+    }
+    Ok(bytes)
+}
+
+// 読み込む文字コードとBOMのバイト数
+fn resolve(bytes: &[u8], encoding: TextEncoding) -> (&'static Encoding, usize) {
+    match Encoding::for_bom(bytes) {
+        Some((encoding, bom_length)) => (encoding, bom_length),
+        None => match encoding.encoding() {
+            Some(encoding) => (encoding, 0),
+            None => (detect(bytes), 0),
+        },
+    }
+}
+
+pub(crate) fn read_file(path: &Path, encoding: TextEncoding) -> io::Result<String> {
+    decode(&std::fs::read(path)?, encoding)
+}
+
+// BOMの無いバイト列の文字コードを推定する
+fn detect(bytes: &[u8]) -> &'static Encoding {
+    if std::str::from_utf8(bytes).is_ok() {
+        return UTF_8;
+    }
+
+    // BOMの無いUTF-16 (MMLはほとんどASCIIの文字なので上位バイトの0が片側に偏る)
+    let half: usize = bytes.len() / 2;
+    let zeros = |offset: usize| -> usize {
+        bytes
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|b| **b == 0)
+            .count()
+    };
+    if half > 0 && zeros(1) * 2 > half {
+        return UTF_16LE;
+    }
+    if half > 0 && zeros(0) * 2 > half {
+        return UTF_16BE;
+    }
+
+    // Shift_JISとEUC-JPは不正なバイト列が無いほうを選ぶ。
+    // 両方で読める場合は半角カタカナが少ないほうを選ぶ (EUC-JPの2バイト文字はShift_JISでは半角カタカナになる)
+    let halfwidth_katakana = |encoding: &'static Encoding| -> Option<usize> {
+        encoding
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .map(|text: Cow<str>| {
+                text.chars()
+                    .filter(|ch| ('\u{FF61}'..='\u{FF9F}').contains(ch))
+                    .count()
+            })
+    };
+    match (halfwidth_katakana(SHIFT_JIS), halfwidth_katakana(EUC_JP)) {
+        (Some(sjis), Some(euc)) if euc < sjis => EUC_JP,
+        (None, Some(_)) => EUC_JP,
+        _ => SHIFT_JIS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "#TITLE かえるの歌\nO4 L4 C\"ド\" D\"レ\" E\"ミ\" F\"ファ\"\n";
+
+    fn encode(encoding: &'static Encoding) -> Vec<u8> {
+        // encoding_rsのencodeはUTF-16への変換に対応していないため自前で変換する
+        if encoding == UTF_16LE {
+            return TEXT.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        }
+        if encoding == UTF_16BE {
+            return TEXT.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        }
+        encoding.encode(TEXT).0.into_owned()
+    }
+
+    #[test]
+    fn detect_encoding() {
+        for encoding in [UTF_8, UTF_16LE, UTF_16BE, SHIFT_JIS, EUC_JP] {
+            let bytes = encode(encoding);
+            assert_eq!(detect(&bytes), encoding);
+            assert_eq!(decode(&bytes, TextEncoding::Auto).unwrap(), TEXT);
+        }
+
+        // BOM付き
+        let mut bytes: Vec<u8> = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend(TEXT.bytes());
+        assert_eq!(decode(&bytes, TextEncoding::Auto).unwrap(), TEXT);
+        let mut bytes: Vec<u8> = vec![0xFF, 0xFE];
+        bytes.extend(encode(UTF_16LE));
+        assert_eq!(decode(&bytes, TextEncoding::ShiftJis).unwrap(), TEXT);
+
+        // 元の文字コードとBOMのまま書き戻す
+        for original in [
+            encode(SHIFT_JIS),
+            encode(EUC_JP),
+            encode(UTF_16BE),
+            bytes.clone(),
+        ] {
+            assert_eq!(
+                super::encode(TEXT, &original, TextEncoding::Auto).unwrap(),
+                original
+            );
+        }
+        let error = super::encode("🎵", &encode(SHIFT_JIS), TextEncoding::Auto).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // 指定した文字コードとして不正なバイト列
+        let error = decode(&encode(SHIFT_JIS), TextEncoding::Utf8).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        assert_eq!(
+            TextEncoding::parse("Shift_JIS"),
+            Some(TextEncoding::ShiftJis)
+        );
+        assert_eq!(TextEncoding::parse("EUC-JP"), Some(TextEncoding::EucJp));
+        assert_eq!(TextEncoding::parse("latin1"), None);
+    }
+}
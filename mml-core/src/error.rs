@@ -0,0 +1,459 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::lang::Lang;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub character: Option<char>,
+    pub col: usize,
+    pub row: usize,
+    // #includeで取り込んだファイルの場合はそのファイル名 (元のソースはファイル名が分かればそれ)
+    pub file: Option<String>,
+}
+
+// エラーの範囲 (startからendの手前まで。startとendが同じ場合はstartの1文字を指す)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    // startの行のソースコード (取得できない場合はNone)
+    pub line: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum MMLError {
+    BarTooLong(Span),
+    BarTooShort(Span),
+    EmptySequence(Span),
+    InvalidBlock(Span),
+    InvalidBlockEnd(Span),
+    InvalidBlockId(Span),
+    InvalidChangeOctave(Span),
+    InvalidCharacter(Span),
+    InvalidComment(Span),
+    InvalidDecreaseOctave(Span),
+    InvalidDefaultDurationValue(Span),
+    InvalidDefine(Span),
+    InvalidDirective(Span),
+    InvalidDuration(Span),
+    InvalidDurationEnd(Span),
+    InvalidInclude(Span),
+    InvalidIncreaseOctave(Span),
+    InvalidLength(Span),
+    InvalidLoop(Span),
+    InvalidLyric(Span),
+    InvalidMacro(Span),
+    InvalidMarker(Span),
+    InvalidMetadata(Span),
+    InvalidNote(Span),
+    InvalidNoteValue(Span),
+    InvalidNoteValueEnd(Span),
+    InvalidNoteValueStart(Span),
+    InvalidOctaveValue(Span),
+    InvalidPlayBlockArgument(Span),
+    InvalidPlayBlockArgumentEnd(Span),
+    InvalidPlayBlockId(Span),
+    InvalidRepeat(Span),
+    InvalidRepeatEnd(Span),
+    InvalidRepeatNumber(Span),
+    InvalidResolution(Span),
+    InvalidTempo(Span),
+    InvalidTimeSignature(Span),
+    InvalidTranspose(Span),
+    InvalidVolume(Span),
+    IoError(io::Error),
+    UnexpectedRemains(Span),
+}
+
+impl MMLError {
+    // エラーの範囲 (IoErrorの場合はNone)
+    pub fn span(&self) -> Option<&Span> {
+        use MMLError::*;
+        match self {
+            BarTooLong(span)
+            | BarTooShort(span)
+            | EmptySequence(span)
+            | InvalidBlock(span)
+            | InvalidBlockEnd(span)
+            | InvalidBlockId(span)
+            | InvalidChangeOctave(span)
+            | InvalidCharacter(span)
+            | InvalidComment(span)
+            | InvalidDecreaseOctave(span)
+            | InvalidDefaultDurationValue(span)
+            | InvalidDefine(span)
+            | InvalidDirective(span)
+            | InvalidDuration(span)
+            | InvalidDurationEnd(span)
+            | InvalidInclude(span)
+            | InvalidIncreaseOctave(span)
+            | InvalidLength(span)
+            | InvalidLoop(span)
+            | InvalidLyric(span)
+            | InvalidMacro(span)
+            | InvalidMarker(span)
+            | InvalidMetadata(span)
+            | InvalidNote(span)
+            | InvalidNoteValue(span)
+            | InvalidNoteValueEnd(span)
+            | InvalidNoteValueStart(span)
+            | InvalidOctaveValue(span)
+            | InvalidPlayBlockArgument(span)
+            | InvalidPlayBlockArgumentEnd(span)
+            | InvalidPlayBlockId(span)
+            | InvalidRepeat(span)
+            | InvalidRepeatEnd(span)
+            | InvalidRepeatNumber(span)
+            | InvalidResolution(span)
+            | InvalidTempo(span)
+            | InvalidTimeSignature(span)
+            | InvalidTranspose(span)
+            | InvalidVolume(span)
+            | UnexpectedRemains(span) => Some(span),
+            IoError(_) => None,
+        }
+    }
+
+    // エラーコード (E0001～、mml-cli explain で説明を表示できる)
+    // 一度割り当てたコードは変えない。新しいエラーには続きの番号を割り当てる
+    pub fn code(&self) -> &'static str {
+        use MMLError::*;
+        match self {
+            BarTooLong(_) => "E0001",
+            BarTooShort(_) => "E0002",
+            EmptySequence(_) => "E0003",
+            InvalidBlock(_) => "E0004",
+            InvalidBlockEnd(_) => "E0005",
+            InvalidBlockId(_) => "E0006",
+            InvalidChangeOctave(_) => "E0007",
+            InvalidCharacter(_) => "E0008",
+            InvalidComment(_) => "E0009",
+            InvalidDecreaseOctave(_) => "E0010",
+            InvalidDefaultDurationValue(_) => "E0011",
+            InvalidDefine(_) => "E0012",
+            InvalidDirective(_) => "E0013",
+            InvalidDuration(_) => "E0014",
+            InvalidDurationEnd(_) => "E0015",
+            InvalidInclude(_) => "E0016",
+            InvalidIncreaseOctave(_) => "E0017",
+            InvalidLength(_) => "E0018",
+            InvalidLoop(_) => "E0019",
+            InvalidLyric(_) => "E0020",
+            InvalidMacro(_) => "E0021",
+            InvalidMarker(_) => "E0022",
+            InvalidMetadata(_) => "E0023",
+            InvalidNote(_) => "E0024",
+            InvalidNoteValue(_) => "E0025",
+            InvalidNoteValueEnd(_) => "E0026",
+            InvalidNoteValueStart(_) => "E0027",
+            InvalidOctaveValue(_) => "E0028",
+            InvalidPlayBlockArgument(_) => "E0029",
+            InvalidPlayBlockArgumentEnd(_) => "E0030",
+            InvalidPlayBlockId(_) => "E0031",
+            InvalidRepeat(_) => "E0032",
+            InvalidRepeatEnd(_) => "E0033",
+            InvalidRepeatNumber(_) => "E0034",
+            InvalidResolution(_) => "E0035",
+            InvalidTempo(_) => "E0036",
+            InvalidTimeSignature(_) => "E0037",
+            InvalidTranspose(_) => "E0038",
+            InvalidVolume(_) => "E0039",
+            IoError(_) => "E0040",
+            UnexpectedRemains(_) => "E0041",
+        }
+    }
+
+    // 表示言語に合わせたエラーの説明文
+    pub fn message_in(&self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.message_en(),
+            Lang::Ja => self.message(),
+        }
+    }
+
+    // エラーの説明文 (英語)
+    pub fn message_en(&self) -> &'static str {
+        use MMLError::*;
+        match self {
+            BarTooLong(_) => "bar is longer than the time signature",
+            BarTooShort(_) => "bar is shorter than the time signature",
+            EmptySequence(_) => "no notes or rests",
+            InvalidBlock(_) => "block has no notes or rests",
+            InvalidBlockEnd(_) => "missing } at the end of the block",
+            InvalidBlockId(_) => "invalid block number or name (numbers are sequential from 0, names must be unique)",
+            InvalidChangeOctave(_) => "missing octave value after O",
+            InvalidCharacter(_) => "unknown character",
+            InvalidComment(_) => "invalid comment (unclosed /* or / not followed by / or *)",
+            InvalidDecreaseOctave(_) => "cannot lower the octave (below O-1)",
+            InvalidDefaultDurationValue(_) => "missing length after L",
+            InvalidDefine(_) => "invalid #define (missing name or value, or duplicate name)",
+            InvalidDirective(_) => "unknown preprocessor directive",
+            InvalidDuration(_) => "invalid length in resolution units (1-127)",
+            InvalidDurationEnd(_) => "missing ) at the end of the length in resolution units",
+            InvalidInclude(_) => "invalid #include or the file cannot be read",
+            InvalidIncreaseOctave(_) => "cannot raise the octave (above O9)",
+            InvalidLength(_) => "invalid length (specify 1 to the resolution)",
+            InvalidLoop(_) => "invalid loop position (each once, at positions played only once, !loopend after !loopstart)",
+            InvalidLyric(_) => "invalid lyric (non-empty text enclosed in \"\")",
+            InvalidMacro(_) => "macro is not defined or expands recursively",
+            InvalidMarker(_) => "unknown marker (!loopstart or !loopend)",
+            InvalidMetadata(_) => "invalid song information (missing text or duplicated)",
+            InvalidNote(_) => "note is out of range (C of O-1 to G of O9)",
+            InvalidNoteValue(_) => "invalid note value (0-127)",
+            InvalidNoteValueEnd(_) => "missing ) at the end of the note value",
+            InvalidNoteValueStart(_) => "missing ( after N",
+            InvalidOctaveValue(_) => "invalid octave value (-1 to 9)",
+            InvalidPlayBlockArgument(_) => "invalid block call argument (transpose, octave, volume)",
+            InvalidPlayBlockArgumentEnd(_) => "missing ) at the end of the block call arguments",
+            InvalidPlayBlockId(_) => "block to play is not defined",
+            InvalidRepeat(_) => "invalid repeat",
+            InvalidRepeatEnd(_) => "missing ] at the end of the repeat",
+            InvalidRepeatNumber(_) => "invalid repeat count (2-127)",
+            InvalidResolution(_) => "invalid resolution (1-127)",
+            InvalidTempo(_) => "invalid tempo (20-508)",
+            InvalidTimeSignature(_) => "invalid time signature (specify once, with a bar length expressible in the resolution)",
+            InvalidTranspose(_) => "transposed note is out of range (C of O-1 to G of O9)",
+            InvalidVolume(_) => "invalid volume (0-100)",
+            IoError(_) => "I/O error",
+            UnexpectedRemains(_) => "unexpected remaining input",
+        }
+    }
+
+    // エラーの説明文
+    pub fn message(&self) -> &'static str {
+        use MMLError::*;
+        match self {
+            BarTooLong(_) => "小節の長さが拍子より長いです",
+            BarTooShort(_) => "小節の長さが拍子より短いです",
+            EmptySequence(_) => "音符・休符が1つもありません",
+            InvalidBlock(_) => "ブロックの中に音符・休符がありません",
+            InvalidBlockEnd(_) => "ブロックの終わりの } がありません",
+            InvalidBlockId(_) => "ブロックの番号か名前が不正です (番号は0からの連番、名前は重複不可)",
+            InvalidChangeOctave(_) => "オクターブ指定 O の後にオクターブの値がありません",
+            InvalidCharacter(_) => "不明な文字です",
+            InvalidComment(_) => "コメントが不正です (/* が閉じていないか、/ の後に / か * がありません)",
+            InvalidDecreaseOctave(_) => "オクターブを下げられません (O-1より下になります)",
+            InvalidDefaultDurationValue(_) => "デフォルト音長指定 L の後に音長がありません",
+            InvalidDefine(_) => "#define の記述が不正です (名前か値が無いか、名前が重複しています)",
+            InvalidDirective(_) => "不明なプリプロセッサ指令です",
+            InvalidDuration(_) => "分解能単位の音長が不正です (1～127)",
+            InvalidDurationEnd(_) => "分解能単位の音長の終わりの ) がありません",
+            InvalidInclude(_) => "#include の記述が不正か、ファイルを読み込めません",
+            InvalidIncreaseOctave(_) => "オクターブを上げられません (O9より上になります)",
+            InvalidLength(_) => "音長が不正です (1～分解能の範囲で指定してください)",
+            InvalidLoop(_) => "ループ位置が不正です (それぞれ1回だけ再生される位置に、!loopstart より後に !loopend)",
+            InvalidLyric(_) => "歌詞が不正です (\"\" で囲んだ空でないテキスト)",
+            InvalidMacro(_) => "マクロが定義されていないか、再帰的に展開されています",
+            InvalidMarker(_) => "不明なマーカーです (!loopstart か !loopend)",
+            InvalidMetadata(_) => "曲の情報が不正です (テキストが無いか、重複しています)",
+            InvalidNote(_) => "音の高さが範囲外です (O-1のC～O9のG)",
+            InvalidNoteValue(_) => "音値が不正です (0～127)",
+            InvalidNoteValueEnd(_) => "音値の終わりの ) がありません",
+            InvalidNoteValueStart(_) => "音値指定 N の後に ( がありません",
+            InvalidOctaveValue(_) => "オクターブの値が不正です (-1～9)",
+            InvalidPlayBlockArgument(_) => "ブロック再生の引数が不正です (transpose, octave, volume)",
+            InvalidPlayBlockArgumentEnd(_) => "ブロック再生の引数の終わりの ) がありません",
+            InvalidPlayBlockId(_) => "再生するブロックが定義されていません",
+            InvalidRepeat(_) => "リピートの記述が不正です",
+            InvalidRepeatEnd(_) => "リピートの終わりの ] がありません",
+            InvalidRepeatNumber(_) => "リピート回数が不正です (2～127)",
+            InvalidResolution(_) => "分解能が不正です (1～127)",
+            InvalidTempo(_) => "テンポが不正です (20～508)",
+            InvalidTimeSignature(_) => "拍子が不正です (1小節の長さが分解能で表せる拍子を1回だけ指定してください)",
+            InvalidTranspose(_) => "移調した音の高さが範囲外です (O-1のC～O9のG)",
+            InvalidVolume(_) => "音量が不正です (0～100)",
+            IoError(_) => "入出力エラーです",
+            UnexpectedRemains(_) => "解釈できない記述が残っています",
+        }
+    }
+
+    // エラーの説明文と該当するソースコードの行を下線付きで表示する文字列
+    //
+    // MMLエラー[E0018]: 音長が不正です (1～分解能の範囲で指定してください)
+    //  --> song.mml:2:4
+    //   |
+    // 2 | O4 C3 D
+    //   |    ^^
+    pub fn render(&self) -> String {
+        self.render_in(Lang::Ja)
+    }
+
+    pub fn render_in(&self, lang: Lang) -> String {
+        let message = self.message_in(lang);
+        match self {
+            MMLError::IoError(error) => format!("{}: {}", message, error),
+            _ => {
+                let label = match lang {
+                    Lang::En => format!("MML error[{}]", self.code()),
+                    Lang::Ja => format!("MMLエラー[{}]", self.code()),
+                };
+                render(&label, message, self.span().unwrap())
+            }
+        }
+    }
+}
+
+// 見出しと説明文と該当するソースコードの行を下線付きで表示する文字列 (エラーと警告で共通)
+pub(crate) fn render(label: &str, message: &str, span: &Span) -> String {
+    let row = span.start.row.to_string();
+    let margin = " ".repeat(row.len());
+
+    let mut lines: Vec<String> = vec![
+        format!("{}: {}", label, message),
+        format!("{} --> {}", margin, location(&span.start)),
+    ];
+
+    let line = match &span.line {
+        Some(line) => line,
+        None => return lines.join("\n"),
+    };
+
+    let start: usize = span.start.col.max(1) - 1;
+    let end: usize = if span.end.file == span.start.file && span.end.row == span.start.row {
+        (span.end.col.max(1) - 1).max(start + 1)
+    } else {
+        // 複数行にまたがる場合は行末まで
+        line.chars().count().max(start + 1)
+    };
+
+    // タブはそのまま写して下線の位置を合わせる。全角文字は2桁とする
+    let mut underline = String::new();
+    for ch in line.chars().take(start) {
+        if ch == '\t' {
+            underline.push('\t');
+        } else {
+            underline.push_str(&" ".repeat(char_width(ch)));
+        }
+    }
+    let mut chars = line.chars().skip(start);
+    for _ in start..end {
+        let width = chars.next().map_or(1, char_width).max(1);
+        underline.push_str(&"^".repeat(width));
+    }
+
+    lines.push(format!("{} |", margin));
+    lines.push(format!("{} | {}", row, line));
+    lines.push(format!("{} | {}", margin, underline));
+
+    lines.join("\n")
+}
+
+pub(crate) fn location(pos: &Position) -> String {
+    match &pos.file {
+        Some(file) => format!("{}:{}:{}", file, pos.row, pos.col),
+        None => format!("{}:{}", pos.row, pos.col),
+    }
+}
+
+// 端末での表示幅 (東アジアの全角文字は2とする)
+pub fn char_width(ch: char) -> usize {
+    match ch as u32 {
+        0x0000..=0x001F | 0x007F => 0,
+        0x1100..=0x115F
+        | 0x2E80..=0x303E
+        | 0x3041..=0x33FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F900..=0x1F9FF
+        | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+impl From<java_data_io::Error> for MMLError {
+    fn from(error: java_data_io::Error) -> Self {
+        use java_data_io::Error::*;
+        match error {
+            IoError(io_error) => MMLError::IoError(io_error),
+            UtfDataFormatError => unreachable!("UtfDataFormatError"),
+        }
+    }
+}
+
+impl From<io::Error> for MMLError {
+    fn from(error: io::Error) -> Self {
+        MMLError::IoError(error)
+    }
+}
+
+impl std::fmt::Display for MMLError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.span() {
+            Some(span) => write!(
+                f,
+                "{}: {} [{}]",
+                location(&span.start),
+                self.message(),
+                self.code()
+            ),
+            None => match self {
+                MMLError::IoError(error) => write!(f, "{}: {}", self.message(), error),
+                _ => f.write_str(self.message()),
+            },
+        }
+    }
+}
+
+impl std::error::Error for MMLError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MMLError::IoError(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(line: &str, start: usize, end: usize) -> Span {
+        let pos = |col: usize| Position {
+            character: line.chars().nth(col - 1),
+            col,
+            row: 3,
+            file: Some("song.mml".to_string()),
+        };
+        Span {
+            start: pos(start),
+            end: pos(end),
+            line: Some(line.to_string()),
+        }
+    }
+
+    #[test]
+    fn render() {
+        let error = MMLError::InvalidLength(span("O4 C3 D", 4, 6));
+        assert_eq!(
+            error.to_string(),
+            format!("song.mml:3:4: {} [E0018]", error.message())
+        );
+        assert_eq!(
+            error.render(),
+            format!(
+                "MMLエラー[E0018]: {}\n  --> song.mml:3:4\n  |\n3 | O4 C3 D\n  |    ^^",
+                error.message()
+            )
+        );
+
+        // 全角文字とタブの後ろでも下線の位置が合う
+        let error = MMLError::InvalidCharacter(span("\tC\"あい\" X", 8, 8));
+        assert!(
+            error.render().ends_with("  | \t        ^"),
+            "{}",
+            error.render()
+        );
+
+        let error = MMLError::InvalidLyric(span("C\"あい", 3, 3));
+        assert!(error.render().ends_with("  |   ^^"), "{}", error.render());
+    }
+}
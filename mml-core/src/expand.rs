@@ -0,0 +1,274 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::analysis::note_name;
+use crate::song::{Annotation, Song};
+use crate::timeline::{self, Event};
+use crate::tone_control;
+
+// 1行の長さの目安 (小節線の無いMMLはこれを超える位置で折り返す)
+const WIDTH: usize = 80;
+
+// ブロック再生とリピートを再生順に展開し、音符ごとにオクターブと長さを明記したMML。
+// 変換結果のSMFは元のMMLと同じになる (音量の指定は音符の音量が変わる位置にだけ書く)
+pub(crate) fn expand(song: &Song) -> String {
+    let mut out = header(song);
+    let resolution: i32 = song.resolution();
+    let mut lines = Lines::default();
+
+    // 再生開始時の音量は100
+    let mut current_volume: i32 = 100;
+    // 歌詞の注釈は音符の前にあるので次の音符に付ける
+    let mut lyric: Option<&str> = None;
+
+    let res: Result<(), ()> = timeline::walk(&song.tseq, |_, event| {
+        match event {
+            Event::Annotation(index) => match &song.annotations[index] {
+                Annotation::BarLine(_) => {
+                    lines.push("|");
+                    lines.break_line();
+                }
+                Annotation::Lyric(text) => lyric = Some(text),
+                Annotation::LoopStart(_) => lines.push("!loopstart"),
+                Annotation::LoopEnd(_) => lines.push("!loopend"),
+                Annotation::Source(_) | Annotation::BlockCall(_) => {}
+            },
+            Event::Note {
+                note,
+                duration,
+                volume,
+            } => {
+                if volume != current_volume {
+                    lines.push(&format!("V{}", volume));
+                    current_volume = volume;
+                }
+                lines.push(&note_code(note, duration, resolution, lyric.take()));
+            }
+            Event::Rest { duration } => {
+                lines.push(&format!("R{}", length(duration, resolution)));
+            }
+        }
+        Ok(())
+    });
+    res.expect("invalid tone sequence");
+
+    out.push_str(&lines.finish());
+    out
+}
+
+// ブロックの定義とブロック再生を保ったMML (リピートは1つの音符・休符の繰り返しだけ [n ] になる)
+pub(crate) fn to_mml(song: &Song) -> String {
+    let tseq: &[u8] = &song.tseq;
+    let mut out = header(song);
+    let resolution: i32 = song.resolution();
+    let mut lines = Lines::default();
+
+    // VERSION, TEMPO, RESOLUTION
+    let mut pos: usize = 6;
+    // トーンシーケンスのブロック番号からMMLのブロック番号 (定義順の連番)
+    let mut numbers: Vec<usize> = vec![0; 128];
+    let mut count: usize = 0;
+    let mut lyric: Option<&str> = None;
+    let mut in_block = false;
+
+    while let Some(&b) = tseq.get(pos) {
+        let cmd: i8 = b as i8;
+        match cmd {
+            tone_control::BLOCK_START => {
+                numbers[(tseq[pos + 1] & 0x7F) as usize] = count;
+                lines.push(&format!("{{{}", count));
+                count += 1;
+                in_block = true;
+            }
+            tone_control::BLOCK_END => {
+                lines.push("}");
+                lines.break_line();
+                in_block = false;
+            }
+            tone_control::PLAY_BLOCK => {
+                lines.push(&format!("${}", numbers[(tseq[pos + 1] & 0x7F) as usize]))
+            }
+            tone_control::SET_VOLUME => lines.push(&format!("V{}", tseq[pos + 1])),
+            tone_control::SILENCE => {
+                lines.push(&format!("R{}", length(tseq[pos + 1].into(), resolution)))
+            }
+            tone_control::REPEAT => {
+                let duration: i32 = tseq[pos + 3].into();
+                let code = if tseq[pos + 2] as i8 == tone_control::SILENCE {
+                    format!("R{}", length(duration, resolution))
+                } else {
+                    note_code(tseq[pos + 2].into(), duration, resolution, None)
+                };
+                lines.push(&format!("[{} {} ]", tseq[pos + 1], code));
+            }
+            tone_control::ANNOTATION => {
+                match &song.annotations[timeline::annotation_index(tseq, pos)] {
+                    Annotation::BarLine(_) => {
+                        lines.push("|");
+                        if !in_block {
+                            lines.break_line();
+                        }
+                    }
+                    Annotation::Lyric(text) => lyric = Some(text),
+                    Annotation::LoopStart(_) => lines.push("!loopstart"),
+                    Annotation::LoopEnd(_) => lines.push("!loopend"),
+                    Annotation::Source(_) | Annotation::BlockCall(_) => {}
+                }
+            }
+            _ => lines.push(&note_code(
+                cmd.into(),
+                tseq[pos + 1].into(),
+                resolution,
+                lyric.take(),
+            )),
+        }
+        pos += timeline::event_size(cmd);
+    }
+
+    out.push_str(&lines.finish());
+    out
+}
+
+// 曲の情報・拍子・楽器・テンポ・分解能の記述
+pub(crate) fn header(song: &Song) -> String {
+    let mut out = String::new();
+    let metadata = song.metadata();
+    let header = [
+        ("TITLE", metadata.title.as_ref()),
+        ("COMPOSER", metadata.composer.as_ref()),
+        ("COPYRIGHT", metadata.copyright.as_ref()),
+    ];
+    for (name, value) in header.iter() {
+        if let Some(value) = value {
+            out.push_str(&format!("#{} {}\n", name, value));
+        }
+    }
+    for comment in metadata.comments.iter() {
+        out.push_str(&format!("#COMMENT {}\n", comment));
+    }
+    if let Some(ts) = song.time_signature() {
+        out.push_str(&format!("#TIME {}/{}\n", ts.numerator, ts.denominator));
+    }
+    if let Some(inst) = song.instrument() {
+        out.push_str(&format!("#INSTRUMENT {}\n", inst as i32));
+    }
+    out.push_str(&format!("T{} %{}\n", song.tempo(), song.resolution()));
+    out
+}
+
+// オクターブと長さを明記した音符の記述 (O4 C4"歌詞")
+pub(crate) fn note_code(note: i32, duration: i32, resolution: i32, lyric: Option<&str>) -> String {
+    let mut code = format!("{}{}", note_name(note), length(duration, resolution));
+    if let Some(lyric) = lyric {
+        code.push_str(&format!("\"{}\"", lyric));
+    }
+    code
+}
+
+// 空白で区切ったコードを行にまとめる (WIDTHを超える位置と指定した位置で改行する)
+#[derive(Default)]
+pub(crate) struct Lines {
+    out: String,
+    line: String,
+}
+
+impl Lines {
+    pub(crate) fn push(&mut self, code: &str) {
+        if !self.line.is_empty() && self.line.len() + 1 + code.len() > WIDTH {
+            self.break_line();
+        }
+        if !self.line.is_empty() {
+            self.line.push(' ');
+        }
+        self.line.push_str(code);
+    }
+
+    pub(crate) fn break_line(&mut self) {
+        if !self.line.is_empty() {
+            self.out.push_str(&self.line);
+            self.out.push('\n');
+            self.line.clear();
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        self.break_line();
+        self.out
+    }
+}
+
+// 長さ (分解能単位) の記述。
+// 割り切れる音符の長さ (付点を含む) で書ける場合はその記述、書けない場合は (長さ) の記述
+pub(crate) fn length(duration: i32, resolution: i32) -> String {
+    for dots in 0..=2 {
+        for num in 1..=(resolution >> dots) {
+            if resolution % (num << dots) != 0 {
+                continue;
+            }
+            let dur: i32 = (0..=dots).map(|i| resolution / (num << i)).sum();
+            if dur == duration {
+                return format!("{}{}", num, ".".repeat(dots));
+            }
+        }
+    }
+    format!("({})", duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inst::INSTRUMENTS;
+
+    fn expand_src(src: &str) -> String {
+        expand(&crate::parse(src).unwrap())
+    }
+
+    // 展開したMMLは元のMMLと同じSMFになる
+    fn assert_same_smf(src: &str) {
+        let expanded = expand_src(src);
+        assert_eq!(
+            crate::convert(src, INSTRUMENTS[0]).unwrap(),
+            crate::convert(&expanded, INSTRUMENTS[0]).unwrap(),
+            "{}",
+            expanded
+        );
+    }
+
+    #[test]
+    fn expand_blocks_and_repeats() {
+        // READMEのブロックの例
+        let src = "{0 O7L16 A B } O5L8 C < D [2 E O3L2 F ] $0 G";
+        assert_eq!(
+            expand_src(src),
+            "T120 %64\nO5 C8 O6 D8 O6 E8 O3 F2 O6 E8 O3 F2 O7 A16 O7 B16 O3 G2\n"
+        );
+        assert_same_smf(src);
+
+        // READMEの音量の例
+        let src = "{0 A V70 B } V90 C D [2 E V80 F ] $0 G";
+        assert_eq!(
+            expand_src(src),
+            "T120 %64\nV90 O4 C4 O4 D4 O4 E4 V80 O4 F4 O4 E4 O4 F4 O4 A4 V70 O4 B4 O4 G4\n"
+        );
+        assert_same_smf(src);
+
+        let src = "#TITLE test\n#TIME 3/4\nT150 %96\n{motif C8\"ら\" D8. }\nL4 R | !loopstart $motif(transpose=2) E(42) | [2 F+8 R8 ] G- | !loopend";
+        assert_eq!(
+            expand_src(src),
+            "#TITLE test\n#TIME 3/4\nT148 %96\nR4 |\n!loopstart O4 D8\"ら\" O4 E8. O4 E4.. |\nO4 F#8 R8 O4 F#8 R8 O4 F#4 |\n!loopend\n"
+        );
+        assert_same_smf(src);
+    }
+
+    #[test]
+    fn length_text() {
+        assert_eq!(length(16, 64), "4");
+        assert_eq!(length(24, 64), "4.");
+        assert_eq!(length(28, 64), "4..");
+        assert_eq!(length(5, 64), "(5)");
+        assert_eq!(length(127, 64), "(127)");
+        assert_eq!(length(0, 64), "(0)");
+        // 割り切れない分解能でも同じ長さになる記述
+        assert_eq!(length(32, 96), "3");
+    }
+}
@@ -0,0 +1,419 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::lang::Lang;
+
+// エラーコードの説明 (mml-cli explain で表示する)
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    // エラーコード (E0001～)
+    pub code: &'static str,
+    // MMLErrorのバリアント名
+    pub name: &'static str,
+    // 規則の説明
+    pub rule: &'static str,
+    // 誤った記述の例と修正した記述の例 (例を示せないエラーはNone)
+    pub wrong: Option<&'static str>,
+    pub corrected: Option<&'static str>,
+}
+
+struct Entry {
+    code: &'static str,
+    name: &'static str,
+    rule_ja: &'static str,
+    rule_en: &'static str,
+    wrong: Option<&'static str>,
+    corrected: Option<&'static str>,
+}
+
+const ENTRIES: [Entry; 41] = [
+    Entry {
+        code: "E0001",
+        name: "BarTooLong",
+        rule_ja: "小節線 | までの長さは #TIME で指定した拍子の1小節の長さと一致しなければならない。\n小節線の前の音符が多すぎるか、音長が長すぎる。",
+        rule_en: "The length up to a bar line | must match one bar of the time signature given by #TIME.\nThere are too many notes before the bar line, or they are too long.",
+        wrong: Some("#TIME 3/4\nL4 C D E F | G2."),
+        corrected: Some("#TIME 3/4\nL4 C D E | F2."),
+    },
+    Entry {
+        code: "E0002",
+        name: "BarTooShort",
+        rule_ja: "小節線 | までの長さは #TIME で指定した拍子の1小節の長さと一致しなければならない。\n最初の小節 (弱起) を除き、短い小節は書けない。",
+        rule_en: "The length up to a bar line | must match one bar of the time signature given by #TIME.\nExcept for the first bar (pickup), a bar may not be shorter.",
+        wrong: Some("#TIME 3/4\nL4 C | D E | F G A"),
+        corrected: Some("#TIME 3/4\nL4 C | D E F | G A B"),
+    },
+    Entry {
+        code: "E0003",
+        name: "EmptySequence",
+        rule_ja: "コード部には音符か休符が1つ以上必要。",
+        rule_en: "The code part needs at least one note or rest.",
+        wrong: Some("T120 %64"),
+        corrected: Some("T120 %64 C D E"),
+    },
+    Entry {
+        code: "E0004",
+        name: "InvalidBlock",
+        rule_ja: "ブロック {番号 1*コード } の中には音符か休符が1つ以上必要。",
+        rule_en: "A block {number 1*code } needs at least one note or rest.",
+        wrong: Some("{0 O5 }\n$0"),
+        corrected: Some("{0 O5 C }\n$0"),
+    },
+    Entry {
+        code: "E0005",
+        name: "InvalidBlockEnd",
+        rule_ja: "ブロックは } で閉じなければならない。",
+        rule_en: "A block must be closed with }.",
+        wrong: Some("{0 C D E ]\n$0"),
+        corrected: Some("{0 C D E }\n$0"),
+    },
+    Entry {
+        code: "E0006",
+        name: "InvalidBlockId",
+        rule_ja: "ブロックの番号は0からの連番 (0～127) で昇順に定義する。\n名前で定義する場合は英字か_で始まる英数字で、同じ名前は定義できない。",
+        rule_en: "Block numbers are defined in ascending sequential order from 0 (0-127).\nNames consist of letters, digits and _, start with a letter or _, and must be unique.",
+        wrong: Some("{1 C D E }\n$1"),
+        corrected: Some("{0 C D E }\n$0"),
+    },
+    Entry {
+        code: "E0007",
+        name: "InvalidChangeOctave",
+        rule_ja: "オクターブ指定コード O の直後にはオクターブの値 (-1～9) が必要。",
+        rule_en: "The octave code O must be followed by an octave value (-1 to 9).",
+        wrong: Some("O C D E"),
+        corrected: Some("O5 C D E"),
+    },
+    Entry {
+        code: "E0008",
+        name: "InvalidCharacter",
+        rule_ja: "コードとして解釈できない文字がある。\n使える文字は mml-cli show-mml-syntax を参照。",
+        rule_en: "There is a character that cannot be read as a code.\nSee mml-cli show-mml-syntax for the characters that can be used.",
+        wrong: Some("C D X E"),
+        corrected: Some("C D E"),
+    },
+    Entry {
+        code: "E0009",
+        name: "InvalidComment",
+        rule_ja: "/* で始めたコメントは */ で閉じなければならない。\n/ の直後には / か * が必要。",
+        rule_en: "A comment started with /* must be closed with */.\nA / must be followed by / or *.",
+        wrong: Some("C /* comment\nD"),
+        corrected: Some("C /* comment */\nD"),
+    },
+    Entry {
+        code: "E0010",
+        name: "InvalidDecreaseOctave",
+        rule_ja: "オクターブ下げコード > でオクターブを-1より下にはできない。",
+        rule_en: "The octave down code > cannot lower the octave below -1.",
+        wrong: Some("O-1 C > C"),
+        corrected: Some("O-1 C < C"),
+    },
+    Entry {
+        code: "E0011",
+        name: "InvalidDefaultDurationValue",
+        rule_ja: "デフォ長さ指定コード L の直後には長さが必要。",
+        rule_en: "The default length code L must be followed by a length.",
+        wrong: Some("L C D E"),
+        corrected: Some("L8 C D E"),
+    },
+    Entry {
+        code: "E0012",
+        name: "InvalidDefine",
+        rule_ja: "#define には名前と値が必要で、同じ名前は定義できない。",
+        rule_en: "#define needs a name and a value, and a name cannot be defined twice.",
+        wrong: Some("#define MOTIF\nC"),
+        corrected: Some("#define MOTIF C D E\n@MOTIF"),
+    },
+    Entry {
+        code: "E0013",
+        name: "InvalidDirective",
+        rule_ja: "# で始まる行は #TITLE #COMPOSER #COPYRIGHT #COMMENT #TIME #define #include のいずれか。",
+        rule_en: "A line starting with # must be one of #TITLE #COMPOSER #COPYRIGHT #COMMENT #TIME #define #include.",
+        wrong: Some("#TEMPO 150\nC D E"),
+        corrected: Some("T152 C D E"),
+    },
+    Entry {
+        code: "E0014",
+        name: "InvalidDuration",
+        rule_ja: "分解能単位の長さ (数字) は1～127で指定する。",
+        rule_en: "A length in resolution units (number) must be 1-127.",
+        wrong: Some("C(200) D"),
+        corrected: Some("C(16) D"),
+    },
+    Entry {
+        code: "E0015",
+        name: "InvalidDurationEnd",
+        rule_ja: "分解能単位の長さ (数字) は ) で閉じなければならない。",
+        rule_en: "A length in resolution units (number) must be closed with ).",
+        wrong: Some("C(16 D"),
+        corrected: Some("C(16) D"),
+    },
+    Entry {
+        code: "E0016",
+        name: "InvalidInclude",
+        rule_ja: "#include の後には \"\" で囲んだファイル名が必要で、そのファイルを読み込めなければならない。\n相対パスは#includeを記述したファイルの場所が基準。",
+        rule_en: "#include must be followed by a file name enclosed in \"\", and the file must be readable.\nRelative paths are relative to the file containing the #include.",
+        wrong: Some("#include motif.mml\nC"),
+        corrected: Some("#include \"motif.mml\"\nC"),
+    },
+    Entry {
+        code: "E0017",
+        name: "InvalidIncreaseOctave",
+        rule_ja: "オクターブ上げコード < でオクターブを9より上にはできない。",
+        rule_en: "The octave up code < cannot raise the octave above 9.",
+        wrong: Some("O9 C < C"),
+        corrected: Some("O9 C > C"),
+    },
+    Entry {
+        code: "E0018",
+        name: "InvalidLength",
+        rule_ja: "長さは1～分解能で指定する。付点を付けた長さも分解能で表せなければならない。",
+        rule_en: "A length must be 1 to the resolution. A dotted length must also be expressible in the resolution.",
+        wrong: Some("%64 C128 D"),
+        corrected: Some("%64 C64 D"),
+    },
+    Entry {
+        code: "E0019",
+        name: "InvalidLoop",
+        rule_ja: "!loopstart と !loopend はそれぞれ1回だけ再生される位置に1つだけ書ける。\n!loopend は !loopstart より後に書く。",
+        rule_en: "!loopstart and !loopend may each appear once, at a position played only once.\n!loopend must come after !loopstart.",
+        wrong: Some("[2 !loopstart C D ]"),
+        corrected: Some("!loopstart [2 C D ]"),
+    },
+    Entry {
+        code: "E0020",
+        name: "InvalidLyric",
+        rule_ja: "歌詞は音符コード・音値コードの直後に \"\" で囲んで書き、空にはできない。",
+        rule_en: "A lyric is written in \"\" right after a note code or a note value code, and cannot be empty.",
+        wrong: Some("C4\"\" D4\"la\""),
+        corrected: Some("C4\"la\" D4\"la\""),
+    },
+    Entry {
+        code: "E0021",
+        name: "InvalidMacro",
+        rule_ja: "@名前 で使うマクロは #define で定義しなければならない。\nマクロの値の中で自身を展開することはできない。",
+        rule_en: "A macro used as @NAME must be defined with #define.\nA macro cannot expand itself within its value.",
+        wrong: Some("@MOTIF G4"),
+        corrected: Some("#define MOTIF C D E\n@MOTIF G4"),
+    },
+    Entry {
+        code: "E0022",
+        name: "InvalidMarker",
+        rule_ja: "! で始まるコードは !loopstart か !loopend。",
+        rule_en: "A code starting with ! must be !loopstart or !loopend.",
+        wrong: Some("!loop C D E"),
+        corrected: Some("!loopstart C D E"),
+    },
+    Entry {
+        code: "E0023",
+        name: "InvalidMetadata",
+        rule_ja: "曲の情報 (#TITLE #COMPOSER #COPYRIGHT) にはテキストが必要で、それぞれ1回だけ書ける。\n#COMMENT は複数書けるがテキストが必要。\n#INSTRUMENT は楽器番号 (1～128) を1回だけ書ける。",
+        rule_en: "Song information (#TITLE #COMPOSER #COPYRIGHT) needs text and may appear only once each.\n#COMMENT may appear more than once but needs text.\n#INSTRUMENT takes an instrument number (1 to 128) and may appear only once.",
+        wrong: Some("#TITLE\nC D E"),
+        corrected: Some("#TITLE My Song\nC D E"),
+    },
+    Entry {
+        code: "E0024",
+        name: "InvalidNote",
+        rule_ja: "音の高さはO-1のC～O9のGの範囲でなければならない。",
+        rule_en: "A note must be in the range from C of O-1 to G of O9.",
+        wrong: Some("O9 A"),
+        corrected: Some("O9 G"),
+    },
+    Entry {
+        code: "E0025",
+        name: "InvalidNoteValue",
+        rule_ja: "音値コード N(音値) の音値は0～127で指定する。",
+        rule_en: "The note value of a note value code N(value) must be 0-127.",
+        wrong: Some("N(128)"),
+        corrected: Some("N(127)"),
+    },
+    Entry {
+        code: "E0026",
+        name: "InvalidNoteValueEnd",
+        rule_ja: "音値コード N(音値) の音値は ) で閉じなければならない。",
+        rule_en: "The note value of a note value code N(value) must be closed with ).",
+        wrong: Some("N(60 C"),
+        corrected: Some("N(60) C"),
+    },
+    Entry {
+        code: "E0027",
+        name: "InvalidNoteValueStart",
+        rule_ja: "音値コード N の直後には ( が必要。",
+        rule_en: "The note value code N must be followed by (.",
+        wrong: Some("N60"),
+        corrected: Some("N(60)"),
+    },
+    Entry {
+        code: "E0028",
+        name: "InvalidOctaveValue",
+        rule_ja: "オクターブの値は-1～9で指定する。",
+        rule_en: "An octave value must be -1 to 9.",
+        wrong: Some("O10 C"),
+        corrected: Some("O9 C"),
+    },
+    Entry {
+        code: "E0029",
+        name: "InvalidPlayBlockArgument",
+        rule_ja: "ブロック再生の引数は transpose octave volume のいずれかで、値の範囲内で指定する。",
+        rule_en: "A block play argument must be one of transpose octave volume, with a value in range.",
+        wrong: Some("{0 C D E }\n$0(speed=2)"),
+        corrected: Some("{0 C D E }\n$0(transpose=2)"),
+    },
+    Entry {
+        code: "E0030",
+        name: "InvalidPlayBlockArgumentEnd",
+        rule_ja: "ブロック再生の引数は ) で閉じなければならない。",
+        rule_en: "Block play arguments must be closed with ).",
+        wrong: Some("{0 C D E }\n$0(transpose=2 C"),
+        corrected: Some("{0 C D E }\n$0(transpose=2) C"),
+    },
+    Entry {
+        code: "E0031",
+        name: "InvalidPlayBlockId",
+        rule_ja: "再生するブロックは番号か名前で定義済みでなければならない。",
+        rule_en: "A block to play must already be defined by number or name.",
+        wrong: Some("{0 C D E }\n$1"),
+        corrected: Some("{0 C D E }\n$0"),
+    },
+    Entry {
+        code: "E0032",
+        name: "InvalidRepeat",
+        rule_ja: "リピート [回数 1*コード ] には回数と、音符か休符が1つ以上必要。",
+        rule_en: "A repeat [count 1*code ] needs a count and at least one note or rest.",
+        wrong: Some("[ C D E ]"),
+        corrected: Some("[2 C D E ]"),
+    },
+    Entry {
+        code: "E0033",
+        name: "InvalidRepeatEnd",
+        rule_ja: "リピートは ] で閉じなければならない。",
+        rule_en: "A repeat must be closed with ].",
+        wrong: Some("[2 C D E"),
+        corrected: Some("[2 C D E ]"),
+    },
+    Entry {
+        code: "E0034",
+        name: "InvalidRepeatNumber",
+        rule_ja: "リピートの回数は2～127で指定する。",
+        rule_en: "A repeat count must be 2-127.",
+        wrong: Some("[1 C D E ]"),
+        corrected: Some("[2 C D E ]"),
+    },
+    Entry {
+        code: "E0035",
+        name: "InvalidResolution",
+        rule_ja: "分解能 %数字 は1～127で、コード部より前に1回だけ指定する。",
+        rule_en: "The resolution %number must be 1-127, given once before the code part.",
+        wrong: Some("%128 C D E"),
+        corrected: Some("%96 C D E"),
+    },
+    Entry {
+        code: "E0036",
+        name: "InvalidTempo",
+        rule_ja: "テンポ T数字 は4の倍数で20～508、コード部より前に1回だけ指定する。",
+        rule_en: "The tempo Tnumber must be a multiple of 4 in 20-508, given once before the code part.",
+        wrong: Some("T600 C D E"),
+        corrected: Some("T200 C D E"),
+    },
+    Entry {
+        code: "E0037",
+        name: "InvalidTimeSignature",
+        rule_ja: "拍子 #TIME 分子/分母 は分子1～99、分母 1 2 4 8 16 32 64 で1回だけ指定する。\n1小節の長さ (分解能×分子÷分母) が割り切れなければならない。",
+        rule_en: "The time signature #TIME numerator/denominator takes a numerator 1-99 and a denominator 1 2 4 8 16 32 64, given once.\nThe bar length (resolution x numerator / denominator) must be a whole number.",
+        wrong: Some("#TIME 3/5\nC D E"),
+        corrected: Some("#TIME 3/4\nC D E"),
+    },
+    Entry {
+        code: "E0038",
+        name: "InvalidTranspose",
+        rule_ja: "ブロック再生の引数 transpose と octave で移した音もO-1のC～O9のGの範囲でなければならない。",
+        rule_en: "Notes moved by the block play arguments transpose and octave must stay in the range from C of O-1 to G of O9.",
+        wrong: Some("{0 O9 G }\n$0(transpose=1)"),
+        corrected: Some("{0 O9 G }\n$0(transpose=-1)"),
+    },
+    Entry {
+        code: "E0039",
+        name: "InvalidVolume",
+        rule_ja: "音量指定コード V音量 の音量は0～100で指定する。",
+        rule_en: "The volume of a volume code Vvolume must be 0-100.",
+        wrong: Some("V120 C D E"),
+        corrected: Some("V100 C D E"),
+    },
+    Entry {
+        code: "E0040",
+        name: "IoError",
+        rule_ja: "MMLファイルの読み込みか、変換結果の書き込みに失敗した。\nファイル名や権限を確認する。",
+        rule_en: "Reading the MML file or writing the result failed.\nCheck the file name and permissions.",
+        wrong: None,
+        corrected: None,
+    },
+    Entry {
+        code: "E0041",
+        name: "UnexpectedRemains",
+        rule_ja: "対応する [ や { の無い ] や } がある。",
+        rule_en: "There is a ] or } without a matching [ or {.",
+        wrong: Some("C D ] E"),
+        corrected: Some("C D E"),
+    },
+];
+
+// エラーコードの一覧
+pub fn error_codes() -> impl Iterator<Item = &'static str> {
+    ENTRIES.iter().map(|entry| entry.code)
+}
+
+// エラーコードの説明を返す (小文字や先頭の0の省略も可、例: e12)。不明なコードの場合はNone
+pub fn explain(code: &str, lang: Lang) -> Option<Explanation> {
+    let number: usize = code
+        .strip_prefix(['E', 'e'])
+        .and_then(|num| num.parse().ok())?;
+    let entry = ENTRIES.get(number.checked_sub(1)?)?;
+    Some(Explanation {
+        code: entry.code,
+        name: entry.name,
+        rule: match lang {
+            Lang::En => entry.rule_en,
+            Lang::Ja => entry.rule_ja,
+        },
+        wrong: entry.wrong,
+        corrected: entry.corrected,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples() {
+        for (i, entry) in ENTRIES.iter().enumerate() {
+            assert_eq!(entry.code, format!("E{:04}", i + 1));
+
+            // 誤った例はそのコードのエラーになり、修正した例はエラーにならない
+            if let Some(wrong) = entry.wrong {
+                match crate::parse(wrong) {
+                    Err(error) => {
+                        assert_eq!(error.code(), entry.code, "{}: {:?}", entry.name, error);
+                        assert!(format!("{:?}", error).starts_with(entry.name));
+                    }
+                    Ok(_) => panic!("{}: no error", entry.name),
+                }
+            }
+            // #includeの例は取り込むファイルが無いので確認しない
+            if let Some(corrected) = entry.corrected.filter(|src| !src.contains("#include")) {
+                assert!(
+                    crate::parse(corrected).is_ok(),
+                    "{}: {:?}",
+                    entry.name,
+                    crate::parse(corrected).err()
+                );
+            }
+        }
+
+        assert_eq!(explain("E0018", Lang::En).unwrap().name, "InvalidLength");
+        assert_eq!(explain("e18", Lang::Ja).unwrap().code, "E0018");
+        assert!(explain("E0000", Lang::Ja).is_none());
+        assert!(explain("E9999", Lang::Ja).is_none());
+        assert!(explain("X0001", Lang::Ja).is_none());
+    }
+}
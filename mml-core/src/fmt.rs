@@ -0,0 +1,867 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::{self, MMLError, Position};
+use crate::input::{InputMode, ParseOptions};
+use crate::inst::INSTRUMENTS;
+use crate::lang::Lang;
+use crate::mml::{self, is_name_char, is_name_start};
+use crate::song::Song;
+use std::path::Path;
+
+// 1行の長さの目安 (これを超える場合は小節線の位置で折り返す)
+const WIDTH: usize = 80;
+
+// ブロックとリピートの中の字下げ
+const INDENT: &str = "    ";
+
+// MMLの整形のエラー
+#[derive(Debug)]
+pub enum FormatError {
+    // MMLにエラーがある (エラーのあるMMLは整形しない)
+    InvalidSource(Vec<MMLError>),
+    // 整形できない記述 (マクロの値で始めて外で閉じるブロックやリピートなど)
+    Unsupported(Position),
+    // 整形したMMLから元のMMLと同じSMFが生成されない
+    Mismatch,
+}
+
+impl FormatError {
+    pub fn message_in(&self, lang: Lang) -> &'static str {
+        match (self, lang) {
+            (FormatError::InvalidSource(_), Lang::Ja) => "MMLにエラーがあるため整形できません",
+            (FormatError::InvalidSource(_), Lang::En) => "cannot format MML with errors",
+            (FormatError::Unsupported(_), Lang::Ja) => {
+                "整形できない記述です (マクロの中と外で対応するブロックやリピートなど)"
+            }
+            (FormatError::Unsupported(_), Lang::En) => {
+                "cannot format this part (e.g. a block or repeat split across a macro)"
+            }
+            (FormatError::Mismatch, Lang::Ja) => "整形すると変換結果が変わるため整形できません",
+            (FormatError::Mismatch, Lang::En) => "formatting would change the converted result",
+        }
+    }
+
+    // 整形できない記述の場合は位置を付ける
+    pub fn render_in(&self, lang: Lang) -> String {
+        let message = self.message_in(lang);
+        match self {
+            FormatError::Unsupported(pos) => format!("{}: {}", error::location(pos), message),
+            _ => message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render_in(Lang::Ja))
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+// MMLを整形する。
+// 大文字小文字と空白をそろえ、ブロックとリピートを字下げし、長い行を小節線の位置で折り返す。コメントは残す。
+// 整形したMMLを読み込み直して元のMMLと同じSMFになることを確認する (同じにならない場合はMismatch)
+pub(crate) fn format(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> Result<String, FormatError> {
+    let song = mml::parse(src, file, options).map_err(FormatError::InvalidSource)?;
+
+    let mut lexer = Lexer {
+        chars: src.chars().collect(),
+        index: 0,
+        row: 1,
+        col: 1,
+        mode: options.input_mode,
+        file: file.map(|path| path.display().to_string()),
+    };
+    let nodes = build_tree(lexer.tokens()?)?;
+
+    let mut printer = Printer::default();
+    printer.print_nodes(&nodes);
+    printer.flush();
+    let formatted = printer.out;
+
+    let check = mml::parse(&formatted, file, options).map_err(|_| FormatError::Mismatch)?;
+    if !same_song(&song, &check) {
+        return Err(FormatError::Mismatch);
+    }
+
+    Ok(formatted)
+}
+
+fn same_song(a: &Song, b: &Song) -> bool {
+    let inst = INSTRUMENTS[0];
+    a.tseq == b.tseq && a.to_smf(inst).ok() == b.to_smf(inst).ok()
+}
+
+#[derive(Debug)]
+enum Kind {
+    // 音符やオクターブ指定などのコマンド、マクロ展開 (@名前)
+    Word(String),
+    BarLine,
+    BlockStart(String),
+    BlockEnd,
+    RepeatStart(String),
+    RepeatEnd,
+    // ; か // で始まる行末までのコメント
+    LineComment(String),
+    // /* */ のコメント (複数行の場合はそのまま書く)
+    BlockComment(String),
+    // # で始まるプリプロセッサ指令と曲の情報・拍子・楽器 (1行で書く)
+    Directive(String),
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: Kind,
+    // 直前の改行の数 (2以上なら空行を残す。0のコメントは行末のコメント)
+    newlines: usize,
+    pos: Position,
+}
+
+// MMLの記述をコマンド単位に区切る (Mmlの読み込みと同じ規則で区切る)
+struct Lexer {
+    chars: Vec<char>,
+    index: usize,
+    row: usize,
+    col: usize,
+    mode: InputMode,
+    file: Option<String>,
+}
+
+impl Lexer {
+    // 元のテキストの文字
+    fn raw(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    // 入力モードに合わせて置き換えた文字
+    fn peek(&self) -> Option<char> {
+        self.raw().map(|ch| self.mode.normalize(ch))
+    }
+
+    fn step(&mut self) {
+        if self.raw() == Some('\n') {
+            self.row += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        self.index += 1;
+    }
+
+    // ファ・ふぁ は2文字で1つの音名 (Mml::next_charと同じ)
+    fn advance(&mut self) {
+        let fa = self.mode == InputMode::Japanese && matches!(self.raw(), Some('フ' | 'ふ'));
+        self.step();
+        if fa && matches!(self.raw(), Some('ァ' | 'ぁ')) {
+            self.step();
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            character: self.raw(),
+            col: self.col,
+            row: self.row,
+            file: self.file.clone(),
+        }
+    }
+
+    fn unsupported<T>(&self) -> Result<T, FormatError> {
+        Err(FormatError::Unsupported(self.position()))
+    }
+
+    fn take_while(&mut self, text: &mut String, f: fn(&char) -> bool) -> usize {
+        let mut count: usize = 0;
+        while let Some(ch) = self.peek().filter(f) {
+            text.push(ch);
+            self.advance();
+            count += 1;
+        }
+        count
+    }
+
+    fn digits(&mut self, text: &mut String) -> Result<(), FormatError> {
+        if self.take_while(text, char::is_ascii_digit) == 0 {
+            return self.unsupported();
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, FormatError> {
+        if self.peek().filter(|ch| is_name_start(*ch)).is_none() {
+            return self.unsupported();
+        }
+        let mut name = String::new();
+        self.take_while(&mut name, |ch| is_name_char(*ch));
+        Ok(name)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self
+            .peek()
+            .filter(|ch| *ch != '\n' && ch.is_whitespace())
+            .is_some()
+        {
+            self.advance();
+        }
+    }
+
+    // 元のテキストのまま行末まで読み込む
+    fn rest_of_line(&mut self, text: &mut String) {
+        while let Some(ch) = self.raw().filter(|ch| *ch != '\n') {
+            text.push(ch);
+            self.step();
+        }
+        text.truncate(text.trim_end().len());
+    }
+
+    // 音長 (4, 8., (16) など、無い場合もある)
+    fn duration(&mut self, text: &mut String) -> Result<(), FormatError> {
+        match self.peek() {
+            Some(ch) if ch.is_ascii_digit() => {
+                self.digits(text)?;
+                self.take_while(text, |ch| *ch == '.');
+            }
+            Some('(') => {
+                text.push('(');
+                self.advance();
+                self.digits(text)?;
+                if self.peek() != Some(')') {
+                    return self.unsupported();
+                }
+                text.push(')');
+                self.advance();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // 音符の直後の "歌詞" (元のテキストのまま)
+    fn lyric(&mut self, text: &mut String) -> Result<(), FormatError> {
+        if self.peek() != Some('"') {
+            return Ok(());
+        }
+        text.push('"');
+        self.step();
+        loop {
+            match self.raw() {
+                Some('"') => break,
+                Some('\n') | None => return self.unsupported(),
+                Some(ch) => text.push(ch),
+            }
+            self.step();
+        }
+        text.push('"');
+        self.advance();
+        Ok(())
+    }
+
+    // 引数付きブロック再生の引数 (transpose=5, volume=80)
+    fn arguments(&mut self, text: &mut String) -> Result<(), FormatError> {
+        let mut args: Vec<String> = Vec::new();
+        self.advance();
+        loop {
+            self.skip_whitespaces_in_arguments()?;
+            if self.peek() == Some(')') {
+                break;
+            }
+            let mut arg = self.name()?.to_ascii_lowercase();
+            self.skip_whitespaces_in_arguments()?;
+            if self.peek() != Some('=') {
+                return self.unsupported();
+            }
+            arg.push('=');
+            self.advance();
+            self.skip_whitespaces_in_arguments()?;
+            self.take_while(&mut arg, |ch| matches!(ch, '+' | '-'));
+            self.digits(&mut arg)?;
+            args.push(arg);
+            self.skip_whitespaces_in_arguments()?;
+            match self.peek() {
+                Some(',') => self.advance(),
+                Some(')') => break,
+                _ => return self.unsupported(),
+            }
+        }
+        self.advance();
+        text.push('(');
+        text.push_str(&args.join(", "));
+        text.push(')');
+        Ok(())
+    }
+
+    // 引数の中のコメントやマクロ展開は整形しない
+    fn skip_whitespaces_in_arguments(&mut self) -> Result<(), FormatError> {
+        while let Some(ch) = self.peek() {
+            if matches!(ch, ';' | '/' | '#' | '@') {
+                return self.unsupported();
+            }
+            if !ch.is_whitespace() {
+                break;
+            }
+            self.advance();
+        }
+        Ok(())
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>, FormatError> {
+        let mut tokens: Vec<Token> = Vec::new();
+        loop {
+            let mut newlines: usize = 0;
+            while let Some(ch) = self.peek().filter(|ch| ch.is_whitespace()) {
+                if ch == '\n' {
+                    newlines += 1;
+                }
+                self.advance();
+            }
+            let pos = self.position();
+            let kind = match self.peek() {
+                Some(ch) => self.token(ch)?,
+                None => break,
+            };
+            tokens.push(Token {
+                kind,
+                newlines,
+                pos,
+            });
+        }
+        Ok(tokens)
+    }
+
+    fn token(&mut self, ch: char) -> Result<Kind, FormatError> {
+        let mut text = String::new();
+        match ch.to_ascii_uppercase() {
+            ';' => {
+                text.push(';');
+                self.step();
+                self.rest_of_line(&mut text);
+                return Ok(Kind::LineComment(text));
+            }
+            '/' => {
+                self.advance();
+                match self.peek() {
+                    Some('/') => {
+                        text.push_str("//");
+                        self.step();
+                        self.rest_of_line(&mut text);
+                        return Ok(Kind::LineComment(text));
+                    }
+                    Some('*') => return self.block_comment(),
+                    _ => return self.unsupported(),
+                }
+            }
+            '#' => {
+                self.advance();
+                return self.directive();
+            }
+            '{' => {
+                text.push('{');
+                self.advance();
+                if self.peek().filter(char::is_ascii_digit).is_some() {
+                    self.digits(&mut text)?;
+                } else {
+                    text.push_str(&self.name()?);
+                }
+                return Ok(Kind::BlockStart(text));
+            }
+            '}' => {
+                self.advance();
+                return Ok(Kind::BlockEnd);
+            }
+            '[' => {
+                text.push('[');
+                self.advance();
+                self.digits(&mut text)?;
+                return Ok(Kind::RepeatStart(text));
+            }
+            ']' => {
+                self.advance();
+                return Ok(Kind::RepeatEnd);
+            }
+            '|' => {
+                self.advance();
+                return Ok(Kind::BarLine);
+            }
+            '@' => {
+                text.push('@');
+                self.advance();
+                text.push_str(&self.name()?);
+            }
+            '!' => {
+                text.push('!');
+                self.advance();
+                text.push_str(&self.name()?.to_ascii_lowercase());
+            }
+            '<' | '>' => {
+                text.push(ch);
+                self.advance();
+            }
+            note @ 'A'..='G' => {
+                text.push(note);
+                self.advance();
+                if let Some(accidental) = self.peek().filter(|ch| matches!(ch, '+' | '#' | '-')) {
+                    text.push(accidental);
+                    self.advance();
+                }
+                self.duration(&mut text)?;
+                self.lyric(&mut text)?;
+            }
+            'R' => {
+                text.push('R');
+                self.advance();
+                self.duration(&mut text)?;
+            }
+            'N' => {
+                text.push('N');
+                self.advance();
+                if self.peek() != Some('(') {
+                    return self.unsupported();
+                }
+                text.push('(');
+                self.advance();
+                self.digits(&mut text)?;
+                if self.peek() != Some(')') {
+                    return self.unsupported();
+                }
+                text.push(')');
+                self.advance();
+                self.duration(&mut text)?;
+                self.lyric(&mut text)?;
+            }
+            'O' => {
+                text.push('O');
+                self.advance();
+                self.take_while(&mut text, |ch| *ch == '-');
+                self.digits(&mut text)?;
+            }
+            'L' => {
+                text.push('L');
+                self.advance();
+                self.duration(&mut text)?;
+            }
+            command @ ('V' | 'T' | '%') => {
+                text.push(command);
+                self.advance();
+                self.digits(&mut text)?;
+            }
+            '$' => {
+                text.push('$');
+                self.advance();
+                if self.peek().filter(char::is_ascii_digit).is_some() {
+                    self.digits(&mut text)?;
+                } else {
+                    text.push_str(&self.name()?);
+                }
+                if self.peek() == Some('(') {
+                    self.arguments(&mut text)?;
+                }
+            }
+            _ => return self.unsupported(),
+        }
+        Ok(Kind::Word(text))
+    }
+
+    // /* から */ まで (Mml::skip_block_commentと同じく開始の * は閉じの * にならない)
+    fn block_comment(&mut self) -> Result<Kind, FormatError> {
+        let mut text = String::from("/*");
+        self.step();
+        let mut last: Option<char> = None;
+        loop {
+            let ch = match self.peek() {
+                Some(ch) => ch,
+                None => return self.unsupported(),
+            };
+            if last == Some('*') && ch == '/' {
+                text.pop();
+                text.push_str("*/");
+                self.step();
+                return Ok(Kind::BlockComment(text));
+            }
+            text.push(self.raw().unwrap());
+            last = Some(ch);
+            self.step();
+        }
+    }
+
+    fn directive(&mut self) -> Result<Kind, FormatError> {
+        let directive = self.name()?.to_ascii_lowercase();
+        self.skip_spaces();
+        let mut text = String::new();
+        match directive.as_str() {
+            "define" => {
+                text.push_str("#define ");
+                text.push_str(&self.name()?);
+                self.skip_spaces();
+                // 値は行末のコメントの手前まで (Mml::parse_defineと同じ)
+                let mut value = String::new();
+                let mut last: Option<char> = None;
+                while let Some(ch) = self.peek() {
+                    if ch == '\n' || ch == ';' || (ch == '/' && last == Some('/')) {
+                        break;
+                    }
+                    value.push(self.raw().unwrap());
+                    last = Some(ch);
+                    self.step();
+                }
+                if last == Some('/') && self.peek() == Some('/') {
+                    value.pop();
+                    // 読み飛ばした / はコメントの始まり
+                    self.index -= 1;
+                    self.col -= 1;
+                }
+                text.push(' ');
+                text.push_str(value.trim_end());
+            }
+            "include" => {
+                if self.peek() != Some('"') {
+                    return self.unsupported();
+                }
+                text.push_str("#include \"");
+                self.step();
+                loop {
+                    match self.raw() {
+                        Some('"') => break,
+                        Some('\n') | None => return self.unsupported(),
+                        Some(ch) => text.push(ch),
+                    }
+                    self.step();
+                }
+                text.push('"');
+                self.step();
+            }
+            "title" | "composer" | "copyright" | "comment" => {
+                text.push('#');
+                text.push_str(&directive.to_ascii_uppercase());
+                text.push(' ');
+                self.rest_of_line(&mut text);
+            }
+            "time" => {
+                text.push_str("#TIME ");
+                self.digits(&mut text)?;
+                if self.peek() != Some('/') {
+                    return self.unsupported();
+                }
+                text.push('/');
+                self.advance();
+                self.digits(&mut text)?;
+            }
+            "instrument" => {
+                text.push_str("#INSTRUMENT ");
+                self.digits(&mut text)?;
+            }
+            _ => return self.unsupported(),
+        }
+        Ok(Kind::Directive(text))
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Leaf(Token),
+    // ブロックかリピート
+    Group {
+        open: Token,
+        children: Vec<Node>,
+        close: Token,
+    },
+}
+
+impl Node {
+    fn newlines(&self) -> usize {
+        match self {
+            Node::Leaf(token) | Node::Group { open: token, .. } => token.newlines,
+        }
+    }
+
+    fn is_bar_line(&self) -> bool {
+        matches!(
+            self,
+            Node::Leaf(Token {
+                kind: Kind::BarLine,
+                ..
+            })
+        )
+    }
+
+    // 1行に並べて書く場合の記述 (ブロックや行末までのコメントなど1行に書けない場合はNone)
+    fn flat(&self) -> Option<String> {
+        match self {
+            Node::Leaf(token) => match &token.kind {
+                Kind::Word(text) => Some(text.clone()),
+                Kind::BarLine => Some("|".to_string()),
+                Kind::BlockComment(text) if !text.contains('\n') => Some(text.clone()),
+                _ => None,
+            },
+            Node::Group { open, children, .. } => {
+                let mut text = match &open.kind {
+                    Kind::RepeatStart(header) => header.clone(),
+                    _ => return None,
+                };
+                for (i, child) in children.iter().enumerate() {
+                    if i > 0 && child.newlines() >= 2 {
+                        return None;
+                    }
+                    text.push(' ');
+                    text.push_str(&child.flat()?);
+                }
+                text.push_str(" ]");
+                Some(text)
+            }
+        }
+    }
+}
+
+// ブロックとリピートの対応を取る
+fn build_tree(tokens: Vec<Token>) -> Result<Vec<Node>, FormatError> {
+    let mut stack: Vec<(Token, Vec<Node>)> = Vec::new();
+    let mut nodes: Vec<Node> = Vec::new();
+    for token in tokens {
+        match token.kind {
+            Kind::BlockStart(_) | Kind::RepeatStart(_) => {
+                stack.push((token, std::mem::take(&mut nodes)));
+            }
+            Kind::BlockEnd | Kind::RepeatEnd => {
+                let (open, parent) = match stack.pop() {
+                    Some(group) => group,
+                    None => return Err(FormatError::Unsupported(token.pos)),
+                };
+                let matched = matches!(
+                    (&open.kind, &token.kind),
+                    (Kind::BlockStart(_), Kind::BlockEnd) | (Kind::RepeatStart(_), Kind::RepeatEnd)
+                );
+                if !matched {
+                    return Err(FormatError::Unsupported(token.pos));
+                }
+                let children = std::mem::replace(&mut nodes, parent);
+                nodes.push(Node::Group {
+                    open,
+                    children,
+                    close: token,
+                });
+            }
+            _ => nodes.push(Node::Leaf(token)),
+        }
+    }
+    match stack.pop() {
+        Some((open, _)) => Err(FormatError::Unsupported(open.pos)),
+        None => Ok(nodes),
+    }
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    line: String,
+    line_indent: usize,
+    indent: usize,
+    // 行末のコメント以外は続けて書かない行 (プリプロセッサ指令など)
+    closed: bool,
+}
+
+impl Printer {
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+        for _ in 0..self.line_indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(&self.line);
+        self.out.push('\n');
+        self.line.clear();
+        self.closed = false;
+    }
+
+    fn blank_line(&mut self) {
+        self.flush();
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        if self.line.is_empty() {
+            self.line_indent = self.indent;
+        } else {
+            self.line.push(' ');
+        }
+        self.line.push_str(text);
+    }
+
+    // 現在の行に続けて幅widthの記述を書けるか
+    fn fits(&self, width: usize) -> bool {
+        self.line.is_empty()
+            || self.line_indent * INDENT.len() + text_width(&self.line) + 1 + width <= WIDTH
+    }
+
+    fn print_nodes(&mut self, nodes: &[Node]) {
+        // 小節線がある場合は小節の途中で折り返さない
+        let has_bar_lines = nodes.iter().any(Node::is_bar_line);
+        let mut bar_start = true;
+
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 && node.newlines() >= 2 {
+                self.blank_line();
+            }
+
+            if let Some(text) = node.flat() {
+                if self.closed {
+                    self.flush();
+                }
+                if has_bar_lines && bar_start && !self.fits(bar_width(&nodes[i..])) {
+                    self.flush();
+                }
+                if !self.fits(text_width(&text)) {
+                    self.flush();
+                }
+                self.push(&text);
+                bar_start = node.is_bar_line();
+                continue;
+            }
+
+            bar_start = false;
+
+            match node {
+                Node::Leaf(token) => match &token.kind {
+                    Kind::LineComment(text) | Kind::BlockComment(text) => {
+                        // 行末のコメントはそのまま行末に、それ以外は単独の行に書く
+                        if token.newlines > 0 {
+                            self.flush();
+                        }
+                        self.push(text);
+                        self.flush();
+                    }
+                    Kind::Directive(text) => {
+                        self.flush();
+                        self.push(text);
+                        self.closed = true;
+                    }
+                    _ => unreachable!("flat token"),
+                },
+                Node::Group {
+                    open,
+                    children,
+                    close,
+                } => self.print_group(open, children, close),
+            }
+        }
+    }
+
+    fn print_group(&mut self, open: &Token, children: &[Node], close: &Token) {
+        let (header, footer) = match &open.kind {
+            Kind::BlockStart(header) => (header, "}"),
+            Kind::RepeatStart(header) => (header, "]"),
+            _ => unreachable!("group header"),
+        };
+        self.flush();
+        self.push(header);
+        self.indent += 1;
+        // 開始の行の行末のコメントはそのまま残す
+        let trailing_comment = matches!(
+            children.first(),
+            Some(Node::Leaf(Token {
+                kind: Kind::LineComment(_),
+                newlines: 0,
+                ..
+            }))
+        );
+        if !trailing_comment {
+            self.flush();
+        }
+        self.print_nodes(children);
+        self.flush();
+        self.indent -= 1;
+        if close.newlines >= 2 && !children.is_empty() {
+            self.blank_line();
+        }
+        self.push(footer);
+        self.flush();
+    }
+}
+
+// 次の小節線までを1行に並べた場合の幅
+fn bar_width(nodes: &[Node]) -> usize {
+    let mut width: usize = 0;
+    for node in nodes {
+        let text = match node.flat() {
+            Some(text) => text,
+            None => break,
+        };
+        if width > 0 {
+            width += 1;
+        }
+        width += text_width(&text);
+        if node.is_bar_line() {
+            break;
+        }
+    }
+    width
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().map(|ch| error::char_width(ch).max(1)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(src: &str) -> String {
+        super::format(src, None, &ParseOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn format_examples() {
+        let src = "t150%96{0gfgf2.}o5[2c>ar]$0ggab-2gaab-<[3c2>a]$0";
+        let formatted = format(src);
+        assert_eq!(
+            formatted,
+            "T150 %96\n{0\n    G F G F2.\n}\nO5 [2 C > A R ] $0 G G A B-2 G A A B- < [3 C2 > A ] $0\n"
+        );
+        assert_eq!(format(&formatted), formatted);
+
+        // コメントと曲の情報と空行は残す
+        let src = "#title Summ ; not a comment\n#define M c d ; motif\n{motif O5 L4 D C ; motif\n\n >B R}\n\n// main\n$motif(Transpose = 5,volume=80) /* end */\n";
+        assert_eq!(
+            format(src),
+            "#TITLE Summ ; not a comment\n#define M c d ; motif\n{motif\n    O5 L4 D C ; motif\n\n    > B R\n}\n\n// main\n$motif(transpose=5, volume=80) /* end */\n"
+        );
+
+        // 長い行は小節線の位置で折り返す
+        let bar = "C8 D8 E8 F8 G8 A8 B8 < C8 > |";
+        let src = [bar; 4].join("");
+        let formatted = format(&src);
+        assert_eq!(formatted, format!("{} {}\n{} {}\n", bar, bar, bar, bar));
+    }
+
+    #[test]
+    fn format_errors() {
+        let options = ParseOptions::default();
+        assert!(matches!(
+            super::format("C D X", None, &options),
+            Err(FormatError::InvalidSource(_))
+        ));
+
+        // マクロの中で始めたリピートを外で閉じている
+        let res = super::format("#define OPEN [2 C\n@OPEN D ]", None, &options);
+        match res {
+            Err(FormatError::Unsupported(pos)) => assert_eq!((pos.row, pos.col), (2, 9)),
+            res => panic!("{:?}", res),
+        }
+
+        // 日本語の入力モードの音名は半角の音名にする (歌詞はそのまま)
+        let options = ParseOptions {
+            input_mode: InputMode::Japanese,
+            ..ParseOptions::default()
+        };
+        let formatted = super::format("ド\"ド\" ファ４　ソ♯", None, &options).unwrap();
+        assert_eq!(formatted, "C\"ド\" F4 G#\n");
+    }
+}
@@ -0,0 +1,81 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::encoding::TextEncoding;
+
+// MMLの入力モード
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputMode {
+    // 半角英数字のMMLだけを受け付ける
+    #[default]
+    Standard,
+    // 全角英数字・記号 (IMEで入力したＣ４やＯ５など) と
+    // カタカナ・ひらがなの音名 (ドレミファソラシ、どれみふぁそらし) と ♯ ♭ も受け付ける
+    Japanese,
+}
+
+impl InputMode {
+    // "standard" か "japanese" (mml-cliの--input-modeで指定する)
+    pub fn parse(s: &str) -> Option<InputMode> {
+        match s.to_ascii_lowercase().as_str() {
+            "standard" => Some(InputMode::Standard),
+            "japanese" => Some(InputMode::Japanese),
+            _ => None,
+        }
+    }
+
+    // 入力の文字をMMLの半角の文字に置き換える (歌詞や曲の情報のテキストには使わない)
+    // ファ・ふぁ の小さいァ・ぁは読み込み側で読み飛ばす
+    pub(crate) fn normalize(self, ch: char) -> char {
+        if self == InputMode::Standard {
+            return ch;
+        }
+        match ch {
+            '\u{FF01}'..='\u{FF5E}' => char::from_u32(ch as u32 - 0xFEE0).unwrap_or(ch),
+            '\u{3000}' => ' ',
+            '♯' => '#',
+            '♭' => '-',
+            'ド' | 'ど' => 'C',
+            'レ' | 'れ' => 'D',
+            'ミ' | 'み' => 'E',
+            'フ' | 'ふ' => 'F',
+            'ソ' | 'そ' => 'G',
+            'ラ' | 'ら' => 'A',
+            'シ' | 'し' => 'B',
+            _ => ch,
+        }
+    }
+}
+
+// MMLの読み込みの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    pub input_mode: InputMode,
+    // ファイル (#includeで取り込むファイルも含む) の文字コード
+    pub encoding: TextEncoding,
+    // MMLでT (テンポ) と% (分解能) を省略した場合の値 (Noneの場合は120と64)。
+    // Tと%で指定できる範囲外の値は使えない
+    pub tempo: Option<i32>,
+    pub resolution: Option<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let japanese = |s: &str| -> String {
+            s.chars()
+                .map(|ch| InputMode::Japanese.normalize(ch))
+                .collect()
+        };
+        assert_eq!(japanese("Ｏ５　Ｃ４（１６）"), "O5 C4(16)");
+        assert_eq!(japanese("ドレミソラシ"), "CDEGAB");
+        assert_eq!(japanese("どれみそらし"), "CDEGAB");
+        assert_eq!(japanese("ド♯シ♭"), "C#B-");
+        assert_eq!(InputMode::Standard.normalize('Ｃ'), 'Ｃ');
+        assert_eq!(InputMode::parse("Japanese"), Some(InputMode::Japanese));
+        assert_eq!(InputMode::parse("ja"), None);
+    }
+}
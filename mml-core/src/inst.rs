@@ -1,6 +1,7 @@
 // mml-core
 // author: Leonardone @ NEETSDKASU
 
+use crate::lang::Lang;
 use std::sync::Once;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -474,6 +475,55 @@ impl InstrumentCategory {
     }
 }
 
+impl Instrument {
+    // 表示言語に合わせた楽器名
+    pub fn name_in(self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.name(),
+            Lang::Ja => self.name_ja(),
+        }
+    }
+}
+
+impl InstrumentCategory {
+    pub fn name_in(self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.name(),
+            Lang::Ja => self.name_ja(),
+        }
+    }
+}
+
+impl Instrument {
+    // 楽器のおおよその音域 (ノート番号)
+    pub fn note_range(self) -> std::ops::RangeInclusive<i32> {
+        self.category().note_range()
+    }
+}
+
+impl InstrumentCategory {
+    // 分類ごとのおおよその音域 (ノート番号)
+    pub fn note_range(self) -> std::ops::RangeInclusive<i32> {
+        use InstrumentCategory::*;
+        match self {
+            Piano => 21..=108,
+            ChromaticPrecussion => 45..=108,
+            Organ => 24..=96,
+            Guitar => 40..=88,
+            Bass => 28..=67,
+            Strings => 28..=103,
+            Ensemble => 28..=96,
+            Brass => 28..=84,
+            Reed => 34..=93,
+            Pipe => 48..=108,
+            SynthLead | SynthPad | SynthEffects => 24..=108,
+            Ethnic => 36..=96,
+            Percussive => 24..=96,
+            SoundEffects => 0..=127,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
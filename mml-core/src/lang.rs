@@ -0,0 +1,36 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+// メッセージや楽器名の表示言語
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    En,
+    Ja,
+}
+
+impl Lang {
+    // "en" や "ja" (環境変数LANGの "ja_JP.UTF-8" のような形式も可) から表示言語を得る
+    pub fn parse(s: &str) -> Option<Lang> {
+        let code = s.split(['_', '-', '.']).next()?;
+        match code.to_ascii_lowercase().as_str() {
+            "en" | "c" | "posix" => Some(Lang::En),
+            "ja" => Some(Lang::Ja),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Lang::parse("en"), Some(Lang::En));
+        assert_eq!(Lang::parse("ja"), Some(Lang::Ja));
+        assert_eq!(Lang::parse("ja_JP.UTF-8"), Some(Lang::Ja));
+        assert_eq!(Lang::parse("en_US.UTF-8"), Some(Lang::En));
+        assert_eq!(Lang::parse("C"), Some(Lang::En));
+        assert_eq!(Lang::parse("fr_FR"), None);
+    }
+}
@@ -1,20 +1,196 @@
 // mml-core
 // author: Leonardone @ NEETSDKASU
 
+mod analysis;
+mod compress;
+mod diff;
+mod encoding;
+mod error;
+mod expand;
+mod explain;
+mod fmt;
+mod input;
 mod inst;
+mod lang;
+mod lint;
 mod midi;
 mod mml;
+mod smf;
+mod song;
+mod timeline;
 mod tone_control;
+mod transform;
 
+pub use analysis::{note_name, Analysis, Block, SequenceEvent, Sound, Summary};
+pub use compress::Compressed;
+pub use diff::{DiffSound, SongDiff, SoundChange};
+pub use encoding::{decode, encode, TextEncoding};
+pub use error::{char_width, MMLError, Position, Span};
+pub use explain::{error_codes, explain, Explanation};
+pub use fmt::FormatError;
+pub use input::{InputMode, ParseOptions};
 pub use inst::{Instrument, InstrumentCategory, INSTRUMENTS, INSTRUMENT_CATEGORIES};
-pub use mml::MMLError;
+pub use lang::Lang;
+pub use lint::{Lint, MMLWarning, LINT_NAMES};
+pub use smf::SmfError;
+pub use song::{Metadata, Song, TimeSignature};
+pub use transform::{Transform, TransformError};
+
+use std::path::{Path, PathBuf};
 
 pub fn convert(src: &str, inst: Instrument) -> Result<Vec<u8>, MMLError> {
-    let tseq = mml::parse(src)?;
-    let inst: i8 = (inst as i32 - 1) as i8;
-    assert!(0 <= inst, "inst {}", inst);
-    let smf = midi::translate(&tseq, inst)?;
-    Ok(smf)
+    parse(src)?.to_smf(inst)
+}
+
+// MMLファイルを読み込んで変換する。
+// エラー位置にはファイル名が付き、#includeはこのファイルのディレクトリからの相対パスになる。
+pub fn convert_file<P: AsRef<Path>>(path: P, inst: Instrument) -> Result<Vec<u8>, MMLError> {
+    parse_file(path)?.to_smf(inst)
+}
+
+// エラーがある場合は最初のエラーを返す
+pub fn parse(src: &str) -> Result<Song, MMLError> {
+    parse_all(src).map_err(first_error)
+}
+
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Song, MMLError> {
+    parse_file_all(path).map_err(first_error)
+}
+
+// エラーがある場合は見つけたエラーを全て返す (エディタとの連携などで使う)
+pub fn parse_all(src: &str) -> Result<Song, Vec<MMLError>> {
+    parse_all_with(src, &ParseOptions::default())
+}
+
+pub fn parse_file_all<P: AsRef<Path>>(path: P) -> Result<Song, Vec<MMLError>> {
+    parse_file_all_with(path, &ParseOptions::default())
+}
+
+// 読み込みの設定 (入力モードや文字コード) を指定して読み込む
+pub fn parse_all_with(src: &str, options: &ParseOptions) -> Result<Song, Vec<MMLError>> {
+    mml::parse(src, None, options)
+}
+
+pub fn parse_file_all_with<P: AsRef<Path>>(
+    path: P,
+    options: &ParseOptions,
+) -> Result<Song, Vec<MMLError>> {
+    let path = path.as_ref();
+    let src = read_file(path, options)?;
+    mml::parse(&src, Some(path), options)
+}
+
+// lintで有効にした確認項目の警告を返す (エラーがある場合は見つけたエラーを全て返す)
+pub fn lint(src: &str, lint: &Lint) -> Result<Vec<MMLWarning>, Vec<MMLError>> {
+    lint_with(src, lint, &ParseOptions::default())
+}
+
+pub fn lint_file<P: AsRef<Path>>(path: P, lint: &Lint) -> Result<Vec<MMLWarning>, Vec<MMLError>> {
+    lint_file_with(path, lint, &ParseOptions::default())
+}
+
+pub fn lint_with(
+    src: &str,
+    lint: &Lint,
+    options: &ParseOptions,
+) -> Result<Vec<MMLWarning>, Vec<MMLError>> {
+    mml::parse_with_lint(src, None, options, lint.clone()).map(|(_, warnings)| warnings)
+}
+
+pub fn lint_file_with<P: AsRef<Path>>(
+    path: P,
+    lint: &Lint,
+    options: &ParseOptions,
+) -> Result<Vec<MMLWarning>, Vec<MMLError>> {
+    let path = path.as_ref();
+    let src = read_file(path, options)?;
+    mml::parse_with_lint(&src, Some(path), options, lint.clone()).map(|(_, warnings)| warnings)
+}
+
+// 音出し・無音とブロックの記述範囲も取得する (エディタとの連携用)。
+// pathはsrcのファイルのパス (#includeの相対パスの基準とエラー位置のファイル名になる)
+pub fn analyze(
+    src: &str,
+    path: Option<&Path>,
+    options: &ParseOptions,
+) -> Result<Analysis, Vec<MMLError>> {
+    analysis::analyze(src, path, options)
+}
+
+// エラーがあっても読み込めた分の解析結果を見つけたエラーとともに返す (編集中でエラーのあるMMLのホバーなどで使う)。
+// エラーから復帰できなかった場合の解析結果はNone
+pub fn analyze_partial(
+    src: &str,
+    path: Option<&Path>,
+    options: &ParseOptions,
+) -> (Option<Analysis>, Vec<MMLError>) {
+    analysis::analyze_partial(src, path, options)
+}
+
+pub fn analyze_file<P: AsRef<Path>>(
+    path: P,
+    options: &ParseOptions,
+) -> Result<Analysis, Vec<MMLError>> {
+    let path = path.as_ref();
+    let src = read_file(path, options)?;
+    analysis::analyze(&src, Some(path), options)
+}
+
+// MMLのファイルと#includeで指定されたファイルのパスを返す (--watchで変更を監視するファイル)。
+// エラーがあっても見つけた分は返す (pathが読み込めない場合はpathだけ)
+pub fn source_files<P: AsRef<Path>>(path: P, options: &ParseOptions) -> Vec<PathBuf> {
+    let path = path.as_ref();
+    let mut files = vec![path.to_path_buf()];
+    if let Ok(src) = read_file(path, options) {
+        files.extend(mml::included_files(&src, Some(path), options));
+    }
+    files
+}
+
+// MMLを整形する (整形しても変換結果のSMFは変わらない)
+pub fn format(src: &str, options: &ParseOptions) -> Result<String, FormatError> {
+    fmt::format(src, None, options)
+}
+
+pub fn format_file<P: AsRef<Path>>(path: P, options: &ParseOptions) -> Result<String, FormatError> {
+    let path = path.as_ref();
+    let src = read_file(path, options).map_err(FormatError::InvalidSource)?;
+    fmt::format(&src, Some(path), options)
+}
+
+// ブロック再生とリピートを展開し、音符ごとにオクターブと長さを明記したMMLにする
+// (展開しても変換結果のSMFは変わらない)
+pub fn expand(src: &str, options: &ParseOptions) -> Result<String, Vec<MMLError>> {
+    parse_all_with(src, options).map(|song| song.expand())
+}
+
+pub fn expand_file<P: AsRef<Path>>(
+    path: P,
+    options: &ParseOptions,
+) -> Result<String, Vec<MMLError>> {
+    parse_file_all_with(path, options).map(|song| song.expand())
+}
+
+// 単音のSMFを読み込む (mml2smfで生成したSMFは元のMMLと同じSMFを生成できるSongになる)
+pub fn read_smf(bytes: &[u8]) -> Result<Song, SmfError> {
+    smf::read(bytes)
+}
+
+pub fn read_smf_file<P: AsRef<Path>>(path: P) -> Result<Song, SmfError> {
+    smf::read(&std::fs::read(path)?)
+}
+
+// SMFのヘッダーで始まるか (MMLとSMFのどちらのファイルかの判定用)
+pub fn is_smf(bytes: &[u8]) -> bool {
+    smf::is_smf(bytes)
+}
+
+fn read_file(path: &Path, options: &ParseOptions) -> Result<String, Vec<MMLError>> {
+    encoding::read_file(path, options.encoding).map_err(|error| vec![error.into()])
+}
+
+fn first_error(errors: Vec<MMLError>) -> MMLError {
+    errors.into_iter().next().expect("no errors")
 }
 
 #[cfg(test)]
@@ -74,4 +250,215 @@ mod tests {
         let res3 = convert(MORGEN_KOMMT_DER_WEIHNACHTSMANN, inst);
         assert!(res3.is_ok(), "{:?}", res3);
     }
+
+    #[test]
+    fn metadata() {
+        let inst = Instrument::AcousticGrandPiano;
+
+        let src = "#TITLE Summ, summ, summ\n#COMPOSER trad.\n#COPYRIGHT (c) 2022 http://example.com\n#COMMENT memo\nC";
+        let song = parse(src).unwrap();
+        assert_eq!(
+            song.metadata(),
+            &Metadata {
+                title: Some("Summ, summ, summ".into()),
+                composer: Some("trad.".into()),
+                copyright: Some("(c) 2022 http://example.com".into()),
+                comments: vec!["memo".into()],
+            }
+        );
+
+        let smf = song.to_smf(inst).unwrap();
+        let plain = convert("C", inst).unwrap();
+        // copyright, title, composer, comment の順にトラックの先頭に書き込まれる
+        let mut events: Vec<u8> = Vec::new();
+        for (meta_type, text) in [
+            (0x02, "(c) 2022 http://example.com"),
+            (0x03, "Summ, summ, summ"),
+            (0x01, "trad."),
+            (0x01, "memo"),
+        ] {
+            events.extend([0x00, 0xFF, meta_type, text.len() as u8]);
+            events.extend(text.as_bytes());
+        }
+        assert_eq!(&smf[..18], &plain[..18]);
+        assert_eq!(&smf[22..22 + events.len()], &events[..]);
+        assert_eq!(&smf[22 + events.len()..], &plain[22..]);
+
+        let res = parse("#TITLE a\n#TITLE b\nC");
+        assert!(
+            matches!(res, Err(MMLError::InvalidMetadata(_))),
+            "{:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn time_signature() {
+        let inst = Instrument::AcousticGrandPiano;
+
+        let plain = convert("L4 C D E F G A", inst).unwrap();
+        let smf = convert("#TIME 6/8\nL4 C D E | F G A |", inst).unwrap();
+
+        // set tempo の後に time signature (6/8) が入る
+        let time_signature = [0x00, 0xFF, 0x58, 0x04, 6, 3, 24, 8];
+        assert_eq!(&smf[..18], &plain[..18]);
+        assert_eq!(&smf[22..29], &plain[22..29]);
+        assert_eq!(&smf[29..37], &time_signature);
+        assert_eq!(&smf[37..], &plain[29..]);
+    }
+
+    #[test]
+    fn lyrics() {
+        let inst = Instrument::AcousticGrandPiano;
+
+        let song = parse(r#"{0 C"ka" R D"e" } [2 $0 ] E"ru""#).unwrap();
+        assert!(song.has_lyrics());
+
+        let smf = song.to_smf(inst).unwrap();
+        let plain = convert("{0 C R D } [2 $0 ] E", inst).unwrap();
+
+        let lyric = |delta: u8, text: &str| {
+            let mut event = vec![delta, 0xFF, 0x05, text.len() as u8];
+            event.extend(text.as_bytes());
+            event
+        };
+        // note on (90 kk vv) / note off (running status)
+        let note = |delta: u8, note: u8| vec![delta, 0x90, note, 127, 16, note, 0];
+
+        let mut track: Vec<u8> = Vec::new();
+        for _ in 0..2 {
+            track.extend(lyric(0, "ka"));
+            track.extend(note(0, 60));
+            track.extend(lyric(16, "e"));
+            track.extend(note(0, 62));
+        }
+        track.extend(lyric(0, "ru"));
+        track.extend(note(0, 64));
+
+        // tempo と program change の後から end of track の前まで
+        assert_eq!(&smf[32..smf.len() - 4], &track[..]);
+        assert_eq!(&smf[smf.len() - 4..], &plain[plain.len() - 4..]);
+
+        let kar = song.to_karaoke(inst).unwrap();
+        assert!(kar.windows(19).any(|w| w == b"@KMIDI KARAOKE FILE"));
+        assert!(!kar.windows(2).any(|w| w == [0xFF, 0x05]));
+
+        let res = parse(r#"C"ka"#);
+        assert!(matches!(res, Err(MMLError::InvalidLyric(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn loop_markers() {
+        let inst = Instrument::AcousticGrandPiano;
+
+        let song = parse("{0 E F } C !loopstart D V50 $0 !loopend G").unwrap();
+
+        let smf = song.to_smf(inst).unwrap();
+        let marker = |text: &str| {
+            let mut event = vec![0xFF, 0x06, text.len() as u8];
+            event.extend(text.as_bytes());
+            event
+        };
+        let loop_start = [marker("loopStart"), vec![0x00, 0xB0, 0x6F, 0x00]].concat();
+        assert!(smf.windows(loop_start.len()).any(|w| w == loop_start));
+        let loop_end = marker("loopEnd");
+        assert!(smf.windows(loop_end.len()).any(|w| w == loop_end));
+
+        // 注釈を取り除けば元のトーンシーケンスと同じ
+        let plain = parse("{0 E F } C D V50 $0 G").unwrap();
+        assert_eq!(song.to_tone_sequence(), plain.tseq);
+        assert_eq!(plain.to_loop_tone_sequence(), None);
+
+        // ループ区間だけを展開したトーンシーケンス
+        let looped: Vec<i8> = song
+            .to_loop_tone_sequence()
+            .unwrap()
+            .into_iter()
+            .map(|b| b as i8)
+            .collect();
+        assert_eq!(
+            looped,
+            [-2, 1, -3, 30, -4, 64, -8, 100, 62, 16, -8, 50, 64, 16, 65, 16]
+        );
+
+        // 繰り返し再生しても前回の最後の音量が残らないように、ループ区間の先頭で音量を指定する
+        let song = parse("C !loopstart D V50 E !loopend").unwrap();
+        let looped = song.to_loop_tone_sequence().unwrap();
+        assert_eq!(
+            looped[6..],
+            [-8i8 as u8, 100, 62, 16, -8i8 as u8, 50, 64, 16]
+        );
+        let song = parse("V70 C !loopstart R V50 E !loopend").unwrap();
+        let looped = song.to_loop_tone_sequence().unwrap();
+        assert_eq!(looped[6..], [-8i8 as u8, 50, -1i8 as u8, 16, 64, 16]);
+
+        let res = parse("C !loopend D");
+        assert!(matches!(res, Err(MMLError::InvalidLoop(_))), "{:?}", res);
+
+        let res = parse("[2 !loopstart C ]");
+        assert!(matches!(res, Err(MMLError::InvalidLoop(_))), "{:?}", res);
+
+        let res = parse("C !loop D");
+        assert!(matches!(res, Err(MMLError::InvalidMarker(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn lint_warnings() {
+        for src in [
+            SUMM_SUMM_SUMM,
+            KUCKUCK_KUCKUCK_RUFTS_AUS_DEM_WALD,
+            MORGEN_KOMMT_DER_WEIHNACHTSMANN,
+        ] {
+            let res = lint(src, &Lint::default());
+            assert!(
+                matches!(&res, Ok(warnings) if warnings.is_empty()),
+                "{:?}",
+                res
+            );
+        }
+
+        let src = "{0 C } {1 D } $1 O4 C O4 D L8 E L8 F V0 G V100 [4 R] C < < < C N(10)";
+        let warnings = lint(src, &Lint::default()).unwrap();
+        let names: Vec<&str> = warnings.iter().map(MMLWarning::name).collect();
+        assert_eq!(
+            names,
+            [
+                "redundant-octave",
+                "redundant-duration",
+                "silent-note",
+                "repeated-rest",
+                "octave-jump",
+                "note-out-of-range",
+                "octave-jump",
+                "unused-block",
+            ]
+        );
+        assert_eq!(warnings[0].span().start.col, 23);
+
+        let mut config = Lint::none();
+        assert!(config.set("unused-block", true));
+        assert!(!config.set("unknown-check", true));
+        let warnings = lint(src, &config).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0], MMLWarning::UnusedBlock(_)));
+
+        // 音域は楽器ごと
+        config = Lint::none();
+        config.note_out_of_range = true;
+        config.instrument = Instrument::Seashore;
+        let res = lint("N(10)", &config);
+        assert!(
+            matches!(&res, Ok(warnings) if warnings.is_empty()),
+            "{:?}",
+            res
+        );
+
+        // MMLの#INSTRUMENTは既定の楽器より優先し、instrument_overrideはMMLの#INSTRUMENTより優先する
+        config.instrument = INSTRUMENTS[0];
+        let res = lint("#INSTRUMENT 33\nN(96)", &config).unwrap();
+        assert_eq!(res.len(), 1, "{:?}", res);
+        config.instrument_override = Some(INSTRUMENTS[0]);
+        let res = lint("#INSTRUMENT 33\nN(96)", &config).unwrap();
+        assert!(res.is_empty(), "{:?}", res);
+    }
 }
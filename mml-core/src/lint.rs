@@ -0,0 +1,199 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::{self, Span};
+use crate::inst::{Instrument, INSTRUMENTS};
+use crate::lang::Lang;
+
+// 文法上は正しいが誤りの可能性が高い記述の警告
+#[derive(Debug, Clone)]
+pub enum MMLWarning {
+    // 楽器の音域外の音値 N(..)
+    NoteOutOfRange(Span),
+    // 直前の音符から2オクターブを超える跳躍
+    OctaveJump(Span),
+    // 現在と同じ音長へのデフォルト音長指定 L
+    RedundantDuration(Span),
+    // 現在と同じオクターブへのオクターブ指定 O
+    RedundantOctave(Span),
+    // 休符1つだけのリピート
+    RepeatedRest(Span),
+    // 音量0 (V0) での音符
+    SilentNote(Span),
+    // 定義したが再生していないブロック
+    UnusedBlock(Span),
+}
+
+// 警告の確認項目の名前 (mml-cli lintの--enable/--disableで指定する)
+pub const LINT_NAMES: [&str; 7] = [
+    "note-out-of-range",
+    "octave-jump",
+    "redundant-duration",
+    "redundant-octave",
+    "repeated-rest",
+    "silent-note",
+    "unused-block",
+];
+
+impl MMLWarning {
+    pub fn span(&self) -> &Span {
+        use MMLWarning::*;
+        match self {
+            NoteOutOfRange(span)
+            | OctaveJump(span)
+            | RedundantDuration(span)
+            | RedundantOctave(span)
+            | RepeatedRest(span)
+            | SilentNote(span)
+            | UnusedBlock(span) => span,
+        }
+    }
+
+    // 確認項目の名前 (LINT_NAMESのいずれか)
+    pub fn name(&self) -> &'static str {
+        use MMLWarning::*;
+        match self {
+            NoteOutOfRange(_) => "note-out-of-range",
+            OctaveJump(_) => "octave-jump",
+            RedundantDuration(_) => "redundant-duration",
+            RedundantOctave(_) => "redundant-octave",
+            RepeatedRest(_) => "repeated-rest",
+            SilentNote(_) => "silent-note",
+            UnusedBlock(_) => "unused-block",
+        }
+    }
+
+    pub fn message_in(&self, lang: Lang) -> &'static str {
+        match lang {
+            Lang::En => self.message_en(),
+            Lang::Ja => self.message(),
+        }
+    }
+
+    pub fn message_en(&self) -> &'static str {
+        use MMLWarning::*;
+        match self {
+            NoteOutOfRange(_) => "note value is outside the instrument's range",
+            OctaveJump(_) => "jump of more than two octaves from the previous note (wrong octave?)",
+            RedundantDuration(_) => "length is the same as the current one",
+            RedundantOctave(_) => "octave is the same as the current one",
+            RepeatedRest(_) => "repeat of a single rest (write a longer rest instead)",
+            SilentNote(_) => "note at volume 0 (V0) is not audible",
+            UnusedBlock(_) => "block is never played",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        use MMLWarning::*;
+        match self {
+            NoteOutOfRange(_) => "音値が楽器の音域外です",
+            OctaveJump(_) => {
+                "直前の音符から2オクターブを超えて跳躍しています (オクターブ指定の誤り?)"
+            }
+            RedundantDuration(_) => "現在と同じ音長を指定しています",
+            RedundantOctave(_) => "現在と同じオクターブを指定しています",
+            RepeatedRest(_) => "休符1つだけをリピートしています (長い休符で書けます)",
+            SilentNote(_) => "音量0 (V0) の音符は聞こえません",
+            UnusedBlock(_) => "ブロックが再生されていません",
+        }
+    }
+
+    pub fn render(&self) -> String {
+        self.render_in(Lang::Ja)
+    }
+
+    pub fn render_in(&self, lang: Lang) -> String {
+        let message = format!("{} [{}]", self.message_in(lang), self.name());
+        let label = match lang {
+            Lang::En => "MML warning",
+            Lang::Ja => "MML警告",
+        };
+        error::render(label, &message, self.span())
+    }
+}
+
+impl std::fmt::Display for MMLWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let location = error::location(&self.span().start);
+        write!(f, "{}: {} [{}]", location, self.message(), self.name())
+    }
+}
+
+// 警告の確認項目の設定 (既定では全て有効)
+#[derive(Debug, Clone)]
+pub struct Lint {
+    pub note_out_of_range: bool,
+    pub octave_jump: bool,
+    pub redundant_duration: bool,
+    pub redundant_octave: bool,
+    pub repeated_rest: bool,
+    pub silent_note: bool,
+    pub unused_block: bool,
+
+    // note_out_of_rangeで音域を確認する楽器 (MMLに#INSTRUMENTが無い場合)
+    pub instrument: Instrument,
+    // MMLの#INSTRUMENTより優先して音域を確認する楽器 (mml-cliの--instrument)
+    pub instrument_override: Option<Instrument>,
+}
+
+impl Default for Lint {
+    fn default() -> Self {
+        Self {
+            note_out_of_range: true,
+            octave_jump: true,
+            redundant_duration: true,
+            redundant_octave: true,
+            repeated_rest: true,
+            silent_note: true,
+            unused_block: true,
+            instrument: INSTRUMENTS[0],
+            instrument_override: None,
+        }
+    }
+}
+
+impl Lint {
+    // 全ての確認項目を無効にした設定
+    pub fn none() -> Self {
+        Self {
+            note_out_of_range: false,
+            octave_jump: false,
+            redundant_duration: false,
+            redundant_octave: false,
+            repeated_rest: false,
+            silent_note: false,
+            unused_block: false,
+            instrument: INSTRUMENTS[0],
+            instrument_override: None,
+        }
+    }
+
+    // 名前 (LINT_NAMESのいずれか) で確認項目を有効・無効にする。不明な名前の場合はfalseを返す
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let flag = match name {
+            "note-out-of-range" => &mut self.note_out_of_range,
+            "octave-jump" => &mut self.octave_jump,
+            "redundant-duration" => &mut self.redundant_duration,
+            "redundant-octave" => &mut self.redundant_octave,
+            "repeated-rest" => &mut self.repeated_rest,
+            "silent-note" => &mut self.silent_note,
+            "unused-block" => &mut self.unused_block,
+            _ => return false,
+        };
+        *flag = enabled;
+        true
+    }
+
+    pub(crate) fn is_enabled(&self, warning: &MMLWarning) -> bool {
+        use MMLWarning::*;
+        match warning {
+            NoteOutOfRange(_) => self.note_out_of_range,
+            OctaveJump(_) => self.octave_jump,
+            RedundantDuration(_) => self.redundant_duration,
+            RedundantOctave(_) => self.redundant_octave,
+            RepeatedRest(_) => self.repeated_rest,
+            SilentNote(_) => self.silent_note,
+            UnusedBlock(_) => self.unused_block,
+        }
+    }
+}
@@ -1,6 +1,8 @@
 // mml-core
 // author: Leonardone @ NEETSDKASU
 
+use crate::song::{Annotation, Song};
+use crate::timeline;
 use crate::tone_control;
 use java_data_io::JavaDataOutput;
 use std::io;
@@ -8,9 +10,10 @@ use std::io;
 // トーンシーケンスイベント列をMIDIフォーマットのバイト列に変換する。
 // トーンシーケンスイベント列に不正は無いことを前提としている。
 // トーンシーケンスイベント列はMML-on-OAPのMMLから変換されていることを前提としている。
-pub(crate) fn translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, io::Error> {
+// karaokeがtrueの場合はカラオケ (.kar) 形式にする。
+pub(crate) fn translate(song: &Song, inst: i8, karaoke: bool) -> Result<Vec<u8>, io::Error> {
     use java_data_io::Error::*;
-    match do_translate(tseq, inst) {
+    match do_translate(song, inst, karaoke) {
         Ok(res) => Ok(res),
         Err(IoError(error)) => Err(error),
         Err(UtfDataFormatError) => unreachable!("UtfDataFormatError"),
@@ -35,7 +38,10 @@ impl GetInt for &[u8] {
     }
 }
 
-fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
+fn do_translate(song: &Song, inst: i8, karaoke: bool) -> Result<Vec<u8>, java_data_io::Error> {
+    let tseq: &[u8] = &song.tseq;
+    let metadata = &song.metadata;
+
     let mut tempo: i32 = 120;
     let mut resolution: i32 = 64;
 
@@ -66,11 +72,7 @@ fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
                 pos += 2;
                 break;
             }
-            if cmd == tone_control::REPEAT {
-                pos += 4;
-            } else {
-                pos += 2;
-            }
+            pos += timeline::event_size(cmd);
         }
     }
 
@@ -98,6 +100,32 @@ fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
 
     const TRACK_START_POS: usize = 22;
 
+    // copyright notice (FF 02 len text)
+    if let Some(copyright) = &metadata.copyright {
+        write_text_event(&mut dst, 0, 0x02, copyright)?;
+    }
+
+    // sequence/track name (FF 03 len text)
+    if let Some(title) = &metadata.title {
+        write_text_event(&mut dst, 0, 0x03, title)?;
+    }
+
+    // text event (FF 01 len text)
+    if let Some(composer) = &metadata.composer {
+        write_text_event(&mut dst, 0, 0x01, composer)?;
+    }
+    for comment in metadata.comments.iter() {
+        write_text_event(&mut dst, 0, 0x01, comment)?;
+    }
+
+    // karaoke header (text event)
+    if karaoke {
+        write_text_event(&mut dst, 0, 0x01, "@KMIDI KARAOKE FILE")?;
+        if let Some(title) = &metadata.title {
+            write_text_event(&mut dst, 0, 0x01, &format!("@T{}", title))?;
+        }
+    }
+
     // set tempo (FF 51 03 tttttt)
     dst.write_byte(0)?; // delta time (= 0)
     dst.write(&[0xFF, 0x51, 0x03])?;
@@ -105,6 +133,16 @@ fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
     let usec_tempo: i32 = 60_000_000 / tempo;
     dst.write(&usec_tempo.to_be_bytes()[1..])?; // tttttt
 
+    // time signature (FF 58 04 nn dd cc bb)
+    if let Some(time_signature) = song.time_signature {
+        dst.write_byte(0)?; // delta time (= 0)
+        dst.write(&[0xFF, 0x58, 0x04])?;
+        dst.write_byte(time_signature.numerator.into())?; // nn
+        dst.write_byte(time_signature.denominator.trailing_zeros() as i32)?; // dd (2^dd)
+        dst.write_byte(24)?; // cc (MIDI clocks per metronome click)
+        dst.write_byte(8)?; // bb (32nd notes per quarter note)
+    }
+
     // program change (Cn xx) (n = channel, xx = inst id)
     dst.write_byte(0)?; // delta time (= 0)
     dst.write_byte(0xC0)?; // Cn
@@ -135,6 +173,37 @@ fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
                 delta_time += tseq.get_i32(pos + 1).unwrap() & 0x7F;
                 pos += 2;
             }
+            tone_control::ANNOTATION => {
+                let index: usize = timeline::annotation_index(tseq, pos);
+                match &song.annotations[index] {
+                    // 小節線はSMFには何も書き込まない
+                    Annotation::BarLine(_) | Annotation::Source(_) | Annotation::BlockCall(_) => {}
+                    Annotation::Lyric(lyric) => {
+                        // lyric (FF 05 len text) (karaoke: text event (FF 01 len text))
+                        let meta_type: u8 = if karaoke { 0x01 } else { 0x05 };
+                        write_text_event(&mut dst, delta_time, meta_type, lyric)?;
+                        delta_time = 0;
+                        // メタイベントの後はランニングステータスを使わない
+                        last_note_on = false;
+                    }
+                    Annotation::LoopStart(_) => {
+                        // marker (FF 06 len text)
+                        write_text_event(&mut dst, delta_time, 0x06, "loopStart")?;
+                        // control change 111 (Bn 6F 00) (RPG Maker のループ開始位置)
+                        dst.write_byte(0)?; // delta time
+                        dst.write(&[0xB0, 0x6F, 0x00])?;
+                        delta_time = 0;
+                        last_note_on = false;
+                    }
+                    Annotation::LoopEnd(_) => {
+                        // marker (FF 06 len text)
+                        write_text_event(&mut dst, delta_time, 0x06, "loopEnd")?;
+                        delta_time = 0;
+                        last_note_on = false;
+                    }
+                }
+                pos += 4;
+            }
             tone_control::REPEAT => {
                 let multiplier: i32 = tseq.get_i32(pos + 1).unwrap() & 0xFF;
                 let cmd: i8 = tseq.get_i8(pos + 2).unwrap();
@@ -212,6 +281,21 @@ fn do_translate(tseq: &[u8], inst: i8) -> Result<Vec<u8>, java_data_io::Error> {
     Ok(buf)
 }
 
+// テキスト系のメタイベント (FF tt len text) を書き込む
+fn write_text_event<W: io::Write>(
+    dst: &mut JavaDataOutput<W>,
+    delta_time: i32,
+    meta_type: u8,
+    text: &str,
+) -> Result<(), java_data_io::Error> {
+    write_delta_time(dst, delta_time)?;
+    dst.write(&[0xFF, meta_type])?;
+    // len is variable-length quantity (same as delta time)
+    write_delta_time(dst, text.len() as i32)?;
+    dst.write(text.as_bytes())?;
+    Ok(())
+}
+
 fn write_delta_time<W: io::Write>(
     dst: &mut JavaDataOutput<W>,
     mut delta_time: i32,
@@ -1,82 +1,155 @@
 // mml-core
 // author: Leonardone @ NEETSDKASU
 
+use crate::analysis::Block;
+use crate::encoding::{self, TextEncoding};
+use crate::error::{MMLError, Position, Span};
+use crate::input::{InputMode, ParseOptions};
+use crate::inst::{Instrument, INSTRUMENTS};
+use crate::lint::{Lint, MMLWarning};
+use crate::song::{Annotation, Metadata, Song, TimeSignature};
+use crate::timeline::{self, Event};
 use crate::tone_control;
 use java_data_io::JavaDataOutput;
 use std::io;
+use std::path::{Path, PathBuf};
 use MMLError::*;
 
-#[derive(Debug, Clone)]
-pub struct Position {
-    pub character: Option<char>,
-    pub col: usize,
-    pub row: usize,
-}
+type Result<T> = std::result::Result<T, MMLError>;
 
-#[derive(Debug)]
-pub enum MMLError {
-    EmptySequence(Position),
-    InvalidBlock(Position),
-    InvalidBlockEnd(Position),
-    InvalidBlockId(Position),
-    InvalidChangeOctave(Position),
-    InvalidCharacter(Position),
-    InvalidDecreaseOctave(Position),
-    InvalidDefaultDurationValue(Position),
-    InvalidDuration(Position),
-    InvalidDurationEnd(Position),
-    InvalidIncreaseOctave(Position),
-    InvalidLength(Position),
-    InvalidNote(Position),
-    InvalidNoteValue(Position),
-    InvalidNoteValueEnd(Position),
-    InvalidNoteValueStart(Position),
-    InvalidOctaveValue(Position),
-    InvalidPlayBlockId(Position),
-    InvalidRepeat(Position),
-    InvalidRepeatEnd(Position),
-    InvalidRepeatNumber(Position),
-    InvalidResolution(Position),
-    InvalidTempo(Position),
-    InvalidVolume(Position),
-    IoError(io::Error),
-    UnexpectedRemains(Position),
-}
+// #includeと@マクロ展開の入れ子の上限
+const MAX_NESTING: usize = 32;
 
-type Result<T> = std::result::Result<T, MMLError>;
+// @マクロ展開の回数の上限 (循環は展開の連鎖で検出するので、念のための上限)
+const MAX_EXPANSIONS: usize = 100_000;
+
+// #includeで取り込む回数の上限 (循環は取り込みの連鎖で検出するので、念のための上限)
+const MAX_INCLUDES: usize = 1_000;
+
+// T (テンポ) と% (分解能) を省略した場合の値 (ParseOptionsで変えられる)
+const DEFAULT_TEMPO: i32 = 120;
+const DEFAULT_RESOLUTION: i32 = 64;
 
 // MMLで記述されたコマンドをトーンシーケンスイベント列に変換する
-pub(crate) fn parse(src: &str) -> Result<Vec<u8>> {
-    let mut mml = Mml::new(src);
+// fileはsrcを読み込んだファイルのパス (#includeの相対パスの基準になる)
+// エラーがある場合は見つけたエラーを全て返す
+pub(crate) fn parse(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> std::result::Result<Song, Vec<MMLError>> {
+    parse_with_lint(src, file, options, Lint::none()).map(|(song, _)| song)
+}
 
-    mml.parse_tempo()?;
+// lintで有効にした確認項目の警告も返す
+pub(crate) fn parse_with_lint(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+    lint: Lint,
+) -> std::result::Result<(Song, Vec<MMLWarning>), Vec<MMLError>> {
+    let mut mml = Mml::new(src, file, options, lint);
+    let song = mml.parse_all()?;
+    Ok((song, mml.warnings))
+}
 
-    mml.parse_resolution()?;
+// #includeで指定されたファイルのパスを返す (エラーがあっても見つけた分は返す)
+pub(crate) fn included_files(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> Vec<PathBuf> {
+    let mut mml = Mml::new(src, file, options, Lint::none());
+    let _ = mml.parse_all();
+    mml.includes
+}
 
-    let mut buf: Vec<u8> = Vec::new();
-    let mut dst = JavaDataOutput::new(&mut buf);
+// 音出し・無音の記述範囲を注釈として記録し、ブロックの定義と再生の記述範囲も返す (エディタとの連携用)
+pub(crate) fn parse_with_sources(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> std::result::Result<(Song, Vec<Block>), Vec<MMLError>> {
+    match parse_partial_with_sources(src, file, options) {
+        (Some(parsed), errors) if errors.is_empty() => Ok(parsed),
+        (_, errors) => Err(errors),
+    }
+}
 
-    dst.write_byte(tone_control::VERSION.into())?;
-    dst.write_byte(1)?;
+// エラーがあっても読み込めた分の曲を見つけたエラーとともに返す (エディタで編集中のMML用)。
+// エラーから復帰できなかった場合はNone
+pub(crate) fn parse_partial_with_sources(
+    src: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> (Option<(Song, Vec<Block>)>, Vec<MMLError>) {
+    let mut mml = Mml::new(src, file, options, Lint::none());
+    mml.record_sources = true;
+    let song = match mml.parse_partial() {
+        Some(song) => song,
+        None => return (None, mml.errors),
+    };
+    let blocks: Vec<Block> = mml
+        .block_spans
+        .into_iter()
+        .zip(mml.block_names)
+        .zip(mml.block_calls)
+        .enumerate()
+        .map(|(id, ((span, name), calls))| Block {
+            id: id as i32,
+            name,
+            span,
+            calls,
+            plays: 0,
+            events: Vec::new(),
+        })
+        .collect();
+    (Some((song, blocks)), mml.errors)
+}
 
-    dst.write_byte(tone_control::TEMPO.into())?;
-    dst.write_byte(mml.tempo >> 2)?;
+// #includeで取り込んだファイルや@で展開したマクロの読み込み状態
+#[derive(Debug)]
+struct Source {
+    chars: Vec<char>,
+    index: usize,
 
-    dst.write_byte(tone_control::RESOLUTION.into())?;
-    dst.write_byte(mml.resolution)?;
+    // 読み終えたら戻る呼び出し元の読み込み位置
+    ret: Position,
 
-    while mml.parse_block(&mut dst)? {}
+    // このテキストに至る展開と取り込みの連鎖 (自身を含む。循環の検出用)。
+    // 値やファイルの末尾の@名前や#includeは呼び出し元を読み終えてから展開するので、
+    // 読み込み状態の入れ子ではなく呼び出した位置の連鎖を引き継いで記録する
+    chain: Vec<Expansion>,
 
-    mml.set_default();
+    // #includeの相対パスの基準ディレクトリ
+    dir: Option<PathBuf>,
+}
 
-    let event: i32 = mml.parse_sequence(&mut dst)?;
-    if event == 0 {
-        return mml.error(EmptySequence);
-    }
+// 展開中のマクロ名か取り込み中のファイルの正規化したパス
+#[derive(Debug, Clone, PartialEq)]
+enum Expansion {
+    Macro(String),
+    File(PathBuf),
+}
 
-    mml.validate_remains()?;
+// parse_commandで読み込んだコマンドの種類
+enum Command {
+    // 内部データの更新、小節線、マーカー (トーンシーケンスイベントを出力しないかANNOTATIONだけ)
+    Setting,
+    // 音出し・無音
+    Sound,
+    // ブロック再生、リピート、ボリューム変更
+    Other,
+    // ブロック/リピートの終わり (] か })
+    End,
+}
 
-    Ok(buf)
+#[derive(Debug)]
+struct Macro {
+    name: String,
+    value: Vec<char>,
+    // 値の記述位置 (展開した値の中のエラーは定義の位置を指す)
+    pos: Position,
 }
 
 #[derive(Debug)]
@@ -84,6 +157,63 @@ struct Mml<'a> {
     src: std::str::Chars<'a>,
     cur: Position,
 
+    // 読み込み中のコマンドの先頭位置 (エラーの範囲の始まり)
+    token_start: Position,
+
+    // 入力モード (curの文字は元のテキストの文字のままで、get_charで置き換えた文字を返す)
+    mode: InputMode,
+
+    // #includeで取り込むファイルの文字コード
+    encoding: TextEncoding,
+
+    // 読み込んだ文字数とコマンドの先頭までに読み込んだ文字数 (エラーからの復帰で読み進めたかを確認する)
+    consumed: usize,
+    token_consumed: usize,
+
+    // 見つけたエラー (エラーの後も読み込みを続けて全てのエラーを報告する)
+    errors: Vec<MMLError>,
+
+    // 警告の確認項目の設定と見つけた警告
+    lint: Lint,
+    warnings: Vec<MMLWarning>,
+
+    // 現在のシーケンスでOやLを明示的に指定したか (冗長な指定の警告用)
+    explicit_octave: bool,
+    explicit_duration: bool,
+
+    // 直前の音符 (跳躍の警告用) と音量0が指定されているか (V0の音符の警告用)
+    last_note: Option<i32>,
+    silent: bool,
+
+    // Vで指定した音量 (引数付きブロック再生の後に戻すため。ブロックの中でVより前は呼び出し元の音量なのでNone)
+    volume: Option<i32>,
+
+    // 元のソースと#includeで取り込んだファイルのテキスト (エラー箇所の行を表示するため)
+    text: &'a str,
+    files: Vec<(String, String)>,
+
+    // 元のソースの#includeの相対パスの基準ディレクトリ
+    dir: Option<PathBuf>,
+
+    // #includeで指定されたファイルのパス (読み込めなかったファイルも含む。変更の監視用)
+    includes: Vec<PathBuf>,
+
+    sources: Vec<Source>,
+    macros: Vec<Macro>,
+    expansions: usize,
+
+    // 元のソースのファイルの正規化したパスと#includeで取り込んだ回数 (循環する#includeの検出用)
+    file: Option<PathBuf>,
+    include_count: usize,
+
+    metadata: Metadata,
+
+    // 拍子と#TIMEの記述範囲
+    time_signature: Option<(TimeSignature, Span)>,
+
+    // トーンシーケンスに載せられない情報 (ANNOTATIONイベントから番号で参照する)
+    annotations: Vec<Annotation>,
+
     next_block_id: i32,
     tempo: i32,
     resolution: i32,
@@ -93,37 +223,263 @@ struct Mml<'a> {
 
     // default duration
     duration: i32,
+
+    // ブロック名 (番号で定義したブロックはNone)
+    block_names: Vec<Option<String>>,
+
+    // ブロックの中身のトーンシーケンスイベント列 (引数付きブロック再生で展開するため)
+    block_bodies: Vec<Vec<u8>>,
+
+    // ブロックの定義位置と再生されたか (未使用のブロックの警告用)
+    block_spans: Vec<Span>,
+    block_used: Vec<bool>,
+
+    // ブロック再生の記述位置 (ブロックの番号ごと)
+    block_calls: Vec<Vec<Span>>,
+
+    // #INSTRUMENTで指定した楽器
+    instrument: Option<Instrument>,
+
+    // 音出し・無音の記述範囲を注釈として書き込むか (エディタとの連携用)
+    record_sources: bool,
 }
 
 impl<'a> Mml<'a> {
-    fn new(src: &'a str) -> Self {
+    fn parse_all(&mut self) -> std::result::Result<Song, Vec<MMLError>> {
+        match self.parse_partial() {
+            Some(song) if self.errors.is_empty() => Ok(song),
+            _ => Err(std::mem::take(&mut self.errors)),
+        }
+    }
+
+    // エラーはself.errorsに記録し、読み込めた分の曲を返す (エラーから復帰できなかった場合はNone)
+    fn parse_partial(&mut self) -> Option<Song> {
+        let tseq = match self.parse_song() {
+            Ok(tseq) => tseq,
+            Err(error) => {
+                self.errors.push(error);
+                return None;
+            }
+        };
+
+        Some(Song {
+            tseq,
+            metadata: std::mem::take(&mut self.metadata),
+            time_signature: self
+                .time_signature
+                .as_ref()
+                .map(|(time_signature, _)| *time_signature),
+            instrument: self.instrument,
+            annotations: std::mem::take(&mut self.annotations),
+        })
+    }
+
+    // エラーから復帰できない場合 (入出力エラーなど) だけErrを返し、
+    // それ以外のエラーはself.errorsに記録して読み込みを続ける
+    fn parse_song(&mut self) -> Result<Vec<u8>> {
+        self.parse_tempo()?;
+
+        self.parse_resolution()?;
+
+        let mut buf: Vec<u8> = Vec::new();
+        let mut dst = JavaDataOutput::new(&mut buf);
+
+        dst.write_byte(tone_control::VERSION.into())?;
+        dst.write_byte(1)?;
+
+        dst.write_byte(tone_control::TEMPO.into())?;
+        dst.write_byte(self.tempo >> 2)?;
+
+        dst.write_byte(tone_control::RESOLUTION.into())?;
+        dst.write_byte(self.resolution)?;
+
+        while self.parse_block(&mut dst)? {}
+
+        self.set_default();
+        // トーンシーケンスの音量の初期値は100
+        self.volume = Some(100);
+
+        let mut event: i32 = self.parse_sequence(&mut dst)?;
+
+        // 対応する [ や { の無い ] や } はエラーを記録して読み飛ばし、続きを読み込む
+        while self.has_char() {
+            self.mark();
+            self.report(UnexpectedRemains);
+            self.next_char();
+            event |= self.parse_sequence(&mut dst)?;
+        }
+
+        // 構文エラーがある場合は音符が欠けているので長さやループ位置の確認はしない
+        if !self.errors.is_empty() {
+            return Ok(buf);
+        }
+
+        if event == 0 {
+            self.report(EmptySequence);
+            return Ok(buf);
+        }
+
+        // 小節とループ位置のエラーでは音符は欠けていないので、読み込めた曲として返す
+        if let Err(error) = self
+            .validate_bars(&buf)
+            .and_then(|_| self.validate_loop(&buf))
+        {
+            self.errors.push(error);
+            return Ok(buf);
+        }
+
+        for id in 0..self.block_used.len() {
+            if !self.block_used[id] {
+                self.warn(MMLWarning::UnusedBlock(self.block_spans[id].clone()));
+            }
+        }
+
+        Ok(buf)
+    }
+
+    fn new(src: &'a str, file: Option<&Path>, options: &ParseOptions, lint: Lint) -> Self {
         let mut chars = src.chars();
-        let cur = chars.next();
+        let cur = Position {
+            character: chars.next(),
+            col: 1,
+            row: 1,
+            file: file.map(|path| path.display().to_string()),
+        };
         Self {
             src: chars,
-            cur: Position {
-                character: cur,
-                col: 1,
-                row: 1,
-            },
+            token_start: cur.clone(),
+            cur,
+            mode: options.input_mode,
+            encoding: options.encoding,
+            consumed: 0,
+            token_consumed: 0,
+            errors: Vec::new(),
+            lint,
+            warnings: Vec::new(),
+            explicit_octave: false,
+            explicit_duration: false,
+            last_note: None,
+            silent: false,
+            volume: None,
+            text: src,
+            files: Vec::new(),
+            dir: file.and_then(Path::parent).map(Path::to_path_buf),
+            includes: Vec::new(),
+            sources: Vec::new(),
+            macros: Vec::new(),
+            expansions: 0,
+            file: file.and_then(|path| std::fs::canonicalize(path).ok()),
+            include_count: 0,
+            metadata: Metadata::default(),
+            time_signature: None,
+            annotations: Vec::new(),
             next_block_id: 0,
-            tempo: 120,
-            resolution: 64,
+            tempo: options.tempo.unwrap_or(DEFAULT_TEMPO),
+            resolution: options.resolution.unwrap_or(DEFAULT_RESOLUTION),
             octave: tone_control::C4.into(),
             duration: 16,
+            block_names: Vec::new(),
+            block_bodies: Vec::new(),
+            block_spans: Vec::new(),
+            block_used: Vec::new(),
+            block_calls: Vec::new(),
+            instrument: None,
+            record_sources: false,
         }
     }
 
-    fn error<T>(&self, f: fn(p: Position) -> MMLError) -> Result<T> {
-        Err(f(self.cur.clone()))
+    fn error<T>(&self, f: fn(s: Span) -> MMLError) -> Result<T> {
+        Err(f(self.span()))
     }
 
-    fn validate_remains(&mut self) -> Result<()> {
-        self.skip_whitespaces();
-        if self.has_char() {
-            self.error(UnexpectedRemains)
+    // エラーを記録して読み込みを続ける
+    fn report(&mut self, f: fn(s: Span) -> MMLError) {
+        let error = f(self.span());
+        self.errors.push(error);
+    }
+
+    // エラーを記録し、次の音符 (CDEFGABR) か [ ] { } $ | か改行まで読み飛ばす。
+    // コマンドの先頭から読み進めていない場合は少なくとも1文字読み飛ばす。
+    fn recover(&mut self, error: MMLError) -> Result<()> {
+        if let IoError(_) = error {
+            return Err(error);
+        }
+        self.errors.push(error);
+        if self.consumed == self.token_consumed {
+            self.next_char();
+        }
+        while let Some(ch) = self.get_char() {
+            if matches!(
+                ch.to_ascii_uppercase(),
+                'A'..='G' | 'R' | '[' | ']' | '{' | '}' | '$' | '|' | '\n'
+            ) {
+                break;
+            }
+            self.next_char();
+        }
+        Ok(())
+    }
+
+    // 有効な確認項目の警告を記録する
+    fn warn(&mut self, warning: MMLWarning) {
+        if self.lint.is_enabled(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    // 読み込み中のコマンドの先頭を記録する
+    fn mark(&mut self) {
+        self.token_start = self.cur.clone();
+        self.token_consumed = self.consumed;
+    }
+
+    // 記録したコマンドの先頭から現在位置の手前までの範囲。
+    // 先頭が別の行 (や別のファイル、マクロの値) にある場合は現在位置の1文字を範囲とする。
+    fn span(&self) -> Span {
+        self.span_from(&self.token_start)
+    }
+
+    fn span_from(&self, start: &Position) -> Span {
+        let start = if start.file == self.cur.file
+            && start.row == self.cur.row
+            && start.col <= self.cur.col
+        {
+            start.clone()
         } else {
-            Ok(())
+            self.cur.clone()
+        };
+        Span {
+            line: self.source_line(&start),
+            start,
+            end: self.cur.clone(),
+        }
+    }
+
+    fn source_line(&self, pos: &Position) -> Option<String> {
+        let text: &str = if pos.file == self.file_name() {
+            self.text
+        } else {
+            let file = pos.file.as_ref()?;
+            &self.files.iter().find(|(name, _)| name == file)?.1
+        };
+        text.lines()
+            .nth(pos.row.checked_sub(1)?)
+            .map(str::to_string)
+    }
+
+    // 元のソースのファイル名
+    fn file_name(&self) -> Option<String> {
+        match self.sources.first() {
+            Some(source) => source.ret.file.clone(),
+            None => self.cur.file.clone(),
+        }
+    }
+
+    // 現在の読み込み位置に至る展開と取り込みの連鎖
+    fn chain(&self) -> &[Expansion] {
+        match self.sources.last() {
+            Some(source) => &source.chain,
+            None => &[],
         }
     }
 
@@ -131,29 +487,518 @@ impl<'a> Mml<'a> {
         self.cur.character.is_some()
     }
 
+    // 入力モードに合わせて置き換えた現在の文字
     fn get_char(&self) -> Option<char> {
+        self.cur.character.map(|ch| self.mode.normalize(ch))
+    }
+
+    // 元のテキストの現在の文字 (歌詞や曲の情報のテキストを読み込む場合に使う)
+    fn get_raw_char(&self) -> Option<char> {
         self.cur.character
     }
 
     fn next_char(&mut self) -> Option<char> {
-        let character = self.src.next();
-        self.cur.col += 1;
-        if matches!(character, Some('\n')) {
+        // ファ・ふぁ は2文字で1つの音名
+        let fa =
+            self.mode == InputMode::Japanese && matches!(self.cur.character, Some('フ' | 'ふ'));
+        self.next_raw_char();
+        if fa && matches!(self.cur.character, Some('ァ' | 'ぁ')) {
+            self.next_raw_char();
+        }
+        self.get_char()
+    }
+
+    fn next_raw_char(&mut self) -> Option<char> {
+        self.consumed += 1;
+        let character = match self.sources.last_mut() {
+            Some(source) => {
+                let character = source.chars.get(source.index).copied();
+                source.index += 1;
+                character
+            }
+            None => self.src.next(),
+        };
+        if character.is_none() {
+            if let Some(source) = self.sources.pop() {
+                // 取り込み元の続きから読み込む
+                self.cur = source.ret;
+                return self.cur.character;
+            }
+        }
+        // 改行文字はその行の末尾とし、次の文字から次の行とする
+        if matches!(self.cur.character, Some('\n')) {
             self.cur.col = 1;
             self.cur.row += 1;
+        } else {
+            self.cur.col += 1;
         }
         self.cur.character = character;
         character
     }
 
+    // 空白の他にコメントとプリプロセッサ指令 (#define, #include) とマクロ展開 (@名前) も読み飛ばす。
+    // これらのエラーは記録して読み込みを続ける (プリプロセッサ指令のエラーは行末まで読み飛ばす)
     fn skip_whitespaces(&mut self) {
         while let Some(ch) = self.get_char() {
-            if ch.is_whitespace() {
-                self.next_char();
-            } else {
+            match ch {
+                ';' => self.skip_line_comment(),
+                '/' => {
+                    self.mark();
+                    match self.next_char() {
+                        Some('/') => self.skip_line_comment(),
+                        Some('*') => self.skip_block_comment(),
+                        _ => self.report(InvalidComment),
+                    }
+                }
+                '#' => {
+                    self.mark();
+                    if let Err(error) = self.parse_directive() {
+                        self.errors.push(error);
+                        self.skip_line_comment();
+                    }
+                }
+                '@' => {
+                    self.mark();
+                    if let Err(error) = self.expand_macro() {
+                        self.errors.push(error);
+                    }
+                }
+                ch if ch.is_whitespace() => {
+                    self.next_char();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // 行末まで読み飛ばす (改行文字は読み飛ばさない)
+    fn skip_line_comment(&mut self) {
+        let depth: usize = self.sources.len();
+        while let Some(ch) = self.get_char() {
+            if ch == '\n' || self.sources.len() < depth {
+                break;
+            }
+            self.next_char();
+        }
+    }
+
+    // /* から */ まで読み飛ばす (入れ子には対応しない)
+    fn skip_block_comment(&mut self) {
+        let depth: usize = self.sources.len();
+        let mut last: Option<char> = None;
+        loop {
+            let ch = self.next_char();
+            if self.sources.len() < depth {
+                // 取り込んだファイルやマクロの中でコメントが閉じていない
+                self.report(InvalidComment);
+                return;
+            }
+            match (last, ch) {
+                (_, None) => {
+                    self.report(InvalidComment);
+                    return;
+                }
+                (Some('*'), Some('/')) => break,
+                _ => last = ch,
+            }
+        }
+        self.next_char();
+    }
+
+    // 改行以外の空白を読み飛ばす (プリプロセッサ指令は1行で記述する)
+    fn skip_spaces(&mut self) {
+        while let Some(ch) = self.get_char() {
+            if ch == '\n' || !ch.is_whitespace() {
                 break;
             }
+            self.next_char();
+        }
+    }
+
+    fn parse_directive(&mut self) -> Result<()> {
+        if self.next_char().filter(|ch| is_name_start(*ch)).is_none() {
+            return self.error(InvalidDirective);
         }
+
+        let directive = self.parse_name().to_ascii_lowercase();
+
+        match directive.as_str() {
+            "define" => self.parse_define(),
+            "include" => self.parse_include(),
+            "title" => {
+                let title = self.parse_metadata_text()?;
+                if self.metadata.title.replace(title).is_some() {
+                    return self.error(InvalidMetadata);
+                }
+                Ok(())
+            }
+            "composer" => {
+                let composer = self.parse_metadata_text()?;
+                if self.metadata.composer.replace(composer).is_some() {
+                    return self.error(InvalidMetadata);
+                }
+                Ok(())
+            }
+            "copyright" => {
+                let copyright = self.parse_metadata_text()?;
+                if self.metadata.copyright.replace(copyright).is_some() {
+                    return self.error(InvalidMetadata);
+                }
+                Ok(())
+            }
+            "comment" => {
+                let comment = self.parse_metadata_text()?;
+                self.metadata.comments.push(comment);
+                Ok(())
+            }
+            "time" => self.parse_time_signature(),
+            "instrument" => self.parse_instrument(),
+            _ => self.error(InvalidDirective),
+        }
+    }
+
+    // #INSTRUMENT 楽器番号 (1～128、mml-cliの--instrumentの指定が無い場合に使う)
+    fn parse_instrument(&mut self) -> Result<()> {
+        self.skip_spaces();
+
+        if self.instrument.is_some() || self.get_char().filter(char::is_ascii_digit).is_none() {
+            return self.error(InvalidMetadata);
+        }
+
+        let num: i32 = self.parse_number();
+
+        if !(1..=INSTRUMENTS.len() as i32).contains(&num) {
+            return self.error(InvalidMetadata);
+        }
+
+        self.instrument = Some(INSTRUMENTS[num as usize - 1]);
+
+        Ok(())
+    }
+
+    // #TIME 拍子 (分子 1～99、分母 1,2,4,8,16,32,64)
+    fn parse_time_signature(&mut self) -> Result<()> {
+        self.skip_spaces();
+
+        if self.time_signature.is_some() || self.get_char().filter(char::is_ascii_digit).is_none() {
+            return self.error(InvalidTimeSignature);
+        }
+
+        let numerator: i32 = self.parse_number();
+
+        if !(1..=99).contains(&numerator) {
+            return self.error(InvalidTimeSignature);
+        }
+
+        if self.get_char() != Some('/') || self.next_char().filter(char::is_ascii_digit).is_none() {
+            return self.error(InvalidTimeSignature);
+        }
+
+        let denominator: i32 = self.parse_number();
+
+        if !(1..=64).contains(&denominator) || denominator.count_ones() != 1 {
+            return self.error(InvalidTimeSignature);
+        }
+
+        let time_signature = TimeSignature {
+            numerator: numerator as u8,
+            denominator: denominator as u8,
+        };
+        self.time_signature = Some((time_signature, self.span()));
+
+        Ok(())
+    }
+
+    fn write_annotation<W: io::Write>(
+        &mut self,
+        dst: &mut JavaDataOutput<W>,
+        annotation: Annotation,
+    ) -> Result<()> {
+        let index: usize = self.annotations.len();
+        assert!(index < 0x100_0000, "too many annotations");
+        self.annotations.push(annotation);
+        dst.write_byte(tone_control::ANNOTATION.into())?;
+        dst.write(&(index as u32).to_be_bytes()[1..])?;
+        Ok(())
+    }
+
+    // 直後の音出し・無音の記述範囲を注釈として書き込む (記録する場合だけ)
+    fn write_source<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<()> {
+        if !self.record_sources {
+            return Ok(());
+        }
+        let span = self.span();
+        self.write_annotation(dst, Annotation::Source(span))
+    }
+
+    // 記述範囲の注釈 (先頭の4バイト) と続く1つのイベントに分ける (注釈が無い場合はNone)
+    fn split_source<'b>(&self, buf: &'b [u8]) -> (Option<&'b [u8]>, &'b [u8]) {
+        if buf.len() == 6 && buf[0] as i8 == tone_control::ANNOTATION {
+            let index: usize = timeline::annotation_index(buf, 0);
+            if let Annotation::Source(_) = self.annotations[index] {
+                return (Some(&buf[..4]), &buf[4..]);
+            }
+        }
+        (None, buf)
+    }
+
+    // 小節線の位置までの長さが拍子に合っているかを確認する。
+    // ブロックやリピートで繰り返される小節線も再生順に確認する。
+    // 最初の小節は短くてもよい (弱起)。最後の小節線以降は確認しない。
+    // 合わない小節線は全て記録する (繰り返し再生される小節線は1回だけ)。
+    fn validate_bars(&mut self, tseq: &[u8]) -> Result<()> {
+        if !self
+            .annotations
+            .iter()
+            .any(|a| matches!(a, Annotation::BarLine(_)))
+        {
+            return Ok(());
+        }
+
+        // #TIMEが無ければ小節線は区切りの目印として扱い、長さは確認しない
+        let Some((time_signature, span)) = self.time_signature.clone() else {
+            return Ok(());
+        };
+
+        let bar_length: i32 = match time_signature.bar_length(self.resolution) {
+            Some(bar_length) => bar_length,
+            None => {
+                let error = InvalidTimeSignature(span);
+                self.errors.push(error);
+                return Ok(());
+            }
+        };
+
+        let mut length: i32 = 0;
+        let mut first_bar = true;
+        let mut errors: Vec<MMLError> = Vec::new();
+
+        timeline::walk(tseq, |_, event| -> Result<()> {
+            match event {
+                Event::Note { duration, .. } | Event::Rest { duration } => length += duration,
+                Event::Annotation(index) => {
+                    if let Annotation::BarLine(span) = &self.annotations[index] {
+                        let reported = errors.iter().any(|e| e.span() == Some(span));
+                        if length > bar_length && !reported {
+                            errors.push(BarTooLong(span.clone()));
+                        }
+                        if length < bar_length && !first_bar && !reported {
+                            errors.push(BarTooShort(span.clone()));
+                        }
+                        length = 0;
+                        first_bar = false;
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        self.errors.extend(errors);
+
+        Ok(())
+    }
+
+    // #TITLE テキスト などの曲の情報
+    // テキストは行末まで (URLなどを書けるように ; や // をコメントとして扱わない)
+    fn parse_metadata_text(&mut self) -> Result<String> {
+        self.skip_spaces();
+
+        let mut text = String::new();
+        while let Some(ch) = self.get_raw_char().filter(|ch| *ch != '\n') {
+            text.push(ch);
+            self.next_raw_char();
+        }
+
+        let text = text.trim_end();
+        if text.is_empty() {
+            return self.error(InvalidMetadata);
+        }
+
+        Ok(text.to_string())
+    }
+
+    // #define 名前 値
+    // 値は行末まで (行末の空白とコメントは含まない)
+    fn parse_define(&mut self) -> Result<()> {
+        self.skip_spaces();
+
+        if self.get_char().filter(|ch| is_name_start(*ch)).is_none() {
+            return self.error(InvalidDefine);
+        }
+
+        let name = self.parse_name();
+        if self.macros.iter().any(|m| m.name == name) {
+            return self.error(InvalidDefine);
+        }
+
+        self.skip_spaces();
+
+        // 値は展開するまで元のテキストの文字のままにする (歌詞などのテキストを置き換えないため)
+        let pos = self.cur.clone();
+        let mut value: Vec<char> = Vec::new();
+        let mode = self.mode;
+        let ends_with_slash =
+            |value: &[char]| value.last().map(|ch| mode.normalize(*ch)) == Some('/');
+        while let Some(ch) = self.get_char() {
+            if ch == '\n' || ch == ';' || (ch == '/' && ends_with_slash(&value)) {
+                break;
+            }
+            value.push(self.get_raw_char().unwrap());
+            self.next_raw_char();
+        }
+        if ends_with_slash(&value) && matches!(self.get_char(), Some('/')) {
+            value.pop();
+        }
+        while value.last().filter(|ch| ch.is_whitespace()).is_some() {
+            value.pop();
+        }
+
+        if value.is_empty() {
+            return self.error(InvalidDefine);
+        }
+
+        self.macros.push(Macro { name, value, pos });
+
+        Ok(())
+    }
+
+    // #include "ファイル名"
+    // ファイル名の相対パスは#includeを記述したファイルのディレクトリが基準
+    fn parse_include(&mut self) -> Result<()> {
+        self.skip_spaces();
+
+        if !matches!(self.get_char(), Some('"')) {
+            return self.error(InvalidInclude);
+        }
+
+        let mut file_name = String::new();
+        loop {
+            match self.next_raw_char() {
+                Some('"') => break,
+                Some('\n') | None => return self.error(InvalidInclude),
+                Some(ch) => file_name.push(ch),
+            }
+        }
+
+        if file_name.is_empty() || self.sources.len() >= MAX_NESTING {
+            return self.error(InvalidInclude);
+        }
+
+        let dir: Option<&Path> = match self.sources.iter().rev().find_map(|s| s.dir.as_ref()) {
+            Some(dir) => Some(dir),
+            None => self.dir.as_deref(),
+        };
+        let path: PathBuf = match dir {
+            Some(dir) => dir.join(&file_name),
+            None => PathBuf::from(&file_name),
+        };
+        if !self.includes.contains(&path) {
+            self.includes.push(path.clone());
+        }
+
+        let text = match encoding::read_file(&path, self.encoding) {
+            Ok(text) => text,
+            Err(_) => return self.error(InvalidInclude),
+        };
+
+        // 取り込み中のファイルを取り込むと循環する
+        let mut chain: Vec<Expansion> = self.chain().to_vec();
+        if let Ok(file) = std::fs::canonicalize(&path) {
+            if self.file.as_ref() == Some(&file) || chain.contains(&Expansion::File(file.clone())) {
+                return self.error(InvalidInclude);
+            }
+            chain.push(Expansion::File(file));
+        }
+        if self.include_count >= MAX_INCLUDES {
+            return self.error(InvalidInclude);
+        }
+        self.include_count += 1;
+
+        // 閉じの"の次から呼び出し元の読み込みを再開する
+        self.next_char();
+
+        let start = Position {
+            character: None,
+            col: 1,
+            row: 1,
+            file: Some(path.display().to_string()),
+        };
+        let dir = path.parent().map(Path::to_path_buf);
+        self.push_source(text.chars().collect(), start, chain, dir);
+        self.files.push((path.display().to_string(), text));
+
+        Ok(())
+    }
+
+    // @名前 で#defineで定義した値に置き換える
+    fn expand_macro(&mut self) -> Result<()> {
+        // 名前の途中で値やファイルを読み終えて呼び出し元に戻る場合があるので、@の位置で連鎖を取っておく
+        let at = self.cur.clone();
+        let mut chain: Vec<Expansion> = self.chain().to_vec();
+
+        if self.next_char().filter(|ch| is_name_start(*ch)).is_none() {
+            return self.error(InvalidMacro);
+        }
+
+        let name = self.parse_name();
+
+        // エラーは呼び出し元に戻っていても@名前を指す
+        let call = || {
+            let end = Position {
+                character: None,
+                col: at.col + 1 + name.chars().count(),
+                ..at.clone()
+            };
+            InvalidMacro(Span {
+                line: self.source_line(&at),
+                start: at.clone(),
+                end,
+            })
+        };
+
+        let index: usize = match self.macros.iter().position(|m| m.name == name) {
+            Some(index) => index,
+            None => return Err(call()),
+        };
+
+        // 展開中のマクロを展開すると循環する
+        let expansion = Expansion::Macro(name.clone());
+        if chain.contains(&expansion)
+            || self.sources.len() >= MAX_NESTING
+            || self.expansions >= MAX_EXPANSIONS
+        {
+            return Err(call());
+        }
+        chain.push(expansion);
+
+        self.expansions += 1;
+
+        let value = self.macros[index].value.clone();
+        let start = self.macros[index].pos.clone();
+        self.push_source(value, start, chain, None);
+
+        Ok(())
+    }
+
+    fn push_source(
+        &mut self,
+        chars: Vec<char>,
+        mut start: Position,
+        chain: Vec<Expansion>,
+        dir: Option<PathBuf>,
+    ) {
+        if chars.is_empty() {
+            return;
+        }
+        start.character = Some(chars[0]);
+        let ret = std::mem::replace(&mut self.cur, start);
+        self.sources.push(Source {
+            chars,
+            index: 1,
+            ret,
+            chain,
+            dir,
+        });
     }
 
     // 数字があれば数値として読み込む。
@@ -181,6 +1026,8 @@ impl<'a> Mml<'a> {
     fn parse_tempo(&mut self) -> Result<()> {
         self.skip_whitespaces();
 
+        self.mark();
+
         if !matches!(self.get_char(), Some('T' | 't')) {
             return Ok(());
         }
@@ -190,15 +1037,18 @@ impl<'a> Mml<'a> {
 
         if (20..=508).contains(&tempo) {
             self.tempo = tempo;
-            Ok(())
         } else {
-            self.error(InvalidTempo)
+            self.report(InvalidTempo);
         }
+
+        Ok(())
     }
 
     fn parse_resolution(&mut self) -> Result<()> {
         self.skip_whitespaces();
 
+        self.mark();
+
         if !matches!(self.get_char(), Some('%')) {
             return Ok(());
         }
@@ -209,59 +1059,132 @@ impl<'a> Mml<'a> {
 
         if (1..=127).contains(&resolution) {
             self.resolution = resolution;
-            Ok(())
         } else {
-            self.error(InvalidResolution)
+            self.report(InvalidResolution);
         }
+
+        Ok(())
     }
 
     fn set_default(&mut self) {
         self.octave = tone_control::C4.into();
         self.duration = (self.resolution >> 2).max(1);
+        self.explicit_octave = false;
+        self.explicit_duration = false;
+        self.last_note = None;
+        self.silent = false;
+        self.volume = None;
     }
 
     fn parse_block<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<bool> {
         self.skip_whitespaces();
 
+        self.mark();
+
         if !matches!(self.get_char(), Some('{')) {
             return Ok(false);
         }
 
-        if self.next_char().filter(char::is_ascii_digit).is_none() {
-            return self.error(InvalidBlockId);
+        // ブロックは番号(連番)か名前で定義する。名前で定義した場合も番号は連番で割り当てる
+        // 番号か名前が不正な場合はエラーを記録して次の番号のブロックとして読み込みを続ける
+        let id: i32 = self.next_block_id;
+        let mut name: Option<String> = None;
+        match self.next_char() {
+            Some(ch) if ch.is_ascii_digit() => {
+                if self.parse_number() != id {
+                    self.report(InvalidBlockId);
+                }
+            }
+            Some(ch) if is_name_start(ch) => {
+                let block_name = self.parse_name();
+                if self.find_block(&block_name).is_some() {
+                    self.report(InvalidBlockId);
+                } else {
+                    name = Some(block_name);
+                }
+            }
+            _ => self.report(InvalidBlockId),
         }
 
-        let id = self.parse_number();
-        if id != self.next_block_id || 127 < id {
+        if 127 < id {
             return self.error(InvalidBlockId);
         }
 
+        let span = self.span();
+
         dst.write_byte(tone_control::BLOCK_START.into())?;
         dst.write_byte(id)?;
 
         self.set_default();
 
-        let event: i32 = self.parse_sequence(dst)?;
-        if event == 0 {
-            return self.error(InvalidBlock);
+        let mut body: Vec<u8> = Vec::new();
+        let mut tmp = JavaDataOutput::new(&mut body);
+
+        let errors: usize = self.errors.len();
+        let event: i32 = self.parse_sequence(&mut tmp)?;
+        if event == 0 && self.errors.len() == errors {
+            self.report(InvalidBlock);
         }
 
         self.skip_whitespaces();
 
-        if !matches!(self.get_char(), Some('}')) {
-            return self.error(InvalidBlockEnd);
+        if matches!(self.get_char(), Some('}')) {
+            self.next_char();
+        } else {
+            self.report(InvalidBlockEnd);
         }
 
-        self.next_char();
+        dst.write(&body)?;
 
         dst.write_byte(tone_control::BLOCK_END.into())?;
         dst.write_byte(id)?;
 
+        self.block_names.push(name);
+        self.block_bodies.push(body);
+        self.block_spans.push(span);
+        self.block_used.push(false);
+        self.block_calls.push(Vec::new());
+
         self.next_block_id += 1;
 
         Ok(true)
     }
 
+    // ブロック名を読み込む (頭文字が英字か_であることは呼び出し側で確認する)
+    fn parse_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(ch) = self.get_char().filter(|ch| is_name_char(*ch)) {
+            name.push(ch);
+            self.next_char();
+        }
+        name
+    }
+
+    fn find_block(&self, name: &str) -> Option<i32> {
+        self.block_names
+            .iter()
+            .position(|block_name| block_name.as_deref() == Some(name))
+            .map(|id| id as i32)
+    }
+
+    // 符号付きの数値を読み込む (+5, -3, 12 など)
+    // 数字が無い場合はNoneを返す
+    fn parse_signed_number(&mut self) -> Option<i32> {
+        let sign: i32 = match self.get_char() {
+            Some('+') => {
+                self.next_char();
+                1
+            }
+            Some('-') => {
+                self.next_char();
+                -1
+            }
+            _ => 1,
+        };
+        self.get_char().filter(char::is_ascii_digit)?;
+        Some(sign * self.parse_number())
+    }
+
     // シーケンスコマンドをトーンシーケンスイベントに変換する
     fn parse_sequence<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<i32> {
         self.skip_whitespaces();
@@ -271,98 +1194,185 @@ impl<'a> Mml<'a> {
         let mut event: i32 = 0;
 
         while self.has_char() {
-            // clippyさん･･･何故わかってくれぬ･･･
-            #[allow(clippy::if_same_then_else)]
-            if self.parse_change_octave()? {
-                self.skip_whitespaces();
-                // これは内部データの更新コマンドだから次のコマンドへ
-                continue;
-            } else if self.parse_change_duration()? {
-                self.skip_whitespaces();
-                // これは内部データの更新コマンドだから次のコマンドへ
-                continue;
-            } else if self.parse_note(dst)? {
-                // 音出しコマンド (CDEFGABで指定)
-                event += 2;
-            } else if self.parse_rest(dst)? {
-                // 無音コマンド
-                event += 2;
-            } else if self.parse_note_value(dst)? {
-                // 音出しコマンド (ノート番号で指定)
-                event += 2;
-            } else if self.parse_play_block(dst)? {
-                // 指定IDのブロックの再生コマンド
-            } else if self.parse_repeat(dst)? {
-                // リピート記述の読み込み
-            } else if self.parse_volume(dst)? {
-                // ボリューム変更コマンド
-            } else if matches!(self.get_char(), Some(']' | '}')) {
+            self.mark();
+            match self.parse_command(dst) {
+                // 内部データの更新コマンドと小節線・マーカーは音を出さないので次のコマンドへ
+                Ok(Command::Setting) => {}
+                Ok(Command::Sound) => {
+                    event += 2;
+                    event |= 1;
+                }
+                Ok(Command::Other) => event |= 1,
                 // ブロック/リピートの終了
-                break;
-            } else {
-                return self.error(InvalidCharacter);
+                Ok(Command::End) => break,
+                Err(error) => self.recover(error)?,
             }
-
             self.skip_whitespaces();
-            event |= 1;
         }
 
         Ok(event)
     }
 
+    // シーケンスコマンドを1つ読み込む
+    fn parse_command<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<Command> {
+        // clippyさん･･･何故わかってくれぬ･･･
+        #[allow(clippy::if_same_then_else)]
+        let command = if self.parse_change_octave()? || self.parse_change_duration()? {
+            // これは内部データの更新コマンド
+            Command::Setting
+        } else if self.parse_bar_line(dst)? || self.parse_marker(dst)? {
+            Command::Setting
+        } else if self.parse_note(dst)? {
+            // 音出しコマンド (CDEFGABで指定)
+            Command::Sound
+        } else if self.parse_rest(dst)? {
+            // 無音コマンド
+            Command::Sound
+        } else if self.parse_note_value(dst)? {
+            // 音出しコマンド (ノート番号で指定)
+            Command::Sound
+        } else if self.parse_play_block(dst)? {
+            // 指定IDのブロックの再生コマンド
+            Command::Other
+        } else if self.parse_repeat(dst)? {
+            // リピート記述の読み込み
+            Command::Other
+        } else if self.parse_volume(dst)? {
+            // ボリューム変更コマンド
+            Command::Other
+        } else if matches!(self.get_char(), Some(']' | '}')) {
+            Command::End
+        } else {
+            return self.error(InvalidCharacter);
+        };
+        Ok(command)
+    }
+
+    // ループ位置の指定が再生順で正しいかを確認する。
+    // !loopstartと!loopendはそれぞれ1回だけ再生される位置に書く必要がある。
+    // !loopendを省略した場合は曲の最後までがループ区間になる。
+    fn validate_loop(&mut self, tseq: &[u8]) -> Result<()> {
+        let mut loop_start: Option<i32> = None;
+        let mut loop_end: Option<i32> = None;
+
+        let result = timeline::walk(tseq, |tick, event| {
+            if let Event::Annotation(index) = event {
+                match &self.annotations[index] {
+                    Annotation::LoopStart(span) if loop_start.replace(tick).is_some() => {
+                        return Err(InvalidLoop(span.clone()));
+                    }
+                    Annotation::LoopEnd(span) => {
+                        if loop_end.replace(tick).is_some() {
+                            return Err(InvalidLoop(span.clone()));
+                        }
+                        if loop_start.filter(|start| *start < tick).is_none() {
+                            return Err(InvalidLoop(span.clone()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(error) = result {
+            self.errors.push(error);
+        }
+
+        Ok(())
+    }
+
+    // !名前 のマーカー (!loopstart, !loopend)
+    fn parse_marker<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<bool> {
+        if !matches!(self.get_char(), Some('!')) {
+            return Ok(false);
+        }
+
+        if self.next_char().filter(|ch| is_name_start(*ch)).is_none() {
+            return self.error(InvalidMarker);
+        }
+
+        let name = self.parse_name().to_ascii_lowercase();
+
+        let annotation = match name.as_str() {
+            "loopstart" => Annotation::LoopStart(self.span()),
+            "loopend" => Annotation::LoopEnd(self.span()),
+            _ => return self.error(InvalidMarker),
+        };
+
+        self.write_annotation(dst, annotation)?;
+
+        Ok(true)
+    }
+
+    fn parse_bar_line<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<bool> {
+        if !matches!(self.get_char(), Some('|')) {
+            return Ok(false);
+        }
+
+        self.next_char();
+
+        self.write_annotation(dst, Annotation::BarLine(self.span()))?;
+
+        Ok(true)
+    }
+
+    // 範囲外のオクターブはエラーにして、続く音符のためにそれまでのオクターブを保つ
     fn parse_change_octave(&mut self) -> Result<bool> {
         match self.get_char() {
             Some('o' | 'O') => {}
             Some('>') => {
-                self.octave -= 12;
-                if self.octave < 0 {
+                if self.octave - 12 < 0 {
                     return self.error(InvalidDecreaseOctave);
-                } else {
-                    self.next_char();
-                    return Ok(true);
                 }
+                self.octave -= 12;
+                self.next_char();
+                self.explicit_octave = true;
+                return Ok(true);
             }
             Some('<') => {
-                self.octave += 12;
-                if self.octave > 127 {
+                if self.octave + 12 > 127 {
                     return self.error(InvalidIncreaseOctave);
-                } else {
-                    self.next_char();
-                    return Ok(true);
                 }
+                self.octave += 12;
+                self.next_char();
+                self.explicit_octave = true;
+                return Ok(true);
             }
             _ => return Ok(false),
         }
 
-        match self.next_char() {
-            Some('-') => {}
-            Some(ch) if ch.is_ascii_digit() => {
-                let oct: i32 = self.parse_number();
-
-                self.octave = (tone_control::C4 as i32) + (oct - 4) * 12;
+        let before: i32 = self.octave;
 
-                if (0..=127).contains(&self.octave) {
-                    return Ok(true);
-                } else {
+        let octave: i32 = match self.next_char() {
+            Some('-') => {
+                if self.next_char().filter(char::is_ascii_digit).is_none() {
                     return self.error(InvalidOctaveValue);
                 }
+                let oct: i32 = self.parse_number();
+                (tone_control::C4 as i32) - (oct + 4) * 12
+            }
+            Some(ch) if ch.is_ascii_digit() => {
+                let oct: i32 = self.parse_number();
+                (tone_control::C4 as i32) + (oct - 4) * 12
             }
             _ => return self.error(InvalidChangeOctave),
-        }
+        };
 
-        if self.next_char().filter(char::is_ascii_digit).is_none() {
+        if !(0..=127).contains(&octave) {
             return self.error(InvalidOctaveValue);
         }
+        self.octave = octave;
+        self.check_octave(before);
+        Ok(true)
+    }
 
-        let oct: i32 = self.parse_number();
-
-        self.octave = (tone_control::C4 as i32) - (oct + 4) * 12;
-
-        if (0..=127).contains(&self.octave) {
-            Ok(true)
-        } else {
-            self.error(InvalidOctaveValue)
+    // 同じオクターブの指定を警告する (シーケンスの最初の指定はデフォルトと同じでも警告しない)
+    fn check_octave(&mut self, before: i32) {
+        if self.explicit_octave && before == self.octave {
+            self.warn(MMLWarning::RedundantOctave(self.span()));
         }
+        self.explicit_octave = true;
     }
 
     fn parse_change_duration(&mut self) -> Result<bool> {
@@ -376,7 +1386,14 @@ impl<'a> Mml<'a> {
             _ => return self.error(InvalidDefaultDurationValue),
         }
 
-        self.duration = self.parse_duration()?;
+        let duration: i32 = self.parse_duration()?;
+
+        // 同じ音長の指定を警告する (シーケンスの最初の指定はデフォルトと同じでも警告しない)
+        if self.explicit_duration && duration == self.duration {
+            self.warn(MMLWarning::RedundantDuration(self.span()));
+        }
+        self.explicit_duration = true;
+        self.duration = duration;
 
         Ok(true)
     }
@@ -416,12 +1433,54 @@ impl<'a> Mml<'a> {
 
         let dur: i32 = self.parse_duration()?;
 
+        self.check_note(note);
+
+        self.parse_lyric(dst)?;
+
+        self.write_source(dst)?;
+
         dst.write_byte(note)?;
         dst.write_byte(dur)?;
 
         Ok(true)
     }
 
+    // 音量0の音符と直前の音符から2オクターブを超える跳躍を警告する
+    fn check_note(&mut self, note: i32) {
+        if self.silent {
+            self.warn(MMLWarning::SilentNote(self.span()));
+        }
+        if let Some(last) = self.last_note.replace(note) {
+            if (note - last).abs() > 24 {
+                self.warn(MMLWarning::OctaveJump(self.span()));
+            }
+        }
+    }
+
+    // 音符の直後の "歌詞" を読み込み、音符の前に注釈として書き込む (音出しと同時に歌詞が出るように)
+    fn parse_lyric<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<()> {
+        if !matches!(self.get_char(), Some('"')) {
+            return Ok(());
+        }
+
+        let mut lyric = String::new();
+        loop {
+            match self.next_raw_char() {
+                Some('"') => break,
+                Some('\n') | None => return self.error(InvalidLyric),
+                Some(ch) => lyric.push(ch),
+            }
+        }
+
+        if lyric.is_empty() {
+            return self.error(InvalidLyric);
+        }
+
+        self.next_char();
+
+        self.write_annotation(dst, Annotation::Lyric(lyric))
+    }
+
     fn parse_rest<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<bool> {
         if !matches!(self.get_char(), Some('R' | 'r')) {
             return Ok(false);
@@ -431,6 +1490,8 @@ impl<'a> Mml<'a> {
 
         let dur: i32 = self.parse_duration()?;
 
+        self.write_source(dst)?;
+
         dst.write_byte(tone_control::SILENCE.into())?;
         dst.write_byte(dur)?;
 
@@ -466,6 +1527,22 @@ impl<'a> Mml<'a> {
 
         let dur: i32 = self.parse_duration()?;
 
+        // --instrument、#INSTRUMENT、既定の楽器の順に決める (変換で使う楽器と同じ)
+        let instrument: Instrument = self
+            .lint
+            .instrument_override
+            .or(self.instrument)
+            .unwrap_or(self.lint.instrument);
+        if !instrument.note_range().contains(&note) {
+            self.warn(MMLWarning::NoteOutOfRange(self.span()));
+        }
+
+        self.check_note(note);
+
+        self.parse_lyric(dst)?;
+
+        self.write_source(dst)?;
+
         dst.write_byte(note)?;
         dst.write_byte(dur)?;
 
@@ -477,43 +1554,194 @@ impl<'a> Mml<'a> {
             return Ok(false);
         }
 
-        if self.next_char().filter(char::is_ascii_digit).is_none() {
+        let id: i32 = match self.next_char() {
+            Some(ch) if ch.is_ascii_digit() => self.parse_number(),
+            Some(ch) if is_name_start(ch) => {
+                let name = self.parse_name();
+                match self.find_block(&name) {
+                    Some(id) => id,
+                    None => return self.error(InvalidPlayBlockId),
+                }
+            }
+            _ => return self.error(InvalidPlayBlockId),
+        };
+
+        if !(0..self.next_block_id).contains(&id) {
             return self.error(InvalidPlayBlockId);
         }
 
-        let id: i32 = self.parse_number();
+        self.block_used[id as usize] = true;
 
-        if !(0..self.next_block_id).contains(&id) {
-            return self.error(InvalidPlayBlockId);
+        if self.record_sources {
+            self.write_annotation(dst, Annotation::BlockCall(id as usize))?;
         }
 
-        dst.write_byte(tone_control::PLAY_BLOCK.into())?;
-        dst.write_byte(id)?;
+        if !matches!(self.get_char(), Some('(')) {
+            let span = self.span();
+            self.block_calls[id as usize].push(span);
+            if self.silent {
+                self.warn(MMLWarning::SilentNote(self.span()));
+            }
+            dst.write_byte(tone_control::PLAY_BLOCK.into())?;
+            dst.write_byte(id)?;
+            return Ok(true);
+        }
+
+        // 引数付きブロック再生 $番号(transpose=5, volume=80, octave=+1)
+        // (同じ引数を2回指定するとエラー)
+        let mut transpose: i32 = 0;
+        let mut volume: Option<i32> = None;
+        let mut arg_names: Vec<String> = Vec::new();
+
+        self.next_char();
+        self.skip_whitespaces();
+
+        while !matches!(self.get_char(), Some(')')) {
+            if self.get_char().filter(|ch| is_name_start(*ch)).is_none() {
+                return self.error(InvalidPlayBlockArgument);
+            }
+            let arg_name = self.parse_name().to_ascii_lowercase();
+            if arg_names.contains(&arg_name) {
+                return self.error(InvalidPlayBlockArgument);
+            }
+            arg_names.push(arg_name.clone());
+            self.skip_whitespaces();
+            if !matches!(self.get_char(), Some('=')) {
+                return self.error(InvalidPlayBlockArgument);
+            }
+            self.next_char();
+            self.skip_whitespaces();
+            let value: i32 = match self.parse_signed_number() {
+                Some(value) => value,
+                None => return self.error(InvalidPlayBlockArgument),
+            };
+            match arg_name.as_str() {
+                "transpose" if (-127..=127).contains(&value) => transpose += value,
+                "octave" if (-10..=10).contains(&value) => transpose += value * 12,
+                "volume" if (0..=100).contains(&value) => volume = Some(value),
+                _ => return self.error(InvalidPlayBlockArgument),
+            }
+            self.skip_whitespaces();
+            match self.get_char() {
+                Some(',') => {
+                    self.next_char();
+                    self.skip_whitespaces();
+                }
+                Some(')') => {}
+                _ => return self.error(InvalidPlayBlockArgumentEnd),
+            }
+        }
+
+        if let Some(vol) = volume {
+            dst.write_byte(tone_control::SET_VOLUME.into())?;
+            dst.write_byte(vol)?;
+        }
+
+        if volume.map_or(self.silent, |vol| vol == 0) {
+            self.warn(MMLWarning::SilentNote(self.span()));
+        }
+
+        if transpose == 0 {
+            dst.write_byte(tone_control::PLAY_BLOCK.into())?;
+            dst.write_byte(id)?;
+        } else {
+            // トーンシーケンスには移調の仕組みが無いのでブロックの中身を展開して書き込む
+            self.write_transposed_block(dst, id as usize, transpose)?;
+        }
+
+        // volumeの指定はこのブロック再生だけなので呼び出し元の音量に戻す
+        // (ブロックの中でVより前の場合は呼び出し元の音量が分からないので戻さない)
+        if let (Some(_), Some(vol)) = (volume, self.volume) {
+            dst.write_byte(tone_control::SET_VOLUME.into())?;
+            dst.write_byte(vol)?;
+        }
+
+        self.next_char();
+
+        let span = self.span();
+        self.block_calls[id as usize].push(span);
 
         Ok(true)
     }
 
+    fn write_transposed_block<W: io::Write>(
+        &self,
+        dst: &mut JavaDataOutput<W>,
+        id: usize,
+        transpose: i32,
+    ) -> Result<()> {
+        let body: &[u8] = &self.block_bodies[id];
+        let mut pos: usize = 0;
+        while let Some(&cmd) = body.get(pos) {
+            match cmd as i8 {
+                tone_control::PLAY_BLOCK => {
+                    let inner_id: usize = (body[pos + 1] & 0x7F).into();
+                    self.write_transposed_block(dst, inner_id, transpose)?;
+                    pos += 2;
+                }
+                tone_control::SET_VOLUME | tone_control::SILENCE => {
+                    dst.write(&body[pos..pos + 2])?;
+                    pos += 2;
+                }
+                tone_control::ANNOTATION => {
+                    dst.write(&body[pos..pos + 4])?;
+                    pos += 4;
+                }
+                tone_control::REPEAT => {
+                    dst.write(&body[pos..pos + 2])?;
+                    if body[pos + 2] as i8 == tone_control::SILENCE {
+                        dst.write(&body[pos + 2..pos + 4])?;
+                    } else {
+                        dst.write_byte(self.transpose_note(body[pos + 2], transpose)?)?;
+                        dst.write_byte(body[pos + 3].into())?;
+                    }
+                    pos += 4;
+                }
+                _ => {
+                    dst.write_byte(self.transpose_note(cmd, transpose)?)?;
+                    dst.write_byte(body[pos + 1].into())?;
+                    pos += 2;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn transpose_note(&self, note: u8, transpose: i32) -> Result<i32> {
+        let note: i32 = note as i32 + transpose;
+        if (0..=127).contains(&note) {
+            Ok(note)
+        } else {
+            self.error(InvalidTranspose)
+        }
+    }
+
     fn parse_repeat<W: io::Write>(&mut self, dst: &mut JavaDataOutput<W>) -> Result<bool> {
         if !matches!(self.get_char(), Some('[')) {
             return Ok(false);
         }
 
-        if self.next_char().filter(char::is_ascii_digit).is_none() {
-            return self.error(InvalidRepeat);
-        }
-
-        let multiplier: i32 = self.parse_number();
+        let start: Position = self.cur.clone();
 
-        if !(2..=127).contains(&multiplier) {
-            return self.error(InvalidRepeatNumber);
+        // 回数が不正な場合はエラーを記録して中身の読み込みを続ける
+        let mut multiplier: i32 = 0;
+        if self.next_char().filter(char::is_ascii_digit).is_none() {
+            self.report(InvalidRepeat);
+        } else {
+            multiplier = self.parse_number();
+            if !(2..=127).contains(&multiplier) {
+                self.report(InvalidRepeatNumber);
+                multiplier = 0;
+            }
         }
 
         let mut buf: Vec<u8> = Vec::new();
         let mut tmp = JavaDataOutput::new(&mut buf);
 
+        let errors: usize = self.errors.len();
         let event: i32 = self.parse_sequence(&mut tmp)?;
 
-        if event == 0 || buf.is_empty() {
+        if (event == 0 || buf.is_empty()) && self.errors.len() == errors {
             return self.error(InvalidRepeat);
         }
 
@@ -525,10 +1753,20 @@ impl<'a> Mml<'a> {
 
         self.next_char();
 
-        if event == 3 && buf.len() == 2 {
+        // 記述範囲の注釈はREPEATの前に1回だけ書き込む
+        let (source, body) = self.split_source(&buf);
+
+        if event == 3 && body.len() == 2 && body[0] as i8 == tone_control::SILENCE {
+            self.warn(MMLWarning::RepeatedRest(self.span_from(&start)));
+        }
+
+        if event == 3 && body.len() == 2 {
+            if let Some(source) = source {
+                dst.write(source)?;
+            }
             dst.write_byte(tone_control::REPEAT.into())?;
             dst.write_byte(multiplier)?;
-            dst.write(&buf)?;
+            dst.write(body)?;
         } else {
             for _ in 0..multiplier {
                 dst.write(&buf)?;
@@ -556,6 +1794,9 @@ impl<'a> Mml<'a> {
         dst.write_byte(tone_control::SET_VOLUME.into())?;
         dst.write_byte(vol)?;
 
+        self.silent = vol == 0;
+        self.volume = Some(vol);
+
         Ok(true)
     }
 
@@ -609,41 +1850,23 @@ impl<'a> Mml<'a> {
     }
 }
 
-impl From<java_data_io::Error> for MMLError {
-    fn from(error: java_data_io::Error) -> Self {
-        use java_data_io::Error::*;
-        match error {
-            IoError(io_error) => MMLError::IoError(io_error),
-            UtfDataFormatError => unreachable!("UtfDataFormatError"),
-        }
-    }
-}
-
-impl From<io::Error> for MMLError {
-    fn from(error: io::Error) -> Self {
-        MMLError::IoError(error)
-    }
-}
-
-impl std::fmt::Display for MMLError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
-    }
+pub(crate) fn is_name_start(ch: char) -> bool {
+    ch.is_ascii_alphabetic() || ch == '_'
 }
 
-impl std::error::Error for MMLError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            MMLError::IoError(error) => Some(error),
-            _ => None,
-        }
-    }
+pub(crate) fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // 最初のエラーだけを確認する
+    fn parse(src: &str, file: Option<&Path>) -> Result<Song> {
+        super::parse(src, file, &ParseOptions::default()).map_err(|mut errors| errors.remove(0))
+    }
+
     const SUMM_SUMM_SUMM: &str = r#"
         {0 O5 L4 D C > B R }
         {1 O4 L8 A B < C > A G4 R4 }
@@ -675,16 +1898,381 @@ mod tests {
 
     #[test]
     fn it_works() {
-        let res1 = parse(SUMM_SUMM_SUMM);
+        let res1 = parse(SUMM_SUMM_SUMM, None);
 
         assert!(res1.is_ok(), "{:?}", res1);
 
-        let res2 = parse(KUCKUCK_KUCKUCK_RUFTS_AUS_DEM_WALD);
+        let res2 = parse(KUCKUCK_KUCKUCK_RUFTS_AUS_DEM_WALD, None);
 
         assert!(res2.is_ok(), "{:?}", res2);
 
-        let res3 = parse(MORGEN_KOMMT_DER_WEIHNACHTSMANN);
+        let res3 = parse(MORGEN_KOMMT_DER_WEIHNACHTSMANN, None);
 
         assert!(res3.is_ok(), "{:?}", res3);
     }
+
+    #[test]
+    fn play_block_with_arguments() {
+        let res = parse(
+            "{motif C D } $motif $0(transpose=2) $motif(octave=+1, volume=80)",
+            None,
+        );
+        assert!(res.is_ok(), "{:?}", res);
+        let tseq: Vec<i8> = res.unwrap().tseq.into_iter().map(|b| b as i8).collect();
+        assert_eq!(
+            tseq,
+            [
+                -2, 1, -3, 30, -4, 64, // header
+                -5, 0, 60, 16, 62, 16, -6, 0, // {motif C D }
+                -7, 0, // $motif
+                62, 16, 64, 16, // $0(transpose=2)
+                -8, 80, 72, 16, 74, 16, -8, 100, // $motif(octave=+1, volume=80)
+            ]
+        );
+
+        // volumeの指定の後は呼び出し元の音量に戻す
+        let res = parse("{0 C } V30 $0(volume=80) D", None);
+        assert!(res.is_ok(), "{:?}", res);
+        let tseq: Vec<i8> = res.unwrap().tseq.into_iter().map(|b| b as i8).collect();
+        assert_eq!(tseq[12..], [-8, 30, -8, 80, -7, 0, -8, 30, 62, 16]);
+
+        // 同じ引数を2回指定するとエラー
+        for src in [
+            "{0 C } $0(transpose=1, transpose=2)",
+            "{0 C } $0(volume=10, VOLUME=20)",
+        ] {
+            let res = parse(src, None);
+            assert!(
+                matches!(res, Err(InvalidPlayBlockArgument(_))),
+                "{}: {:?}",
+                src,
+                res
+            );
+        }
+
+        let res = parse("{0 O9 G } $0(transpose=1)", None);
+        assert!(matches!(res, Err(InvalidTranspose(_))), "{:?}", res);
+
+        let res = parse("{0 C } $0(pitch=1)", None);
+        assert!(matches!(res, Err(InvalidPlayBlockArgument(_))), "{:?}", res);
+
+        let res = parse("{0 C } $0(transpose=1", None);
+        assert!(
+            matches!(res, Err(InvalidPlayBlockArgumentEnd(_))),
+            "{:?}",
+            res
+        );
+
+        let res = parse("{0 C } $intro", None);
+        assert!(matches!(res, Err(InvalidPlayBlockId(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn comments_and_preprocessor() {
+        let plain = parse("T132 O5 C D E", None).unwrap().tseq;
+
+        let src =
+            "; line comment\n// line comment\n/* block\n comment */ T132 O5 C /* */ D E ; end";
+        let res = parse(src, None);
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(res.unwrap().tseq, plain);
+
+        let src = "#define TEMPO T132 ; comment\n#define MELODY C D E\n@TEMPO O5 @MELODY";
+        let res = parse(src, None);
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(res.unwrap().tseq, plain);
+
+        // マクロの値の中のエラーは定義の位置を指す
+        let src = "#define BAD C D X\n\nO5 @BAD";
+        match parse(src, None) {
+            Err(InvalidCharacter(span)) => {
+                let pos = span.start;
+                assert_eq!((pos.row, pos.col, pos.character), (1, 17, Some('X')));
+                assert_eq!(span.line.as_deref(), Some("#define BAD C D X"));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let res = parse("#define LOOP @LOOP\n@LOOP C", None);
+        assert!(matches!(res, Err(InvalidMacro(_))), "{:?}", res);
+
+        // 値の末尾で自身や呼び出し元を参照するマクロも循環として呼び出した@名前を指す
+        for (src, row) in [
+            ("#define A C @A\n@A D", 1),
+            ("#define A C @B\n#define B D @A\n@A E", 2),
+        ] {
+            let errors = super::parse(src, None, &ParseOptions::default()).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}: {:?}", src, errors);
+            match &errors[0] {
+                InvalidMacro(span) => {
+                    assert_eq!((span.start.row, span.start.col), (row, 13), "{}", src);
+                    assert_eq!(span.end.col, 15, "{}", src);
+                    assert_eq!(span.line.as_deref(), src.lines().nth(row - 1), "{}", src);
+                }
+                error => panic!("{}: {:?}", src, error),
+            }
+        }
+        // 読み終えたマクロは循環ではない
+        let res = parse("#define A C\n#define B @A\n#define X @B @B\n@X @B", None);
+        assert!(res.is_ok(), "{:?}", res);
+
+        let res = parse("@UNDEFINED C", None);
+        assert!(matches!(res, Err(InvalidMacro(_))), "{:?}", res);
+
+        let res = parse("C /* unterminated", None);
+        assert!(matches!(res, Err(InvalidComment(_))), "{:?}", res);
+
+        let res = parse("#pragma C", None);
+        assert!(matches!(res, Err(InvalidDirective(_))), "{:?}", res);
+
+        let dir = std::env::temp_dir().join(format!("mml-core-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("melody.mml"), "; included\nC D E\n").unwrap();
+        std::fs::write(dir.join("broken.mml"), "C X\nD\n").unwrap();
+        let main_file = dir.join("main.mml");
+
+        let res = parse("T132 O5 #include \"melody.mml\"", Some(&main_file));
+        assert!(res.is_ok(), "{:?}", res);
+        assert_eq!(res.unwrap().tseq, plain);
+
+        match parse("C #include \"broken.mml\" D", Some(&main_file)) {
+            Err(InvalidCharacter(span)) => {
+                let pos = span.start;
+                assert_eq!((pos.row, pos.col), (1, 3));
+                assert!(pos.file.unwrap().ends_with("broken.mml"));
+                assert_eq!(span.line.as_deref(), Some("C X"));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let res = parse("#include \"missing.mml\" C", Some(&main_file));
+        assert!(matches!(res, Err(InvalidInclude(_))), "{:?}", res);
+
+        // 自身を取り込むファイルや互いに取り込むファイル (末尾に改行が無く取り込む前に読み終える場合も)
+        std::fs::write(dir.join("self.mml"), "C #include \"self.mml\"").unwrap();
+        std::fs::write(dir.join("ping.mml"), "C #include \"pong.mml\"").unwrap();
+        std::fs::write(dir.join("pong.mml"), "D #include \"ping.mml\"").unwrap();
+        for file in ["self.mml", "ping.mml"] {
+            let src = format!("#include \"{}\"", file);
+            let res = parse(&src, Some(&main_file));
+            assert!(matches!(res, Err(InvalidInclude(_))), "{:?}", res);
+        }
+        std::fs::write(&main_file, "C #include \"main.mml\"").unwrap();
+        let res = parse("C #include \"main.mml\"", Some(&main_file));
+        assert!(matches!(res, Err(InvalidInclude(_))), "{:?}", res);
+
+        // 読み込めなかったファイルも監視するファイルに含める
+        let files = included_files(
+            "#include \"melody.mml\" #include \"missing.mml\" #include \"melody.mml\"",
+            Some(&main_file),
+            &ParseOptions::default(),
+        );
+        assert_eq!(files, [dir.join("melody.mml"), dir.join("missing.mml")]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bar_lines() {
+        let res = parse("#TIME 3/4\nL4 C D E | F G A | B", None);
+        assert!(res.is_ok(), "{:?}", res);
+        let song = res.unwrap();
+        assert_eq!(
+            song.time_signature,
+            Some(TimeSignature {
+                numerator: 3,
+                denominator: 4
+            })
+        );
+        // 小節線を書いてもトーンシーケンスの音は変わらない
+        let plain = parse("L4 C D E F G A B", None).unwrap();
+        assert_eq!(timeline_notes(&song.tseq), timeline_notes(&plain.tseq));
+
+        // 弱起
+        let res = parse("#TIME 3/4\nL4 C | D E F | G", None);
+        assert!(res.is_ok(), "{:?}", res);
+
+        // 拍子の指定が無ければ小節の長さは確認しない
+        let res = parse("L4 C D E | F G A B C | D", None);
+        assert!(res.is_ok(), "{:?}", res);
+
+        // ブロックとリピートの中の小節線も再生順に確認する
+        let res = parse("#TIME 4/4\n{0 L2 C D | } [2 L4 E F G A | ] $0", None);
+        assert!(res.is_ok(), "{:?}", res);
+
+        match parse("#TIME 3/4\nL4 C D E | F G A B | C", None) {
+            Err(BarTooLong(span)) => {
+                assert_eq!((span.start.row, span.start.col), (2, 20));
+                assert_eq!(span.start.character, Some('|'));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let res = parse("#TIME 3/4\nL4 C D E | F G | A", None);
+        assert!(matches!(res, Err(BarTooShort(_))), "{:?}", res);
+
+        let res = parse("#TIME 4/4\n{0 L4 C D E | } $0 L2 F | $0", None);
+        assert!(matches!(res, Err(BarTooShort(_))), "{:?}", res);
+
+        let res = parse("#TIME 3/5\nC", None);
+        assert!(matches!(res, Err(InvalidTimeSignature(_))), "{:?}", res);
+
+        let res = parse("%6\n#TIME 3/8\nC |", None);
+        assert!(matches!(res, Err(InvalidTimeSignature(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn error_spans() {
+        // 改行の次の行の列は1から数える
+        match parse("%8 O4 C D\n  E16 F", None) {
+            Err(InvalidLength(span)) => {
+                assert_eq!((span.start.row, span.start.col), (2, 3));
+                assert_eq!((span.end.row, span.end.col), (2, 6));
+                assert_eq!(span.line.as_deref(), Some("  E16 F"));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        match parse("C D\n!loopend E", None) {
+            Err(InvalidLoop(span)) => {
+                assert_eq!((span.start.row, span.start.col), (2, 1));
+                assert_eq!((span.end.row, span.end.col), (2, 9));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        // 閉じていない歌詞は行末までを範囲とする
+        match parse("C\"ko\nD", None) {
+            Err(InvalidLyric(span)) => {
+                assert_eq!((span.start.row, span.start.col), (1, 1));
+                assert_eq!((span.end.row, span.end.col), (1, 5));
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn error_recovery() {
+        let src = "T10 O4 C X D\n[1 E F ] G3000 A\n{ C } ] B %";
+        let errors = super::parse(src, None, &ParseOptions::default()).unwrap_err();
+        let found: Vec<String> = errors
+            .iter()
+            .map(|e| {
+                let name = format!("{:?}", e);
+                let span = e.span().unwrap();
+                format!(
+                    "{} {}:{}",
+                    &name[..name.find('(').unwrap()],
+                    span.start.row,
+                    span.start.col
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                "InvalidTempo 1:1",
+                "InvalidCharacter 1:10",
+                "InvalidRepeatNumber 2:1",
+                "InvalidLength 2:10",
+                "InvalidCharacter 3:1",
+                "UnexpectedRemains 3:5",
+                "UnexpectedRemains 3:7",
+                "InvalidCharacter 3:11",
+            ]
+        );
+
+        // 構文エラーが無ければ小節の長さの誤りを全て報告する
+        let errors = super::parse(
+            "#TIME 4/4\nL4 C D E F | G A B | C D E F G | C",
+            None,
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], BarTooShort(_)), "{:?}", errors);
+        assert!(matches!(errors[1], BarTooLong(_)), "{:?}", errors);
+
+        // エラーの後のリピートの開始やブロック再生・小節線は読み飛ばさない (閉じ括弧などのエラーにならない)
+        for src in [
+            "c x [2 d ] e",
+            "{0 c } c x $0 e",
+            "{0 c x [2 d ] } $0",
+            "#TIME 1/4\nc x | d",
+        ] {
+            let errors = super::parse(src, None, &ParseOptions::default()).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}: {:?}", src, errors);
+            assert!(matches!(errors[0], InvalidCharacter(_)), "{:?}", errors);
+        }
+
+        // 範囲外のオクターブの後の音符はそれまでのオクターブで読む (音符のエラーにならない)
+        for src in ["O10 C D", "O-2 C D", "O9 < C D", "O-1 > C D"] {
+            let errors = super::parse(src, None, &ParseOptions::default()).unwrap_err();
+            assert_eq!(errors.len(), 1, "{}: {:?}", src, errors);
+        }
+    }
+
+    #[test]
+    fn default_tempo_and_resolution() {
+        let options = ParseOptions {
+            tempo: Some(140),
+            resolution: Some(96),
+            ..ParseOptions::default()
+        };
+        let song = super::parse("C", None, &options).unwrap();
+        assert_eq!((song.tempo(), song.resolution()), (140, 96));
+        // 既定の長さは分解能の1/4
+        assert_eq!(song.tseq[6..8], [tone_control::C4 as u8, 24]);
+        // MMLのTと%が優先される
+        let song = super::parse("T100 %32 C", None, &options).unwrap();
+        assert_eq!((song.tempo(), song.resolution()), (100, 32));
+    }
+
+    #[test]
+    fn japanese_input() {
+        let options = ParseOptions {
+            input_mode: InputMode::Japanese,
+            ..ParseOptions::default()
+        };
+        let japanese = |src: &str| super::parse(src, None, &options);
+
+        let song =
+            japanese("Ｏ５　Ｌ８ ド レ ミ ファ ソ ラ シ ＜ど ふぁ４ ド♯ シ♭（１６）").unwrap();
+        let expected = parse("O5 L8 C D E F G A B < C F4 C# B-(16)", None).unwrap();
+        assert_eq!(song.tseq, expected.tseq);
+
+        // 歌詞と曲の情報のテキストは置き換えない
+        let song = japanese("#TITLE ドレミの歌\nド\"ド\" レ\"ファ\"").unwrap();
+        assert_eq!(song.metadata().title.as_deref(), Some("ドレミの歌"));
+        let lyrics: Vec<&str> = song
+            .annotations
+            .iter()
+            .filter_map(|a| match a {
+                Annotation::Lyric(lyric) => Some(lyric.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lyrics, vec!["ド", "ファ"]);
+
+        // エラー位置は元のテキストの位置 (ファは2文字)
+        let errors = japanese("ファ ソ Ｘ").unwrap_err();
+        let span = errors[0].span().unwrap();
+        assert!(matches!(errors[0], InvalidCharacter(_)), "{:?}", errors);
+        assert_eq!((span.start.col, span.start.character), (6, Some('Ｘ')));
+
+        // 標準の入力モードでは受け付けない
+        assert!(matches!(parse("ドレミ", None), Err(InvalidCharacter(_))));
+    }
+
+    fn timeline_notes(tseq: &[u8]) -> Vec<(i32, Event)> {
+        let mut events: Vec<(i32, Event)> = Vec::new();
+        timeline::walk::<_, ()>(tseq, |tick, event| {
+            if !matches!(event, Event::Annotation(_)) {
+                events.push((tick, event));
+            }
+            Ok(())
+        })
+        .unwrap();
+        events
+    }
 }
@@ -0,0 +1,561 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::error::{Position, Span};
+use crate::inst::INSTRUMENTS;
+use crate::lang::Lang;
+use crate::song::{Annotation, Metadata, Song, TimeSignature};
+use crate::tone_control;
+use std::io;
+
+// SMFの読み込みのエラー
+#[derive(Debug)]
+pub enum SmfError {
+    IoError(io::Error),
+    // SMFの形式が不正 (ファイルの先頭からのバイト位置)
+    InvalidFormat(usize),
+    // SMPTE形式の時間単位には対応していない
+    UnsupportedDivision,
+    // 同時に鳴る音がある (トーンシーケンスは単音だけ) (SMFのティック)
+    Polyphony(u32),
+    // 分解能127以下で表せない時刻がある
+    InvalidTiming,
+    // 長さが分解能単位で127を超える音符がある (SMFのティック)
+    NoteTooLong(u32),
+    // 音符が1つも無い
+    NoNotes,
+    // ループ位置のマーカーが不正 (それぞれ1つまで、loopEndはloopStartより後) (SMFのティック)
+    InvalidLoop(u32),
+}
+
+impl SmfError {
+    pub fn render_in(&self, lang: Lang) -> String {
+        match (self, lang) {
+            (SmfError::IoError(error), _) => error.to_string(),
+            (SmfError::InvalidFormat(pos), Lang::Ja) => {
+                format!("SMFの形式が不正です (位置: {}バイト)", pos)
+            }
+            (SmfError::InvalidFormat(pos), Lang::En) => {
+                format!("invalid SMF format (at byte {})", pos)
+            }
+            (SmfError::UnsupportedDivision, Lang::Ja) => {
+                "SMPTE形式の時間単位のSMFには対応していません".to_string()
+            }
+            (SmfError::UnsupportedDivision, Lang::En) => {
+                "SMF with SMPTE time division is not supported".to_string()
+            }
+            (SmfError::Polyphony(tick), Lang::Ja) => {
+                format!(
+                    "同時に鳴る音があります (ティック: {}) (単音だけ読み込めます)",
+                    tick
+                )
+            }
+            (SmfError::Polyphony(tick), Lang::En) => {
+                format!(
+                    "overlapping notes at tick {} (only monophonic SMF can be read)",
+                    tick
+                )
+            }
+            (SmfError::InvalidTiming, Lang::Ja) => {
+                "分解能127以下で表せない時刻があります".to_string()
+            }
+            (SmfError::InvalidTiming, Lang::En) => {
+                "some timing cannot be represented with a resolution of 127 or less".to_string()
+            }
+            (SmfError::NoteTooLong(tick), Lang::Ja) => {
+                format!("長さが127を超える音符があります (ティック: {})", tick)
+            }
+            (SmfError::NoteTooLong(tick), Lang::En) => {
+                format!("note longer than 127 at tick {}", tick)
+            }
+            (SmfError::NoNotes, Lang::Ja) => "音符が1つもありません".to_string(),
+            (SmfError::NoNotes, Lang::En) => "no notes".to_string(),
+            (SmfError::InvalidLoop(tick), Lang::Ja) => {
+                format!(
+                    "ループ位置のマーカーが不正です (ティック: {}) (loopStartとloopEndはそれぞれ1つまで、loopStartより後にloopEnd)",
+                    tick
+                )
+            }
+            (SmfError::InvalidLoop(tick), Lang::En) => {
+                format!(
+                    "invalid loop marker at tick {} (at most one loopStart and one loopEnd, loopEnd after loopStart)",
+                    tick
+                )
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SmfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render_in(Lang::Ja))
+    }
+}
+
+impl std::error::Error for SmfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SmfError::IoError(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SmfError {
+    fn from(error: io::Error) -> Self {
+        SmfError::IoError(error)
+    }
+}
+
+// SMFのヘッダーで始まるか
+pub(crate) fn is_smf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"MThd")
+}
+
+struct Note {
+    start: u32,
+    end: u32,
+    key: u8,
+    velocity: u8,
+}
+
+// 音符以外の時刻の決まったイベント
+enum Marker {
+    Lyric(String),
+    LoopStart,
+    LoopEnd,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, SmfError> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(SmfError::InvalidFormat(self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], SmfError> {
+        let data = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(SmfError::InvalidFormat(self.pos))?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn int(&mut self, len: usize) -> Result<u32, SmfError> {
+        let data = self.take(len)?;
+        Ok(data.iter().fold(0, |n, b| (n << 8) | (*b as u32)))
+    }
+
+    // 可変長数値 (デルタタイムなど)
+    fn var_len(&mut self) -> Result<u32, SmfError> {
+        let mut n: u32 = 0;
+        for _ in 0..4 {
+            let b = self.byte()?;
+            n = (n << 7) | (b & 0x7F) as u32;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(SmfError::InvalidFormat(self.pos))
+    }
+}
+
+// 単音のSMFを読み込む。
+// 音符・テンポ (最初の指定)・拍子・楽器 (最初のプログラムチェンジ)・曲の情報・歌詞・ループ位置のマーカーを読み込む。
+pub(crate) fn read(bytes: &[u8]) -> Result<Song, SmfError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(SmfError::InvalidFormat(0));
+    }
+    let header_len: usize = reader.int(4)? as usize;
+    let header_end: usize = reader.pos + header_len;
+    let _format = reader.int(2)?;
+    let tracks: u32 = reader.int(2)?;
+    let division: u32 = reader.int(2)?;
+    if division & 0x8000 != 0 {
+        return Err(SmfError::UnsupportedDivision);
+    }
+    if division == 0 {
+        return Err(SmfError::InvalidFormat(reader.pos - 2));
+    }
+    reader.pos = header_end;
+
+    let mut notes: Vec<Note> = Vec::new();
+    let mut markers: Vec<(u32, Marker)> = Vec::new();
+    let mut metadata = Metadata::default();
+    let mut usec_tempo: Option<u32> = None;
+    let mut time_signature: Option<TimeSignature> = None;
+    let mut program: Option<u8> = None;
+    let mut karaoke = false;
+
+    for _ in 0..tracks {
+        let id = reader.take(4)?.to_vec();
+        let len: usize = reader.int(4)? as usize;
+        let end: usize = reader.pos + len;
+        if id != b"MTrk" {
+            reader.pos = end;
+            continue;
+        }
+        let mut tick: u32 = 0;
+        let mut running: Option<u8> = None;
+        // 鳴っている音 (チャンネル, キー) の開始時刻とベロシティ
+        let mut sounding: Vec<(u8, u8, u32, u8)> = Vec::new();
+        while reader.pos < end {
+            tick = tick
+                .checked_add(reader.var_len()?)
+                .ok_or(SmfError::InvalidFormat(reader.pos))?;
+            let status: u8 = match reader.bytes.get(reader.pos) {
+                Some(&b) if b & 0x80 != 0 => {
+                    reader.pos += 1;
+                    b
+                }
+                _ => running.ok_or(SmfError::InvalidFormat(reader.pos))?,
+            };
+            match status {
+                0xFF => {
+                    running = None;
+                    let meta_type: u8 = reader.byte()?;
+                    let len: usize = reader.var_len()? as usize;
+                    let data = reader.take(len)?;
+                    let text = || String::from_utf8_lossy(data).into_owned();
+                    match meta_type {
+                        // 作曲者もコメントもテキスト (FF 01) なので全てコメントにする
+                        0x01 if data == b"@KMIDI KARAOKE FILE" => karaoke = true,
+                        0x01 if karaoke && data.starts_with(b"@") => {}
+                        0x01 if karaoke => markers.push((tick, Marker::Lyric(text()))),
+                        0x01 => metadata.comments.push(text()),
+                        0x02 if metadata.copyright.is_none() => metadata.copyright = Some(text()),
+                        0x03 if metadata.title.is_none() => metadata.title = Some(text()),
+                        0x05 => markers.push((tick, Marker::Lyric(text()))),
+                        0x06 if data == b"loopStart" => markers.push((tick, Marker::LoopStart)),
+                        0x06 if data == b"loopEnd" => markers.push((tick, Marker::LoopEnd)),
+                        0x51 if len == 3 && usec_tempo.is_none() => {
+                            usec_tempo = Some(data.iter().fold(0, |n, b| (n << 8) | (*b as u32)))
+                        }
+                        0x58 if len == 4 && time_signature.is_none() => {
+                            time_signature = Some(TimeSignature {
+                                numerator: data[0],
+                                denominator: 1u8.checked_shl(data[1] as u32).unwrap_or(0),
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    running = None;
+                    let len: usize = reader.var_len()? as usize;
+                    reader.take(len)?;
+                }
+                0x80..=0xEF => {
+                    running = Some(status);
+                    let channel: u8 = status & 0x0F;
+                    let data1: u8 = reader.byte()?;
+                    let data2: u8 = match status & 0xF0 {
+                        0xC0 | 0xD0 => 0,
+                        _ => reader.byte()?,
+                    };
+                    match status & 0xF0 {
+                        0x90 if data2 > 0 => sounding.push((channel, data1, tick, data2)),
+                        0x80 | 0x90 => {
+                            if let Some(index) = sounding
+                                .iter()
+                                .position(|&(ch, key, _, _)| ch == channel && key == data1)
+                            {
+                                let (_, key, start, velocity) = sounding.remove(index);
+                                notes.push(Note {
+                                    start,
+                                    end: tick,
+                                    key,
+                                    velocity,
+                                });
+                            }
+                        }
+                        0xC0 if program.is_none() => program = Some(data1 & 0x7F),
+                        _ => {}
+                    }
+                }
+                _ => return Err(SmfError::InvalidFormat(reader.pos - 1)),
+            }
+        }
+        reader.pos = end;
+    }
+
+    notes.sort_by_key(|note| (note.start, note.key));
+    for pair in notes.windows(2) {
+        if pair[1].start < pair[0].end {
+            return Err(SmfError::Polyphony(pair[1].start));
+        }
+    }
+    if notes.is_empty() {
+        return Err(SmfError::NoNotes);
+    }
+    markers.sort_by_key(|(tick, _)| *tick);
+    validate_loop(&markers)?;
+
+    // 全音符のティック数が127以下ならそのまま分解能にする (mml2smfで生成したSMFは元の分解能になる)。
+    // 127を超える場合は全ての時刻の最大公約数を単位にする
+    let whole: u32 = division * 4;
+    let mut resolution: u32 = whole;
+    if whole > 127 {
+        let ticks = notes
+            .iter()
+            .flat_map(|note| [note.start, note.end])
+            .chain(markers.iter().map(|(tick, _)| *tick));
+        resolution = whole / ticks.fold(whole, gcd);
+        if resolution > 127 {
+            return Err(SmfError::InvalidTiming);
+        }
+        while resolution * 2 <= 64 {
+            resolution *= 2;
+        }
+    }
+    // resolution <= wholeなので結果はtick以下になる
+    let to_duration =
+        |tick: u32| -> u32 { (tick as u64 * resolution as u64 / whole as u64) as u32 };
+
+    let tempo: i32 = match usec_tempo {
+        Some(usec) if usec > 0 => (60_000_000.0 / usec as f64 / 4.0).round() as i32 * 4,
+        _ => 120,
+    }
+    .clamp(20, 508);
+
+    let mut tseq: Vec<u8> = vec![
+        tone_control::VERSION as u8,
+        1,
+        tone_control::TEMPO as u8,
+        (tempo >> 2) as u8,
+        tone_control::RESOLUTION as u8,
+        resolution as u8,
+    ];
+    let mut annotations: Vec<Annotation> = Vec::new();
+    let mut current_volume: i32 = 100;
+    let mut time: u32 = 0;
+    let mut markers = markers.into_iter().peekable();
+
+    for note in notes.iter() {
+        let start: u32 = to_duration(note.start);
+        while let Some((tick, marker)) = markers.next_if(|(tick, _)| *tick <= note.start) {
+            write_rest(&mut tseq, &mut time, to_duration(tick));
+            write_marker(&mut tseq, &mut annotations, marker);
+        }
+        write_rest(&mut tseq, &mut time, start);
+        let duration: u32 = to_duration(note.end) - start;
+        if duration > 127 {
+            return Err(SmfError::NoteTooLong(note.start));
+        }
+        let volume: i32 = volume(note.velocity);
+        if volume != current_volume {
+            tseq.push(tone_control::SET_VOLUME as u8);
+            tseq.push(volume as u8);
+            current_volume = volume;
+        }
+        tseq.push(note.key);
+        tseq.push(duration as u8);
+        time = start + duration;
+    }
+    for (tick, marker) in markers {
+        write_rest(&mut tseq, &mut time, to_duration(tick));
+        write_marker(&mut tseq, &mut annotations, marker);
+    }
+
+    let time_signature = time_signature.filter(|ts| {
+        (1..=99).contains(&ts.numerator)
+            && [1, 2, 4, 8, 16, 32, 64].contains(&ts.denominator)
+            && ts.bar_length(resolution as i32).is_some()
+    });
+
+    Ok(Song {
+        tseq,
+        metadata,
+        time_signature,
+        instrument: program.map(|program| INSTRUMENTS[program as usize]),
+        annotations,
+    })
+}
+
+// MMLの!loopstartと!loopendと同じ制限 (loopEndを省略した場合は曲の最後までがループ区間)
+fn validate_loop(markers: &[(u32, Marker)]) -> Result<(), SmfError> {
+    let mut loop_start: Option<u32> = None;
+    let mut loop_end: Option<u32> = None;
+    for &(tick, ref marker) in markers.iter() {
+        match marker {
+            Marker::LoopStart if loop_start.replace(tick).is_some() || loop_end.is_some() => {
+                return Err(SmfError::InvalidLoop(tick));
+            }
+            Marker::LoopEnd => {
+                if loop_end.replace(tick).is_some() {
+                    return Err(SmfError::InvalidLoop(tick));
+                }
+                if loop_start.filter(|start| *start < tick).is_none() {
+                    return Err(SmfError::InvalidLoop(tick));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// ベロシティになる音量 (velocity = 127 * volume / 100 の逆、無い場合は最も近い音量)
+fn volume(velocity: u8) -> i32 {
+    let velocity: i32 = velocity.into();
+    (0..=100)
+        .min_by_key(|volume| (127 * volume / 100 - velocity).abs())
+        .unwrap()
+}
+
+// timeからtoまでの無音 (1イベントは127まで)
+fn write_rest(tseq: &mut Vec<u8>, time: &mut u32, to: u32) {
+    while *time < to {
+        let duration: u32 = (to - *time).min(127);
+        tseq.push(tone_control::SILENCE as u8);
+        tseq.push(duration as u8);
+        *time += duration;
+    }
+}
+
+fn write_marker(tseq: &mut Vec<u8>, annotations: &mut Vec<Annotation>, marker: Marker) {
+    let annotation = match marker {
+        Marker::Lyric(lyric) => Annotation::Lyric(lyric),
+        Marker::LoopStart => Annotation::LoopStart(no_span()),
+        Marker::LoopEnd => Annotation::LoopEnd(no_span()),
+    };
+    let index: usize = annotations.len();
+    annotations.push(annotation);
+    tseq.push(tone_control::ANNOTATION as u8);
+    tseq.extend_from_slice(&(index as u32).to_be_bytes()[1..]);
+}
+
+// SMFから読み込んだ注釈には記述範囲が無い
+fn no_span() -> Span {
+    let position = Position {
+        character: None,
+        col: 0,
+        row: 0,
+        file: None,
+    };
+    Span {
+        start: position.clone(),
+        end: position,
+        line: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MMLから生成したSMFを読み込んで生成し直すと同じSMFになる
+    #[test]
+    fn read_generated_smf() {
+        let sources = [
+            "{0 O7L16 A B } O5L8 C < D [2 E O3L2 F ] $0 G",
+            "#TITLE t\n#COMPOSER c\n#COMMENT x\n#COPYRIGHT (c)\n#TIME 3/4\n#INSTRUMENT 33\nT150 %96\nL4 R | !loopstart V80 C\"ら\" D8. R(30) | [3 F+8 ] R8 V100 E4 | !loopend",
+            "T92 %48 C16 D12 E(5) R(1) F",
+        ];
+        for src in sources.iter() {
+            let song = crate::parse(src).unwrap();
+            let inst = song.instrument().unwrap_or(INSTRUMENTS[0]);
+            let smf = song.to_smf(inst).unwrap();
+            let read = read(&smf).unwrap();
+            assert_eq!(read.to_smf(inst).unwrap(), smf, "{}", src);
+        }
+    }
+
+    #[test]
+    fn invalid_smf() {
+        let smf = crate::convert("C D", INSTRUMENTS[0]).unwrap();
+        assert!(matches!(read(&smf[..20]), Err(SmfError::InvalidFormat(_))));
+
+        // 和音は読み込めない
+        let chord = track(&[0, 0x90, 60, 100, 0, 64, 100, 16, 60, 0, 0, 64, 0]);
+        assert!(matches!(read(&chord), Err(SmfError::Polyphony(0))));
+
+        // 音符の無いSMFは読み込めない
+        assert!(matches!(read(&track(&[])), Err(SmfError::NoNotes)));
+        let marker_only = track(&[
+            0, 0xFF, 0x06, 9, b'l', b'o', b'o', b'p', b'S', b't', b'a', b'r', b't',
+        ]);
+        assert!(matches!(read(&marker_only), Err(SmfError::NoNotes)));
+    }
+
+    // 大きな時刻でも計算が溢れない
+    #[test]
+    fn large_ticks() {
+        // 分解能64 (4分音符16ティック) で2^26ティック後の音符
+        let smf = track(&[0xA0, 0x80, 0x80, 0x00, 0x90, 60, 100, 16, 60, 0]);
+        let song = read(&smf).unwrap();
+        assert_eq!(song.tseq[song.tseq.len() - 2..], [60, 16]);
+
+        // 時刻がu32を超えるSMFは不正
+        let mut events: Vec<u8> = Vec::new();
+        for _ in 0..17 {
+            events.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x01, 0]);
+        }
+        assert!(matches!(
+            read(&track(&events)),
+            Err(SmfError::InvalidFormat(_))
+        ));
+    }
+
+    // ループ位置のマーカーはMMLの!loopstartと!loopendと同じ制限
+    #[test]
+    fn invalid_loop_marker() {
+        let note = [0x90, 60, 100, 16, 60, 0];
+        let loop_start = [
+            0xFF, 0x06, 9, b'l', b'o', b'o', b'p', b'S', b't', b'a', b'r', b't',
+        ];
+        let loop_end = [0xFF, 0x06, 7, b'l', b'o', b'o', b'p', b'E', b'n', b'd'];
+        let cases: [(&[&[u8]], Option<u32>); 5] = [
+            (&[&loop_start, &note, &loop_end], None),
+            (&[&loop_start, &note], None),
+            (&[&loop_end, &note, &loop_start], Some(0)),
+            (&[&note, &loop_end], Some(16)),
+            (&[&loop_start, &note, &loop_start], Some(16)),
+        ];
+        for (events, expected) in cases.iter() {
+            let mut data: Vec<u8> = Vec::new();
+            for event in events.iter() {
+                data.push(0);
+                data.extend_from_slice(event);
+            }
+            let smf = track(&data);
+            match (read(&smf), expected) {
+                (Ok(song), None) => {
+                    // 読み込んだ曲は圧縮できる
+                    assert!(song.compress(None).is_ok());
+                }
+                (Err(SmfError::InvalidLoop(tick)), Some(expected)) => assert_eq!(tick, *expected),
+                (res, _) => panic!("{:?} {:?}", events, res.map(|song| song.tseq)),
+            }
+        }
+    }
+
+    // 1トラックのSMF (分解能16)
+    fn track(events: &[u8]) -> Vec<u8> {
+        let mut smf: Vec<u8> = b"MThd".to_vec();
+        smf.extend_from_slice(&[0, 0, 0, 6, 0, 0, 0, 1, 0, 16]);
+        smf.extend_from_slice(b"MTrk");
+        smf.extend_from_slice(&(events.len() as u32 + 4).to_be_bytes());
+        smf.extend_from_slice(events);
+        smf.extend_from_slice(&[0, 0xFF, 0x2F, 0]);
+        smf
+    }
+}
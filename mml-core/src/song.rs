@@ -0,0 +1,243 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::compress::{self, Compressed};
+use crate::diff::{self, SongDiff};
+use crate::error::{MMLError, Span};
+use crate::expand;
+use crate::inst::Instrument;
+use crate::midi;
+use crate::timeline::{self, Event};
+use crate::tone_control;
+use crate::transform::{self, Transform, TransformError};
+
+// 曲の情報 (#TITLE, #COMPOSER, #COPYRIGHT, #COMMENT)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub composer: Option<String>,
+    pub copyright: Option<String>,
+    pub comments: Vec<String>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.composer.is_none()
+            && self.copyright.is_none()
+            && self.comments.is_empty()
+    }
+}
+
+// 拍子 (#TIME 3/4 なら numerator = 3, denominator = 4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+impl TimeSignature {
+    // 1小節の長さ (分解能単位)、割り切れない場合はNone
+    pub fn bar_length(self, resolution: i32) -> Option<i32> {
+        let length: i32 = resolution * self.numerator as i32;
+        let denominator: i32 = self.denominator.into();
+        if length % denominator == 0 {
+            Some(length / denominator)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self {
+            numerator: 4,
+            denominator: 4,
+        }
+    }
+}
+
+// トーンシーケンスに載せられない情報 (tone_control::ANNOTATIONで参照する)
+#[derive(Debug, Clone)]
+pub(crate) enum Annotation {
+    BarLine(Span),
+    // 直後の音符の歌詞
+    Lyric(String),
+    // ループの開始位置と終了位置 (!loopstart, !loopend)
+    LoopStart(Span),
+    LoopEnd(Span),
+    // 直後の音出し・無音の記述範囲 (エディタとの連携用に記録した場合だけ)
+    Source(Span),
+    // ブロック再生のブロックの番号 (エディタとの連携用に記録した場合だけ)
+    BlockCall(usize),
+}
+
+// MMLを解析した結果
+#[derive(Debug, Clone)]
+pub struct Song {
+    // トーンシーケンスイベント列
+    pub(crate) tseq: Vec<u8>,
+    pub(crate) metadata: Metadata,
+    pub(crate) time_signature: Option<TimeSignature>,
+    pub(crate) instrument: Option<Instrument>,
+    pub(crate) annotations: Vec<Annotation>,
+}
+
+impl Song {
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    // #TIMEで指定した拍子
+    pub fn time_signature(&self) -> Option<TimeSignature> {
+        self.time_signature
+    }
+
+    // #INSTRUMENTで指定した楽器
+    pub fn instrument(&self) -> Option<Instrument> {
+        self.instrument
+    }
+
+    // テンポ (T)
+    pub fn tempo(&self) -> i32 {
+        (self.tseq[3] as i32) << 2
+    }
+
+    // 分解能 (%、全音符の長さ)
+    pub fn resolution(&self) -> i32 {
+        self.tseq[5].into()
+    }
+
+    // 時刻 (分解能単位) を秒にする
+    pub fn seconds(&self, tick: i32) -> f64 {
+        tick as f64 * 240.0 / (self.tempo() * self.resolution()) as f64
+    }
+
+    // 歌詞がある場合はLyric (FF 05) として書き込む
+    pub fn to_smf(&self, inst: Instrument) -> Result<Vec<u8>, MMLError> {
+        self.translate(inst, false)
+    }
+
+    // カラオケ (.kar) 形式で書き込む。
+    // 先頭に "@KMIDI KARAOKE FILE" を入れ、歌詞はText (FF 01) として書き込む。
+    // 歌詞の先頭の / は改行、\ は改段落としてカラオケプレイヤーが扱う。
+    pub fn to_karaoke(&self, inst: Instrument) -> Result<Vec<u8>, MMLError> {
+        self.translate(inst, true)
+    }
+
+    // 歌詞が付いているか
+    pub fn has_lyrics(&self) -> bool {
+        self.annotations
+            .iter()
+            .any(|a| matches!(a, Annotation::Lyric(_)))
+    }
+
+    // ブロック再生とリピートを再生順に展開したMML (音符ごとにオクターブと長さを明記する)
+    pub fn expand(&self) -> String {
+        expand::expand(self)
+    }
+
+    // ブロックの定義とブロック再生を保ったMML (変形した曲をMMLで出力する場合など)
+    pub fn to_mml(&self) -> String {
+        expand::to_mml(self)
+    }
+
+    // 移調・拡大縮小・逆行・反行した曲
+    pub fn transform(&self, transform: Transform) -> Result<Song, TransformError> {
+        transform::transform(self, transform)
+    }
+
+    // ブロック再生とリピートを展開した音出し・無音の違い (otherを新しい曲として比較する)。
+    // 記述範囲はanalyzeで読み込んだSongの場合だけ分かる
+    pub fn diff(&self, other: &Song) -> SongDiff {
+        diff::diff(self, other)
+    }
+
+    // 繰り返し現れる並びをブロックにしてトーンシーケンスを小さくしたMML。
+    // target_bytesを指定した場合はそのバイト数以下になった時点で圧縮をやめる (ブロックが少ない方が読みやすいため)。
+    // 圧縮したMMLが読み込めない場合 (音符・休符の無い曲など) はエラー
+    pub fn compress(&self, target_bytes: Option<usize>) -> Result<Compressed, MMLError> {
+        compress::compress(self, target_bytes)
+    }
+
+    // MMAPIのToneControlで再生できるトーンシーケンス (独自の注釈イベントを取り除いたもの)
+    pub fn to_tone_sequence(&self) -> Vec<u8> {
+        let mut tseq: Vec<u8> = Vec::with_capacity(self.tseq.len());
+        let mut pos: usize = 0;
+        while let Some(&cmd) = self.tseq.get(pos) {
+            let size: usize = timeline::event_size(cmd as i8);
+            if cmd as i8 != tone_control::ANNOTATION {
+                tseq.extend_from_slice(&self.tseq[pos..pos + size]);
+            }
+            pos += size;
+        }
+        tseq
+    }
+
+    // ループ区間 (!loopstart～!loopend) だけのトーンシーケンス。
+    // ToneControlにはループ位置の指定が無いので、これを繰り返し再生 (setLoopCount(-1)) すればループ区間が保たれる。
+    // ブロックやリピートは展開される。ループ位置の指定が無い場合はNone。
+    pub fn to_loop_tone_sequence(&self) -> Option<Vec<u8>> {
+        if !self
+            .annotations
+            .iter()
+            .any(|a| matches!(a, Annotation::LoopStart(_)))
+        {
+            return None;
+        }
+
+        // VERSION, TEMPO, RESOLUTION
+        let mut tseq: Vec<u8> = self.tseq[..6].to_vec();
+        let mut in_loop = false;
+        let mut current_volume: i32 = 100;
+        // 繰り返し再生すると前回の最後の音量が残るので、ループ区間の先頭で必ず音量を指定する
+        // (ループ区間の最初の音符の音量。音符が無い場合は音量は関係ない)
+        let mut head: Option<usize> = None;
+
+        let res: Result<(), ()> = timeline::walk(&self.tseq, |_, event| {
+            match event {
+                Event::Annotation(index) => match &self.annotations[index] {
+                    Annotation::LoopStart(_) => {
+                        in_loop = true;
+                        head = Some(tseq.len());
+                    }
+                    Annotation::LoopEnd(_) => in_loop = false,
+                    _ => {}
+                },
+                Event::Note {
+                    note,
+                    duration,
+                    volume,
+                } if in_loop => {
+                    if let Some(pos) = head.take() {
+                        tseq.splice(pos..pos, [tone_control::SET_VOLUME as u8, volume as u8]);
+                        current_volume = volume;
+                    } else if volume != current_volume {
+                        tseq.push(tone_control::SET_VOLUME as u8);
+                        tseq.push(volume as u8);
+                        current_volume = volume;
+                    }
+                    tseq.push(note as u8);
+                    tseq.push(duration as u8);
+                }
+                Event::Rest { duration } if in_loop => {
+                    tseq.push(tone_control::SILENCE as u8);
+                    tseq.push(duration as u8);
+                }
+                _ => {}
+            }
+            Ok(())
+        });
+        res.ok()?;
+
+        Some(tseq)
+    }
+
+    fn translate(&self, inst: Instrument, karaoke: bool) -> Result<Vec<u8>, MMLError> {
+        let inst: i8 = (inst as i32 - 1) as i8;
+        assert!(0 <= inst, "inst {}", inst);
+        let smf = midi::translate(self, inst, karaoke)?;
+        Ok(smf)
+    }
+}
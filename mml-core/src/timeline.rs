@@ -0,0 +1,141 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::tone_control;
+
+// 再生順にたどったトーンシーケンスイベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    // volumeはトーンシーケンスの音量 (0～100)
+    Note {
+        note: i32,
+        duration: i32,
+        volume: i32,
+    },
+    Rest {
+        duration: i32,
+    },
+    // 注釈の番号 (Song::annotationsの添字)
+    Annotation(usize),
+}
+
+// 注釈イベント (tone_control::ANNOTATION) の番号を読み込む
+pub(crate) fn annotation_index(tseq: &[u8], pos: usize) -> usize {
+    let mut index: usize = 0;
+    for b in tseq[pos + 1..pos + 4].iter() {
+        index = (index << 8) | (*b as usize);
+    }
+    index
+}
+
+// イベントのバイト数
+pub(crate) fn event_size(cmd: i8) -> usize {
+    match cmd {
+        tone_control::REPEAT | tone_control::ANNOTATION => 4,
+        _ => 2,
+    }
+}
+
+// トーンシーケンスイベント列を再生順にたどる。
+// fには各イベントの開始時刻 (分解能単位) とイベントを渡す。
+// トーンシーケンスイベント列に不正は無いことを前提としている。
+pub(crate) fn walk<F, E>(tseq: &[u8], mut f: F) -> Result<(), E>
+where
+    F: FnMut(i32, Event) -> Result<(), E>,
+{
+    let mut block_pos: Vec<usize> = vec![0; 128];
+
+    // VERSION
+    let mut pos: usize = 2;
+
+    if matches!(tseq.get(pos).map(|b| *b as i8), Some(tone_control::TEMPO)) {
+        pos += 2;
+    }
+
+    if matches!(
+        tseq.get(pos).map(|b| *b as i8),
+        Some(tone_control::RESOLUTION)
+    ) {
+        pos += 2;
+    }
+
+    while matches!(
+        tseq.get(pos).map(|b| *b as i8),
+        Some(tone_control::BLOCK_START)
+    ) {
+        let block_id: usize = tseq[pos + 1].into();
+        block_pos[block_id] = pos + 2;
+        pos += 2;
+        while let Some(cmd) = tseq.get(pos).map(|b| *b as i8) {
+            pos += event_size(cmd);
+            if cmd == tone_control::BLOCK_END {
+                break;
+            }
+        }
+    }
+
+    let mut tick: i32 = 0;
+    let mut volume: i32 = 100;
+    let mut pos_stack: Vec<usize> = Vec::new();
+
+    while let Some(cmd) = tseq.get(pos).map(|b| *b as i8) {
+        match cmd {
+            tone_control::PLAY_BLOCK => {
+                pos_stack.push(pos + 2);
+                let block_id: usize = (tseq[pos + 1] & 0x7F).into();
+                pos = block_pos[block_id];
+                continue;
+            }
+            tone_control::BLOCK_END => {
+                pos = pos_stack.pop().unwrap();
+                continue;
+            }
+            tone_control::SET_VOLUME => volume = (tseq[pos + 1] & 0x7F).into(),
+            tone_control::SILENCE => {
+                let duration: i32 = (tseq[pos + 1] & 0x7F).into();
+                f(tick, Event::Rest { duration })?;
+                tick += duration;
+            }
+            tone_control::REPEAT => {
+                let multiplier: i32 = tseq[pos + 1].into();
+                let note: i8 = tseq[pos + 2] as i8;
+                let duration: i32 = (tseq[pos + 3] & 0x7F).into();
+                for _ in 0..multiplier {
+                    if note == tone_control::SILENCE {
+                        f(tick, Event::Rest { duration })?;
+                    } else {
+                        let note: i32 = note.into();
+                        f(
+                            tick,
+                            Event::Note {
+                                note,
+                                duration,
+                                volume,
+                            },
+                        )?;
+                    }
+                    tick += duration;
+                }
+            }
+            tone_control::ANNOTATION => {
+                f(tick, Event::Annotation(annotation_index(tseq, pos)))?;
+            }
+            _ => {
+                let note: i32 = cmd.into();
+                let duration: i32 = (tseq[pos + 1] & 0x7F).into();
+                f(
+                    tick,
+                    Event::Note {
+                        note,
+                        duration,
+                        volume,
+                    },
+                )?;
+                tick += duration;
+            }
+        }
+        pos += event_size(cmd);
+    }
+
+    Ok(())
+}
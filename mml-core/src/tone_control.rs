@@ -11,3 +11,7 @@ pub(crate) const SET_VOLUME: i8 = -8;
 pub(crate) const REPEAT: i8 = -9;
 pub(crate) const C4: i8 = 60;
 pub(crate) const SILENCE: i8 = -1;
+
+// MMAPIのToneControlには無い独自のイベント (SMFにだけ反映する小節線や歌詞などの注釈)
+// ANNOTATION, 注釈の番号(24bit BE) の4バイト
+pub(crate) const ANNOTATION: i8 = -10;
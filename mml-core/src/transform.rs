@@ -0,0 +1,586 @@
+// mml-core
+// author: Leonardone @ NEETSDKASU
+
+use crate::lang::Lang;
+use crate::song::{Annotation, Song, TimeSignature};
+use crate::timeline::{self, Event};
+use crate::tone_control;
+
+// 曲の変形
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    // 移調 (半音単位)
+    Transpose(i32),
+    // 音の長さをnumerator/denominator倍にする (拡大・縮小)
+    Scale { numerator: i32, denominator: i32 },
+    // 逆行 (最後の音から順に並べる)
+    Retrograde,
+    // 反行 (pivotの音値を軸に音程を上下反転する)
+    Invert { pivot: i32 },
+}
+
+// 変形のエラー
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransformError {
+    // 変形すると音値が0～127を超える音符がある (変形前の音値)
+    NoteOutOfRange(i32),
+    // 分解能127以下で長さを表せない音符がある (変形前の長さ)
+    InvalidDuration(i32),
+    // 倍率が0以下
+    InvalidScale,
+}
+
+impl TransformError {
+    pub fn render_in(&self, lang: Lang) -> String {
+        match (self, lang) {
+            (TransformError::NoteOutOfRange(note), Lang::Ja) => {
+                format!("音値が0～127の範囲を超える音符があります (変形前の音値: {})", note)
+            }
+            (TransformError::NoteOutOfRange(note), Lang::En) => {
+                format!("a note goes out of the range 0-127 (note number before transforming: {})", note)
+            }
+            (TransformError::InvalidDuration(duration), Lang::Ja) => format!(
+                "分解能127以下で長さを表せない音符があります (変形前の長さ: {})",
+                duration
+            ),
+            (TransformError::InvalidDuration(duration), Lang::En) => format!(
+                "a note length cannot be represented with a resolution of 127 or less (length before transforming: {})",
+                duration
+            ),
+            (TransformError::InvalidScale, Lang::Ja) => "倍率は正の数で指定してください".to_string(),
+            (TransformError::InvalidScale, Lang::En) => "the scale must be positive".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render_in(Lang::Ja))
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+// トーンシーケンスを分けたもの (ブロックの中身とメインのシーケンスは別々に変形する)
+struct Sequences {
+    // VERSION, TEMPO, RESOLUTION
+    header: Vec<u8>,
+    // ブロック番号と中身
+    blocks: Vec<(u8, Vec<u8>)>,
+    main: Vec<u8>,
+}
+
+impl Sequences {
+    fn split(tseq: &[u8]) -> Self {
+        let header: Vec<u8> = tseq[..6].to_vec();
+        let mut blocks: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut pos: usize = 6;
+        while matches!(
+            tseq.get(pos).map(|b| *b as i8),
+            Some(tone_control::BLOCK_START)
+        ) {
+            let id: u8 = tseq[pos + 1];
+            pos += 2;
+            let start: usize = pos;
+            while tseq[pos] as i8 != tone_control::BLOCK_END {
+                pos += timeline::event_size(tseq[pos] as i8);
+            }
+            blocks.push((id, tseq[start..pos].to_vec()));
+            pos += 2;
+        }
+        let main: Vec<u8> = tseq[pos..].to_vec();
+        Sequences {
+            header,
+            blocks,
+            main,
+        }
+    }
+
+    fn join(&self) -> Vec<u8> {
+        let mut tseq: Vec<u8> = self.header.clone();
+        for (id, body) in self.blocks.iter() {
+            tseq.extend_from_slice(&[tone_control::BLOCK_START as u8, *id]);
+            tseq.extend_from_slice(body);
+            tseq.extend_from_slice(&[tone_control::BLOCK_END as u8, *id]);
+        }
+        tseq.extend_from_slice(&self.main);
+        tseq
+    }
+
+    fn bodies(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.blocks.iter().map(|(_, body)| body).chain([&self.main])
+    }
+
+    // ブロックの中身とメインのシーケンスをそれぞれfで置き換える
+    fn map<F>(&mut self, mut f: F) -> Result<(), TransformError>
+    where
+        F: FnMut(&[u8]) -> Result<Vec<u8>, TransformError>,
+    {
+        for (_, body) in self.blocks.iter_mut() {
+            *body = f(body)?;
+        }
+        self.main = f(&self.main)?;
+        Ok(())
+    }
+}
+
+// ブロックの定義とブロック再生は保ったまま変形する (逆行で音量の異なる音符がある場合はブロックを展開する)
+pub(crate) fn transform(song: &Song, transform: Transform) -> Result<Song, TransformError> {
+    let mut song: Song = song.clone();
+    let mut seqs = Sequences::split(&song.tseq);
+    match transform {
+        Transform::Transpose(semitones) => {
+            seqs.map(|body| map_notes(body, |note| note + semitones))?
+        }
+        Transform::Invert { pivot } => seqs.map(|body| map_notes(body, |note| 2 * pivot - note))?,
+        Transform::Scale {
+            numerator,
+            denominator,
+        } => scale(&mut song, &mut seqs, numerator, denominator)?,
+        Transform::Retrograde => retrograde(&mut song, &mut seqs),
+    }
+    song.tseq = seqs.join();
+    Ok(song)
+}
+
+// 音符 (REPEATの音符も) の音値を置き換える
+fn map_notes<F>(body: &[u8], f: F) -> Result<Vec<u8>, TransformError>
+where
+    F: Fn(i32) -> i32,
+{
+    let map = |note: u8| -> Result<u8, TransformError> {
+        let mapped: i32 = f(note.into());
+        if (0..=127).contains(&mapped) {
+            Ok(mapped as u8)
+        } else {
+            Err(TransformError::NoteOutOfRange(note.into()))
+        }
+    };
+    let mut mapped: Vec<u8> = body.to_vec();
+    let mut pos: usize = 0;
+    while let Some(&b) = body.get(pos) {
+        let cmd: i8 = b as i8;
+        if cmd >= 0 {
+            mapped[pos] = map(b)?;
+        } else if cmd == tone_control::REPEAT && body[pos + 2] as i8 != tone_control::SILENCE {
+            mapped[pos + 2] = map(body[pos + 2])?;
+        }
+        pos += timeline::event_size(cmd);
+    }
+    Ok(mapped)
+}
+
+// 音の長さを倍率にする。
+// 長さが整数にならないか127を超える場合は、曲の速さが変わらないように分解能を変えて表せるか探す。
+// 拍子も同じ倍率にし (3/4拍子の2倍は6/4拍子)、拍子で表せない場合は拍子と小節線を取り除く
+fn scale(
+    song: &mut Song,
+    seqs: &mut Sequences,
+    numerator: i32,
+    denominator: i32,
+) -> Result<(), TransformError> {
+    if numerator <= 0 || denominator <= 0 {
+        return Err(TransformError::InvalidScale);
+    }
+    let resolution: i32 = song.resolution();
+
+    // 音符の長さ (休符は分けて書けるので長さの上限は無い)
+    let mut notes: Vec<i32> = Vec::new();
+    let mut rests: Vec<i32> = Vec::new();
+    for body in seqs.bodies() {
+        let mut pos: usize = 0;
+        while let Some(&b) = body.get(pos) {
+            let cmd: i8 = b as i8;
+            match cmd {
+                tone_control::SILENCE => rests.push(body[pos + 1].into()),
+                tone_control::REPEAT if body[pos + 2] as i8 == tone_control::SILENCE => {
+                    rests.push(body[pos + 3].into())
+                }
+                tone_control::REPEAT => notes.push(body[pos + 3].into()),
+                _ if cmd >= 0 => notes.push(body[pos + 1].into()),
+                _ => {}
+            }
+            pos += timeline::event_size(cmd);
+        }
+    }
+
+    // 新しい分解能での長さ (整数にならない場合はNone)
+    let scaled = |duration: i32, new_resolution: i32| -> Option<i32> {
+        let n: i64 = duration as i64 * numerator as i64 * new_resolution as i64;
+        let d: i64 = denominator as i64 * resolution as i64;
+        if n % d == 0 {
+            Some((n / d) as i32)
+        } else {
+            None
+        }
+    };
+    let fits = |new_resolution: i32| -> bool {
+        notes
+            .iter()
+            .all(|&d| scaled(d, new_resolution).is_some_and(|d| d <= 127))
+            && rests.iter().all(|&d| scaled(d, new_resolution).is_some())
+    };
+    // 元の分解能に近い分解能から探す
+    let mut candidates: Vec<i32> = (1..=127).collect();
+    candidates.sort_by_key(|&r| ((r - resolution).abs(), -r));
+    let new_resolution: i32 = match candidates.into_iter().find(|&r| fits(r)) {
+        Some(r) => r,
+        None => {
+            let duration = notes
+                .iter()
+                .copied()
+                .find(|&d| scaled(d, resolution).is_none_or(|d| d > 127))
+                .unwrap_or(0);
+            return Err(TransformError::InvalidDuration(duration));
+        }
+    };
+
+    seqs.header[5] = new_resolution as u8;
+    seqs.map(|body| {
+        let mut scaled_body: Vec<u8> = Vec::with_capacity(body.len());
+        let mut pos: usize = 0;
+        while let Some(&b) = body.get(pos) {
+            let cmd: i8 = b as i8;
+            let size: usize = timeline::event_size(cmd);
+            match cmd {
+                tone_control::SILENCE => {
+                    let duration = scaled(body[pos + 1].into(), new_resolution).unwrap();
+                    push_rest(&mut scaled_body, duration);
+                }
+                tone_control::REPEAT => {
+                    let duration = scaled(body[pos + 3].into(), new_resolution).unwrap();
+                    if duration > 127 {
+                        // 休符のREPEATは長さを分けて繰り返す
+                        for _ in 0..body[pos + 1] {
+                            push_rest(&mut scaled_body, duration);
+                        }
+                    } else {
+                        scaled_body.extend_from_slice(&body[pos..pos + 3]);
+                        scaled_body.push(duration as u8);
+                    }
+                }
+                _ if cmd >= 0 => {
+                    let duration = scaled(body[pos + 1].into(), new_resolution).unwrap();
+                    scaled_body.extend_from_slice(&[b, duration as u8]);
+                }
+                _ => scaled_body.extend_from_slice(&body[pos..pos + size]),
+            }
+            pos += size;
+        }
+        Ok(scaled_body)
+    })?;
+
+    if let Some(ts) = song.time_signature {
+        song.time_signature = scale_time_signature(ts, numerator, denominator)
+            .filter(|ts| ts.bar_length(new_resolution).is_some());
+        if song.time_signature.is_none() {
+            let annotations = &song.annotations;
+            seqs.map(|body| {
+                Ok(filter_annotations(body, |index| {
+                    !matches!(annotations[index], Annotation::BarLine(_))
+                }))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// 127を超える休符は分けて書く
+fn push_rest(body: &mut Vec<u8>, mut duration: i32) {
+    while duration > 0 {
+        let part: i32 = duration.min(127);
+        body.extend_from_slice(&[tone_control::SILENCE as u8, part as u8]);
+        duration -= part;
+    }
+}
+
+// 拍子を倍率にする (そのままの分子・分母で書けない場合は約分する)
+fn scale_time_signature(
+    ts: TimeSignature,
+    numerator: i32,
+    denominator: i32,
+) -> Option<TimeSignature> {
+    let valid = |n: i32, d: i32| -> Option<TimeSignature> {
+        if (1..=99).contains(&n) && [1, 2, 4, 8, 16, 32, 64].contains(&d) {
+            Some(TimeSignature {
+                numerator: n as u8,
+                denominator: d as u8,
+            })
+        } else {
+            None
+        }
+    };
+    let n: i32 = ts.numerator as i32 * numerator;
+    let d: i32 = ts.denominator as i32 * denominator;
+    let g: i32 = gcd(n, d);
+    valid(n, d).or_else(|| valid(n / g, d / g))
+}
+
+fn gcd(a: i32, b: i32) -> i32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+// keepがfalseを返す注釈を取り除く
+fn filter_annotations<F>(body: &[u8], keep: F) -> Vec<u8>
+where
+    F: Fn(usize) -> bool,
+{
+    let mut filtered: Vec<u8> = Vec::with_capacity(body.len());
+    let mut pos: usize = 0;
+    while let Some(&b) = body.get(pos) {
+        let cmd: i8 = b as i8;
+        let size: usize = timeline::event_size(cmd);
+        if cmd != tone_control::ANNOTATION || keep(timeline::annotation_index(body, pos)) {
+            filtered.extend_from_slice(&body[pos..pos + size]);
+        }
+        pos += size;
+    }
+    filtered
+}
+
+// 逆行。ブロックの中身とメインのシーケンスをそれぞれ逆順にする (歌詞などの音符の前の注釈は音符と一緒に動かす)。
+// ループの開始位置と終了位置は入れ替える。
+// 音量は状態なので、全ての音符が同じ音量の場合は最初に1回だけ指定し、異なる場合はブロックを展開して音符ごとの音量にする
+fn retrograde(song: &mut Song, seqs: &mut Sequences) {
+    let mut volumes: Vec<i32> = Vec::new();
+    let res: Result<(), ()> = timeline::walk(&song.tseq, |_, event| {
+        if let Event::Note { volume, .. } = event {
+            if !volumes.contains(&volume) {
+                volumes.push(volume);
+            }
+        }
+        Ok(())
+    });
+    res.expect("invalid tone sequence");
+
+    let has_loop_end = song
+        .annotations
+        .iter()
+        .any(|a| matches!(a, Annotation::LoopEnd(_)));
+    let mut loop_start: Option<usize> = None;
+    for (index, annotation) in song.annotations.iter_mut().enumerate() {
+        *annotation = match annotation.clone() {
+            Annotation::LoopStart(span) => {
+                loop_start = Some(index);
+                Annotation::LoopEnd(span)
+            }
+            Annotation::LoopEnd(span) => Annotation::LoopStart(span),
+            annotation => annotation,
+        };
+    }
+    let annotations = &song.annotations;
+    // 音符の前に置かれる注釈
+    let attached = |index: usize| {
+        matches!(
+            annotations[index],
+            Annotation::Lyric(_) | Annotation::Source(_) | Annotation::BlockCall(_)
+        )
+    };
+
+    let mut main: Vec<u8> = Vec::new();
+    if volumes.len() <= 1 {
+        let volume: i32 = volumes.first().copied().unwrap_or(100);
+        if volume != 100 {
+            main.extend_from_slice(&[tone_control::SET_VOLUME as u8, volume as u8]);
+        }
+        for (_, body) in seqs.blocks.iter_mut() {
+            *body = reverse_units(body, &attached);
+        }
+        seqs.main = reverse_units(&seqs.main, &attached);
+        main.append(&mut seqs.main);
+    } else {
+        // (音符の前の注釈, イベント, 音量)
+        let mut units: Vec<(Vec<u8>, Vec<u8>, i32)> = Vec::new();
+        let mut pending: Vec<u8> = Vec::new();
+        let tseq: &[u8] = &song.tseq;
+        let res: Result<(), ()> = timeline::walk(tseq, |_, event| {
+            let (bytes, volume): (Vec<u8>, i32) = match event {
+                Event::Annotation(index) => {
+                    let mut bytes: Vec<u8> = vec![tone_control::ANNOTATION as u8];
+                    bytes.extend_from_slice(&(index as u32).to_be_bytes()[1..]);
+                    if attached(index) {
+                        pending.extend(bytes);
+                        return Ok(());
+                    }
+                    (bytes, -1)
+                }
+                Event::Note {
+                    note,
+                    duration,
+                    volume,
+                } => (vec![note as u8, duration as u8], volume),
+                Event::Rest { duration } => (vec![tone_control::SILENCE as u8, duration as u8], -1),
+            };
+            units.push((std::mem::take(&mut pending), bytes, volume));
+            Ok(())
+        });
+        res.expect("invalid tone sequence");
+        let mut current_volume: i32 = 100;
+        for (before, bytes, volume) in units.into_iter().rev() {
+            main.extend(before);
+            if volume >= 0 && volume != current_volume {
+                main.extend_from_slice(&[tone_control::SET_VOLUME as u8, volume as u8]);
+                current_volume = volume;
+            }
+            main.extend(bytes);
+        }
+        seqs.blocks.clear();
+    }
+
+    // 曲の最後の小節線は逆順にすると曲の最初になるので最後に戻す
+    let mut pos: usize = 0;
+    if main
+        .first()
+        .is_some_and(|&b| b as i8 == tone_control::SET_VOLUME)
+    {
+        pos += timeline::event_size(tone_control::SET_VOLUME);
+    }
+    if main
+        .get(pos)
+        .is_some_and(|&b| b as i8 == tone_control::ANNOTATION)
+        && matches!(
+            song.annotations[timeline::annotation_index(&main, pos)],
+            Annotation::BarLine(_)
+        )
+    {
+        let size: usize = timeline::event_size(tone_control::ANNOTATION);
+        let bar_line: Vec<u8> = main.drain(pos..pos + size).collect();
+        main.extend(bar_line);
+    }
+
+    // 終了位置を省略したループは曲の最初からループの開始位置までになる
+    if let (Some(index), false) = (loop_start, has_loop_end) {
+        let span = match &song.annotations[index] {
+            Annotation::LoopEnd(span) => span.clone(),
+            _ => unreachable!(),
+        };
+        let mut start: Vec<u8> = vec![tone_control::ANNOTATION as u8];
+        start.extend_from_slice(&(song.annotations.len() as u32).to_be_bytes()[1..]);
+        song.annotations.push(Annotation::LoopStart(span));
+        main.splice(0..0, start);
+    }
+    seqs.main = main;
+}
+
+// イベントごとに逆順にする (attachedがtrueの注釈は直後のイベントと一緒に動かす)。音量の指定は取り除く
+fn reverse_units<F>(body: &[u8], attached: &F) -> Vec<u8>
+where
+    F: Fn(usize) -> bool,
+{
+    let mut units: Vec<&[u8]> = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut pos: usize = 0;
+    while let Some(&b) = body.get(pos) {
+        let cmd: i8 = b as i8;
+        let size: usize = timeline::event_size(cmd);
+        if cmd == tone_control::ANNOTATION && attached(timeline::annotation_index(body, pos)) {
+            start.get_or_insert(pos);
+        } else if cmd != tone_control::SET_VOLUME {
+            units.push(&body[start.take().unwrap_or(pos)..pos + size]);
+        }
+        pos += size;
+    }
+    if let Some(start) = start {
+        units.push(&body[start..]);
+    }
+    units.into_iter().rev().flatten().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform_src(src: &str, transform: Transform) -> Result<String, TransformError> {
+        let song = crate::parse(src).unwrap();
+        let transformed = super::transform(&song, transform)?;
+        let mml = transformed.to_mml();
+        // 出力したMMLは同じ曲になる
+        let reparsed = crate::parse(&mml).unwrap();
+        assert_eq!(
+            reparsed.to_smf(crate::inst::INSTRUMENTS[0]).unwrap(),
+            transformed.to_smf(crate::inst::INSTRUMENTS[0]).unwrap(),
+            "{}",
+            mml
+        );
+        Ok(mml)
+    }
+
+    #[test]
+    fn transpose_and_invert() {
+        let src = "{0 C D } $0 [3 E ] O9 F";
+        assert_eq!(
+            transform_src(src, Transform::Transpose(2)).unwrap(),
+            "T120 %64\n{0 O4 D4 O4 E4 }\n$0 [3 O4 F#4 ] O9 G4\n"
+        );
+        assert_eq!(
+            transform_src(src, Transform::Transpose(3)),
+            Err(TransformError::NoteOutOfRange(125))
+        );
+        assert_eq!(
+            transform_src("C D E", Transform::Invert { pivot: 62 }).unwrap(),
+            "T120 %64\nO4 E4 O4 D4 O4 C4\n"
+        );
+    }
+
+    #[test]
+    fn scale_durations() {
+        let src = "#TIME 3/4\n{0 C8 D8 } L4 $0 E | F2. | R1 R2";
+        assert_eq!(
+            transform_src(
+                src,
+                Transform::Scale {
+                    numerator: 2,
+                    denominator: 1
+                }
+            )
+            .unwrap(),
+            "#TIME 6/4\nT120 %64\n{0 O4 C4 O4 D4 }\n$0 O4 E2 |\nO4 F1. |\nR(127) R64 R1\n"
+        );
+        // 分解能64では32分音符の1/3を表せないので分解能を変える
+        assert_eq!(
+            transform_src(
+                "C32 D4",
+                Transform::Scale {
+                    numerator: 1,
+                    denominator: 3
+                }
+            )
+            .unwrap(),
+            "T120 %96\nO4 C96 O4 D12\n"
+        );
+        assert_eq!(
+            transform_src(
+                "C1 C(1)",
+                Transform::Scale {
+                    numerator: 3,
+                    denominator: 1
+                }
+            ),
+            Err(TransformError::InvalidDuration(64))
+        );
+    }
+
+    #[test]
+    fn retrograde_sequence() {
+        let src = "{0 C D\"ら\" } V80 $0 E | F R";
+        assert_eq!(
+            transform_src(src, Transform::Retrograde).unwrap(),
+            "T120 %64\n{0 O4 D4\"ら\" O4 C4 }\nV80 R4 O4 F4 |\nO4 E4 $0\n"
+        );
+        // 最後の小節線は最後のまま
+        assert_eq!(
+            transform_src("#TIME 2/4\nC D | E2 |", Transform::Retrograde).unwrap(),
+            "#TIME 2/4\nT120 %64\nO4 E2 |\nO4 D4 O4 C4 |\n"
+        );
+        // 音量が異なる音符がある場合はブロックを展開する
+        let src = "{0 C V50 D } $0 !loopstart E";
+        assert_eq!(
+            transform_src(src, Transform::Retrograde).unwrap(),
+            "T120 %64\n!loopstart V50 O4 E4 !loopend O4 D4 V100 O4 C4\n"
+        );
+    }
+}
@@ -0,0 +1,402 @@
+// mml-lsp
+// author: Leonardone @ NEETSDKASU
+
+use mml_core::{Analysis, Lang, MMLError, ParseOptions, Position, Span};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+// ホバーで表示する再生位置の数の上限 (ブロックやリピートで何度も再生される音符)
+const MAX_HOVER_TICKS: usize = 8;
+
+// エディタで開いているMMLのテキストと解析結果
+pub(crate) struct Document {
+    uri: String,
+    path: Option<PathBuf>,
+    text: String,
+    // エラーがある場合も読み込めた分の解析結果でホバーなどに答える
+    analysis: Option<Analysis>,
+    errors: Vec<MMLError>,
+}
+
+impl Document {
+    pub(crate) fn new(uri: &str, text: String, options: &ParseOptions) -> Self {
+        let path = uri_to_path(uri);
+        let (analysis, errors) = mml_core::analyze_partial(&text, path.as_deref(), options);
+        Self {
+            uri: uri.to_string(),
+            path,
+            text,
+            analysis,
+            errors,
+        }
+    }
+
+    // エラー位置のファイル名 (mml_core::Positionのfileと同じ書式)
+    fn file_name(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.display().to_string())
+    }
+
+    // このテキストの中の位置か (#includeで取り込んだファイルやマクロの値の位置ではないか)
+    fn is_here(&self, pos: &Position) -> bool {
+        pos.file == self.file_name()
+    }
+
+    fn line(&self, row: usize) -> &str {
+        self.text.split('\n').nth(row - 1).unwrap_or("")
+    }
+
+    // LSPの位置 (0始まりの行とUTF-16単位の文字位置) をMMLの位置 (1始まりの行と文字単位の列) にする
+    pub(crate) fn position(&self, line: usize, character: usize) -> (usize, usize) {
+        let row = line + 1;
+        let col = char_col(self.line(row), character);
+        (row, col)
+    }
+
+    pub(crate) fn range(&self, span: &Span) -> Value {
+        range(&self.text, span)
+    }
+
+    fn contains(&self, span: &Span, row: usize, col: usize) -> bool {
+        self.is_here(&span.start)
+            && span.start.row == row
+            && (span.start.col..end_col(span)).contains(&col)
+    }
+
+    // MMLErrorの診断情報 (他のファイルの中のエラーは先頭に位置を付けて報告する)
+    pub(crate) fn diagnostics(&self, lang: Lang) -> Vec<Value> {
+        self.errors
+            .iter()
+            .map(|error| {
+                let (range, message) = match error.span() {
+                    Some(span) if self.is_here(&span.start) => {
+                        (self.range(span), error.message_in(lang).to_string())
+                    }
+                    Some(span) => (
+                        json!({"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}),
+                        format!("{}: {}", location(&span.start), error.message_in(lang)),
+                    ),
+                    None => (
+                        json!({"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}),
+                        error.render_in(lang),
+                    ),
+                };
+                json!({
+                    "range": range,
+                    "severity": 1,
+                    "code": error.code(),
+                    "source": "mml",
+                    "message": message,
+                })
+            })
+            .collect()
+    }
+
+    // カーソル位置の音符・休符の音の高さと再生位置
+    pub(crate) fn hover(&self, row: usize, col: usize, lang: Lang) -> Option<Value> {
+        let analysis = self.analysis.as_ref()?;
+        let sounds: Vec<&mml_core::Sound> = analysis
+            .sounds
+            .iter()
+            .filter(|sound| self.contains(&sound.span, row, col))
+            .collect();
+        let first = sounds.first()?;
+        let song = &analysis.song;
+
+        let mut lines: Vec<String> = Vec::new();
+        match first.note {
+            Some(note) => {
+                let hz: f64 = 440.0 * 2f64.powf((note - 69) as f64 / 12.0);
+                lines.push(tr!(
+                    lang,
+                    "**{}** (ノート番号 {}、{:.2} Hz)",
+                    "**{}** (note number {}, {:.2} Hz)",
+                    mml_core::note_name(note),
+                    note,
+                    hz
+                ));
+                lines.push(tr!(lang, "音量: {}", "volume: {}", first.volume));
+            }
+            None => lines.push(tr!(lang, "**休符**", "**rest**")),
+        }
+        lines.push(tr!(
+            lang,
+            "長さ: {} ({:.3}秒)",
+            "duration: {} ({:.3} s)",
+            first.duration,
+            song.seconds(first.duration)
+        ));
+
+        // 移調したブロック再生では音の高さが再生位置ごとに違う
+        let mut ticks: Vec<String> = Vec::new();
+        for sound in sounds.iter().take(MAX_HOVER_TICKS) {
+            let mut tick = tr!(
+                lang,
+                "- {} tick ({:.3}秒)",
+                "- tick {} ({:.3} s)",
+                sound.tick,
+                song.seconds(sound.tick)
+            );
+            if let Some(note) = sound.note.filter(|note| Some(*note) != first.note) {
+                tick.push(' ');
+                tick.push_str(&mml_core::note_name(note));
+            }
+            ticks.push(tick);
+        }
+        if sounds.len() > MAX_HOVER_TICKS {
+            ticks.push(tr!(
+                lang,
+                "- ほか{}回",
+                "- and {} more",
+                sounds.len() - MAX_HOVER_TICKS
+            ));
+        }
+        lines.push(format!(
+            "{}\n{}",
+            tr!(lang, "再生位置:", "played at:"),
+            ticks.join("\n")
+        ));
+
+        Some(json!({
+            "contents": {"kind": "markdown", "value": lines.join("\n\n")},
+            "range": self.range(&first.span),
+        }))
+    }
+
+    // カーソル位置のブロック再生 ($番号、$名前) のブロックの定義位置
+    pub(crate) fn definition(&self, row: usize, col: usize) -> Option<Value> {
+        let analysis = self.analysis.as_ref()?;
+        let block = analysis
+            .blocks
+            .iter()
+            .find(|block| block.calls.iter().any(|span| self.contains(span, row, col)))?;
+        let span = &block.span;
+        if self.is_here(&span.start) {
+            return Some(json!({"uri": self.uri, "range": self.range(span)}));
+        }
+        // #includeで取り込んだファイルの中のブロック
+        let path = PathBuf::from(span.start.file.as_ref()?);
+        let text = std::fs::read_to_string(&path).unwrap_or_default();
+        Some(json!({"uri": path_to_uri(&path), "range": range(&text, span)}))
+    }
+
+    // #INSTRUMENT の後で楽器番号と楽器名を補完する
+    pub(crate) fn completion(&self, row: usize, col: usize, lang: Lang) -> Vec<Value> {
+        let before: String = self.line(row).chars().take(col - 1).collect();
+        let digits: usize = before
+            .chars()
+            .rev()
+            .take_while(char::is_ascii_digit)
+            .count();
+        let directive = before[..before.len() - digits].trim_end();
+        if directive.len() == before.len() - digits
+            || !directive.eq_ignore_ascii_case("#INSTRUMENT")
+        {
+            return Vec::new();
+        }
+        let line = row - 1;
+        let start = before.encode_utf16().count() - digits;
+        let end = start + digits;
+        mml_core::INSTRUMENTS
+            .iter()
+            .map(|inst| {
+                let number = (*inst as i32).to_string();
+                json!({
+                    "label": format!("{} - {}", number, inst.name_in(lang)),
+                    "kind": 12,
+                    "detail": inst.category().name_in(lang),
+                    "filterText": number,
+                    "sortText": format!("{:03}", *inst as i32),
+                    "textEdit": {
+                        "range": {
+                            "start": {"line": line, "character": start},
+                            "end": {"line": line, "character": end},
+                        },
+                        "newText": number,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+fn location(pos: &Position) -> String {
+    match &pos.file {
+        Some(file) => format!("{}:{}:{}", file, pos.row, pos.col),
+        None => format!("{}:{}", pos.row, pos.col),
+    }
+}
+
+// 範囲の終わりの列 (startとendが同じ場合や行が違う場合はstartの1文字)
+fn end_col(span: &Span) -> usize {
+    if span.end.row == span.start.row && span.end.col > span.start.col {
+        span.end.col
+    } else {
+        span.start.col + 1
+    }
+}
+
+fn range(text: &str, span: &Span) -> Value {
+    let line = text.split('\n').nth(span.start.row - 1).unwrap_or("");
+    json!({
+        "start": {"line": span.start.row - 1, "character": utf16_col(line, span.start.col)},
+        "end": {"line": span.start.row - 1, "character": utf16_col(line, end_col(span))},
+    })
+}
+
+// 1始まりの文字単位の列をUTF-16単位の文字位置にする
+fn utf16_col(line: &str, col: usize) -> usize {
+    line.chars().take(col - 1).map(char::len_utf16).sum()
+}
+
+// UTF-16単位の文字位置を1始まりの文字単位の列にする
+fn char_col(line: &str, character: usize) -> usize {
+    let mut units: usize = 0;
+    let mut col: usize = 1;
+    for ch in line.chars() {
+        if units >= character {
+            break;
+        }
+        units += ch.len_utf16();
+        col += 1;
+    }
+    col
+}
+
+// file:///home/user/music.mml のようなURIをパスにする (file以外のURIはNone)
+pub(crate) fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let rest = uri.strip_prefix("file://")?;
+    // ホスト名 (localhostなど) は読み飛ばす
+    let path = &rest[rest.find('/')?..];
+    let mut bytes: Vec<u8> = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex: String = iter.by_ref().take(2).map(char::from).collect();
+            bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // Windowsのドライブ名 (/C:/Users/...)
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    };
+    Some(PathBuf::from(path))
+}
+
+pub(crate) fn path_to_uri(path: &Path) -> String {
+    let path = path.display().to_string().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~:".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        let options = ParseOptions::default();
+        let doc = Document::new(
+            "untitled:1",
+            "C D\n\u{1F3B5}E F\"歌\" G".to_string(),
+            &options,
+        );
+        assert_eq!(doc.position(1, 2), (2, 2));
+        assert_eq!(doc.position(1, 5), (2, 5));
+        assert_eq!(utf16_col("\u{1F3B5}E F", 4), 4);
+        assert_eq!(char_col("\u{1F3B5}E F", 4), 4);
+
+        let uri = "file:///home/user/my%20music.mml";
+        let path = uri_to_path(uri).unwrap();
+        assert_eq!(path, PathBuf::from("/home/user/my music.mml"));
+        assert_eq!(path_to_uri(&path), uri);
+        assert_eq!(
+            uri_to_path("file:///C:/mml/a.mml"),
+            Some(PathBuf::from("C:/mml/a.mml"))
+        );
+        assert_eq!(uri_to_path("untitled:1"), None);
+    }
+
+    #[test]
+    fn features() {
+        let options = ParseOptions::default();
+        let doc = Document::new(
+            "untitled:1",
+            "#INSTRUMENT 3\n{0 C D }\n[2 $0 ] E".to_string(),
+            &options,
+        );
+
+        let hover = doc.hover(2, 4, Lang::En).unwrap();
+        let value = hover["contents"]["value"].as_str().unwrap();
+        assert!(
+            value.starts_with("**O4 C** (note number 60, 261.63 Hz)"),
+            "{}",
+            value
+        );
+        assert!(
+            value.contains("- tick 0 (0.000 s)\n- tick 32 (1.000 s)"),
+            "{}",
+            value
+        );
+        assert!(doc.hover(2, 3, Lang::En).is_none());
+
+        let definition = doc.definition(3, 5).unwrap();
+        assert_eq!(
+            definition["range"]["start"],
+            json!({"line": 1, "character": 0})
+        );
+        assert!(doc.definition(3, 1).is_none());
+
+        let items = doc.completion(1, 14, Lang::En);
+        assert_eq!(items.len(), mml_core::INSTRUMENTS.len());
+        assert_eq!(items[0]["textEdit"]["newText"], "1");
+        assert_eq!(items[0]["textEdit"]["range"]["start"]["character"], 12);
+        assert!(doc.completion(2, 3, Lang::En).is_empty());
+
+        let doc = Document::new("untitled:1", "C D\nE X".to_string(), &options);
+        let diagnostics = doc.diagnostics(Lang::En);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["code"], "E0008");
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({"line": 1, "character": 2})
+        );
+    }
+
+    // 編集中でエラーのあるMMLでも読み込めた分でホバーと定義へのジャンプに答える
+    #[test]
+    fn features_with_errors() {
+        let options = ParseOptions::default();
+        let sources = [
+            "#TIME 1/4\n{0 C D }\nE X $0",
+            "#TIME 1/4\n{0 C D }\nE $0 | F",
+            "#TIME 1/4\n{0 C D }\nE | $0 !loopend",
+        ];
+        for src in sources.iter() {
+            let doc = Document::new("untitled:1", src.to_string(), &options);
+            assert_eq!(doc.diagnostics(Lang::En).len(), 1, "{}", src);
+            let hover = doc.hover(3, 1, Lang::En).expect(src);
+            let value = hover["contents"]["value"].as_str().unwrap();
+            assert!(value.starts_with("**O4 E**"), "{}", value);
+            let col = src.rfind('\n').map(|pos| src[pos..].find('$').unwrap());
+            let definition = doc.definition(3, col.unwrap()).expect(src);
+            assert_eq!(
+                definition["range"]["start"],
+                json!({"line": 1, "character": 0})
+            );
+        }
+    }
+}
@@ -0,0 +1,219 @@
+// mml-lsp
+// author: Leonardone @ NEETSDKASU
+
+use mml_core::Lang;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+
+// 表示言語に合わせて日本語か英語の書式で文字列を作る
+macro_rules! tr {
+    ($lang:expr, $ja:literal, $en:literal $(, $arg:expr)* $(,)?) => {
+        match $lang {
+            Lang::Ja => format!($ja $(, $arg)*),
+            Lang::En => format!($en $(, $arg)*),
+        }
+    };
+}
+
+mod document;
+mod rpc;
+
+use document::Document;
+
+// JSON-RPCのエラーコード
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// 標準入出力でLanguage Server Protocolのメッセージをやり取りする
+fn main() -> Result<(), ()> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+
+    let mut server = Server::new();
+
+    loop {
+        let message = match rpc::read_message(&mut input) {
+            Ok(Some(message)) => message,
+            // exitの前に入力が終わった
+            Ok(None) => return Err(()),
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("{}", error);
+                continue;
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                return Err(());
+            }
+        };
+
+        let method = match message["method"].as_str() {
+            Some(method) => method,
+            // クライアントからの応答 (このサーバーからは要求を送らない)
+            None => continue,
+        };
+
+        if method == "exit" {
+            return if server.shutdown { Ok(()) } else { Err(()) };
+        }
+
+        let params = &message["params"];
+        let replies: Vec<Value> = match message.get("id") {
+            Some(id) => {
+                let reply = match server.request(method, params) {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Err((code, msg)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {"code": code, "message": msg},
+                    }),
+                };
+                vec![reply]
+            }
+            None => server.notify(method, params),
+        };
+
+        for reply in replies.iter() {
+            if let Err(error) = rpc::write_message(&mut output, reply) {
+                eprintln!("{}", error);
+                return Err(());
+            }
+        }
+    }
+}
+
+struct Server {
+    lang: Lang,
+    options: mml_core::ParseOptions,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+impl Server {
+    // 表示言語は環境変数LANGから決める (initializeのlocaleの指定があればそれ)
+    fn new() -> Self {
+        let lang = std::env::var("LANG")
+            .ok()
+            .and_then(|lang| Lang::parse(&lang))
+            .unwrap_or(Lang::Ja);
+        Self {
+            lang,
+            options: mml_core::ParseOptions::default(),
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        match method {
+            "initialize" => Ok(self.initialize(params)),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/hover" => {
+                let (doc, row, col) = self.document_position(params)?;
+                Ok(doc.hover(row, col, self.lang).unwrap_or(Value::Null))
+            }
+            "textDocument/definition" => {
+                let (doc, row, col) = self.document_position(params)?;
+                Ok(doc.definition(row, col).unwrap_or(Value::Null))
+            }
+            "textDocument/completion" => {
+                let (doc, row, col) = self.document_position(params)?;
+                Ok(Value::from(doc.completion(row, col, self.lang)))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+        }
+    }
+
+    // initializationOptionsで入力モードと文字コードを指定できる
+    // {"inputMode": "japanese", "encoding": "shift_jis"}
+    fn initialize(&mut self, params: &Value) -> Value {
+        if let Some(lang) = params["locale"].as_str().and_then(Lang::parse) {
+            self.lang = lang;
+        }
+        let init = &params["initializationOptions"];
+        if let Some(mode) = init["inputMode"]
+            .as_str()
+            .and_then(mml_core::InputMode::parse)
+        {
+            self.options.input_mode = mode;
+        }
+        if let Some(encoding) = init["encoding"]
+            .as_str()
+            .and_then(mml_core::TextEncoding::parse)
+        {
+            self.options.encoding = encoding;
+        }
+        json!({
+            "capabilities": {
+                // 変更のたびにテキスト全体を受け取る
+                "textDocumentSync": 1,
+                "hoverProvider": true,
+                "definitionProvider": true,
+                "completionProvider": {"triggerCharacters": [" "]},
+            },
+            "serverInfo": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+        })
+    }
+
+    // 通知を処理し、送り返す通知 (診断情報) を返す
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri,
+            None => return Vec::new(),
+        };
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // 変更後のテキスト全体 (textDocumentSyncが1なので最後の変更が全体)
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            _ => None,
+        };
+        let text = match text {
+            Some(text) => text.to_string(),
+            None => return Vec::new(),
+        };
+        let doc = Document::new(uri, text, &self.options);
+        let diagnostics = doc.diagnostics(self.lang);
+        self.documents.insert(uri.to_string(), doc);
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+
+    // 開いているテキストとカーソル位置 (1始まりの行と列)
+    fn document_position(
+        &self,
+        params: &Value,
+    ) -> Result<(&Document, usize, usize), (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str();
+        let line = params["position"]["line"].as_u64();
+        let character = params["position"]["character"].as_u64();
+        let (doc, line, character) =
+            match (uri.and_then(|uri| self.documents.get(uri)), line, character) {
+                (Some(doc), Some(line), Some(character)) => (doc, line, character),
+                _ => return Err((INVALID_PARAMS, "unknown document or position".to_string())),
+            };
+        let (row, col) = doc.position(line as usize, character as usize);
+        Ok((doc, row, col))
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": {"uri": uri, "diagnostics": diagnostics},
+    })
+}
@@ -0,0 +1,39 @@
+// mml-lsp
+// author: Leonardone @ NEETSDKASU
+
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+// Content-Lengthのヘッダーと本文 (JSON) のメッセージを読み込む (入力の終わりの場合はNone)
+// ヘッダーや本文が不正な場合はInvalidDataの入出力エラーを返す
+pub(crate) fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    let mut body: Vec<u8> = vec![0; length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub(crate) fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}